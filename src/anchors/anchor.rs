@@ -1,34 +1,152 @@
 use ethabi::Token;
+use serde_derive::{Deserialize, Serialize};
 use std::fmt;
+use std::sync::{Arc, RwLock};
 use tokio_core::reactor;
 use web3::contract::tokens::Tokenize;
+use web3::contract::Options;
+use web3::futures::future::{self, Either};
 use web3::futures::prelude::*;
 use web3::futures::sync::mpsc;
 use web3::futures::try_ready;
-use web3::types::{BlockHeader, BlockId, BlockNumber, H256, U64};
+use web3::types::{BlockHeader, BlockId, BlockNumber, H256, U256, U64};
 use web3::DuplexTransport;
 
+use super::backfill::BackfillAnchors;
+use super::check::{is_anchor_leader, AnchorNeeded};
 use crate::eth::transaction::SendTransaction;
 use crate::extensions::flushed::Flushed;
 use crate::extensions::timeout::Timeout;
+use crate::metrics;
 use crate::relay::Network;
 
 /// Represents a block on the sidechain to be anchored to the homechain
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Anchor {
     block_hash: H256,
     block_number: U64,
 }
 
 impl Anchor {
-    ///Returns a SendTransaction Future to post the anchor to the ERC20Relay contract
+    /// Returns a new Anchor
+    ///
+    /// # Arguments
+    ///
+    /// * `block_hash` - Hash of the anchored block
+    /// * `block_number` - Number of the anchored block
+    pub fn new(block_hash: H256, block_number: U64) -> Self {
+        Anchor {
+            block_hash,
+            block_number,
+        }
+    }
+
+    /// Returns a Future that resolves to the anchor of a block, or None if the block is not found
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the block is
+    /// * `block_number` - Number of the block to anchor
+    pub fn fetch<T: DuplexTransport + 'static>(
+        source: &Network<T>,
+        block_number: U64,
+    ) -> Box<dyn Future<Item = Option<Anchor>, Error = ()>> {
+        let network_type = source.network_type;
+        let block_id = BlockId::Number(BlockNumber::Number(block_number));
+        Box::new(
+            source
+                .web3
+                .eth()
+                .block(block_id)
+                .map(move |block| match block {
+                    Some(b) => {
+                        if b.number.is_none() {
+                            warn!("no block number in anchor block on {:?}", network_type);
+                            return None;
+                        }
+
+                        if b.hash.is_none() {
+                            warn!("no block hash in anchor block on {:?}", network_type);
+                            return None;
+                        }
+
+                        Some(Anchor {
+                            block_hash: b.hash.unwrap(),
+                            block_number: b.number.unwrap(),
+                        })
+                    }
+                    None => {
+                        warn!("no block found for anchor confirmations on {:?}", network_type);
+                        None
+                    }
+                })
+                .map_err(move |e| {
+                    error!("error waiting for anchor confirmations on {:?}: {:?}", network_type, e);
+                }),
+        )
+    }
+
+    /// Returns a Future that resolves to the anchor at the given index on the ERC20Relay contract
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the anchors are posted
+    /// * `index` - Index of the anchor on the contract
+    pub fn on_contract<T: DuplexTransport + 'static>(
+        target: &Network<T>,
+        index: u64,
+    ) -> Box<dyn Future<Item = Anchor, Error = ()>> {
+        let network_type = target.network_type;
+        Box::new(
+            target
+                .relay
+                .query(
+                    "anchors",
+                    U256::from(index),
+                    None,
+                    Options::default(),
+                    BlockNumber::Latest,
+                )
+                .map(|(block_hash, block_number): (H256, U256)| {
+                    Anchor::new(block_hash, U64::from(block_number.low_u64()))
+                })
+                .map_err(move |e| {
+                    error!("error getting anchor {} on {:?}: {:?}", index, network_type, e);
+                }),
+        )
+    }
+
+    pub fn block_hash(&self) -> H256 {
+        self.block_hash
+    }
+
+    pub fn block_number(&self) -> U64 {
+        self.block_number
+    }
+
+    ///Returns a Future that posts the anchor to the ERC20Relay contract, unless it is already there and needs no vote
+    ///from this relay
     ///
     /// # Arguments
     ///
     /// * `target` - Network to post the anchor
-    fn process<T: DuplexTransport + 'static>(&self, target: &Network<T>) -> Box<dyn Future<Item = (), Error = ()>> {
-        info!("anchoring block {} to {:?}", self, target.network_type);
-        Box::new(SendTransaction::new(target, "anchor", self, target.retries).or_else(|_| Ok(())))
+    pub fn process<T: DuplexTransport + 'static>(&self, target: &Network<T>) -> Box<dyn Future<Item = (), Error = ()>> {
+        let anchor = *self;
+        let target = target.clone();
+        Box::new(
+            AnchorNeeded::new(&target, self)
+                // Better to pay for an anchor twice than to miss it
+                .or_else(|_| Ok(true))
+                .and_then(move |needed| {
+                    if needed {
+                        info!("anchoring block {} to {:?}", anchor, target.network_type);
+                        Either::A(SendTransaction::new(&target, "anchor", &anchor, target.retries).or_else(|_| Ok(())))
+                    } else {
+                        info!("block {} is already anchored on {:?}", anchor, target.network_type);
+                        Either::B(future::ok(()))
+                    }
+                }),
+        )
     }
 }
 
@@ -100,6 +218,19 @@ impl<T: DuplexTransport + 'static> Future for ProcessAnchors<T> {
                             return Err(());
                         }
                     };
+                    match self.source.pause.write() {
+                        Ok(mut pause) => {
+                            if pause.buffer_anchor(&a) {
+                                info!("anchors are paused, buffering anchor {}", a);
+                                metrics::paused(&pause.status());
+                                continue;
+                            }
+                        }
+                        Err(e) => {
+                            error!("error acquiring pause lock: {:?}", e);
+                            return Err(());
+                        }
+                    };
                     self.handle.spawn(a.process(&self.target));
                 }
                 None => {
@@ -116,6 +247,8 @@ pub struct WatchAnchors<T: DuplexTransport + 'static> {
     header: Option<BlockHeader>,
     tx: mpsc::UnboundedSender<Anchor>,
     source: Network<T>,
+    target: Network<T>,
+    verified: Arc<RwLock<u64>>,
     handle: reactor::Handle,
 }
 
@@ -125,8 +258,14 @@ impl<T: DuplexTransport + 'static> WatchAnchors<T> {
     /// # Arguments
     ///
     /// * `source` - Network where the block headers are found
+    /// * `target` - Network where the anchors are posted
     /// * `handle` - Handle to spawn new futures
-    pub fn new(source: &Network<T>, tx: mpsc::UnboundedSender<Anchor>, handle: &reactor::Handle) -> Self {
+    pub fn new(
+        source: &Network<T>,
+        target: &Network<T>,
+        tx: mpsc::UnboundedSender<Anchor>,
+        handle: &reactor::Handle,
+    ) -> Self {
         let network_type = source.network_type;
         let h = handle.clone();
         let handle = handle.clone();
@@ -137,7 +276,7 @@ impl<T: DuplexTransport + 'static> WatchAnchors<T> {
             .web3
             .eth_subscribe()
             .subscribe_new_heads()
-            .flushed(&flushed)
+            .flushed(&flushed, &source.shutdown)
             .timeout(timeout, &h)
             .map_err(move |e| {
                 error!("error in anchor stream on {:?}: {:?}", network_type, e);
@@ -148,10 +287,17 @@ impl<T: DuplexTransport + 'static> WatchAnchors<T> {
             header: None,
             tx,
             source: source.clone(),
+            target: target.clone(),
+            verified: Arc::new(RwLock::new(0)),
             handle,
         }
     }
 
+    /// Returns a BackfillAnchors Future, that checks the anchors on the target and posts the missing ones
+    pub fn backfill(&self) -> BackfillAnchors<T> {
+        BackfillAnchors::new(&self.source, &self.target, self.tx.clone(), &self.verified)
+    }
+
     fn process_header(&self, header: &BlockHeader) {
         header
             .number
@@ -165,53 +311,37 @@ impl<T: DuplexTransport + 'static> WatchAnchors<T> {
     fn send_anchor(&self, block_number: U64) {
         let tx = self.tx.clone();
         let network_type = self.source.network_type;
-        let web3 = self.source.web3.clone();
-        let handle = self.handle.clone();
         let confirmations = self.source.confirmations;
-        match block_number
+        let remainder = block_number
             .checked_rem(self.source.anchor_frequency.into())
-            .map(|u| u.low_u64())
-        {
+            .map(|u| u.low_u64());
+        if remainder == Some(0) {
+            // Once per anchor height, look for anchors missed while disconnected
+            self.handle.spawn(self.backfill());
+        }
+        match remainder {
             Some(c) if c == self.source.confirmations => {
-                let block_id = BlockId::Number(BlockNumber::Number(block_number - confirmations));
-
-                handle.spawn(
-                    web3.eth()
-                        .block(block_id)
-                        .and_then(move |block| match block {
-                            Some(b) => {
-                                if b.number.is_none() {
-                                    warn!("no block number in anchor block on {:?}", network_type);
-                                    return Ok(());
-                                }
-
-                                if b.hash.is_none() {
-                                    warn!("no block hash in anchor block on {:?}", network_type);
-                                    return Ok(());
-                                }
-
-                                let block_hash: H256 = b.hash.unwrap();
-                                let block_number: U64 = b.number.unwrap();
-
-                                let anchor = Anchor {
-                                    block_hash,
-                                    block_number,
-                                };
-
-                                info!("anchor block confirmed, anchoring on {:?}: {}", network_type, &anchor);
-
-                                tx.unbounded_send(anchor).unwrap();
-                                Ok(())
-                            }
-                            None => {
-                                warn!("no block found for anchor confirmations on {:?}", network_type);
-                                Ok(())
+                let height = block_number.as_u64() - confirmations;
+                if !is_anchor_leader(
+                    &self.source.anchor_leaders,
+                    self.source.account,
+                    height,
+                    self.source.anchor_frequency,
+                ) {
+                    // The leader posts it. If it does not, BackfillAnchors on every relay finds the height missing
+                    // between the anchors on the contract once the next height is confirmed, and posts it
+                    debug!("not the leader for anchor #{} on {:?}, skipping", height, network_type);
+                    return;
+                }
+                self.handle.spawn(
+                    Anchor::fetch(&self.source, block_number - confirmations).map(move |anchor| {
+                        if let Some(anchor) = anchor {
+                            info!("anchor block confirmed, anchoring on {:?}: {}", network_type, &anchor);
+                            if tx.unbounded_send(anchor).is_err() {
+                                warn!("error sending anchor on {:?}, processing stopped", network_type);
                             }
-                        })
-                        .or_else(move |e| {
-                            error!("error waiting for anchor confirmations on {:?}: {:?}", network_type, e);
-                            Ok(())
-                        }),
+                        }
+                    }),
                 );
             }
             _ => (),
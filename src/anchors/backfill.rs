@@ -0,0 +1,266 @@
+use std::cmp;
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+use web3::contract::Options;
+use web3::futures::future::join_all;
+use web3::futures::prelude::*;
+use web3::futures::sync::mpsc;
+use web3::futures::try_ready;
+use web3::types::{BlockNumber, U256, U64};
+use web3::DuplexTransport;
+
+use super::anchor::Anchor;
+use crate::metrics;
+use crate::relay::Network;
+
+/// Most missing anchors posted by a single BackfillAnchors
+pub const MAX_ANCHOR_BACKFILL: usize = 10;
+/// Most anchors on the contract read by a single BackfillAnchors, to find the heights missing between them
+pub const MAX_ANCHOR_VERIFY: u64 = 100;
+
+/// Returns the anchor heights missing since the oldest of the given anchors, oldest first, at most
+/// MAX_ANCHOR_BACKFILL of them. This covers heights skipped between anchors, as well as those after the latest one.
+/// Without any anchor, or without a frequency, there is nothing to fill in.
+/// The newest confirmed height is left out, as WatchAnchors posts it as soon as it is confirmed
+///
+/// # Arguments
+///
+/// * `anchored` - Block numbers of the latest anchors on the contract
+/// * `confirmed` - Last confirmed block on the sidechain
+/// * `frequency` - Number of blocks between anchors
+pub fn missing_anchor_heights(anchored: &BTreeSet<u64>, confirmed: u64, frequency: u64) -> Vec<u64> {
+    let oldest = match anchored.iter().next() {
+        Some(oldest) if frequency > 0 => *oldest,
+        _ => return Vec::new(),
+    };
+    let newest = confirmed - confirmed % frequency;
+    let first = (oldest / frequency + 1) * frequency;
+    (first..newest)
+        .step_by(frequency as usize)
+        .filter(|height| !anchored.contains(height))
+        .take(MAX_ANCHOR_BACKFILL)
+        .collect()
+}
+
+enum BackfillAnchorsState {
+    GetLength(Box<dyn Future<Item = (U256, U64), Error = ()>>),
+    GetAnchors(u64, u64, u64, Box<dyn Future<Item = Vec<Anchor>, Error = ()>>),
+    Verify(
+        u64,
+        u64,
+        BTreeSet<u64>,
+        Box<dyn Future<Item = Vec<(Anchor, Option<Anchor>)>, Error = ()>>,
+    ),
+    Fetch(Box<dyn Future<Item = Vec<Option<Anchor>>, Error = ()>>),
+}
+
+/// Future that reads the latest anchors posted on the homechain, checks the ones it has not checked yet against the
+/// sidechain, and sends the anchors missing among them, or since the latest one, to ProcessAnchors
+pub struct BackfillAnchors<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    target: Network<T>,
+    tx: mpsc::UnboundedSender<Anchor>,
+    verified: Arc<RwLock<u64>>,
+    state: BackfillAnchorsState,
+}
+
+impl<T: DuplexTransport + 'static> BackfillAnchors<T> {
+    /// Returns a newly created BackfillAnchors Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the anchored blocks are
+    /// * `target` - Network where the anchors are posted
+    /// * `tx` - Sender of missing anchors to ProcessAnchors
+    /// * `verified` - Number of anchors on the contract already checked against the source
+    pub fn new(
+        source: &Network<T>,
+        target: &Network<T>,
+        tx: mpsc::UnboundedSender<Anchor>,
+        verified: &Arc<RwLock<u64>>,
+    ) -> Self {
+        let (source_type, target_type) = (source.network_type, target.network_type);
+        let length = target
+            .relay
+            .query("anchorsLength", (), None, Options::default(), BlockNumber::Latest)
+            .map_err(move |e| {
+                error!("error getting number of anchors on {:?}: {:?}", target_type, e);
+            });
+        let head = source.web3.eth().block_number().map_err(move |e| {
+            error!("error getting block number on {:?}: {:?}", source_type, e);
+        });
+        BackfillAnchors {
+            source: source.clone(),
+            target: target.clone(),
+            tx,
+            verified: verified.clone(),
+            state: BackfillAnchorsState::GetLength(Box::new(length.join(head))),
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for BackfillAnchors<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let network_type = self.target.network_type;
+        loop {
+            let next = match self.state {
+                BackfillAnchorsState::GetLength(ref mut future) => {
+                    let (length, head) = try_ready!(future.poll());
+                    let length = length.low_u64();
+                    if length == 0 {
+                        debug!("no anchors on {:?} yet", network_type);
+                        return Ok(Async::Ready(()));
+                    }
+                    let confirmed = head.as_u64().saturating_sub(self.source.confirmations);
+                    let verified = *self.verified.read().map_err(|e| {
+                        error!("error acquiring verified anchors lock: {:?}", e);
+                    })?;
+                    // Every anchor in the window is read to find the heights skipped between them, but only the ones
+                    // not checked yet are checked against the sidechain
+                    let from = length.saturating_sub(MAX_ANCHOR_VERIFY);
+                    let verify_from = cmp::max(from, verified);
+                    let futures: Vec<Box<dyn Future<Item = Anchor, Error = ()>>> = (from..length)
+                        .map(|index| Anchor::on_contract(&self.target, index))
+                        .collect();
+                    BackfillAnchorsState::GetAnchors(length, confirmed, verify_from - from, Box::new(join_all(futures)))
+                }
+                BackfillAnchorsState::GetAnchors(length, confirmed, unverified, ref mut future) => {
+                    let anchors = try_ready!(future.poll());
+                    let anchored: BTreeSet<u64> = anchors.iter().map(|anchor| anchor.block_number().as_u64()).collect();
+                    let futures: Vec<Box<dyn Future<Item = (Anchor, Option<Anchor>), Error = ()>>> = anchors
+                        .into_iter()
+                        .skip(unverified as usize)
+                        .filter(|anchor| anchor.block_number().as_u64() <= confirmed)
+                        .map(
+                            |anchor| -> Box<dyn Future<Item = (Anchor, Option<Anchor>), Error = ()>> {
+                                Box::new(
+                                    Anchor::fetch(&self.source, anchor.block_number())
+                                        .map(move |canonical| (anchor, canonical)),
+                                )
+                            },
+                        )
+                        .collect();
+                    BackfillAnchorsState::Verify(length, confirmed, anchored, Box::new(join_all(futures)))
+                }
+                BackfillAnchorsState::Verify(length, confirmed, ref anchored, ref mut future) => {
+                    let checked = try_ready!(future.poll());
+                    for (anchor, canonical) in checked {
+                        match canonical {
+                            Some(ref canonical) if *canonical == anchor => {}
+                            Some(canonical) => {
+                                error!(
+                                    "anchor {} on {:?} does not match the canonical block {} on {:?}",
+                                    anchor, network_type, canonical, self.source.network_type
+                                );
+                                metrics::anchor_mismatch();
+                            }
+                            None => {
+                                error!(
+                                    "anchor {} on {:?} has no block on {:?}",
+                                    anchor, network_type, self.source.network_type
+                                );
+                                metrics::anchor_mismatch();
+                            }
+                        }
+                    }
+                    *self.verified.write().map_err(|e| {
+                        error!("error acquiring verified anchors lock: {:?}", e);
+                    })? = length;
+                    if let Some(latest) = anchored.iter().next_back() {
+                        metrics::latest_anchor(*latest);
+                    }
+                    let missing = missing_anchor_heights(anchored, confirmed, self.source.anchor_frequency);
+                    if missing.is_empty() {
+                        return Ok(Async::Ready(()));
+                    }
+                    info!("{} anchors missing on {:?}: {:?}", missing.len(), network_type, missing);
+                    let futures: Vec<Box<dyn Future<Item = Option<Anchor>, Error = ()>>> = missing
+                        .into_iter()
+                        .map(|height| Anchor::fetch(&self.source, height.into()))
+                        .collect();
+                    BackfillAnchorsState::Fetch(Box::new(join_all(futures)))
+                }
+                BackfillAnchorsState::Fetch(ref mut future) => {
+                    let anchors = try_ready!(future.poll());
+                    for anchor in anchors.into_iter().flatten() {
+                        info!("backfilling anchor {} on {:?}", anchor, network_type);
+                        if self.tx.unbounded_send(anchor).is_err() {
+                            warn!("anchors are no longer processed, dropping anchor {}", anchor);
+                        }
+                    }
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anchors::check::is_anchor_leader;
+    use web3::types::Address;
+
+    #[test]
+    fn missing_anchor_heights_should_skip_the_newest_height() {
+        // arrange
+        let frequency = 100;
+        let anchored = |heights: &[u64]| heights.iter().copied().collect::<BTreeSet<u64>>();
+        // act
+        let missing = missing_anchor_heights(&anchored(&[100, 200]), 650, frequency);
+        let none_missing = missing_anchor_heights(&anchored(&[400, 500]), 650, frequency);
+        let no_anchors = missing_anchor_heights(&anchored(&[]), 650, frequency);
+        let capped = missing_anchor_heights(&anchored(&[0]), 100_000, frequency);
+        // assert
+        assert_eq!(missing, vec![300, 400, 500]);
+        assert!(none_missing.is_empty());
+        assert!(no_anchors.is_empty());
+        assert_eq!(capped.len(), MAX_ANCHOR_BACKFILL);
+        assert_eq!(capped[0], 100);
+    }
+
+    #[test]
+    fn missing_anchor_heights_should_fill_holes_between_anchors() {
+        // arrange
+        let anchored: BTreeSet<u64> = vec![100, 200, 400, 700].into_iter().collect();
+        // act
+        let missing = missing_anchor_heights(&anchored, 950, 100);
+        // assert
+        assert_eq!(missing, vec![300, 500, 600, 800]);
+    }
+
+    #[test]
+    fn missing_anchor_heights_should_be_empty_without_a_frequency() {
+        // arrange
+        let anchored: BTreeSet<u64> = vec![100, 200].into_iter().collect();
+        // act
+        let missing = missing_anchor_heights(&anchored, 950, 0);
+        // assert
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn missing_anchor_heights_should_fill_heights_of_skipped_leader() {
+        // arrange
+        let frequency = 100;
+        let leaders = vec![
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+        ];
+        let down = leaders[1];
+        // Every leader posts the anchors of its turns, except the one that is down
+        let anchored: BTreeSet<u64> = (0..=6)
+            .map(|turn| turn * frequency)
+            .filter(|height| !is_anchor_leader(&leaders, down, *height, frequency))
+            .collect();
+        // act
+        let missing = missing_anchor_heights(&anchored, 750, frequency);
+        // assert
+        assert_eq!(missing, vec![100, 400]);
+    }
+}
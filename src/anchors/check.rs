@@ -0,0 +1,238 @@
+use web3::contract::Options;
+use web3::futures::future::join_all;
+use web3::futures::prelude::*;
+use web3::futures::try_ready;
+use web3::types::{Address, BlockNumber, Bytes, CallRequest, U256};
+use web3::{DuplexTransport, Error};
+
+use super::anchor::Anchor;
+use crate::eth::transaction::BuildTransaction;
+use crate::eth::utils::is_rpc_revert;
+use crate::relay::Network;
+
+/// Number of the latest anchors on the contract searched for an anchor about to be posted
+pub const ANCHOR_LOOKBACK: u64 = 10;
+
+/// Returns true if the account is the one to post the anchor at the given height.
+/// Leaders take turns by anchor height, in address order. Without leaders, every relay posts every anchor
+///
+/// # Arguments
+///
+/// * `leaders` - Verifier addresses taking turns to post anchors, sorted
+/// * `account` - Account of this relay
+/// * `block_number` - Height of the anchor
+/// * `frequency` - Number of blocks between anchors
+pub fn is_anchor_leader(leaders: &[Address], account: Address, block_number: u64, frequency: u64) -> bool {
+    if leaders.is_empty() {
+        return true;
+    }
+    let turn = (block_number / frequency) % leaders.len() as u64;
+    leaders[turn as usize] == account
+}
+
+enum AnchorNeededState {
+    GetLength(Box<dyn Future<Item = U256, Error = ()>>),
+    FindAnchor(Box<dyn Future<Item = Vec<Anchor>, Error = ()>>),
+    EstimateVote(Box<dyn Future<Item = U256, Error = Error>>),
+}
+
+/// Future that resolves to true if an anchor still has to be sent by this relay.
+/// It does not, if the anchor is already among the latest on the contract and the contract would not take a vote for
+/// it from this relay, because it already voted or the anchor needs no more votes.
+/// If the node fails to estimate the vote for any other reason, the anchor is sent anyway
+pub struct AnchorNeeded<T: DuplexTransport + 'static> {
+    target: Network<T>,
+    anchor: Anchor,
+    state: AnchorNeededState,
+}
+
+impl<T: DuplexTransport + 'static> AnchorNeeded<T> {
+    /// Returns a newly created AnchorNeeded Future
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the anchor is posted
+    /// * `anchor` - Anchor about to be posted
+    pub fn new(target: &Network<T>, anchor: &Anchor) -> Self {
+        let network_type = target.network_type;
+        let future = target
+            .relay
+            .query("anchorsLength", (), None, Options::default(), BlockNumber::Latest)
+            .map_err(move |e| {
+                error!("error getting number of anchors on {:?}: {:?}", network_type, e);
+            });
+        AnchorNeeded {
+            target: target.clone(),
+            anchor: *anchor,
+            state: AnchorNeededState::GetLength(Box::new(future)),
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for AnchorNeeded<T> {
+    type Item = bool;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let network_type = self.target.network_type;
+        loop {
+            let next = match self.state {
+                AnchorNeededState::GetLength(ref mut future) => {
+                    let length = try_ready!(future.poll()).low_u64();
+                    let futures: Vec<Box<dyn Future<Item = Anchor, Error = ()>>> =
+                        (length.saturating_sub(ANCHOR_LOOKBACK)..length)
+                            .map(|index| Anchor::on_contract(&self.target, index))
+                            .collect();
+                    AnchorNeededState::FindAnchor(Box::new(join_all(futures)))
+                }
+                AnchorNeededState::FindAnchor(ref mut future) => {
+                    let anchors = try_ready!(future.poll());
+                    if !anchors.contains(&self.anchor) {
+                        return Ok(Async::Ready(true));
+                    }
+                    let input_data = BuildTransaction::function_data(&self.target, "anchor", self.anchor)?;
+                    let future = self.target.web3.eth().estimate_gas(
+                        CallRequest {
+                            from: Some(self.target.account),
+                            to: self.target.relay.address(),
+                            gas: None,
+                            gas_price: None,
+                            value: None,
+                            data: Some(Bytes(input_data)),
+                        },
+                        None,
+                    );
+                    AnchorNeededState::EstimateVote(Box::new(future))
+                }
+                AnchorNeededState::EstimateVote(ref mut future) => {
+                    // The contract reverts votes it does not take, a vote it rejects costs less than a missed anchor
+                    return match future.poll() {
+                        Ok(Async::Ready(_)) => Ok(Async::Ready(true)),
+                        Ok(Async::NotReady) => Ok(Async::NotReady),
+                        Err(ref e) if is_rpc_revert(e) => {
+                            debug!("anchor vote would be rejected on {:?}: {:?}", network_type, e);
+                            Ok(Async::Ready(false))
+                        }
+                        Err(e) => {
+                            warn!(
+                                "error estimating anchor vote on {:?}, sending it anyway: {:?}",
+                                network_type, e
+                            );
+                            Ok(Async::Ready(true))
+                        }
+                    };
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::transport::MockTransport;
+    use crate::relay::NetworkType;
+    use jsonrpc_core as rpc;
+    use tokio_core::reactor;
+    use web3::types::{H256, U64};
+
+    const RELAY_ABI: &str = r#"[
+        {
+          "constant": true,
+          "inputs": [],
+          "name": "anchorsLength",
+          "outputs": [{"name": "", "type": "uint256"}],
+          "payable": false,
+          "stateMutability": "view",
+          "type": "function"
+        },
+        {
+          "constant": true,
+          "inputs": [{"name": "", "type": "uint256"}],
+          "name": "anchors",
+          "outputs": [{"name": "blockHash", "type": "bytes32"}, {"name": "blockNumber", "type": "uint256"}],
+          "payable": false,
+          "stateMutability": "view",
+          "type": "function"
+        },
+        {
+          "constant": false,
+          "inputs": [{"name": "blockHash", "type": "bytes32"}, {"name": "blockNumber", "type": "uint256"}],
+          "name": "anchor",
+          "outputs": [],
+          "payable": false,
+          "stateMutability": "nonpayable",
+          "type": "function"
+        }
+    ]"#;
+
+    /// Returns a mock whose contract already holds the anchor of block 100 with hash 1
+    fn mock_with_anchor() -> MockTransport {
+        let mut mock = MockTransport::new();
+        mock.add_method_response("eth_call", rpc::Value::String(format!("0x{:064x}", 1)));
+        mock.add_method_response("eth_call", rpc::Value::String(format!("0x{:064x}{:064x}", 1, 100)));
+        mock
+    }
+
+    fn anchor_needed(mock: &MockTransport) -> bool {
+        let mut eloop = reactor::Core::new().unwrap();
+        let address = "0x7e7087c25df885f97aeacbfae84ea12016799eee";
+        let target = mock
+            .new_network(NetworkType::Home)
+            .unwrap()
+            .with_contracts(address, "[]", address, RELAY_ABI)
+            .unwrap();
+        let anchor = Anchor::new(H256::from_low_u64_be(1), U64::from(100));
+        eloop.run(AnchorNeeded::new(&target, &anchor)).unwrap()
+    }
+
+    #[test]
+    fn anchor_needed_should_skip_a_vote_the_contract_reverts() {
+        // arrange
+        let mut mock = mock_with_anchor();
+        mock.add_method_error(
+            "eth_estimateGas",
+            Error::Rpc(rpc::Error {
+                code: rpc::ErrorCode::ServerError(-32000),
+                message: "execution reverted".to_string(),
+                data: None,
+            }),
+        );
+        // act
+        let needed = anchor_needed(&mock);
+        // assert
+        assert!(!needed);
+    }
+
+    #[test]
+    fn anchor_needed_should_send_the_vote_if_the_estimate_fails() {
+        // arrange
+        let mut mock = mock_with_anchor();
+        mock.add_method_error("eth_estimateGas", Error::Transport("connection reset".to_string()));
+        // act
+        let needed = anchor_needed(&mock);
+        // assert
+        assert!(needed);
+        assert_eq!(mock.requests("eth_estimateGas").len(), 1);
+    }
+
+    #[test]
+    fn is_anchor_leader_should_rotate_by_anchor_height() {
+        // arrange
+        let leaders = vec![
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+        ];
+        let account = Address::from_low_u64_be(2);
+        // act
+        let turns: Vec<bool> = (0..4)
+            .map(|anchor| is_anchor_leader(&leaders, account, anchor * 100, 100))
+            .collect();
+        let no_leaders = is_anchor_leader(&[], account, 0, 100);
+        // assert
+        assert_eq!(turns, vec![false, true, false, false]);
+        assert!(no_leaders);
+    }
+}
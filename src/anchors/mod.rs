@@ -1 +1,3 @@
 pub mod anchor;
+pub mod backfill;
+pub mod check;
@@ -26,6 +26,9 @@ pub enum OperationError {
 
     #[fail(display = "Unable to build transaction: {}", _0)]
     CouldNotBuildTransaction(String),
+
+    #[fail(display = "Unable to open state store: {}", _0)]
+    CouldNotOpenStateStore(String),
 }
 
 #[derive(Fail, Debug)]
@@ -42,9 +45,69 @@ pub enum EndpointError {
     #[fail(display = "unable to get relay status.")]
     UnableToGetStatus,
 
+    #[fail(display = "unable to get relay metrics.")]
+    UnableToGetMetrics,
+
     #[fail(display = "unable to get nectar balances.")]
     UnableToGetBalances,
 
+    #[fail(display = "unable to get withdrawals.")]
+    UnableToGetWithdrawals,
+
+    #[fail(display = "unable to read the audit log.")]
+    UnableToGetTransfers,
+
+    #[fail(display = "unable to get held transfers.")]
+    UnableToGetHeld,
+
+    #[fail(display = "no held transfer with withdrawal hash: {}.", _0)]
+    HeldTransferNotFound(String),
+
+    #[fail(display = "invalid withdrawal hash: {}.", _0)]
+    BadWithdrawalHash(String),
+
+    #[fail(display = "unauthorized.")]
+    Unauthorized,
+
+    #[fail(display = "invalid rescan range: from {} is after to {}.", _0, _1)]
+    BadRescanRange(u64, u64),
+
+    #[fail(display = "unable to get rescan job.")]
+    UnableToGetRescan,
+
+    #[fail(display = "no rescan job with id: {}.", _0)]
+    RescanNotFound(u64),
+
+    #[fail(display = "unable to plan flush.")]
+    UnableToPlanFlush,
+
+    #[fail(display = "invalid report format: {}, must be json or csv.", _0)]
+    BadFormat(String),
+
+    #[fail(display = "unable to get balance snapshot.")]
+    UnableToSnapshotBalances,
+
+    #[fail(display = "unable to get pause state.")]
+    UnableToGetPaused,
+
+    #[fail(
+        display = "invalid pause target: {}, must be home-to-side, side-to-home or anchors.",
+        _0
+    )]
+    BadPauseTarget(String),
+
+    #[fail(display = "unable to get transaction queue.")]
+    UnableToGetQueue,
+
+    #[fail(display = "unable to anchor block.")]
+    UnableToAnchor,
+
+    #[fail(display = "no confirmed sidechain block: {}.", _0)]
+    AnchorBlockNotFound(u64),
+
+    #[fail(display = "no confirmed relay transfers in transaction: {}.", _0)]
+    TransfersNotFound(String),
+
     #[fail(display = "timeout")]
     Timeout,
 }
@@ -56,7 +119,26 @@ impl ResponseError for EndpointError {
             EndpointError::BadTransactionHash(_) => HttpResponse::new(http::StatusCode::BAD_REQUEST),
             EndpointError::UnableToSend => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
             EndpointError::UnableToGetStatus => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToGetMetrics => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
             EndpointError::UnableToGetBalances => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToGetWithdrawals => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToGetTransfers => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToGetHeld => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::HeldTransferNotFound(_) => HttpResponse::new(http::StatusCode::NOT_FOUND),
+            EndpointError::BadWithdrawalHash(_) => HttpResponse::new(http::StatusCode::BAD_REQUEST),
+            EndpointError::Unauthorized => HttpResponse::new(http::StatusCode::UNAUTHORIZED),
+            EndpointError::BadRescanRange(_, _) => HttpResponse::new(http::StatusCode::BAD_REQUEST),
+            EndpointError::UnableToGetRescan => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::RescanNotFound(_) => HttpResponse::new(http::StatusCode::NOT_FOUND),
+            EndpointError::UnableToPlanFlush => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::BadFormat(_) => HttpResponse::new(http::StatusCode::BAD_REQUEST),
+            EndpointError::UnableToSnapshotBalances => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToGetPaused => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::BadPauseTarget(_) => HttpResponse::new(http::StatusCode::BAD_REQUEST),
+            EndpointError::UnableToGetQueue => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::UnableToAnchor => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EndpointError::AnchorBlockNotFound(_) => HttpResponse::new(http::StatusCode::NOT_FOUND),
+            EndpointError::TransfersNotFound(_) => HttpResponse::new(http::StatusCode::NOT_FOUND),
             EndpointError::Timeout => HttpResponse::new(http::StatusCode::REQUEST_TIMEOUT),
         }
     }
@@ -78,6 +160,9 @@ pub enum ConfigError {
     #[fail(display = "invalid anchor frequency, must be non-zero")]
     InvalidAnchorFrequency,
 
+    #[fail(display = "invalid anchor leaders: {}", _0)]
+    InvalidAnchorLeaders(String),
+
     #[fail(display = "invalid lookback interval, must be below {}", _0)]
     InvalidLookbackInterval(u64),
 
@@ -86,4 +171,25 @@ pub enum ConfigError {
 
     #[fail(display = "invalid port, must be between 0 and 65535")]
     InvalidPort,
+
+    #[fail(display = "invalid gas settings: {}", _0)]
+    InvalidGas(String),
+
+    #[fail(display = "missing setting {}", _0)]
+    MissingSetting(String),
+
+    #[fail(display = "invalid abi file {}", _0)]
+    InvalidAbiFile(String),
+
+    #[fail(display = "invalid timeout settings: {}", _0)]
+    InvalidTimeout(String),
+
+    #[fail(display = "invalid limit settings: {}", _0)]
+    InvalidLimit(String),
+
+    #[fail(display = "invalid flush settings: {}", _0)]
+    InvalidFlushPolicy(String),
+
+    #[fail(display = "invalid scan settings: {}", _0)]
+    InvalidScan(String),
 }
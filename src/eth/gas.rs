@@ -0,0 +1,335 @@
+use serde_json::{json, Value};
+use std::cmp;
+use std::str::FromStr;
+use web3::futures::future;
+use web3::futures::prelude::*;
+use web3::types::U256;
+use web3::{DuplexTransport, Transport, Web3};
+
+use crate::relay::NetworkType;
+use crate::relay_config::settings::{Gas, GasPriceKind};
+
+/// Precision used when scaling U256 values by a float multiplier
+const SCALE_PRECISION: u64 = 1_000;
+
+/// How the gas price of a transaction is chosen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GasPriceStrategy {
+    /// Always use the same gas price
+    Fixed(U256),
+    /// Use eth_gasPrice, multiplied by the given multiplier
+    Node { multiplier: f64 },
+    /// Send type-2 transactions, with fees derived from eth_feeHistory
+    Eip1559 {
+        /// Number of past blocks to look at
+        fee_history_blocks: u64,
+        /// Percentile of priority fees paid in those blocks to use as our priority fee
+        priority_fee_percentile: f64,
+        /// Multiplier on the next base fee, to stay includable while the base fee rises
+        base_fee_multiplier: f64,
+    },
+}
+
+/// Gas price chosen for a single transaction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GasPrice {
+    /// Legacy transaction gas price
+    Legacy(U256),
+    /// Type-2 transaction fees
+    Eip1559 { max_fee: U256, priority_fee: U256 },
+}
+
+/// Gas settings for all transactions sent to a single network
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GasStrategy {
+    /// Whether transactions on this network are free, and have a gas price of zero
+    pub free: bool,
+    /// How the gas price is chosen
+    pub price: GasPriceStrategy,
+    /// Lowest gas price (or max fee) to ever pay
+    pub min_price: U256,
+    /// Highest gas price (or max fee) to ever pay
+    pub max_price: U256,
+    /// Multiplier on eth_estimateGas for the gas limit
+    pub limit_margin: f64,
+    /// Highest gas limit to ever use
+    pub limit_cap: U256,
+    /// Number of blocks to wait for a transaction to be mined before replacing it, 0 never replaces
+    pub stuck_blocks: u64,
+    /// Percent to raise the gas price by when replacing a transaction
+    pub bump_percent: u64,
+    /// Highest gas price (or max fee) a replacement may use
+    pub bump_ceiling: U256,
+}
+
+impl Default for GasStrategy {
+    fn default() -> Self {
+        GasStrategy {
+            free: false,
+            price: GasPriceStrategy::Node { multiplier: 1.0 },
+            min_price: U256::zero(),
+            max_price: U256::max_value(),
+            limit_margin: 1.2,
+            limit_cap: 500_000.into(),
+            stuck_blocks: 20,
+            bump_percent: 15,
+            bump_ceiling: U256::max_value(),
+        }
+    }
+}
+
+impl GasStrategy {
+    /// Returns the GasStrategy for a network, from its settings
+    ///
+    /// # Arguments
+    ///
+    /// * `free` - Whether transactions on the network are free
+    /// * `gas` - Gas settings of the network
+    pub fn from_settings(free: bool, gas: &Gas) -> Self {
+        let price = match gas.strategy {
+            GasPriceKind::Fixed => GasPriceStrategy::Fixed(gas.price.unwrap_or_default().into()),
+            GasPriceKind::Node => GasPriceStrategy::Node {
+                multiplier: gas.multiplier,
+            },
+            GasPriceKind::Eip1559 => GasPriceStrategy::Eip1559 {
+                fee_history_blocks: gas.fee_history_blocks,
+                priority_fee_percentile: gas.priority_fee_percentile,
+                base_fee_multiplier: gas.base_fee_multiplier,
+            },
+        };
+        let max_price = gas.max_price.map_or_else(U256::max_value, U256::from);
+        GasStrategy {
+            free,
+            price,
+            min_price: gas.min_price.map_or_else(U256::zero, U256::from),
+            max_price,
+            limit_margin: gas.limit_margin,
+            limit_cap: gas.limit_cap.into(),
+            stuck_blocks: gas.stuck_blocks,
+            bump_percent: gas.bump_percent,
+            bump_ceiling: gas.bump_ceiling.map_or(max_price, U256::from),
+        }
+    }
+
+    /// Returns the gas limit to use, given the result of eth_estimateGas
+    ///
+    /// # Arguments
+    ///
+    /// * `estimate` - Gas estimated by the node
+    pub fn gas_limit(&self, estimate: U256) -> U256 {
+        cmp::min(scale(estimate, self.limit_margin), self.limit_cap)
+    }
+
+    /// Returns the gas price kept between the configured min and max
+    ///
+    /// # Arguments
+    ///
+    /// * `price` - Gas price to clamp
+    pub fn clamp(&self, price: U256) -> U256 {
+        cmp::min(cmp::max(price, self.min_price), self.max_price)
+    }
+
+    /// Returns the gas price for a replacement of a transaction sent with the given gas price.
+    /// Returns None on free networks, or when the bumped price would go over the ceiling
+    ///
+    /// # Arguments
+    ///
+    /// * `price` - Gas price of the transaction being replaced
+    pub fn bump(&self, price: GasPrice) -> Option<GasPrice> {
+        if self.free {
+            return None;
+        }
+        // Nodes only accept a replacement priced at least 10% above the original, bump_percent is validated to be
+        // at least that, and the extra wei covers rounding down
+        let bump = |value: U256| value.saturating_mul((100 + self.bump_percent).into()) / 100 + 1;
+        let bumped = match price {
+            GasPrice::Legacy(gas_price) => GasPrice::Legacy(bump(gas_price)),
+            GasPrice::Eip1559 { max_fee, priority_fee } => GasPrice::Eip1559 {
+                max_fee: bump(max_fee),
+                priority_fee: bump(priority_fee),
+            },
+        };
+        let highest = match bumped {
+            GasPrice::Legacy(gas_price) => gas_price,
+            GasPrice::Eip1559 { max_fee, .. } => max_fee,
+        };
+        if highest > self.bump_ceiling {
+            None
+        } else {
+            Some(bumped)
+        }
+    }
+
+    /// Returns a Future that resolves to the gas price for the next transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `web3` - Web3 of the network the transaction is sent to
+    /// * `network_type` - Network the transaction is sent to, for logging
+    pub fn gas_price<T: DuplexTransport + 'static>(
+        &self,
+        web3: &Web3<T>,
+        network_type: NetworkType,
+    ) -> Box<dyn Future<Item = GasPrice, Error = ()>> {
+        if self.free {
+            return Box::new(future::ok(GasPrice::Legacy(U256::zero())));
+        }
+        let strategy = *self;
+        match self.price {
+            GasPriceStrategy::Fixed(price) => Box::new(future::ok(GasPrice::Legacy(strategy.clamp(price)))),
+            GasPriceStrategy::Node { multiplier } => Box::new(
+                web3.eth()
+                    .gas_price()
+                    .map(move |price| GasPrice::Legacy(strategy.clamp(scale(price, multiplier))))
+                    .map_err(move |e| {
+                        error!("error fetching current gas price on {:?}: {}", network_type, e);
+                    }),
+            ),
+            GasPriceStrategy::Eip1559 {
+                fee_history_blocks,
+                priority_fee_percentile,
+                base_fee_multiplier,
+            } => {
+                let params = vec![
+                    json!(format!("{:#x}", fee_history_blocks)),
+                    json!("latest"),
+                    json!([priority_fee_percentile]),
+                ];
+                Box::new(
+                    web3.transport()
+                        .execute("eth_feeHistory", params)
+                        .map_err(move |e| {
+                            error!("error fetching fee history on {:?}: {}", network_type, e);
+                        })
+                        .and_then(move |history| {
+                            let (base_fee, priority_fee) = parse_fee_history(&history).ok_or_else(|| {
+                                error!("invalid fee history on {:?}: {:?}", network_type, history);
+                            })?;
+                            let max_fee =
+                                strategy.clamp(scale(base_fee, base_fee_multiplier).saturating_add(priority_fee));
+                            Ok(GasPrice::Eip1559 {
+                                max_fee,
+                                priority_fee: cmp::min(priority_fee, max_fee),
+                            })
+                        }),
+                )
+            }
+        }
+    }
+}
+
+/// Returns the value multiplied by a float multiplier
+///
+/// # Arguments
+///
+/// * `value` - Value to scale
+/// * `multiplier` - Non-negative multiplier
+pub fn scale(value: U256, multiplier: f64) -> U256 {
+    let factor = U256::from((multiplier * SCALE_PRECISION as f64).round() as u64);
+    value.saturating_mul(factor) / SCALE_PRECISION
+}
+
+/// Returns the base fee of the next block and the average priority fee from an eth_feeHistory response
+///
+/// # Arguments
+///
+/// * `history` - Result of eth_feeHistory, requested with a single reward percentile
+pub fn parse_fee_history(history: &Value) -> Option<(U256, U256)> {
+    // baseFeePerGas has one more entry than the number of blocks, for the block after the newest one
+    let base_fee = history["baseFeePerGas"].as_array()?.last().and_then(parse_quantity)?;
+    let rewards = history["reward"]
+        .as_array()?
+        .iter()
+        .map(|reward| reward.get(0).and_then(parse_quantity))
+        .collect::<Option<Vec<U256>>>()?;
+    let priority_fee = if rewards.is_empty() {
+        U256::zero()
+    } else {
+        rewards.iter().fold(U256::zero(), |sum, reward| sum + *reward) / rewards.len()
+    };
+    Some((base_fee, priority_fee))
+}
+
+fn parse_quantity(value: &Value) -> Option<U256> {
+    let hex = value.as_str()?;
+    U256::from_str(hex.trim_start_matches("0x")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fee_history_should_use_next_base_fee_and_average_reward() {
+        // arrange
+        let history = json!({
+            "oldestBlock": "0x10",
+            "baseFeePerGas": ["0x64", "0x6e", "0x78"],
+            "gasUsedRatio": [0.5, 0.7],
+            "reward": [["0xa"], ["0x14"]]
+        });
+        // act
+        let fees = parse_fee_history(&history);
+        // assert
+        assert_eq!(fees, Some((U256::from(120), U256::from(15))));
+    }
+
+    #[test]
+    fn gas_limit_should_add_margin_up_to_cap() {
+        // arrange
+        let strategy = GasStrategy {
+            limit_margin: 1.5,
+            limit_cap: 250_000.into(),
+            ..GasStrategy::default()
+        };
+        // act
+        let under_cap = strategy.gas_limit(100_000.into());
+        let over_cap = strategy.gas_limit(200_000.into());
+        // assert
+        assert_eq!(under_cap, U256::from(150_000));
+        assert_eq!(over_cap, U256::from(250_000));
+    }
+
+    #[test]
+    fn bump_should_raise_price_by_at_least_ten_percent_up_to_ceiling() {
+        // arrange
+        let strategy = GasStrategy {
+            bump_percent: 10,
+            bump_ceiling: 120.into(),
+            ..GasStrategy::default()
+        };
+        // act
+        let bumped = strategy.bump(GasPrice::Eip1559 {
+            max_fee: 100.into(),
+            priority_fee: 5.into(),
+        });
+        let over_ceiling = strategy.bump(GasPrice::Legacy(110.into()));
+        // assert
+        assert_eq!(
+            bumped,
+            Some(GasPrice::Eip1559 {
+                max_fee: 111.into(),
+                priority_fee: 6.into(),
+            })
+        );
+        assert_eq!(over_ceiling, None);
+    }
+
+    #[test]
+    fn clamp_should_keep_price_between_min_and_max() {
+        // arrange
+        let strategy = GasStrategy {
+            min_price: 10.into(),
+            max_price: 100.into(),
+            ..GasStrategy::default()
+        };
+        // act
+        let clamped = vec![
+            strategy.clamp(1.into()),
+            strategy.clamp(50.into()),
+            strategy.clamp(1000.into()),
+        ];
+        // assert
+        assert_eq!(clamped, vec![U256::from(10), U256::from(50), U256::from(100)]);
+    }
+}
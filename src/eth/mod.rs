@@ -1,6 +1,12 @@
 pub mod contracts;
 pub mod event;
+pub mod gas;
+pub mod polling;
+pub mod queue;
+pub mod scan;
 pub mod transaction;
+pub mod transport;
 pub mod utils;
+pub mod websocket;
 
 pub use event::Event;
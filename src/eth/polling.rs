@@ -0,0 +1,371 @@
+use jsonrpc_core as rpc;
+use serde_json::json;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_core::reactor;
+use web3::api::SubscriptionId;
+use web3::futures::future::{self, Either};
+use web3::futures::prelude::*;
+use web3::futures::stream;
+use web3::futures::sync::mpsc;
+use web3::transports::{Http, Result};
+use web3::{BatchTransport, DuplexTransport, Error, RequestId, Transport};
+
+use super::utils::hex_value;
+use crate::relay_config::settings::RpcEndpoint;
+
+/// Most blocks covered by a single poll, the rest are covered by the next one right away
+const MAX_POLL_BLOCKS: u64 = 100;
+
+/// Most HTTP requests in flight at once
+const MAX_PARALLEL_REQUESTS: usize = 64;
+
+/// What a subscription emits
+#[derive(Clone, Debug)]
+enum SubscriptionKind {
+    NewHeads,
+    Logs(rpc::Value),
+}
+
+impl SubscriptionKind {
+    /// Returns the last block this subscription covers. Logs wait until they are confirmed, so a reorg replaces
+    /// them before they are emitted
+    ///
+    /// # Arguments
+    ///
+    /// * `latest` - Latest block on the chain
+    /// * `confirmations` - Blocks a log has to be under before it is emitted
+    fn head(&self, latest: u64, confirmations: u64) -> u64 {
+        match self {
+            SubscriptionKind::NewHeads => latest,
+            SubscriptionKind::Logs(_) => latest.saturating_sub(confirmations),
+        }
+    }
+}
+
+/// Receivers of the subscriptions, until DuplexTransport::subscribe takes them
+type Subscriptions = Rc<RefCell<BTreeMap<SubscriptionId, Option<mpsc::UnboundedReceiver<rpc::Value>>>>>;
+
+/// Returns the blocks to cover in the next poll, or None if there is no new block
+///
+/// # Arguments
+///
+/// * `last` - Last block covered so far, None before the first poll
+/// * `latest` - Latest block on the chain
+fn poll_range(last: Option<u64>, latest: u64) -> Option<(u64, u64)> {
+    match last {
+        // Subscriptions only emit what comes after they are made
+        None => Some((latest, latest)),
+        Some(last) if latest > last => Some((last + 1, cmp::min(latest, last + MAX_POLL_BLOCKS))),
+        Some(_) => None,
+    }
+}
+
+/// HTTP transport for nodes without websockets.
+/// eth_subscribe and eth_unsubscribe are handled here, by polling new blocks and logs with eth_blockNumber,
+/// eth_getBlockByNumber and eth_getLogs, and emitting them as subscription notifications. Reorgs are not seen, so logs
+/// are only emitted once they are confirmations blocks deep, and are never removed
+#[derive(Clone, Debug)]
+pub struct PollingTransport {
+    http: Http,
+    handle: reactor::Handle,
+    interval: Duration,
+    confirmations: u64,
+    endpoint: Arc<RwLock<Option<String>>>,
+    next_subscription: Rc<Cell<u64>>,
+    subscriptions: Subscriptions,
+}
+
+impl PollingTransport {
+    /// Returns a new PollingTransport, sending requests on the given event loop
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - HTTP RPC endpoint of the chain
+    /// * `handle` - Handle to the event loop
+    /// * `interval` - Time between polls for new blocks
+    /// * `confirmations` - Blocks a log has to be under before it is emitted
+    pub fn new(
+        endpoint: &RpcEndpoint,
+        handle: &reactor::Handle,
+        interval: Duration,
+        confirmations: u64,
+    ) -> Result<Self> {
+        let http = Http::with_event_loop(&endpoint.uri, handle, MAX_PARALLEL_REQUESTS)?;
+        Ok(PollingTransport {
+            http,
+            handle: handle.clone(),
+            interval,
+            confirmations,
+            endpoint: Arc::new(RwLock::new(Some(endpoint.name()))),
+            next_subscription: Rc::new(Cell::new(0)),
+            subscriptions: Rc::new(RefCell::new(BTreeMap::new())),
+        })
+    }
+
+    /// Returns the name of the endpoint, shared so it can be shown in the status
+    pub fn endpoint(&self) -> Arc<RwLock<Option<String>>> {
+        self.endpoint.clone()
+    }
+
+    /// Starts polling for an eth_subscribe request, and returns the subscription id
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Params of the eth_subscribe request
+    fn start_subscription(&self, params: &rpc::Params) -> std::result::Result<rpc::Value, Error> {
+        let params = match params {
+            rpc::Params::Array(params) => params.clone(),
+            _ => Vec::new(),
+        };
+        let kind = match params.get(0).and_then(|kind| kind.as_str()) {
+            Some("newHeads") => SubscriptionKind::NewHeads,
+            Some("logs") => SubscriptionKind::Logs(params.get(1).cloned().unwrap_or_else(|| json!({}))),
+            kind => return Err(Error::Transport(format!("unsupported subscription {:?}", kind))),
+        };
+        let number = self.next_subscription.get() + 1;
+        self.next_subscription.set(number);
+        let id = format!("0x{:x}", number);
+        let (tx, rx) = mpsc::unbounded();
+        self.subscriptions
+            .borrow_mut()
+            .insert(SubscriptionId::from(id.clone()), Some(rx));
+        self.handle
+            .spawn(PollSubscription::new(self, SubscriptionId::from(id.clone()), kind, tx));
+        Ok(rpc::Value::String(id))
+    }
+
+    /// Stops polling for an eth_unsubscribe request
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Params of the eth_unsubscribe request
+    fn stop_subscription(&self, params: &rpc::Params) -> rpc::Value {
+        let id = match params {
+            rpc::Params::Array(params) => params.get(0).and_then(|id| id.as_str()).map(String::from),
+            _ => None,
+        };
+        let removed = id.map_or(false, |id| {
+            self.subscriptions
+                .borrow_mut()
+                .remove(&SubscriptionId::from(id))
+                .is_some()
+        });
+        rpc::Value::Bool(removed)
+    }
+}
+
+impl Transport for PollingTransport {
+    type Out = Box<dyn Future<Item = rpc::Value, Error = Error>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        self.http.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        if let rpc::Call::MethodCall(ref call) = request {
+            match call.method.as_str() {
+                "eth_subscribe" => return Box::new(future::result(self.start_subscription(&call.params))),
+                "eth_unsubscribe" => return Box::new(future::ok(self.stop_subscription(&call.params))),
+                _ => {}
+            }
+        }
+        Box::new(self.http.send(id, request))
+    }
+}
+
+impl BatchTransport for PollingTransport {
+    type Batch = Box<dyn Future<Item = Vec<Result<rpc::Value>>, Error = Error>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, rpc::Call)>,
+    {
+        Box::new(self.http.send_batch(requests))
+    }
+}
+
+impl DuplexTransport for PollingTransport {
+    type NotificationStream = Box<dyn Stream<Item = rpc::Value, Error = Error> + Send + 'static>;
+
+    fn subscribe(&self, id: &SubscriptionId) -> Self::NotificationStream {
+        let rx = self.subscriptions.borrow_mut().get_mut(id).and_then(Option::take);
+        match rx {
+            Some(rx) => Box::new(rx.map_err(|()| Error::Internal)),
+            None => Box::new(stream::once(Err(Error::Transport(format!(
+                "unknown subscription {:?}",
+                id
+            ))))),
+        }
+    }
+
+    fn unsubscribe(&self, id: &SubscriptionId) {
+        self.subscriptions.borrow_mut().remove(id);
+    }
+}
+
+enum PollState {
+    Wait(reactor::Timeout),
+    Fetch(Box<dyn Future<Item = Option<(u64, u64, Vec<rpc::Value>)>, Error = Error>>),
+}
+
+/// Future that polls new blocks or logs for a subscription, until it is unsubscribed
+struct PollSubscription {
+    transport: PollingTransport,
+    id: SubscriptionId,
+    kind: SubscriptionKind,
+    tx: mpsc::UnboundedSender<rpc::Value>,
+    last: Option<u64>,
+    state: PollState,
+}
+
+impl PollSubscription {
+    fn new(
+        transport: &PollingTransport,
+        id: SubscriptionId,
+        kind: SubscriptionKind,
+        tx: mpsc::UnboundedSender<rpc::Value>,
+    ) -> Self {
+        let timeout = reactor::Timeout::new(Duration::from_secs(0), &transport.handle).expect("error creating timeout");
+        PollSubscription {
+            transport: transport.clone(),
+            id,
+            kind,
+            tx,
+            last: None,
+            state: PollState::Wait(timeout),
+        }
+    }
+
+    /// Returns a Future that resolves to the blocks covered, the last block to cover, and the notifications to emit, or
+    /// None if there is no new block to cover
+    fn fetch(&self) -> Box<dyn Future<Item = Option<(u64, u64, Vec<rpc::Value>)>, Error = Error>> {
+        let http = self.transport.http.clone();
+        let kind = self.kind.clone();
+        let last = self.last;
+        let confirmations = self.transport.confirmations;
+        let future = self
+            .transport
+            .http
+            .execute("eth_blockNumber", vec![])
+            .and_then(|latest| hex_value(&latest).ok_or_else(|| Error::InvalidResponse("invalid block number".into())))
+            .and_then(move |latest| {
+                let latest = kind.head(latest, confirmations);
+                let (from, to) = match poll_range(last, latest) {
+                    Some(range) => range,
+                    None => return Either::A(future::ok(None)),
+                };
+                let notifications: Box<dyn Future<Item = Vec<rpc::Value>, Error = Error>> = match kind {
+                    SubscriptionKind::NewHeads => {
+                        let blocks = (from..=to).map(|number| {
+                            http.execute(
+                                "eth_getBlockByNumber",
+                                vec![format!("0x{:x}", number).into(), false.into()],
+                            )
+                        });
+                        Box::new(
+                            future::join_all(blocks.collect::<Vec<_>>())
+                                .map(|blocks| blocks.into_iter().filter(|block| !block.is_null()).collect()),
+                        )
+                    }
+                    SubscriptionKind::Logs(mut filter) => {
+                        filter["fromBlock"] = format!("0x{:x}", from).into();
+                        filter["toBlock"] = format!("0x{:x}", to).into();
+                        Box::new(http.execute("eth_getLogs", vec![filter]).map(|logs| match logs {
+                            rpc::Value::Array(logs) => logs,
+                            _ => Vec::new(),
+                        }))
+                    }
+                };
+                Either::B(notifications.map(move |notifications| Some((to, latest, notifications))))
+            });
+        Box::new(future)
+    }
+}
+
+impl Future for PollSubscription {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if !self.transport.subscriptions.borrow().contains_key(&self.id) {
+                return Ok(Async::Ready(()));
+            }
+            let delay = match self.state {
+                PollState::Wait(ref mut timeout) => {
+                    let ready = timeout.poll().map_err(|e| {
+                        error!("error waiting to poll subscription {:?}: {:?}", self.id, e);
+                    })?;
+                    if ready.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = PollState::Fetch(self.fetch());
+                    continue;
+                }
+                PollState::Fetch(ref mut future) => match future.poll() {
+                    Ok(Async::Ready(Some((to, latest, notifications)))) => {
+                        for notification in notifications {
+                            if self.tx.unbounded_send(notification).is_err() {
+                                return Ok(Async::Ready(()));
+                            }
+                        }
+                        self.last = Some(to);
+                        if to < latest {
+                            Duration::from_secs(0)
+                        } else {
+                            self.transport.interval
+                        }
+                    }
+                    Ok(Async::Ready(None)) => self.transport.interval,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        // The subscription goes quiet, and times out in the watcher if the node stays unreachable
+                        warn!("error polling subscription {:?}: {:?}", self.id, e);
+                        self.transport.interval
+                    }
+                },
+            };
+            let timeout = reactor::Timeout::new(delay, &self.transport.handle).expect("error creating timeout");
+            self.state = PollState::Wait(timeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_range_should_cover_new_blocks_in_chunks() {
+        // act
+        let first = poll_range(None, 500);
+        let nothing_new = poll_range(Some(500), 500);
+        let next = poll_range(Some(500), 503);
+        let behind = poll_range(Some(500), 1000);
+        // assert
+        assert_eq!(first, Some((500, 500)));
+        assert_eq!(nothing_new, None);
+        assert_eq!(next, Some((501, 503)));
+        assert_eq!(behind, Some((501, 600)));
+    }
+
+    #[test]
+    fn head_should_hold_back_logs_until_confirmed() {
+        // arrange
+        let heads = SubscriptionKind::NewHeads;
+        let logs = SubscriptionKind::Logs(json!({}));
+        // act
+        let heads_head = heads.head(500, 12);
+        let logs_head = logs.head(500, 12);
+        let early_logs_head = logs.head(5, 12);
+        // assert
+        assert_eq!(heads_head, 500);
+        assert_eq!(logs_head, 488);
+        assert_eq!(early_logs_head, 0);
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use web3::futures::prelude::*;
+use web3::futures::sync::{mpsc, oneshot};
+use web3::futures::try_ready;
+use web3::types::{BlockNumber, H256, U256};
+use web3::DuplexTransport;
+
+use super::transaction::{BuildTransaction, SignedTransaction};
+use crate::metrics;
+use crate::relay::Network;
+
+/// Hands out nonces for a single account, and keeps track of the transaction sent with each one.
+///
+/// Nonces given back after a failed send are handed out again before any new nonce, so a failure does not leave a gap.
+#[derive(Debug)]
+pub struct NonceManager {
+    next: U256,
+    released: BTreeSet<U256>,
+    pending: BTreeMap<U256, H256>,
+}
+
+impl NonceManager {
+    /// Returns a NonceManager starting at the given nonce
+    ///
+    /// # Arguments
+    ///
+    /// * `next` - Next unused nonce of the account
+    pub fn new(next: U256) -> Self {
+        NonceManager {
+            next,
+            released: BTreeSet::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the lowest unused nonce
+    pub fn take(&mut self) -> U256 {
+        if let Some(nonce) = self.gap() {
+            self.released.remove(&nonce);
+            return nonce;
+        }
+        let nonce = self.next;
+        self.next += U256::one();
+        nonce
+    }
+
+    /// Gives back a nonce that was not used
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce returned by take
+    pub fn release(&mut self, nonce: U256) {
+        self.pending.remove(&nonce);
+        if nonce + U256::one() == self.next {
+            self.next = nonce;
+        } else if nonce < self.next {
+            self.released.insert(nonce);
+        }
+    }
+
+    /// Returns the lowest nonce that was given back while later nonces are in use
+    pub fn gap(&self) -> Option<U256> {
+        self.released.iter().next().copied()
+    }
+
+    /// Records the transaction sent with a nonce
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce of the transaction
+    /// * `hash` - Hash of the transaction, or its latest replacement
+    pub fn sent(&mut self, nonce: U256, hash: H256) {
+        self.pending.insert(nonce, hash);
+    }
+
+    /// Skips a nonce held by a transaction this manager did not send. Nothing is recorded as pending for it, since
+    /// there is no transaction of ours to wait on
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce returned by take
+    pub fn occupied(&mut self, nonce: U256) {
+        self.pending.remove(&nonce);
+        self.released.remove(&nonce);
+        if nonce >= self.next {
+            self.next = nonce + U256::one();
+        }
+    }
+
+    /// Forgets the transaction sent with a nonce, once it has been mined
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce of the mined transaction
+    pub fn mined(&mut self, nonce: U256) {
+        self.pending.remove(&nonce);
+    }
+
+    /// Starts over from the next nonce reported by the node
+    ///
+    /// # Arguments
+    ///
+    /// * `next` - Pending transaction count of the account
+    pub fn resync(&mut self, next: U256) {
+        self.next = next;
+        self.released.clear();
+        self.pending.retain(|nonce, _| *nonce < next);
+    }
+
+    /// Returns the hash of the transaction sent with every nonce that is not mined yet
+    pub fn pending(&self) -> &BTreeMap<U256, H256> {
+        &self.pending
+    }
+
+    /// Returns the nonce handed out once every given back nonce is used
+    pub fn next(&self) -> U256 {
+        self.next
+    }
+
+    /// Returns the nonces given back, that are handed out first
+    pub fn released(&self) -> &BTreeSet<U256> {
+        &self.released
+    }
+}
+
+/// Contract call waiting in the queue
+pub struct QueuedTransaction {
+    function: String,
+    input_data: Vec<u8>,
+    retries: u64,
+    reply: oneshot::Sender<Result<SignedTransaction, ()>>,
+}
+
+/// Message to the queue processor
+enum QueueMessage {
+    /// Contract call to send
+    Send(QueuedTransaction),
+    /// Stop taking contract calls, and stop once the queue is empty
+    Close,
+}
+
+/// Handle to the transaction queue of a network. Every contract call on the network goes through it, so nonces are
+/// handed out and transactions broadcast one at a time
+#[derive(Clone)]
+pub struct TransactionQueue {
+    tx: mpsc::UnboundedSender<QueueMessage>,
+    rx: Arc<RwLock<Option<mpsc::UnboundedReceiver<QueueMessage>>>>,
+    nonces: Arc<RwLock<NonceManager>>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl TransactionQueue {
+    /// Returns a new TransactionQueue
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Next unused nonce of the relay account
+    pub fn new(nonce: U256) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        TransactionQueue {
+            tx,
+            rx: Arc::new(RwLock::new(Some(rx))),
+            nonces: Arc::new(RwLock::new(NonceManager::new(nonce))),
+            depth: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queues a contract call, and returns a Future that resolves to the signed transaction once it is broadcast
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - Name of the contract function, for logging
+    /// * `input_data` - Function data of the contract call
+    /// * `retries` - Number of times to retry with another nonce on nonce errors
+    pub fn submit(
+        &self,
+        function: &str,
+        input_data: Vec<u8>,
+        retries: u64,
+    ) -> Box<dyn Future<Item = SignedTransaction, Error = ()>> {
+        let (reply, result) = oneshot::channel();
+        let queued = QueuedTransaction {
+            function: function.to_string(),
+            input_data,
+            retries,
+            reply,
+        };
+        if self.tx.unbounded_send(QueueMessage::Send(queued)).is_err() {
+            error!("transaction queue closed, cannot send {}", function);
+            return Box::new(Err(()).into_future());
+        }
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        Box::new(result.map_err(|_| ()).and_then(|result| result))
+    }
+
+    /// Records the hash of a replacement for the transaction with the given nonce
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce of the replaced transaction
+    /// * `hash` - Hash of the replacement
+    pub fn replaced(&self, nonce: U256, hash: H256) {
+        if let Ok(mut nonces) = self.nonces.write() {
+            nonces.sent(nonce, hash);
+        }
+    }
+
+    /// Records that the transaction with the given nonce was mined
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - Nonce of the mined transaction
+    pub fn mined(&self, nonce: U256) {
+        if let Ok(mut nonces) = self.nonces.write() {
+            nonces.mined(nonce);
+        }
+    }
+
+    /// Returns the number of contract calls waiting to be sent
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of transactions sent but not mined yet
+    pub fn pending(&self) -> usize {
+        self.nonces
+            .read()
+            .map(|nonces| nonces.pending().len())
+            .unwrap_or_default()
+    }
+
+    /// Returns the nonce and latest hash of every transaction sent but not mined yet
+    pub fn pending_transactions(&self) -> Vec<(U256, H256)> {
+        self.nonces
+            .read()
+            .map(|nonces| nonces.pending().iter().map(|(nonce, hash)| (*nonce, *hash)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the next new nonce, and the nonces given back that are handed out before it
+    pub fn nonces(&self) -> (U256, Vec<U256>) {
+        self.nonces
+            .read()
+            .map(|nonces| (nonces.next(), nonces.released().iter().copied().collect()))
+            .unwrap_or_default()
+    }
+
+    /// Returns true once every queued contract call was sent, and every sent transaction was mined
+    pub fn is_drained(&self) -> bool {
+        self.depth() == 0 && self.pending() == 0
+    }
+
+    /// Stops the queue. Contract calls submitted after this fail right away
+    pub fn close(&self) {
+        let _ = self.tx.unbounded_send(QueueMessage::Close);
+    }
+
+    /// Returns a Future that processes the queue of this network. Only the first call returns a Future that does
+    /// anything, later calls resolve right away
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network the queue belongs to
+    pub fn process<T: DuplexTransport + 'static>(&self, target: &Network<T>) -> ProcessTransactionQueue<T> {
+        let rx = self.rx.write().ok().and_then(|mut rx| rx.take());
+        ProcessTransactionQueue {
+            target: target.clone(),
+            nonces: self.nonces.clone(),
+            depth: self.depth.clone(),
+            rx,
+            closed: false,
+            fill_gaps: true,
+            state: QueueState::Wait,
+        }
+    }
+}
+
+enum QueueState<T: DuplexTransport + 'static> {
+    Wait,
+    Build(Option<QueuedTransaction>, U256, BuildTransaction<T>),
+    Send(
+        Option<QueuedTransaction>,
+        SignedTransaction,
+        Box<dyn Future<Item = H256, Error = web3::Error>>,
+    ),
+    Resync(
+        Option<QueuedTransaction>,
+        Box<dyn Future<Item = U256, Error = web3::Error>>,
+    ),
+}
+
+/// Why the node rejected a transaction
+#[derive(Debug, PartialEq)]
+enum SendFailure {
+    /// The node already has this exact transaction
+    AlreadyKnown,
+    /// Our nonce is out of sync with the node
+    Desync,
+    /// Another transaction is pending with this nonce
+    Occupied,
+    /// Anything else
+    Other,
+}
+
+impl SendFailure {
+    fn from_message(message: &str) -> Self {
+        let message = message.to_lowercase();
+        if message.contains("already known") || message.contains("known transaction") {
+            SendFailure::AlreadyKnown
+        } else if message.contains("nonce too low") || message.contains("nonce too high") {
+            SendFailure::Desync
+        } else if message.contains("replacement transaction underpriced") || message.contains("replacement underpriced")
+        {
+            SendFailure::Occupied
+        } else {
+            SendFailure::Other
+        }
+    }
+}
+
+fn lock(nonces: &Arc<RwLock<NonceManager>>) -> Result<RwLockWriteGuard<NonceManager>, ()> {
+    nonces.write().map_err(|e| {
+        error!("error getting nonce lock: {:?}", e);
+    })
+}
+
+/// Takes the next nonce for a queued contract call, and starts building it
+fn build<T: DuplexTransport + 'static>(
+    target: &Network<T>,
+    nonces: &Arc<RwLock<NonceManager>>,
+    queued: QueuedTransaction,
+) -> Result<QueueState<T>, ()> {
+    let nonce = lock(nonces)?.take();
+    let future = BuildTransaction::new(target, queued.input_data.clone(), nonce);
+    Ok(QueueState::Build(Some(queued), nonce, future))
+}
+
+/// Future that builds and broadcasts queued contract calls one at a time.
+/// Resyncs the nonce on nonce errors, and fills nonce gaps left by failed sends with empty transactions
+pub struct ProcessTransactionQueue<T: DuplexTransport + 'static> {
+    target: Network<T>,
+    nonces: Arc<RwLock<NonceManager>>,
+    depth: Arc<AtomicUsize>,
+    rx: Option<mpsc::UnboundedReceiver<QueueMessage>>,
+    closed: bool,
+    fill_gaps: bool,
+    state: QueueState<T>,
+}
+
+impl<T: DuplexTransport + 'static> Future for ProcessTransactionQueue<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let target = self.target.clone();
+        let network_type = target.network_type;
+        loop {
+            metrics::transaction_queue_depth(network_type, self.depth.load(Ordering::SeqCst));
+            let next = match self.state {
+                QueueState::Wait => {
+                    let polled = match self.rx {
+                        Some(ref mut rx) => rx.poll()?,
+                        None => return Ok(Async::Ready(())),
+                    };
+                    match polled {
+                        Async::Ready(Some(QueueMessage::Send(queued))) => {
+                            self.depth.fetch_sub(1, Ordering::SeqCst);
+                            if self.closed {
+                                warn!(
+                                    "transaction queue on {:?} closed, dropping {}",
+                                    network_type, queued.function
+                                );
+                                let _ = queued.reply.send(Err(()));
+                                continue;
+                            }
+                            self.fill_gaps = true;
+                            build(&target, &self.nonces, queued)?
+                        }
+                        Async::Ready(Some(QueueMessage::Close)) => {
+                            info!("closing transaction queue on {:?}", network_type);
+                            self.closed = true;
+                            if let Some(ref mut rx) = self.rx {
+                                rx.close();
+                            }
+                            continue;
+                        }
+                        Async::Ready(None) => return Ok(Async::Ready(())),
+                        Async::NotReady => {
+                            let gap = lock(&self.nonces)?.gap();
+                            match gap {
+                                // Nothing else to send, but a failed send left a gap that blocks later nonces
+                                Some(nonce) if self.fill_gaps => {
+                                    info!("filling nonce gap {} on {:?}", nonce, network_type);
+                                    let nonce = lock(&self.nonces)?.take();
+                                    QueueState::Build(None, nonce, BuildTransaction::filler(&target, nonce))
+                                }
+                                _ => return Ok(Async::NotReady),
+                            }
+                        }
+                    }
+                }
+                QueueState::Build(ref mut queued, nonce, ref mut future) => match future.poll() {
+                    Ok(Async::Ready(transaction)) => {
+                        let send_future = target.web3.eth().send_raw_transaction(transaction.raw.clone().into());
+                        QueueState::Send(queued.take(), transaction, Box::new(send_future))
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(()) => {
+                        lock(&self.nonces)?.release(nonce);
+                        match queued.take() {
+                            Some(queued) => {
+                                error!("error building {} on {:?}", queued.function, network_type);
+                                let _ = queued.reply.send(Err(()));
+                            }
+                            None => {
+                                // Do not retry the filler until there is something new to send
+                                error!("error building nonce gap filler on {:?}", network_type);
+                                self.fill_gaps = false;
+                            }
+                        }
+                        QueueState::Wait
+                    }
+                },
+                QueueState::Send(ref mut queued, ref transaction, ref mut future) => {
+                    let failure = match future.poll() {
+                        Ok(Async::Ready(hash)) => {
+                            lock(&self.nonces)?.sent(transaction.nonce, hash);
+                            None
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            let message = format!("{:?}", e);
+                            match SendFailure::from_message(&message) {
+                                SendFailure::AlreadyKnown => {
+                                    lock(&self.nonces)?.sent(transaction.nonce, transaction.hash);
+                                    None
+                                }
+                                failure => {
+                                    warn!(
+                                        "error sending transaction with nonce {} on {:?}: {}",
+                                        transaction.nonce, network_type, message
+                                    );
+                                    Some(failure)
+                                }
+                            }
+                        }
+                    };
+                    let mut queued = match queued.take() {
+                        Some(queued) => queued,
+                        None => {
+                            if failure.is_some() {
+                                error!("error sending nonce gap filler on {:?}", network_type);
+                                lock(&self.nonces)?.release(transaction.nonce);
+                                self.fill_gaps = false;
+                            }
+                            self.state = QueueState::Wait;
+                            continue;
+                        }
+                    };
+                    match failure {
+                        None => {
+                            let _ = queued.reply.send(Ok(transaction.clone()));
+                            QueueState::Wait
+                        }
+                        Some(SendFailure::Desync) if queued.retries > 0 => {
+                            info!(
+                                "Nonce desync detected on {:?}, resyncing nonce and retrying",
+                                network_type
+                            );
+                            metrics::nonce_resynced(network_type);
+                            queued.retries -= 1;
+                            let future = target
+                                .web3
+                                .eth()
+                                .transaction_count(target.account, Some(BlockNumber::Pending));
+                            QueueState::Resync(Some(queued), Box::new(future))
+                        }
+                        Some(SendFailure::Occupied) if queued.retries > 0 => {
+                            // A transaction we do not know about holds this nonce, leave it be and use the next one
+                            queued.retries -= 1;
+                            lock(&self.nonces)?.occupied(transaction.nonce);
+                            build(&target, &self.nonces, queued)?
+                        }
+                        Some(_) => {
+                            lock(&self.nonces)?.release(transaction.nonce);
+                            error!("error sending {} on {:?}", queued.function, network_type);
+                            let _ = queued.reply.send(Err(()));
+                            QueueState::Wait
+                        }
+                    }
+                }
+                QueueState::Resync(ref mut queued, ref mut future) => {
+                    let count = try_ready!(future.poll().map_err(|e| {
+                        error!("Error getting transaction count on {:?}: {:?}", network_type, e);
+                    }));
+                    lock(&self.nonces)?.resync(count);
+                    match queued.take() {
+                        Some(queued) => build(&target, &self.nonces, queued)?,
+                        None => QueueState::Wait,
+                    }
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_failure_should_classify_node_errors() {
+        // arrange
+        let messages = vec![
+            "already known",
+            "Transaction with the same hash was already imported. known transaction: 0x00",
+            "nonce too low",
+            "nonce too high",
+            "replacement transaction underpriced",
+            "insufficient funds for gas * price + value",
+        ];
+        // act
+        let failures: Vec<SendFailure> = messages.iter().map(|m| SendFailure::from_message(m)).collect();
+        // assert
+        assert_eq!(
+            failures,
+            vec![
+                SendFailure::AlreadyKnown,
+                SendFailure::AlreadyKnown,
+                SendFailure::Desync,
+                SendFailure::Desync,
+                SendFailure::Occupied,
+                SendFailure::Other,
+            ]
+        );
+    }
+
+    #[test]
+    fn take_should_reuse_released_nonce_before_next() {
+        // arrange
+        let mut nonces = NonceManager::new(5.into());
+        let first = nonces.take();
+        let second = nonces.take();
+        let third = nonces.take();
+        // act
+        nonces.release(second);
+        let gap = nonces.gap();
+        let reused = nonces.take();
+        let next = nonces.take();
+        // assert
+        assert_eq!((first, second, third), (5.into(), 6.into(), 7.into()));
+        assert_eq!(gap, Some(6.into()));
+        assert_eq!(reused, 6.into());
+        assert_eq!(next, 8.into());
+    }
+
+    #[test]
+    fn release_should_not_leave_gap_for_last_nonce() {
+        // arrange
+        let mut nonces = NonceManager::new(5.into());
+        let nonce = nonces.take();
+        // act
+        nonces.release(nonce);
+        // assert
+        assert_eq!(nonces.gap(), None);
+        assert_eq!(nonces.take(), 5.into());
+    }
+
+    #[test]
+    fn occupied_should_skip_nonce_without_pending_transaction() {
+        // arrange
+        let mut nonces = NonceManager::new(5.into());
+        let nonce = nonces.take();
+        // act
+        nonces.occupied(nonce);
+        // assert
+        assert!(nonces.pending().is_empty());
+        assert_eq!(nonces.gap(), None);
+        assert_eq!(nonces.take(), 6.into());
+    }
+
+    #[test]
+    fn resync_should_drop_gaps_and_pending_above_next() {
+        // arrange
+        let mut nonces = NonceManager::new(0.into());
+        for i in 0..4 {
+            let nonce = nonces.take();
+            nonces.sent(nonce, H256::from_low_u64_be(i));
+        }
+        nonces.release(1.into());
+        // act
+        nonces.resync(3.into());
+        // assert
+        assert_eq!(nonces.gap(), None);
+        assert_eq!(nonces.pending().len(), 2);
+        assert_eq!(nonces.take(), 3.into());
+    }
+}
@@ -0,0 +1,290 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use web3::futures::prelude::*;
+use web3::types::{Filter, Log};
+use web3::{DuplexTransport, Error};
+
+use crate::relay::Network;
+
+/// Parts of RPC errors that mean the node refused a log query for its size, rather than failed
+const REFUSALS: [&str; 6] = ["more than", "too many", "too large", "exceed", "timeout", "timed out"];
+
+/// How a log scan splits its block range into queries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanConfig {
+    /// Most log queries in flight at once
+    pub concurrency: usize,
+    /// Blocks in the first query
+    pub initial_window: u64,
+    /// Fewest blocks in a query, a refused window this small fails the scan
+    pub min_window: u64,
+    /// Most blocks in a query
+    pub max_window: u64,
+    /// Windows with fewer logs than this are sparse, and double the following windows
+    pub sparse_logs: usize,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            concurrency: 4,
+            initial_window: 1_000,
+            min_window: 10,
+            max_window: 100_000,
+            sparse_logs: 1_000,
+        }
+    }
+}
+
+/// Returns true if the node refused the log query because of its size, or did not answer it in time
+///
+/// # Arguments
+///
+/// * `error` - Error returned for the query
+pub fn is_refused(error: &Error) -> bool {
+    match error {
+        Error::Unreachable => true,
+        Error::Rpc(e) => {
+            let message = e.message.to_lowercase();
+            REFUSALS.iter().any(|refusal| message.contains(refusal))
+        }
+        Error::Transport(message) => {
+            let message = message.to_lowercase();
+            message.contains("timeout") || message.contains("timed out")
+        }
+        _ => false,
+    }
+}
+
+/// Splits a block range into windows, shrinking them when the node refuses one and growing them while they are sparse
+#[derive(Debug)]
+pub struct ScanWindows {
+    config: ScanConfig,
+    next: u64,
+    to: u64,
+    window: u64,
+    /// Halves of refused windows, fetched before any new window
+    retries: BTreeSet<(u64, u64)>,
+}
+
+impl ScanWindows {
+    /// Returns windows covering the range
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - How windows are sized
+    /// * `from` - First block of the range
+    /// * `to` - Last block of the range
+    pub fn new(config: ScanConfig, from: u64, to: u64) -> Self {
+        let window = cmp::min(cmp::max(config.initial_window, config.min_window), config.max_window);
+        ScanWindows {
+            config,
+            next: from,
+            to,
+            window: cmp::max(window, 1),
+            retries: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the first and last block of the next window to fetch, if any are left
+    ///
+    /// # Arguments
+    ///
+    /// * `fresh` - Whether a window that was not refused before can be returned, retries are always returned first
+    pub fn next_window(&mut self, fresh: bool) -> Option<(u64, u64)> {
+        if let Some(&retry) = self.retries.iter().next() {
+            self.retries.remove(&retry);
+            return Some(retry);
+        }
+        if !fresh || self.next > self.to {
+            return None;
+        }
+        let from = self.next;
+        let to = cmp::min(from.saturating_add(self.window - 1), self.to);
+        self.next = to + 1;
+        Some((from, to))
+    }
+
+    /// Records the number of logs in a fetched window, growing the next windows if it was sparse
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Number of logs in the window
+    pub fn fetched(&mut self, logs: usize) {
+        if logs < self.config.sparse_logs {
+            self.window = cmp::min(self.window.saturating_mul(2), self.config.max_window);
+        }
+    }
+
+    /// Splits a refused window in two and shrinks the next windows. Returns false if it is too small to split
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - First block of the window
+    /// * `to` - Last block of the window
+    pub fn refused(&mut self, from: u64, to: u64) -> bool {
+        let blocks = to - from + 1;
+        if blocks <= self.config.min_window || blocks == 1 {
+            return false;
+        }
+        let half = blocks / 2;
+        self.window = cmp::max(cmp::min(self.window, half), self.config.min_window);
+        self.retries.insert((from, from + half - 1));
+        self.retries.insert((from + half, to));
+        true
+    }
+}
+
+/// Stream of the logs in a block range, one window at a time in block order, with the last block of each window.
+/// Windows are fetched concurrently up to the scan concurrency of the network, and sized by ScanWindows
+pub struct ScanLogs<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    filter: Box<dyn Fn(u64, u64) -> Filter>,
+    windows: ScanWindows,
+    pending: Vec<(u64, u64, Box<dyn Future<Item = Vec<Log>, Error = Error>>)>,
+    /// Fetched windows waiting for the windows before them, by first block
+    fetched: BTreeMap<u64, (u64, Vec<Log>)>,
+    /// First block of the next window to yield
+    next: u64,
+    to: u64,
+}
+
+impl<T: DuplexTransport + 'static> ScanLogs<T> {
+    /// Returns a newly created ScanLogs Stream
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network to get logs from
+    /// * `from` - First block to scan
+    /// * `to` - Last block to scan
+    /// * `filter` - Returns the log filter between two blocks
+    pub fn new<F>(source: &Network<T>, from: u64, to: u64, filter: F) -> Self
+    where
+        F: Fn(u64, u64) -> Filter + 'static,
+    {
+        ScanLogs {
+            source: source.clone(),
+            filter: Box::new(filter),
+            windows: ScanWindows::new(source.scan, from, to),
+            pending: Vec::new(),
+            fetched: BTreeMap::new(),
+            next: from,
+            to,
+        }
+    }
+
+    /// Sends queries for the next windows, up to the scan concurrency. New windows wait while too many fetched windows
+    /// wait for the ones before them
+    fn fill(&mut self) {
+        let concurrency = cmp::max(self.source.scan.concurrency, 1);
+        while self.pending.len() < concurrency {
+            let fresh = self.pending.len() + self.fetched.len() < 2 * concurrency;
+            match self.windows.next_window(fresh) {
+                Some((from, to)) => {
+                    let future = self.source.web3.eth().logs((self.filter)(from, to));
+                    self.pending.push((from, to, Box::new(future)));
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Stream for ScanLogs<T> {
+    type Item = (u64, Vec<Log>);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let network_type = self.source.network_type;
+        loop {
+            if let Some((to, logs)) = self.fetched.remove(&self.next) {
+                self.next = to + 1;
+                return Ok(Async::Ready(Some((to, logs))));
+            }
+            if self.next > self.to {
+                return Ok(Async::Ready(None));
+            }
+            self.fill();
+            let mut progress = false;
+            let mut i = 0;
+            while i < self.pending.len() {
+                match self.pending[i].2.poll() {
+                    Ok(Async::NotReady) => i += 1,
+                    Ok(Async::Ready(logs)) => {
+                        let (from, to, _) = self.pending.remove(i);
+                        debug!(
+                            "found {} logs between {} and {} on {:?}",
+                            logs.len(),
+                            from,
+                            to,
+                            network_type
+                        );
+                        self.windows.fetched(logs.len());
+                        self.fetched.insert(from, (to, logs));
+                        progress = true;
+                    }
+                    Err(e) => {
+                        let (from, to, _) = self.pending.remove(i);
+                        if is_refused(&e) && self.windows.refused(from, to) {
+                            debug!(
+                                "splitting logs between {} and {} on {:?}: {:?}",
+                                from, to, network_type, e
+                            );
+                            progress = true;
+                        } else {
+                            error!(
+                                "error getting logs between {} and {} on {:?}: {:?}",
+                                from, to, network_type, e
+                            );
+                            return Err(());
+                        }
+                    }
+                }
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_should_split_refused_and_grow_sparse() {
+        // arrange
+        let config = ScanConfig {
+            concurrency: 2,
+            initial_window: 100,
+            min_window: 10,
+            max_window: 400,
+            sparse_logs: 5,
+        };
+        let mut windows = ScanWindows::new(config, 0, 999);
+        let refusal = Error::Rpc(jsonrpc_core::Error {
+            code: jsonrpc_core::ErrorCode::ServerError(-32005),
+            message: "query returned more than 10000 results".to_string(),
+            data: None,
+        });
+        // act
+        let first = windows.next_window(true);
+        let second = windows.next_window(true);
+        let split = windows.refused(0, 99);
+        windows.fetched(50);
+        let retried = (windows.next_window(true), windows.next_window(true));
+        let shrunk = windows.next_window(true);
+        windows.fetched(1);
+        let grown = windows.next_window(true);
+        // assert
+        assert!(is_refused(&refusal));
+        assert!(!is_refused(&Error::Internal));
+        assert_eq!((first, second), (Some((0, 99)), Some((100, 199))));
+        assert!(split);
+        assert_eq!(retried, (Some((0, 49)), Some((50, 99))));
+        assert_eq!(shrunk, Some((200, 249)));
+        assert_eq!(grown, Some((250, 349)));
+        assert!(!windows.refused(0, 9));
+    }
+}
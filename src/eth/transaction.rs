@@ -1,104 +1,269 @@
 use common_types::transaction::{Action, Transaction as RawTransactionRequest, UnverifiedTransaction};
+use ethkey::Signature;
 use ethstore::accounts_dir::RootDiskDirectory;
 use ethstore::{EthStore, SimpleSecretStore, StoreAccountRef};
 use rlp::Encodable;
 use rlp::RlpStream;
-use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tiny_keccak::keccak256;
+use web3::confirm::wait_for_transaction_confirmation;
 use web3::contract::tokens::Tokenize;
+use web3::futures::future::{self, join_all};
 use web3::futures::prelude::*;
 use web3::futures::try_ready;
-use web3::types::{TransactionReceipt, U256};
+use web3::types::{Address, Bytes, CallRequest, TransactionReceipt, H256, U256};
 use web3::DuplexTransport;
 
+use super::gas::GasPrice;
 use crate::errors::OperationError;
+use crate::metrics;
 use crate::relay::Network;
+use crate::store::audit::{AuditRecord, AuditStatus, AuditTransfer};
 
-pub enum TransactionState<T, P>
+/// EIP-2718 type of EIP-1559 transactions
+const EIP1559_TRANSACTION_TYPE: u8 = 0x02;
+/// Gas limit of a plain transfer, used by nonce gap fillers
+const FILLER_GAS_LIMIT: u64 = 21_000;
+/// How often to check for new blocks while waiting on a sent transaction
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub enum TransactionState<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
-    Build(BuildTransaction<T, P>),
-    Send(Box<dyn Future<Item = TransactionReceipt, Error = web3::error::Error>>),
-    ResyncNonce(Box<dyn Future<Item = U256, Error = ()>>),
+    Queued(Box<dyn Future<Item = SignedTransaction, Error = ()>>),
+    Build(BuildTransaction<T>),
+    Send(
+        SignedTransaction,
+        Box<dyn Future<Item = H256, Error = web3::error::Error>>,
+    ),
+    Wait(
+        SignedTransaction,
+        Box<dyn Future<Item = Option<TransactionReceipt>, Error = ()>>,
+    ),
+    Confirm(
+        SignedTransaction,
+        Box<dyn Future<Item = TransactionReceipt, Error = web3::error::Error>>,
+    ),
+}
+
+/// A signed transaction, with everything needed to replace it
+#[derive(Clone, Debug)]
+pub struct SignedTransaction {
+    pub to: Address,
+    pub data: Vec<u8>,
+    pub raw: Vec<u8>,
+    pub hash: H256,
+    pub nonce: U256,
+    pub gas: U256,
+    pub gas_price: GasPrice,
 }
 
-/// This struct implements Future so that it is easy to build a transaction, with the proper gas price in a future
-pub struct BuildTransaction<T, P>
+/// This struct implements Future so that it is easy to build a transaction, with the proper gas price and gas limit
+/// in a future
+pub struct BuildTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
     target: Network<T>,
-    function: String,
-    params: P,
-    nonce: Option<U256>,
-    gas_future: Box<dyn Future<Item = U256, Error = ()>>,
+    to: Address,
+    input_data: Vec<u8>,
+    nonce: U256,
+    gas_future: Box<dyn Future<Item = (U256, GasPrice), Error = ()>>,
 }
 
-impl<T, P> BuildTransaction<T, P>
+impl<T> BuildTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
-    pub fn new(target: &Network<T>, function_name: &str, params: P, nonce: Option<U256>) -> Self {
+    /// Returns a BuildTransaction for a call to the relay contract, with an estimated gas limit
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the transaction will be sent
+    /// * `input_data` - Function data for the relay contract call
+    /// * `nonce` - Transaction nonce
+    pub fn new(target: &Network<T>, input_data: Vec<u8>, nonce: U256) -> Self {
         let network_type = target.network_type;
-        let gas_future = target.web3.eth().gas_price().map_err(move |e| {
-            error!("error fetching current gas price on {:?}: {}", network_type, e);
-        });
+        let gas = target.gas;
+        let estimate_future = target
+            .web3
+            .eth()
+            .estimate_gas(
+                CallRequest {
+                    from: Some(target.account),
+                    to: target.relay.address(),
+                    gas: None,
+                    gas_price: None,
+                    value: None,
+                    data: Some(Bytes(input_data.clone())),
+                },
+                None,
+            )
+            .map(move |estimate| gas.gas_limit(estimate))
+            .map_err(move |e| {
+                error!("error estimating gas on {:?}: {}", network_type, e);
+            });
+
+        BuildTransaction {
+            target: target.clone(),
+            to: target.relay.address(),
+            input_data,
+            nonce,
+            gas_future: Box::new(estimate_future.join(target.gas.gas_price(&target.web3, network_type))),
+        }
+    }
+
+    /// Returns a BuildTransaction that replaces an already sent transaction, using the same nonce and gas limit
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the transaction was sent
+    /// * `replaced` - The transaction being replaced
+    /// * `gas_price` - The bumped gas price
+    pub fn replacement(target: &Network<T>, replaced: &SignedTransaction, gas_price: GasPrice) -> Self {
+        BuildTransaction {
+            target: target.clone(),
+            to: replaced.to,
+            input_data: replaced.data.clone(),
+            nonce: replaced.nonce,
+            gas_future: Box::new(future::ok((replaced.gas, gas_price))),
+        }
+    }
 
+    /// Returns a BuildTransaction for an empty transfer to the relay account, used to fill a nonce gap
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network with the nonce gap
+    /// * `nonce` - The unused nonce
+    pub fn filler(target: &Network<T>, nonce: U256) -> Self {
+        let gas_price = target.gas.gas_price(&target.web3, target.network_type);
         BuildTransaction {
             target: target.clone(),
-            function: function_name.to_string(),
-            params,
+            to: target.account,
+            input_data: Vec::new(),
             nonce,
-            gas_future: Box::new(gas_future),
+            gas_future: Box::new(gas_price.map(|gas_price| (FILLER_GAS_LIMIT.into(), gas_price))),
         }
     }
 
-    /// Mutates a RlpStream with ready to send transaction data
+    /// Returns the input data for a call to a relay contract function
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network with the relay contract
+    /// * `function_name` - Name of the contract function to call
+    /// * `params` - Params for the contract function
+    pub fn function_data<P: Tokenize>(target: &Network<T>, function_name: &str, params: P) -> Result<Vec<u8>, ()> {
+        target.relay.get_function_data(function_name, params).map_err(move |e| {
+            error!("error build input data: {}", e);
+        })
+    }
+
+    /// Returns a signed legacy transaction, ready to send
     ///
     /// # Arguments
     ///
-    /// * `input_data` - Function data from the contract this transaction is being built for
     /// * `gas` - The gas limit for this transaction
     /// * `gas_price` - The gas price for this transaction
     /// * `value` - The eth value transferred in this transaction
     /// * `nonce` - Transaction nonce
     pub fn build_transaction(
         &self,
-        input_data: &[u8],
         gas: U256,
         gas_price: U256,
         value: U256,
         nonce: U256,
-    ) -> Result<RlpStream, OperationError> {
+    ) -> Result<Vec<u8>, OperationError> {
         let store = self.get_store_for_keyfiles();
         let transaction_request = RawTransactionRequest {
-            action: Action::Call(self.target.relay.address()),
+            action: Action::Call(self.to),
             gas,
             gas_price,
             value,
             nonce,
-            data: input_data.to_vec(),
+            data: self.input_data.clone(),
         };
-        let password = self.target.password.clone();
         let raw_tx = transaction_request.hash(Some(self.target.chain_id));
-        let signed_tx = store
+        let signed_tx = self.sign(&store, &raw_tx)?;
+        let tx_with_sig: UnverifiedTransaction =
+            transaction_request.with_signature(signed_tx, Some(self.target.chain_id));
+        let mut stream = RlpStream::new();
+        tx_with_sig.rlp_append(&mut stream);
+        Ok(stream.out())
+    }
+
+    /// Returns a signed EIP-1559 (type-2) transaction, ready to send
+    ///
+    /// # Arguments
+    ///
+    /// * `gas` - The gas limit for this transaction
+    /// * `max_fee` - The max fee per gas for this transaction
+    /// * `priority_fee` - The max priority fee per gas for this transaction
+    /// * `value` - The eth value transferred in this transaction
+    /// * `nonce` - Transaction nonce
+    pub fn build_eip1559_transaction(
+        &self,
+        gas: U256,
+        max_fee: U256,
+        priority_fee: U256,
+        value: U256,
+        nonce: U256,
+    ) -> Result<Vec<u8>, OperationError> {
+        let store = self.get_store_for_keyfiles();
+        let mut stream = RlpStream::new();
+        stream.begin_list(9);
+        self.append_eip1559_fields(&mut stream, gas, max_fee, priority_fee, value, nonce);
+        let mut payload = vec![EIP1559_TRANSACTION_TYPE];
+        payload.extend_from_slice(&stream.out());
+        let signature = self.sign(&store, &H256(keccak256(&payload)))?;
+
+        let mut stream = RlpStream::new();
+        stream.begin_list(12);
+        self.append_eip1559_fields(&mut stream, gas, max_fee, priority_fee, value, nonce);
+        stream.append(&signature.v());
+        stream.append(&U256::from(signature.r()));
+        stream.append(&U256::from(signature.s()));
+        let mut raw = vec![EIP1559_TRANSACTION_TYPE];
+        raw.extend_from_slice(&stream.out());
+        Ok(raw)
+    }
+
+    /// Appends the unsigned fields of an EIP-1559 transaction, with an empty access list
+    fn append_eip1559_fields(
+        &self,
+        stream: &mut RlpStream,
+        gas: U256,
+        max_fee: U256,
+        priority_fee: U256,
+        value: U256,
+        nonce: U256,
+    ) {
+        stream.append(&self.target.chain_id);
+        stream.append(&nonce);
+        stream.append(&priority_fee);
+        stream.append(&max_fee);
+        stream.append(&gas);
+        stream.append(&self.to);
+        stream.append(&value);
+        stream.append(&self.input_data);
+        stream.begin_list(0);
+    }
+
+    /// Signs the transaction hash with the relay account
+    fn sign(&self, store: &EthStore, hash: &H256) -> Result<Signature, OperationError> {
+        let password = self.target.password.clone();
+        store
             .sign(
                 &StoreAccountRef::root(self.target.account.0.into()),
                 &password.into(),
-                &raw_tx,
+                hash,
             )
             .map_err(move |e| {
                 error!("error signing transaction: {}", e);
                 OperationError::CouldNotBuildTransaction("Could not sign transaction".to_string())
-            })?;
-        let tx_with_sig: UnverifiedTransaction =
-            transaction_request.with_signature(signed_tx, Some(self.target.chain_id));
-        let mut stream = RlpStream::new();
-        tx_with_sig.rlp_append(&mut stream);
-        Ok(stream)
+            })
     }
 
     /// Returns a keyfile store of accounts to be used for signing
@@ -117,35 +282,32 @@ where
     }
 }
 
-impl<T, P> Future for BuildTransaction<T, P>
+impl<T> Future for BuildTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
-    type Item = RlpStream;
+    type Item = SignedTransaction;
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        // Get gas price
-        let eth_gas_price = try_ready!(self.gas_future.poll());
-        if let Some(nonce) = self.nonce {
-            self.target.nonce.store(nonce.as_u64() as usize, Ordering::SeqCst);
-        }
-        // create input data
-        let params = self.params.clone();
-        let function = self.function.clone();
-        let input_data = self
-            .target
-            .relay
-            .get_function_data(&function, params)
-            .map_err(move |e| {
-                error!("error build input data: {}", e);
-            })?;
-        let gas = self.target.get_gas_limit();
-        let gas_price = self.target.finalize_gas_price(eth_gas_price);
-        let nonce = U256::from(self.target.nonce.load(Ordering::SeqCst));
-        self.target.nonce.fetch_add(1, Ordering::SeqCst);
-        match self.build_transaction(&input_data, gas, gas_price, 0.into(), nonce) {
-            Ok(stream) => Ok(Async::Ready(stream)),
+        // Get gas limit and gas price
+        let (gas, gas_price) = try_ready!(self.gas_future.poll());
+        let nonce = self.nonce;
+        let result = match gas_price {
+            GasPrice::Legacy(gas_price) => self.build_transaction(gas, gas_price, 0.into(), nonce),
+            GasPrice::Eip1559 { max_fee, priority_fee } => {
+                self.build_eip1559_transaction(gas, max_fee, priority_fee, 0.into(), nonce)
+            }
+        };
+        match result {
+            Ok(raw) => Ok(Async::Ready(SignedTransaction {
+                to: self.to,
+                data: self.input_data.clone(),
+                hash: H256(keccak256(&raw)),
+                raw,
+                nonce,
+                gas,
+                gas_price,
+            })),
             Err(e) => {
                 error!("error building transaction: {}", e);
                 Err(())
@@ -154,23 +316,25 @@ where
     }
 }
 
-/// Future that calls the ERC20RelayContract to approve a transfer across the relay
-pub struct SendTransaction<T, P>
+/// Future that calls the ERC20RelayContract to approve a transfer across the relay.
+/// The call goes through the transaction queue of the target network, which picks the nonce and broadcasts it.
+/// If the transaction is not mined after the configured number of blocks, it is replaced by one with the same nonce and
+/// a higher gas price
+pub struct SendTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
     function: String,
     target: Network<T>,
-    state: TransactionState<T, P>,
-    params: P,
-    retries: u64, // amount of times relay should try to resync nonce
+    state: TransactionState<T>,
+    hashes: Vec<H256>, // hashes of the original transaction and every replacement
+    audit: Option<AuditTransfer>,
+    sent: Option<Instant>,
 }
 
-impl<T, P> SendTransaction<T, P>
+impl<T> SendTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
     /// Returns a newly created ApproveWithdrawal Future
     ///
@@ -179,102 +343,296 @@ where
     /// * `target` - Network where the withdrawal will be posted to the contract
     /// * `function` - Name of the function to call
     /// * `params` - Vec of Tokens corresponsind to the params for the contract function parameters
-    pub fn new(target: &Network<T>, function: &str, params: &P, retries: u64) -> Self {
+    /// * `retries` - Number of times the queue should retry with a resynced nonce
+    pub fn new<P: Tokenize + Clone>(target: &Network<T>, function: &str, params: &P, retries: u64) -> Self {
         let target = target.clone();
-        let future = BuildTransaction::new(&target, function, params.clone(), None);
-        let state = TransactionState::Build(future);
+        let future: Box<dyn Future<Item = SignedTransaction, Error = ()>> =
+            match BuildTransaction::function_data(&target, function, params.clone()) {
+                Ok(input_data) => target.transactions.submit(function, input_data, retries),
+                Err(()) => Box::new(future::err(())),
+            };
 
         SendTransaction {
             function: function.to_string(),
             target,
-            state,
-            params: params.clone(),
-            retries,
+            state: TransactionState::Queued(future),
+            hashes: Vec::new(),
+            audit: None,
+            sent: None,
+        }
+    }
+
+    /// Records the transaction in the audit log of the target network as it is sent, replaced and mined
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - Transfer the transaction is sent for
+    pub fn audited(mut self, transfer: AuditTransfer) -> Self {
+        self.audit = Some(transfer);
+        self
+    }
+
+    fn record(&self, status: AuditStatus) {
+        if let Some(transfer) = &self.audit {
+            let elapsed = self.sent.map(|sent| sent.elapsed().as_secs());
+            let record = AuditRecord::new(transfer, &self.function, self.hashes.last().copied(), status, elapsed);
+            self.target.audit.append(&record);
         }
     }
+
+    /// Returns a Future that resolves to the receipt of the first of the sent transactions to be mined, or None if
+    /// none of them were mined after stuck_blocks blocks
+    fn wait_for_receipt(&self) -> Box<dyn Future<Item = Option<TransactionReceipt>, Error = ()>> {
+        let network_type = self.target.network_type;
+        let web3 = self.target.web3.clone();
+        let hashes = self.hashes.clone();
+        let blocks = match self.target.gas.stuck_blocks {
+            0 => u64::max_value(),
+            blocks => blocks,
+        };
+        Box::new(
+            web3.eth_filter()
+                .create_blocks_filter()
+                .and_then(move |filter| {
+                    filter
+                        .stream(RECEIPT_POLL_INTERVAL)
+                        .take(blocks)
+                        .and_then(move |_| {
+                            let futures: Vec<_> = hashes
+                                .iter()
+                                .map(|hash| web3.eth().transaction_receipt(*hash))
+                                .collect();
+                            join_all(futures)
+                        })
+                        .filter_map(|receipts| {
+                            receipts
+                                .into_iter()
+                                .flatten()
+                                .find(|receipt| receipt.block_number.is_some())
+                        })
+                        .into_future()
+                        .map(|(receipt, _)| receipt)
+                        .map_err(|(e, _)| e)
+                })
+                .map_err(move |e| {
+                    error!("error waiting for transaction receipt on {:?}: {:?}", network_type, e);
+                }),
+        )
+    }
 }
 
-impl<T, P: 'static> Future for SendTransaction<T, P>
+impl<T> Future for SendTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone,
 {
     type Item = ();
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let function = self.function.clone();
         let target = self.target.clone();
-        let params = self.params.clone();
+        let network_type = target.network_type;
         loop {
             let next = match self.state {
+                TransactionState::Queued(ref mut future) => {
+                    let transaction = match future.poll() {
+                        Ok(Async::Ready(transaction)) => transaction,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            metrics::transaction_failed(network_type, &function);
+                            self.record(AuditStatus::Failed);
+                            return Err(e);
+                        }
+                    };
+                    metrics::transaction_sent(network_type, &function);
+                    self.hashes.push(transaction.hash);
+                    self.sent = Some(Instant::now());
+                    self.record(AuditStatus::Sent);
+                    TransactionState::Wait(transaction, self.wait_for_receipt())
+                }
                 TransactionState::Build(ref mut future) => {
-                    let rlp_stream = try_ready!(future.poll());
-                    let send_future = self.target.relay.send_raw_call_with_confirmations(
-                        rlp_stream.as_raw().into(),
-                        self.target.confirmations as usize,
-                    );
-                    TransactionState::Send(Box::new(send_future))
+                    let transaction = try_ready!(future.poll());
+                    let send_future = target.web3.eth().send_raw_transaction(transaction.raw.clone().into());
+                    TransactionState::Send(transaction, Box::new(send_future))
                 }
-                TransactionState::Send(ref mut future) => {
-                    let target = self.target.clone();
-                    let network_type = target.network_type;
-                    let function = self.function.clone();
-                    match future.poll() {
-                        Ok(Async::Ready(receipt)) => {
-                            match receipt.status {
-                                Some(result) => {
-                                    if result == 1.into() {
-                                        info!("{} on {:?} successful: {:?}", function, network_type, receipt);
-                                    } else {
-                                        error!("{} on {:?} failed: {:?}", function, network_type, receipt);
-                                        return Err(());
-                                    }
-                                }
-                                None => {
-                                    error!(
-                                        "{} receipt on {:?} has no status: {:?}",
-                                        function, network_type, receipt
-                                    );
-                                }
-                            }
-                            return Ok(Async::Ready(()));
+                TransactionState::Send(ref transaction, ref mut future) => match future.poll() {
+                    Ok(Async::Ready(hash)) => {
+                        info!(
+                            "replaced {} on {:?} with {:?}, nonce {}, gas price {:?}",
+                            function, network_type, hash, transaction.nonce, transaction.gas_price
+                        );
+                        metrics::transaction_replaced(network_type, &function);
+                        target.transactions.replaced(transaction.nonce, hash);
+                        self.hashes.push(hash);
+                        self.record(AuditStatus::Replaced);
+                        TransactionState::Wait(transaction.clone(), self.wait_for_receipt())
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        // The replaced transaction may have been mined in the meantime, keep waiting on it
+                        warn!("error replacing {} on {:?}: {:?}", function, network_type, e);
+                        TransactionState::Wait(transaction.clone(), self.wait_for_receipt())
+                    }
+                },
+                TransactionState::Wait(ref transaction, ref mut future) => match try_ready!(future.poll()) {
+                    Some(receipt) => {
+                        target.transactions.mined(transaction.nonce);
+                        let future = wait_for_transaction_confirmation(
+                            target.web3.transport().clone(),
+                            receipt.transaction_hash,
+                            RECEIPT_POLL_INTERVAL,
+                            target.confirmations as usize,
+                        );
+                        TransactionState::Confirm(transaction.clone(), Box::new(future))
+                    }
+                    None => match target.gas.bump(transaction.gas_price) {
+                        Some(gas_price) => {
+                            warn!(
+                                "{} on {:?} not mined after {} blocks, replacing {:?}",
+                                function, network_type, target.gas.stuck_blocks, transaction.hash
+                            );
+                            TransactionState::Build(BuildTransaction::replacement(&target, transaction, gas_price))
+                        }
+                        None => {
+                            warn!(
+                                "{} on {:?} not mined, but cannot raise the gas price further, waiting on {:?}",
+                                function, network_type, self.hashes
+                            );
+                            TransactionState::Wait(transaction.clone(), self.wait_for_receipt())
                         }
+                    },
+                },
+                TransactionState::Confirm(_, ref mut future) => {
+                    let receipt = match future.poll() {
+                        Ok(Async::Ready(receipt)) => receipt,
                         Ok(Async::NotReady) => return Ok(Async::NotReady),
                         Err(e) => {
-                            let message = format!("{} failed: {:?}", function, e);
-                            if self.retries > 0 && message.contains("nonce too low") {
-                                info!(
-                                    "Nonce desync detected on {:?}, resyncing nonce and retrying",
-                                    network_type
-                                );
-                                let nonce_future =
-                                    target
-                                        .web3
-                                        .eth()
-                                        .transaction_count(target.account, None)
-                                        .map_err(move |_e| {
-                                            error!("Error getting transaction count on {:?}", network_type);
-                                        });
-                                TransactionState::ResyncNonce(Box::new(nonce_future))
+                            error!("error confirming transaction on {:?}: {:?}", network_type, e);
+                            metrics::transaction_failed(network_type, &function);
+                            self.record(AuditStatus::Failed);
+                            return Err(());
+                        }
+                    };
+                    // The mined transaction may be an earlier one than the last replacement
+                    self.hashes.retain(|hash| *hash != receipt.transaction_hash);
+                    self.hashes.push(receipt.transaction_hash);
+                    match receipt.status {
+                        Some(result) => {
+                            if result == 1.into() {
+                                info!("{} on {:?} successful: {:?}", function, network_type, receipt);
+                                metrics::transaction_succeeded(network_type, &function);
+                                self.record(AuditStatus::Succeeded);
                             } else {
-                                error!("error sending transaction on {:?}: {:?}", network_type, e);
+                                error!("{} on {:?} failed: {:?}", function, network_type, receipt);
+                                metrics::transaction_failed(network_type, &function);
+                                self.record(AuditStatus::Failed);
                                 return Err(());
                             }
                         }
+                        None => {
+                            error!(
+                                "{} receipt on {:?} has no status: {:?}",
+                                function, network_type, receipt
+                            );
+                            self.record(AuditStatus::Succeeded);
+                        }
                     }
-                }
-                TransactionState::ResyncNonce(ref mut future) => {
-                    let nonce = try_ready!(future.poll());
-                    let function = function.clone();
-                    let params = params.clone();
-                    let future = BuildTransaction::new(&target, &function, params, Some(nonce));
-                    TransactionState::Build(future)
+                    return Ok(Async::Ready(()));
                 }
             };
             self.state = next;
-            if let TransactionState::Build(_) = self.state {
-                self.retries -= 1;
-            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::gas::GasStrategy;
+    use crate::mock::transport::MockTransport;
+    use crate::relay::NetworkType;
+    use serde_json::{json, Value};
+    use tokio_core::reactor;
+    use web3::types::H2048;
+
+    /// Account of a keyfile in docker/keyfiles, with the password of the mock network
+    const KEYFILE_ACCOUNT: &str = "085ba02c35555fecb8147820135fdfec10eed85a";
+
+    fn receipt(hash: H256) -> Value {
+        json!({
+            "transactionHash": hash,
+            "transactionIndex": "0x0",
+            "blockHash": H256::from_low_u64_be(0x10),
+            "blockNumber": "0x10",
+            "cumulativeGasUsed": "0x5208",
+            "gasUsed": "0x5208",
+            "contractAddress": null,
+            "logs": [],
+            "status": "0x1",
+            "logsBloom": H2048::zero(),
+        })
+    }
+
+    #[test]
+    fn send_should_replace_stuck_transaction_and_accept_earlier_receipt() {
+        // arrange
+        let mut eloop = reactor::Core::new().unwrap();
+        let mut mock = MockTransport::new();
+        let mut target = mock.new_network(NetworkType::Home).unwrap();
+        target.account = KEYFILE_ACCOUNT.parse().unwrap();
+        target.keydir = "docker/keyfiles".to_string();
+        target.gas = GasStrategy {
+            stuck_blocks: 1,
+            bump_percent: 10,
+            ..GasStrategy::default()
+        };
+        let stuck = SignedTransaction {
+            to: target.relay.address(),
+            data: Vec::new(),
+            raw: Vec::new(),
+            hash: H256::from_low_u64_be(1),
+            nonce: 7.into(),
+            gas: 100_000.into(),
+            gas_price: GasPrice::Legacy(100.into()),
+        };
+        let bumped = target.gas.bump(stuck.gas_price).unwrap();
+        let replacement = eloop
+            .run(BuildTransaction::replacement(&target, &stuck, bumped))
+            .unwrap();
+        for _ in 0..3 {
+            mock.add_method_response("eth_newBlockFilter", json!("0x1"));
+        }
+        for _ in 0..4 {
+            mock.add_method_response("eth_getFilterChanges", json!([H256::from_low_u64_be(0x10)]));
+        }
+        // Not mined within stuck_blocks, then mined before its replacement
+        mock.add_method_response("eth_getTransactionReceipt", Value::Null);
+        mock.add_method_response("eth_getTransactionReceipt", receipt(stuck.hash));
+        mock.add_method_response("eth_getTransactionReceipt", Value::Null);
+        for _ in 0..3 {
+            mock.add_method_response("eth_getTransactionReceipt", receipt(stuck.hash));
+        }
+        mock.add_method_response("eth_sendRawTransaction", json!(replacement.hash));
+        for _ in 0..2 {
+            mock.add_method_response("eth_blockNumber", json!("0x10"));
+        }
+        let mut send = SendTransaction {
+            function: "approveWithdrawal".to_string(),
+            target: target.clone(),
+            state: TransactionState::Queued(Box::new(future::ok(stuck.clone()))),
+            hashes: Vec::new(),
+            audit: None,
+            sent: None,
+        };
+        // act
+        let result = eloop.run(&mut send);
+        // assert
+        assert!(result.is_ok());
+        assert_eq!(bumped, GasPrice::Legacy(111.into()));
+        assert_eq!(
+            mock.requests("eth_sendRawTransaction"),
+            vec![vec![json!(Bytes(replacement.raw.clone()))]]
+        );
+        assert_eq!(send.hashes, vec![replacement.hash, stuck.hash]);
+        assert_eq!(target.transactions.pending(), 0);
+    }
+}
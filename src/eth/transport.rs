@@ -0,0 +1,85 @@
+use jsonrpc_core as rpc;
+use std::sync::{Arc, RwLock};
+use web3::api::SubscriptionId;
+use web3::futures::prelude::*;
+use web3::transports::Result;
+use web3::{BatchTransport, DuplexTransport, Error, RequestId, Transport};
+
+use super::polling::PollingTransport;
+use super::websocket::{CheckEndpoints, ReconnectingWebSocket};
+
+/// Transport of a chain, picked in the settings.
+/// Both chains of a relay share one transport type, so this lets each chain use a different one
+#[derive(Clone, Debug)]
+pub enum ChainTransport {
+    WebSocket(ReconnectingWebSocket),
+    Polling(PollingTransport),
+}
+
+impl ChainTransport {
+    /// Returns the name of the endpoint in use, shared with the transport so it stays current
+    pub fn active_endpoint(&self) -> Arc<RwLock<Option<String>>> {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.active_endpoint(),
+            ChainTransport::Polling(http) => http.endpoint(),
+        }
+    }
+
+    /// Returns a Future that checks the health of the endpoints, if the transport has several to pick from
+    pub fn check_health(&self) -> Option<CheckEndpoints> {
+        match self {
+            ChainTransport::WebSocket(ws) => Some(ws.check_health()),
+            ChainTransport::Polling(_) => None,
+        }
+    }
+}
+
+impl Transport for ChainTransport {
+    type Out = Box<dyn Future<Item = rpc::Value, Error = Error>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.prepare(method, params),
+            ChainTransport::Polling(http) => http.prepare(method, params),
+        }
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.send(id, request),
+            ChainTransport::Polling(http) => http.send(id, request),
+        }
+    }
+}
+
+impl BatchTransport for ChainTransport {
+    type Batch = Box<dyn Future<Item = Vec<Result<rpc::Value>>, Error = Error>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, rpc::Call)>,
+    {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.send_batch(requests),
+            ChainTransport::Polling(http) => http.send_batch(requests),
+        }
+    }
+}
+
+impl DuplexTransport for ChainTransport {
+    type NotificationStream = Box<dyn Stream<Item = rpc::Value, Error = Error> + Send + 'static>;
+
+    fn subscribe(&self, id: &SubscriptionId) -> Self::NotificationStream {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.subscribe(id),
+            ChainTransport::Polling(http) => http.subscribe(id),
+        }
+    }
+
+    fn unsubscribe(&self, id: &SubscriptionId) {
+        match self {
+            ChainTransport::WebSocket(ws) => ws.unsubscribe(id),
+            ChainTransport::Polling(http) => http.unsubscribe(id),
+        }
+    }
+}
@@ -1,3 +1,9 @@
+use jsonrpc_core as rpc;
+use web3::Error;
+
+/// Parts of the errors nodes return when a contract call reverts
+const REVERTS: [&str; 4] = ["revert", "invalid opcode", "bad instruction", "vm execution error"];
+
 // From ethereum_types but not reexported by web3
 pub fn clean_0x(s: &str) -> &str {
     if s.starts_with("0x") {
@@ -7,6 +13,26 @@ pub fn clean_0x(s: &str) -> &str {
     }
 }
 
+/// Returns the number in a hex quantity returned by a node
+pub fn hex_value(value: &rpc::Value) -> Option<u64> {
+    value.as_str().and_then(|s| u64::from_str_radix(clean_0x(s), 16).ok())
+}
+
+/// Returns true if a node answered a call or a gas estimate with a revert, rather than failing to answer
+///
+/// # Arguments
+///
+/// * `error` - Error returned by the node
+pub fn is_rpc_revert(error: &Error) -> bool {
+    match error {
+        Error::Rpc(e) => {
+            let message = e.message.to_lowercase();
+            REVERTS.iter().any(|revert| message.contains(revert))
+        }
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -28,4 +54,38 @@ mod tests {
         // assert
         assert_eq!(result, "string");
     }
+
+    #[test]
+    fn hex_value_should_parse_quantities() {
+        // act
+        let quantity = hex_value(&rpc::Value::String("0x1a".to_string()));
+        let invalid = hex_value(&rpc::Value::String("0xzz".to_string()));
+        let number = hex_value(&rpc::Value::from(26));
+        // assert
+        assert_eq!(quantity, Some(26));
+        assert_eq!(invalid, None);
+        assert_eq!(number, None);
+    }
+
+    #[test]
+    fn is_rpc_revert_should_only_match_reverts() {
+        // arrange
+        let rpc_error = |message: &str| {
+            Error::Rpc(rpc::Error {
+                code: rpc::ErrorCode::ServerError(-32000),
+                message: message.to_string(),
+                data: None,
+            })
+        };
+        // act
+        let results = vec![
+            is_rpc_revert(&rpc_error(
+                "gas required exceeds allowance or always failing transaction: execution reverted",
+            )),
+            is_rpc_revert(&rpc_error("header not found")),
+            is_rpc_revert(&Error::Transport("connection reset".to_string())),
+        ];
+        // assert
+        assert_eq!(results, vec![true, false, false]);
+    }
 }
@@ -0,0 +1,625 @@
+use jsonrpc_core as rpc;
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor;
+use web3::api::SubscriptionId;
+use web3::futures::future::{self, Either};
+use web3::futures::prelude::*;
+use web3::futures::stream;
+use web3::futures::try_ready;
+use web3::helpers;
+use web3::transports::{Result, WebSocket};
+use web3::{BatchTransport, DuplexTransport, Error, RequestId, Transport};
+
+use super::utils::hex_value;
+use crate::relay_config::settings::RpcEndpoint;
+
+/// Exponential backoff between attempts, doubling the delay each time up to a max
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    /// Returns a new Backoff
+    ///
+    /// # Arguments
+    ///
+    /// * `initial` - Delay before the first attempt
+    /// * `max` - Longest delay between attempts
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt, and doubles the one after
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+        delay
+    }
+
+    /// Goes back to the initial delay, after a successful attempt
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// What an endpoint has to meet to be used
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// Time between checks of every endpoint
+    pub interval: Duration,
+    /// Oldest the latest block can be, in seconds
+    pub max_head_age: u64,
+    /// Fewest peers the node can have
+    pub min_peers: u64,
+    /// Chain ID the node has to be on
+    pub chain_id: u64,
+}
+
+impl Health {
+    /// Returns why an endpoint is unhealthy, or None if it is healthy
+    ///
+    /// # Arguments
+    ///
+    /// * `head_timestamp` - Timestamp of the latest block on the endpoint
+    /// * `peers` - Peer count of the endpoint
+    /// * `chain_id` - Chain ID of the endpoint
+    /// * `now` - Current unix time in seconds
+    fn problem(&self, head_timestamp: u64, peers: u64, chain_id: u64, now: u64) -> Option<String> {
+        if chain_id != self.chain_id {
+            Some(format!("on chain {} instead of {}", chain_id, self.chain_id))
+        } else if peers < self.min_peers {
+            Some(format!("only {} peers", peers))
+        } else if now.saturating_sub(head_timestamp) > self.max_head_age {
+            Some(format!("latest block is {}s old", now.saturating_sub(head_timestamp)))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns true if the error means the websocket is no longer usable
+fn is_connection_error(error: &Error) -> bool {
+    match error {
+        Error::Unreachable | Error::Transport(_) | Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the endpoint to move to given which endpoints are healthy, or None to stay on the active one
+///
+/// # Arguments
+///
+/// * `active` - Index of the endpoint in use
+/// * `endpoints` - Priority and health of every endpoint, sorted by priority
+fn preferred(active: usize, endpoints: &[(u32, bool)]) -> Option<usize> {
+    let best = endpoints.iter().position(|&(_, healthy)| healthy)?;
+    let (active_priority, active_healthy) = endpoints[active];
+    if best != active && (!active_healthy || endpoints[best].0 < active_priority) {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+struct EndpointState {
+    endpoint: RpcEndpoint,
+    socket: Option<WebSocket>,
+    healthy: bool,
+}
+
+#[derive(Debug)]
+struct Connection {
+    socket: WebSocket,
+    active: usize,
+    endpoints: Vec<EndpointState>,
+    subscriptions: Vec<SubscriptionId>,
+    reconnecting: bool,
+    attempts: usize,
+    backoff: Backoff,
+}
+
+impl Connection {
+    /// Returns the endpoint to try after the active one failed.
+    /// The preferred healthy endpoint if there is one, otherwise each endpoint in turn
+    fn failover(&self) -> usize {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .position(|(index, state)| index != self.active && state.healthy)
+            .unwrap_or((self.active + 1 + self.attempts) % self.endpoints.len())
+    }
+}
+
+/// Websocket transport over one or more endpoints of the same chain, in order of priority.
+/// Requests that fail with a connection error, or get no response in time, move it to another endpoint (or open a new
+/// websocket to the same one) in the background. The health of every endpoint is checked at an interval, and the
+/// transport moves to the preferred healthy one. Subscriptions cannot be moved, so they fail on a move and have to be
+/// made again
+#[derive(Clone, Debug)]
+pub struct ReconnectingWebSocket {
+    handle: reactor::Handle,
+    request_timeout: Duration,
+    health: Health,
+    id: Arc<AtomicUsize>,
+    connection: Rc<RefCell<Connection>>,
+    active: Arc<RwLock<Option<String>>>,
+}
+
+impl ReconnectingWebSocket {
+    /// Returns a new ReconnectingWebSocket, connecting to the endpoint with the lowest priority on the given event loop
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoints` - Endpoints of the chain
+    /// * `handle` - Handle to the event loop the websockets run on
+    /// * `request_timeout` - How long to wait for a response before considering the websocket dropped
+    /// * `backoff` - Delays between reconnection attempts
+    /// * `health` - What endpoints have to meet to be used
+    pub fn new(
+        mut endpoints: Vec<RpcEndpoint>,
+        handle: &reactor::Handle,
+        request_timeout: Duration,
+        backoff: Backoff,
+        health: Health,
+    ) -> Result<Self> {
+        endpoints.sort_by_key(|endpoint| endpoint.priority);
+        let first = endpoints
+            .first()
+            .ok_or_else(|| Error::Transport("no endpoints".to_string()))?;
+        let socket = WebSocket::with_event_loop(&first.uri, handle)?;
+        let active = Arc::new(RwLock::new(Some(first.name())));
+        let endpoints = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(index, endpoint)| EndpointState {
+                endpoint,
+                socket: if index == 0 { Some(socket.clone()) } else { None },
+                healthy: true,
+            })
+            .collect();
+        Ok(ReconnectingWebSocket {
+            handle: handle.clone(),
+            request_timeout,
+            health,
+            id: Arc::new(AtomicUsize::new(1)),
+            connection: Rc::new(RefCell::new(Connection {
+                socket,
+                active: 0,
+                endpoints,
+                subscriptions: Vec::new(),
+                reconnecting: false,
+                attempts: 0,
+                backoff,
+            })),
+            active,
+        })
+    }
+
+    /// Returns the name of the endpoint in use, shared so it can be shown in the status
+    pub fn active_endpoint(&self) -> Arc<RwLock<Option<String>>> {
+        self.active.clone()
+    }
+
+    /// Returns a Future that checks the health of every endpoint at the health interval
+    pub fn check_health(&self) -> CheckEndpoints {
+        CheckEndpoints::new(self)
+    }
+
+    /// Starts opening a new websocket after the backoff delay, unless already doing so
+    pub fn reconnect(&self) {
+        let (delay, index, name) = {
+            let mut connection = self.connection.borrow_mut();
+            if connection.reconnecting {
+                return;
+            }
+            connection.reconnecting = true;
+            let active = connection.active;
+            connection.endpoints[active].healthy = false;
+            let index = connection.failover();
+            let name = connection.endpoints[index].endpoint.name();
+            (connection.backoff.delay(), index, name)
+        };
+        warn!("websocket dropped, connecting to {} in {}s", name, delay.as_secs());
+        let transport = self.clone();
+        let timeout = reactor::Timeout::new(delay, &self.handle).expect("error creating timeout");
+        let future = timeout
+            .map_err(|_| Error::Internal)
+            .and_then({
+                let transport = self.clone();
+                move |_| transport.connect(index)
+            })
+            .then(move |result| {
+                let failed = {
+                    let mut connection = transport.connection.borrow_mut();
+                    connection.reconnecting = false;
+                    match result {
+                        Ok(socket) => {
+                            connection.attempts = 0;
+                            connection.backoff.reset();
+                            connection.endpoints[index].healthy = true;
+                            transport.activate(&mut connection, index, socket);
+                            false
+                        }
+                        Err(e) => {
+                            error!("error connecting to {}: {:?}", name, e);
+                            connection.attempts += 1;
+                            connection.endpoints[index].healthy = false;
+                            true
+                        }
+                    }
+                };
+                if failed {
+                    transport.reconnect();
+                }
+                Ok(())
+            });
+        self.handle.spawn(future);
+    }
+
+    /// Makes the endpoint the one in use, ending the subscriptions on the websocket used so far
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - Borrowed connection state
+    /// * `index` - Index of the endpoint
+    /// * `socket` - Websocket to the endpoint
+    fn activate(&self, connection: &mut Connection, index: usize, socket: WebSocket) {
+        let previous = connection.socket.clone();
+        connection.socket = socket.clone();
+        connection.endpoints[index].socket = Some(socket);
+        connection.active = index;
+        // Their watchers fail once the streams end, and the relay subscribes again on this websocket
+        for id in connection.subscriptions.drain(..) {
+            previous.unsubscribe(&id);
+        }
+        let name = connection.endpoints[index].endpoint.name();
+        info!("using endpoint {}", name);
+        if let Ok(mut active) = self.active.write() {
+            *active = Some(name);
+        }
+    }
+
+    /// Returns a Future that resolves to a new websocket to the endpoint, once it answers a request
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the endpoint
+    fn connect(&self, index: usize) -> Box<dyn Future<Item = WebSocket, Error = Error>> {
+        let uri = self.connection.borrow().endpoints[index].endpoint.uri.clone();
+        let socket = match WebSocket::with_event_loop(&uri, &self.handle) {
+            Ok(socket) => socket,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        // The websocket connects in the background, so only a response tells it is usable
+        let (id, request) = self.prepare("eth_blockNumber", vec![]);
+        let probe = socket.send(id, request).map(move |_| socket);
+        self.with_timeout(probe)
+    }
+
+    /// Returns a Future that resolves if the endpoint is healthy, or fails with the reason it is not
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the endpoint
+    fn check(&self, index: usize) -> Box<dyn Future<Item = (), Error = String>> {
+        let socket = {
+            let mut connection = self.connection.borrow_mut();
+            let state = &mut connection.endpoints[index];
+            match state.socket {
+                Some(ref socket) => socket.clone(),
+                None => match WebSocket::with_event_loop(&state.endpoint.uri, &self.handle) {
+                    Ok(socket) => {
+                        state.socket = Some(socket.clone());
+                        socket
+                    }
+                    Err(e) => return Box::new(future::err(format!("{:?}", e))),
+                },
+            }
+        };
+        let request = |method: &str, params: Vec<rpc::Value>| {
+            let (id, call) = self.prepare(method, params);
+            socket.send(id, call)
+        };
+        let head = request("eth_getBlockByNumber", vec!["latest".into(), false.into()]);
+        let peers = request("net_peerCount", vec![]);
+        let chain_id = request("eth_chainId", vec![]);
+        let health = self.health.clone();
+        let future = self
+            .with_timeout(head.join3(peers, chain_id))
+            .map_err(|e| format!("{:?}", e))
+            .and_then(move |(head, peers, chain_id)| {
+                let head_timestamp = hex_value(&head["timestamp"]).ok_or("no timestamp in latest block")?;
+                let peers = hex_value(&peers).ok_or("invalid peer count")?;
+                let chain_id = hex_value(&chain_id).ok_or("invalid chain id")?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0);
+                match health.problem(head_timestamp, peers, chain_id, now) {
+                    Some(problem) => Err(problem),
+                    None => Ok(()),
+                }
+            });
+        Box::new(future)
+    }
+
+    /// Records the results of a health check, and moves to the preferred healthy endpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `results` - Result of the check of every endpoint
+    fn checked(&self, results: Vec<std::result::Result<(), String>>) {
+        let mut connection = self.connection.borrow_mut();
+        let active = connection.active;
+        for (index, (state, result)) in connection.endpoints.iter_mut().zip(results).enumerate() {
+            match result {
+                Ok(()) => {
+                    if !state.healthy {
+                        info!("endpoint {} is healthy again", state.endpoint.name());
+                    }
+                    state.healthy = true;
+                }
+                Err(problem) => {
+                    if state.healthy {
+                        warn!("endpoint {} is unhealthy: {}", state.endpoint.name(), problem);
+                    }
+                    state.healthy = false;
+                    // Standby websockets are opened again on the next check, the one in use is left to reconnect
+                    if index != active {
+                        state.socket = None;
+                    }
+                }
+            }
+        }
+        if connection.reconnecting {
+            return;
+        }
+        let endpoints: Vec<(u32, bool)> = connection
+            .endpoints
+            .iter()
+            .map(|state| (state.endpoint.priority, state.healthy && state.socket.is_some()))
+            .collect();
+        if let Some(index) = preferred(active, &endpoints) {
+            if let Some(socket) = connection.endpoints[index].socket.clone() {
+                self.activate(&mut connection, index, socket);
+            }
+        }
+    }
+
+    /// Returns the websocket currently in use
+    fn socket(&self) -> WebSocket {
+        self.connection.borrow().socket.clone()
+    }
+
+    /// Returns the future, failing with Unreachable if it does not resolve within the request timeout
+    fn with_timeout<F, I>(&self, future: F) -> Box<dyn Future<Item = I, Error = Error>>
+    where
+        F: Future<Item = I, Error = Error> + 'static,
+        I: 'static,
+    {
+        let timeout = reactor::Timeout::new(self.request_timeout, &self.handle).expect("error creating timeout");
+        Box::new(future.select2(timeout).then(|result| match result {
+            Ok(Either::A((item, _))) => Ok(item),
+            Ok(Either::B(_)) => Err(Error::Unreachable),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B(_)) => {
+                error!("Timeout broken");
+                Err(Error::Internal)
+            }
+        }))
+    }
+
+    /// Returns the future with a timeout, reconnecting if it fails with a connection error
+    fn watch<F, I>(&self, future: F) -> Box<dyn Future<Item = I, Error = Error>>
+    where
+        F: Future<Item = I, Error = Error> + 'static,
+        I: 'static,
+    {
+        let transport = self.clone();
+        Box::new(self.with_timeout(future).map_err(move |e| {
+            if is_connection_error(&e) {
+                transport.reconnect();
+            }
+            e
+        }))
+    }
+}
+
+impl Transport for ReconnectingWebSocket {
+    type Out = Box<dyn Future<Item = rpc::Value, Error = Error>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        // Ids come from here rather than the websocket, so they stay unique across websockets
+        let id = self.id.fetch_add(1, Ordering::AcqRel);
+        let call = helpers::build_request(id, method, params);
+        (id, call)
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        self.watch(self.socket().send(id, request))
+    }
+}
+
+impl BatchTransport for ReconnectingWebSocket {
+    type Batch = Box<dyn Future<Item = Vec<Result<rpc::Value>>, Error = Error>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, rpc::Call)>,
+    {
+        self.watch(self.socket().send_batch(requests))
+    }
+}
+
+impl DuplexTransport for ReconnectingWebSocket {
+    type NotificationStream = Box<dyn Stream<Item = rpc::Value, Error = Error> + Send + 'static>;
+
+    fn subscribe(&self, id: &SubscriptionId) -> Self::NotificationStream {
+        let mut connection = self.connection.borrow_mut();
+        connection.subscriptions.push(id.clone());
+        // The stream only ends when the subscription is moved off its websocket, as FlushedStream stops polling
+        // before it unsubscribes
+        let closed = stream::once(Err(Error::Transport(
+            "subscription closed by endpoint change".to_string(),
+        )));
+        Box::new(connection.socket.subscribe(id).chain(closed))
+    }
+
+    fn unsubscribe(&self, id: &SubscriptionId) {
+        let mut connection = self.connection.borrow_mut();
+        connection.subscriptions.retain(|subscription| subscription != id);
+        connection.socket.unsubscribe(id)
+    }
+}
+
+/// Future that checks the health of every endpoint of a transport at an interval, and moves the transport to the
+/// preferred healthy endpoint
+pub struct CheckEndpoints {
+    transport: ReconnectingWebSocket,
+    timeout: reactor::Timeout,
+    checks: Option<Box<dyn Future<Item = Vec<std::result::Result<(), String>>, Error = ()>>>,
+}
+
+impl CheckEndpoints {
+    /// Returns a newly created CheckEndpoints Future
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - Transport whose endpoints are checked
+    fn new(transport: &ReconnectingWebSocket) -> Self {
+        let timeout =
+            reactor::Timeout::new(transport.health.interval, &transport.handle).expect("error creating timeout");
+        CheckEndpoints {
+            transport: transport.clone(),
+            timeout,
+            checks: None,
+        }
+    }
+}
+
+impl Future for CheckEndpoints {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.checks {
+                Some(ref mut future) => {
+                    let results = try_ready!(future.poll());
+                    self.transport.checked(results);
+                    self.checks = None;
+                    self.timeout = reactor::Timeout::new(self.transport.health.interval, &self.transport.handle)
+                        .expect("error creating timeout");
+                }
+                None => {
+                    try_ready!(self.timeout.poll().map_err(|e| {
+                        error!("error waiting for the next health check: {:?}", e);
+                    }));
+                    let count = self.transport.connection.borrow().endpoints.len();
+                    let checks: Vec<_> = (0..count)
+                        .map(|index| self.transport.check(index).then(Ok::<_, ()>))
+                        .collect();
+                    self.checks = Some(Box::new(future::join_all(checks)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_should_double_up_to_max_and_reset() {
+        // arrange
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        // act
+        let delays: Vec<u64> = (0..5).map(|_| backoff.delay().as_secs()).collect();
+        backoff.reset();
+        let after_reset = backoff.delay();
+        // assert
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        assert_eq!(after_reset, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn only_connection_errors_should_reconnect() {
+        // act
+        let unreachable = is_connection_error(&Error::Unreachable);
+        let transport = is_connection_error(&Error::Transport("closed".to_string()));
+        let rpc = is_connection_error(&Error::InvalidResponse("bad".to_string()));
+        // assert
+        assert!(unreachable);
+        assert!(transport);
+        assert!(!rpc);
+    }
+
+    #[test]
+    fn health_should_check_chain_id_peers_and_head_age() {
+        // arrange
+        let health = Health {
+            interval: Duration::from_secs(30),
+            max_head_age: 60,
+            min_peers: 1,
+            chain_id: 1337,
+        };
+        // act
+        let healthy = health.problem(1000, 3, 1337, 1030);
+        let wrong_chain = health.problem(1000, 3, 1, 1030);
+        let no_peers = health.problem(1000, 0, 1337, 1030);
+        let stale = health.problem(1000, 3, 1337, 1100);
+        // assert
+        assert_eq!(healthy, None);
+        assert_eq!(wrong_chain, Some("on chain 1 instead of 1337".to_string()));
+        assert_eq!(no_peers, Some("only 0 peers".to_string()));
+        assert_eq!(stale, Some("latest block is 100s old".to_string()));
+    }
+
+    #[test]
+    fn endpoint_name_should_leave_out_credentials_and_path() {
+        // arrange
+        let endpoint = |uri: &str, name: Option<&str>| RpcEndpoint {
+            uri: uri.to_string(),
+            priority: 0,
+            name: name.map(String::from),
+        };
+        // act
+        let keyed = endpoint("wss://mainnet.example.io/ws/v3/secret", None).name();
+        let credentials = endpoint("ws://user:secret@localhost:8546", None).name();
+        let named = endpoint("ws://localhost:8546", Some("backup")).name();
+        // assert
+        assert_eq!(keyed, "mainnet.example.io");
+        assert_eq!(credentials, "localhost:8546");
+        assert_eq!(named, "backup");
+    }
+
+    #[test]
+    fn preferred_should_fail_over_and_back() {
+        // act
+        let stay = preferred(0, &[(0, true), (1, true)]);
+        let fail_over = preferred(0, &[(0, false), (1, true)]);
+        let fail_back = preferred(1, &[(0, true), (1, true)]);
+        let same_priority = preferred(1, &[(0, true), (0, true)]);
+        let none_healthy = preferred(0, &[(0, false), (1, false)]);
+        // assert
+        assert_eq!(stay, None);
+        assert_eq!(fail_over, Some(1));
+        assert_eq!(fail_back, Some(0));
+        assert_eq!(same_priority, None);
+        assert_eq!(none_healthy, None);
+    }
+}
@@ -5,16 +5,18 @@ use web3::futures::prelude::*;
 use web3::futures::try_ready;
 use web3::DuplexTransport;
 
+use super::shutdown::Shutdown;
 use crate::eth::Event;
 
 /// FlushedStream adds a flush check to an existing Stream.
-/// It exits and calls unsubscribe on original stream
+/// It exits and calls unsubscribe on original stream, after a flush or when the relay shuts down
 pub struct FlushedStream<T, I>
 where
     T: DuplexTransport + 'static,
     I: serde::de::DeserializeOwned + 'static,
 {
     flushed: Arc<RwLock<Option<Event>>>,
+    shutdown: Shutdown,
     subscribe: SubscriptionResult<T, I>,
     stream: Option<SubscriptionStream<T, I>>,
     unsubscribe: Option<Box<dyn Future<Item = bool, Error = web3::Error>>>,
@@ -30,10 +32,12 @@ where
     /// # Arguments
     ///
     /// * `flushed` - Event marking the flush
+    /// * `shutdown` - Shutdown signal of the network
     /// * `state` - SubscriptionResult from eth_subscribe()
-    pub fn new(flushed: &Arc<RwLock<Option<Event>>>, subscribe: SubscriptionResult<T, I>) -> Self {
+    pub fn new(flushed: &Arc<RwLock<Option<Event>>>, shutdown: &Shutdown, subscribe: SubscriptionResult<T, I>) -> Self {
         FlushedStream {
             flushed: flushed.clone(),
+            shutdown: shutdown.clone(),
             subscribe,
             stream: None,
             unsubscribe: None,
@@ -49,7 +53,7 @@ where
     type Item = I;
     type Error = web3::Error;
 
-    /// Returns an item from the stream, or None if flushed
+    /// Returns an item from the stream, or None if flushed or shut down
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         let flushed = self.flushed.clone();
         loop {
@@ -63,7 +67,7 @@ where
                     error!("error acquiring flush event lock: {:?}", e);
                     web3::Error::Internal
                 })?;
-                if lock.is_some() {
+                if lock.is_some() || self.shutdown.poll_triggered() {
                     let stream = self.stream.take().unwrap();
                     self.unsubscribe = Some(Box::new(stream.unsubscribe()));
                     continue;
@@ -92,7 +96,8 @@ where
     ///
     /// * `self` - Existing Stream that this is added to. Consumes self.
     /// * `flushed` - Event that triggered flush
-    fn flushed(self, flushed: &Arc<RwLock<Option<Event>>>) -> FlushedStream<T, I>;
+    /// * `shutdown` - Shutdown signal of the network
+    fn flushed(self, flushed: &Arc<RwLock<Option<Event>>>, shutdown: &Shutdown) -> FlushedStream<T, I>;
 }
 
 /// Add Flushed trait to SubscriptionResult, which is returned by web3.eth_subscribe()
@@ -101,7 +106,7 @@ where
     T: DuplexTransport + 'static,
     I: serde::de::DeserializeOwned + 'static,
 {
-    fn flushed(self, flushed: &Arc<RwLock<Option<Event>>>) -> FlushedStream<T, I> {
-        FlushedStream::new(flushed, self)
+    fn flushed(self, flushed: &Arc<RwLock<Option<Event>>>, shutdown: &Shutdown) -> FlushedStream<T, I> {
+        FlushedStream::new(flushed, shutdown, self)
     }
 }
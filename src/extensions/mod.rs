@@ -1,3 +1,4 @@
 pub mod flushed;
 pub mod removed;
+pub mod shutdown;
 pub mod timeout;
@@ -4,12 +4,14 @@ use web3::DuplexTransport;
 
 use crate::relay::{Network, TransferApprovalState};
 
+/// ExitOnLogRemoved wraps a Future waiting on a transfer, and resolves to None if the transfer log is removed first.
+/// Once the wrapped Future completes, the transfer is advanced to its next state in the store
 pub struct ExitOnLogRemoved<T, I, E>
 where
     T: DuplexTransport + 'static,
 {
     target: Network<T>,
-    tx_hash: H256,
+    withdrawal_hash: H256,
     future: Box<dyn Future<Item = I, Error = E>>,
 }
 
@@ -17,13 +19,54 @@ impl<T, I, E> ExitOnLogRemoved<T, I, E>
 where
     T: DuplexTransport + 'static,
 {
-    pub fn new(target: &Network<T>, tx_hash: H256, future: Box<dyn Future<Item = I, Error = E>>) -> Self {
+    pub fn new(target: &Network<T>, withdrawal_hash: H256, future: Box<dyn Future<Item = I, Error = E>>) -> Self {
+        // Keeps the transfer in the store while this waits, so a removal is still seen
+        if let Ok(mut pending) = target.pending.write().map_err(|e| {
+            error!("Failed to acquire write lock {:?}", e);
+        }) {
+            pending.watch(withdrawal_hash);
+        }
         ExitOnLogRemoved {
             target: target.clone(),
-            tx_hash,
+            withdrawal_hash,
             future,
         }
     }
+
+    /// Moves the transfer along once the wrapped future completes (Seen -> Confirmed, Sent -> Mined)
+    fn advance_state(&self) -> Result<(), ()> {
+        let mut pending = self.target.pending.write().map_err(|e| {
+            error!("Failed to acquire write lock {:?}", e);
+        })?;
+        if let Some(state) = pending.peek(&self.withdrawal_hash).copied() {
+            let next = state.completed();
+            if next != state {
+                pending.put(self.withdrawal_hash, next);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the transfer was removed
+    fn is_removed(&self) -> Result<bool, ()> {
+        let pending = self.target.pending.read().map_err(|e| {
+            error!("Failed to acquire read lock {:?}", e);
+        })?;
+        Ok(pending.peek(&self.withdrawal_hash) == Some(&TransferApprovalState::Removed))
+    }
+}
+
+impl<T, I, E> Drop for ExitOnLogRemoved<T, I, E>
+where
+    T: DuplexTransport + 'static,
+{
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.target.pending.write().map_err(|e| {
+            error!("Failed to acquire write lock {:?}", e);
+        }) {
+            pending.unwatch(self.withdrawal_hash);
+        }
+    }
 }
 
 impl<T, I, E> Future for ExitOnLogRemoved<T, I, E>
@@ -35,11 +78,15 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match self.future.poll() {
-            Ok(Async::Ready(result)) => Ok(Async::Ready(Some(result))),
+            Ok(Async::Ready(result)) => {
+                // The result is returned either way, a state that did not advance is picked up on the next start
+                let _ = self.advance_state();
+                Ok(Async::Ready(Some(result)))
+            }
             Ok(Async::NotReady) => {
                 // Check removed status
-                match self.target.pending.read().unwrap().peek(&self.tx_hash) {
-                    Some(TransferApprovalState::Removed) => Ok(Async::Ready(None)),
+                match self.is_removed() {
+                    Ok(true) => Ok(Async::Ready(None)),
                     _ => Ok(Async::NotReady),
                 }
             }
@@ -59,8 +106,8 @@ where
     ///
     /// * `self` - Existing Future that this is added to. Consumes self.
     /// * `target` - Target network to check against
-    /// * `tx_hash` - Tx hash to check for removal
-    fn cancel_removed(self, target: &Network<T>, tx_hash: H256) -> ExitOnLogRemoved<T, I, E>;
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer to check for removal
+    fn cancel_removed(self, target: &Network<T>, withdrawal_hash: H256) -> ExitOnLogRemoved<T, I, E>;
 }
 
 #[cfg(test)]
@@ -102,8 +149,8 @@ mod tests {
     where
         T: DuplexTransport + 'static,
     {
-        fn cancel_removed(self, target: &Network<T>, tx_hash: H256) -> ExitOnLogRemoved<T, (), ()> {
-            ExitOnLogRemoved::<T, (), ()>::new(target, tx_hash, Box::new(self))
+        fn cancel_removed(self, target: &Network<T>, withdrawal_hash: H256) -> ExitOnLogRemoved<T, (), ()> {
+            ExitOnLogRemoved::<T, (), ()>::new(target, withdrawal_hash, Box::new(self))
         }
     }
 
@@ -148,6 +195,32 @@ mod tests {
         assert_eq!(result, Some(()))
     }
 
+    #[test]
+    fn check_log_removed_future_should_mark_sent_transfer_mined() {
+        // arrange
+        let mut eloop = tokio_core::reactor::Core::new().unwrap();
+        let handle = eloop.handle();
+        let mock = MockTransport::new();
+        let target = mock.new_network(NetworkType::Home).unwrap();
+        let future = ExitOnLogRemoved::<MockTransport, (), std::io::Error>::new(
+            &target,
+            H256::zero(),
+            Box::new(reactor::Timeout::new(Duration::from_secs(1), &handle).unwrap()),
+        );
+        target
+            .pending
+            .write()
+            .unwrap()
+            .put(H256::zero(), TransferApprovalState::Sent);
+        // act
+        eloop.run(future).unwrap();
+        // assert
+        assert_eq!(
+            target.pending.read().unwrap().peek(&H256::zero()),
+            Some(&TransferApprovalState::Mined)
+        );
+    }
+
     #[test]
     fn check_log_removed_impl_should_return_none_if_not_removed() {
         // arrange
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use web3::futures::task::{self, Task};
+
+/// Signal shared between everything that has to wind down when the relay shuts down.
+/// Tasks that check it with poll_triggered are woken up when it is triggered
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    completed: Arc<AtomicBool>,
+    tasks: Arc<Mutex<Vec<Task>>>,
+}
+
+impl Shutdown {
+    /// Returns a new Shutdown that is not triggered
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Triggers the shutdown, and wakes every task waiting on it
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        if let Ok(mut tasks) = self.tasks.lock() {
+            for task in tasks.drain(..) {
+                task.notify();
+            }
+        }
+    }
+
+    /// Returns true if the shutdown was triggered
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the shutdown was triggered. Otherwise the current task is woken up once it is.
+    /// Must be called from within a task
+    pub fn poll_triggered(&self) -> bool {
+        if self.is_triggered() {
+            return true;
+        }
+        if let Ok(mut tasks) = self.tasks.lock() {
+            if !tasks.iter().any(|task| task.will_notify_current()) {
+                tasks.push(task::current());
+            }
+        }
+        // Triggered while registering
+        self.is_triggered()
+    }
+
+    /// Marks the shutdown as done, once everything has wound down
+    pub fn complete(&self) {
+        self.completed.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once the shutdown is done
+    pub fn is_completed(&self) -> bool {
+        self.completed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio_core::reactor;
+    use web3::futures::prelude::*;
+
+    struct WaitForShutdown {
+        shutdown: Shutdown,
+    }
+
+    impl Future for WaitForShutdown {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            if self.shutdown.poll_triggered() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn trigger_should_wake_waiting_task() {
+        // arrange
+        let mut eloop = reactor::Core::new().unwrap();
+        let handle = eloop.handle();
+        let shutdown = Shutdown::new();
+        let trigger = shutdown.clone();
+        let timeout = reactor::Timeout::new(Duration::from_millis(10), &handle).unwrap();
+        handle.spawn(timeout.map(move |_| trigger.trigger()).map_err(|_| ()));
+        // act
+        let result = eloop.run(WaitForShutdown {
+            shutdown: shutdown.clone(),
+        });
+        // assert
+        assert!(result.is_ok());
+        assert!(shutdown.is_triggered());
+        assert!(!shutdown.is_completed());
+    }
+}
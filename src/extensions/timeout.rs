@@ -7,6 +7,7 @@ use web3::futures::prelude::*;
 use web3::DuplexTransport;
 
 use crate::extensions::flushed::FlushedStream;
+use crate::metrics;
 
 /// TimeoutStream adds a timeout to an existing Stream.
 /// returns Err if too much time has passed since the last object from the stream
@@ -69,7 +70,10 @@ where
             // If the stream does not have the next element, check the timeout
             Ok(Async::NotReady) => match self.timeout.poll() {
                 // If the timeout is triggered, error out
-                Ok(Async::Ready(_)) => Err(Error::Unreachable),
+                Ok(Async::Ready(_)) => {
+                    metrics::subscription_timed_out();
+                    Err(Error::Unreachable)
+                }
                 // If timeout not triggered, return NotReady
                 Ok(Async::NotReady) => Ok(Async::NotReady),
                 // If timeout errors out, return error
@@ -1,28 +1,29 @@
-use std::cmp;
 use std::collections::HashMap;
 use web3::contract::Options;
 use web3::futures::prelude::*;
 use web3::futures::try_ready;
-use web3::types::{Address, BlockNumber, FilterBuilder, Log, H256, U256, U64};
+use web3::types::{Address, BlockNumber, FilterBuilder, H256, U256, U64};
 
 use crate::eth::contracts::TRANSFER_EVENT_SIGNATURE;
+use crate::eth::scan::ScanLogs;
 use crate::eth::transaction::SendTransaction;
 use crate::relay::Network;
 use crate::transfers::transfer::Transfer;
 use crate::transfers::withdrawal::ApproveParams;
 use web3::DuplexTransport;
 
-pub enum CheckBalancesState {
+pub enum CheckBalancesState<T: DuplexTransport + 'static> {
     GetEndingBlock(Box<dyn Future<Item = U64, Error = ()>>),
-    GetLogWindow(u64, u64, Box<dyn Future<Item = Vec<Log>, Error = ()>>),
+    ScanLogs(u64, ScanLogs<T>),
 }
 
 /// Get all balances for all wallets with tokens by looking over the entire history of the chain.
 /// Keep track of all balances, and add and remove as transfers occur
-/// In order to avoid crashes, it does this by looking at windows of 1000 blocks rather than the whole chain
+/// In order to avoid crashes, it does this by looking at windows of blocks rather than the whole chain, fetched
+/// concurrently and sized by the scan settings of the network
 pub struct CheckBalances<T: DuplexTransport + 'static> {
     source: Network<T>,
-    state: CheckBalancesState,
+    state: CheckBalancesState<T>,
     balances: HashMap<Address, U256>,
 }
 
@@ -34,14 +35,7 @@ impl<T: DuplexTransport + 'static> CheckBalances<T> {
     /// * `block` - Optional ending block, gets latest if None
     pub fn new(source: &Network<T>, block: Option<U64>) -> Self {
         let state = match block {
-            Some(b) => {
-                let window_end = cmp::min(b.as_u64(), 1000);
-                CheckBalancesState::GetLogWindow(
-                    b.as_u64(),
-                    window_end,
-                    CheckBalances::build_next_window(source, 0, window_end),
-                )
-            }
+            Some(b) => CheckBalancesState::ScanLogs(b.as_u64(), CheckBalances::scan(source, b.as_u64())),
             None => {
                 let future = source.web3.eth().block_number().map_err(move |e| {
                     error!("error getting block number {:?}", e);
@@ -56,26 +50,48 @@ impl<T: DuplexTransport + 'static> CheckBalances<T> {
         }
     }
 
-    /// Build a filter and look at all logs in the given range for transfer events.
+    /// Returns a Stream of all transfer events from the first block to the given block
     /// # Arguments
     ///
     /// * `source` - Network where the transfers were performed
-    /// * `start` - Start of the next window
-    /// * `end` - End of the next window
-    fn build_next_window(source: &Network<T>, start: u64, end: u64) -> Box<dyn Future<Item = Vec<Log>, Error = ()>> {
+    /// * `end` - Last block to scan
+    fn scan(source: &Network<T>, end: u64) -> ScanLogs<T> {
         let token_address: Address = source.token.address();
-        let filter = FilterBuilder::default()
-            .address(vec![token_address])
-            .from_block(BlockNumber::from(start))
-            .to_block(BlockNumber::from(end))
-            .topics(Some(vec![TRANSFER_EVENT_SIGNATURE.into()]), None, None, None)
-            .build();
-        //self.state = ;
-        let future = source.web3.eth().logs(filter).map_err(move |e| {
-            error!("error getting block number {:?}", e);
-        });
-        Box::new(future)
+        ScanLogs::new(source, 0, end, move |start, end| {
+            FilterBuilder::default()
+                .address(vec![token_address])
+                .from_block(BlockNumber::from(start))
+                .to_block(BlockNumber::from(end))
+                .topics(Some(vec![TRANSFER_EVENT_SIGNATURE.into()]), None, None, None)
+                .build()
+        })
+    }
+}
+
+/// Moves a transferred amount between two balances
+///
+/// # Arguments
+///
+/// * `balances` - Balances by address
+/// * `sender` - Address the tokens are transferred from
+/// * `receiver` - Address the tokens are transferred to
+/// * `amount` - Amount transferred
+fn apply_transfer(balances: &mut HashMap<Address, U256>, sender: Address, receiver: Address, amount: U256) {
+    let zero = U256::zero();
+    // Mints come from the zero address, which only holds burnt tokens, so it is never debited
+    if sender != Address::zero() {
+        // Don't care if source doesn't exist, because it is likely a mint in that case
+        balances
+            .entry(sender)
+            .and_modify(|v| {
+                if !v.is_zero() {
+                    *v -= amount;
+                }
+            })
+            .or_insert(zero);
     }
+    let dest_balance = balances.entry(receiver).or_insert(zero);
+    *dest_balance += amount;
 }
 
 impl<T: DuplexTransport + 'static> Future for CheckBalances<T> {
@@ -87,54 +103,30 @@ impl<T: DuplexTransport + 'static> Future for CheckBalances<T> {
             let next = match self.state {
                 CheckBalancesState::GetEndingBlock(ref mut future) => {
                     let block = try_ready!(future.poll());
-                    let window_end = cmp::min(block.as_u64(), 1000);
-                    let future = CheckBalances::build_next_window(&source, 0, window_end);
-                    CheckBalancesState::GetLogWindow(block.as_u64(), window_end, future)
+                    CheckBalancesState::ScanLogs(block.as_u64(), CheckBalances::scan(&source, block.as_u64()))
                 }
-                CheckBalancesState::GetLogWindow(end, window_end, ref mut future) => {
-                    let logs = try_ready!(future.poll());
-                    debug!(
-                        "found {} logs with transfers between {} and {}",
-                        logs.len(),
-                        window_end - 1000,
-                        window_end,
-                    );
-                    // Process existing logs
-                    logs.iter().for_each(|log| {
-                        if Some(true) != log.removed {
-                            let sender_address: Address = log.topics[1].into();
-                            let receiver_address: Address = log.topics[2].into();
-                            let amount: U256 = log.data.0[..32].into();
-                            debug!("{} transferred {} to {}", sender_address, amount, receiver_address);
-                            // Don't care if source doesn't exist, because it is likely a mint in that case
-                            let zero = U256::zero();
-                            self.balances
-                                .entry(sender_address)
-                                .and_modify(|v| {
-                                    if !v.is_zero() {
-                                        *v -= amount;
-                                    }
-                                })
-                                .or_insert(zero);
-                            let dest_balance = self.balances.entry(receiver_address).or_insert(zero);
-                            *dest_balance += amount;
-                        }
-                    });
-
-                    debug!("Window end is {} of {} blocks", window_end, end);
-                    // Setup next window
-                    if window_end < end {
-                        let next_window_end = cmp::min(end, window_end + 1000);
-                        let future = CheckBalances::build_next_window(&source, window_end + 1, next_window_end);
-                        CheckBalancesState::GetLogWindow(end, next_window_end, future)
-                    } else {
-                        return Ok(Async::Ready(
-                            self.balances
-                                .iter()
-                                .map(|(key, value)| Wallet::new(key, value))
-                                .collect(),
-                        ));
+                CheckBalancesState::ScanLogs(end, ref mut stream) => {
+                    // Windows come in block order, so transfers are applied in the order they happened
+                    while let Some((window_end, logs)) = try_ready!(stream.poll()) {
+                        debug!("found {} logs with transfers up to {}", logs.len(), window_end);
+                        // Process existing logs
+                        logs.iter().for_each(|log| {
+                            if Some(true) != log.removed {
+                                let sender_address: Address = log.topics[1].into();
+                                let receiver_address: Address = log.topics[2].into();
+                                let amount: U256 = log.data.0[..32].into();
+                                debug!("{} transferred {} to {}", sender_address, amount, receiver_address);
+                                apply_transfer(&mut self.balances, sender_address, receiver_address, amount);
+                            }
+                        });
+                        debug!("Window end is {} of {} blocks", window_end, end);
                     }
+                    return Ok(Async::Ready(
+                        self.balances
+                            .iter()
+                            .map(|(key, value)| Wallet::new(key, value))
+                            .collect(),
+                    ));
                 }
             };
             self.state = next;
@@ -234,7 +226,7 @@ impl Wallet {
         transaction_hash: &H256,
         block_hash: &H256,
         block_number: U64,
-    ) -> SendTransaction<T, ApproveParams> {
+    ) -> SendTransaction<T> {
         let approve_params = ApproveParams {
             destination: self.address,
             amount: self.balance,
@@ -245,3 +237,22 @@ impl Wallet {
         SendTransaction::new(&target, "approveWithdrawal", &approve_params, target.retries)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_transfer_should_not_debit_the_zero_address() {
+        // arrange
+        let mut balances = HashMap::new();
+        let holder = Address::from_low_u64_be(1);
+        // act
+        apply_transfer(&mut balances, Address::zero(), holder, 100.into());
+        apply_transfer(&mut balances, holder, Address::zero(), 10.into());
+        apply_transfer(&mut balances, Address::zero(), holder, 50.into());
+        // assert
+        assert_eq!(balances.get(&holder), Some(&140.into()));
+        assert_eq!(balances.get(&Address::zero()), Some(&10.into()));
+    }
+}
@@ -6,6 +6,7 @@ use web3::types::{BlockNumber, FilterBuilder, Log, TransactionReceipt, U256, U64
 use crate::eth::contracts::FLUSH_EVENT_SIGNATURE;
 use crate::eth::Event;
 use crate::relay::Network;
+use crate::transfers::transfer::log_withdrawal_hash;
 use web3::DuplexTransport;
 
 enum CheckForPastFlushState {
@@ -81,7 +82,8 @@ impl<T: DuplexTransport + 'static> Future for CheckForPastFlush<T> {
                         let removed = log.removed.unwrap_or(false);
                         match log.transaction_hash {
                             Some(tx_hash) => {
-                                let future = source.get_receipt(removed, tx_hash);
+                                let withdrawal_hash = log_withdrawal_hash(log).unwrap_or(tx_hash);
+                                let future = source.get_receipt(removed, tx_hash, withdrawal_hash);
                                 CheckForPastFlushState::GetFlushReceipt(Box::new(log.clone()), Box::new(future))
                             }
                             None => {
@@ -1,7 +1,13 @@
 pub mod balances;
 pub mod check;
+pub mod plan;
+pub mod policy;
 pub mod process;
+pub mod snapshot;
 
 pub use self::balances::*;
 pub use self::check::*;
+pub use self::plan::*;
+pub use self::policy::*;
 pub use self::process::*;
+pub use self::snapshot::*;
@@ -0,0 +1,475 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+use web3::futures::prelude::*;
+use web3::futures::try_ready;
+use web3::types::{Address, BlockId, BlockNumber, H256, U256, U64};
+use web3::DuplexTransport;
+
+use crate::flush::{CheckBalances, FilterContracts, FilterLowBalance, FlushPolicy, FlushRemaining, Wallet};
+use crate::relay::Network;
+
+/// Format of a report written for operators
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl ReportFormat {
+    /// Returns the content type of reports in this format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ReportFormat::Json => "application/json",
+            ReportFormat::Csv => "text/csv",
+        }
+    }
+}
+
+/// What a flush does with a wallet holding tokens on the flushed chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalletPlan {
+    /// Withdrawn to the same address on the other chain
+    Withdraw,
+    /// Skipped, tokens at the zero address are burnt
+    ZeroAddress,
+    /// Skipped, the address is on the flush denylist
+    Denied,
+    /// Skipped, the address has code and is not on the flush allowlist
+    Contract,
+    /// Skipped, the balance does not cover the fees of a withdrawal
+    LowBalance,
+}
+
+impl WalletPlan {
+    fn as_str(self) -> &'static str {
+        match self {
+            WalletPlan::Withdraw => "withdraw",
+            WalletPlan::ZeroAddress => "zero_address",
+            WalletPlan::Denied => "denied",
+            WalletPlan::Contract => "contract",
+            WalletPlan::LowBalance => "low_balance",
+        }
+    }
+}
+
+/// A wallet in a flush plan
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlannedWallet {
+    pub address: Address,
+    pub balance: U256,
+    pub plan: WalletPlan,
+    /// Address the balance is withdrawn to on the other chain
+    pub destination: Option<Address>,
+    /// Block number given to the withdrawal, the flush block plus the offset of the wallet, so each withdrawal hash is
+    /// unique
+    pub block_number: Option<U64>,
+}
+
+/// Everything a flush at a given block does, without sending any transaction
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FlushPlan {
+    pub block_number: U64,
+    pub block_hash: H256,
+    /// Fees of a withdrawal on the other chain
+    pub fees: U256,
+    /// Every wallet with tokens, by address
+    pub wallets: Vec<PlannedWallet>,
+    pub fee_wallet: Address,
+    /// Relay contract balance on the other chain left after the withdrawals, sent to the fee wallet
+    pub leftover: U256,
+    /// Block number given to the withdrawal of the leftover
+    pub leftover_block_number: U64,
+    /// Hash of the flush policy the plan follows, which must match on every relay
+    #[serde(default)]
+    pub policy_hash: H256,
+}
+
+impl FlushPlan {
+    /// Returns the plan of a flush, given the wallets left after each filter
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - Block of the flush
+    /// * `block_hash` - Hash of the block of the flush
+    /// * `wallets` - Every wallet with tokens
+    /// * `eligible` - Wallets left after dropping the zero address, denied wallets and contracts not allowed
+    /// * `withdrawn` - Wallets left after dropping balances below the fees
+    /// * `without_code` - Addresses of the wallets without code
+    /// * `fees` - Fees of a withdrawal on the other chain
+    /// * `contract_balance` - Relay contract balance on the other chain
+    /// * `fee_wallet` - Wallet the leftover is sent to
+    /// * `policy` - Wallets the flush withdraws or skips whether they have code or not, and where to
+    pub fn new(
+        block_number: U64,
+        block_hash: H256,
+        wallets: &[Wallet],
+        eligible: &[Wallet],
+        withdrawn: &[Wallet],
+        without_code: &HashSet<Address>,
+        fees: U256,
+        contract_balance: U256,
+        fee_wallet: Address,
+        policy: &FlushPolicy,
+    ) -> Self {
+        let eligible: HashSet<Address> = eligible.iter().map(|wallet| wallet.address).collect();
+        let mut withdrawn = withdrawn.to_vec();
+        // ProcessFlush hands out offsets in this order. Wallets without code come first, so contracts added by the
+        // allowlist do not shift their offsets
+        withdrawn.sort_by_key(|wallet| (!without_code.contains(&wallet.address), wallet.address));
+        let mut wallets = wallets.to_vec();
+        wallets.sort();
+        let planned = wallets
+            .iter()
+            .map(|wallet| {
+                let offset = withdrawn.iter().position(|w| w.address == wallet.address);
+                let plan = if offset.is_some() {
+                    WalletPlan::Withdraw
+                } else if wallet.address == Address::zero() {
+                    WalletPlan::ZeroAddress
+                } else if policy.is_denied(&wallet.address) {
+                    WalletPlan::Denied
+                } else if !eligible.contains(&wallet.address) {
+                    WalletPlan::Contract
+                } else {
+                    WalletPlan::LowBalance
+                };
+                PlannedWallet {
+                    address: wallet.address,
+                    balance: wallet.balance,
+                    plan,
+                    destination: offset.map(|_| policy.destination(&wallet.address)),
+                    block_number: offset.map(|offset| block_number + offset),
+                }
+            })
+            .collect();
+        let total = withdrawn
+            .iter()
+            .fold(U256::zero(), |total, wallet| total + wallet.balance);
+        FlushPlan {
+            block_number,
+            block_hash,
+            fees,
+            wallets: planned,
+            fee_wallet,
+            leftover: contract_balance.saturating_sub(total),
+            leftover_block_number: block_number + withdrawn.len() + 1,
+            policy_hash: policy.hash(),
+        }
+    }
+
+    /// Returns the plan in the given format
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - Format of the report
+    pub fn render(&self, format: ReportFormat) -> Result<String, serde_json::Error> {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self),
+            ReportFormat::Csv => Ok(self.to_csv()),
+        }
+    }
+
+    /// Returns the wallets with tokens the flush leaves out because they are contracts or denied. Their balances end
+    /// up in the leftover sent to the fee wallet
+    pub fn excluded(&self) -> Vec<&PlannedWallet> {
+        self.wallets
+            .iter()
+            .filter(|wallet| wallet.plan == WalletPlan::Contract || wallet.plan == WalletPlan::Denied)
+            .collect()
+    }
+
+    /// Returns the plan as CSV, one row per wallet and a last row for the leftover
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("address,balance,plan,block_number,destination\n");
+        for wallet in &self.wallets {
+            let block_number = wallet.block_number.map(|n| n.to_string()).unwrap_or_default();
+            let destination = wallet.destination.map(|a| format!("{:?}", a)).unwrap_or_default();
+            csv.push_str(&format!(
+                "{:?},{},{},{},{}\n",
+                wallet.address,
+                wallet.balance,
+                wallet.plan.as_str(),
+                block_number,
+                destination
+            ));
+        }
+        csv.push_str(&format!(
+            "{:?},{},leftover,{},{:?}\n",
+            self.fee_wallet, self.leftover, self.leftover_block_number, self.fee_wallet
+        ));
+        csv
+    }
+}
+
+enum PlanFlushState {
+    CheckBalances(Box<dyn Future<Item = Vec<Wallet>, Error = ()>>),
+    FilterContracts(FilterContracts),
+    FilterLowBalance(FilterLowBalance),
+    GetLeftover(Box<dyn Future<Item = (U256, Address, H256), Error = ()>>),
+}
+
+/// Future that works out the plan of a flush at a given block: the balances, the filters and the offsets ProcessFlush
+/// uses, and the leftover sent to the fee wallet. Wallets are withdrawn or skipped following the flush policy of the
+/// source network
+pub struct PlanFlush<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    target: Network<T>,
+    block_number: U64,
+    wallets: Vec<Wallet>,
+    eligible: Vec<Wallet>,
+    withdrawn: Vec<Wallet>,
+    without_code: HashSet<Address>,
+    fees: U256,
+    state: PlanFlushState,
+}
+
+impl<T: DuplexTransport + 'static> PlanFlush<T> {
+    /// Returns a newly created PlanFlush Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network being flushed
+    /// * `target` - Network the balances are withdrawn to
+    /// * `block_number` - Block of the flush
+    pub fn new(source: &Network<T>, target: &Network<T>, block_number: U64) -> Self {
+        PlanFlush {
+            source: source.clone(),
+            target: target.clone(),
+            block_number,
+            wallets: Vec::new(),
+            eligible: Vec::new(),
+            withdrawn: Vec::new(),
+            without_code: HashSet::new(),
+            fees: U256::zero(),
+            state: PlanFlushState::CheckBalances(Box::new(CheckBalances::new(source, Some(block_number)))),
+        }
+    }
+
+    /// Returns a Future that resolves to the relay contract balance and fee wallet on the target, and the hash of the
+    /// flush block
+    fn get_leftover(&self) -> Box<dyn Future<Item = (U256, Address, H256), Error = ()>> {
+        let block_number = self.block_number;
+        let network_type = self.source.network_type;
+        let block_hash = self
+            .source
+            .web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Number(block_number)))
+            .map_err(move |e| {
+                error!("error getting block {} on {:?}: {:?}", block_number, network_type, e);
+            })
+            .and_then(move |block| match block.and_then(|block| block.hash) {
+                Some(hash) => Ok(hash),
+                None => {
+                    error!("no block {} on {:?}", block_number, network_type);
+                    Err(())
+                }
+            });
+        Box::new(
+            FlushRemaining::get_balance(&self.target).join3(FlushRemaining::get_fee_wallet(&self.target), block_hash),
+        )
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for PlanFlush<T> {
+    type Item = FlushPlan;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                PlanFlushState::CheckBalances(ref mut future) => {
+                    self.wallets = try_ready!(future.poll());
+                    let policy = &self.source.flush_policy;
+                    let wallets = self
+                        .wallets
+                        .iter()
+                        .filter(|wallet| !policy.is_denied(&wallet.address))
+                        .cloned()
+                        .collect();
+                    PlanFlushState::FilterContracts(FilterContracts::new(&self.source, wallets))
+                }
+                PlanFlushState::FilterContracts(ref mut future) => {
+                    self.without_code = try_ready!(future.poll()).iter().map(|wallet| wallet.address).collect();
+                    let non_contracts = &self.without_code;
+                    let policy = &self.source.flush_policy;
+                    // Allowed contracts are withdrawn along with the wallets without code
+                    self.eligible = self
+                        .wallets
+                        .iter()
+                        .filter(|wallet| wallet.address != Address::zero() && !policy.is_denied(&wallet.address))
+                        .filter(|wallet| non_contracts.contains(&wallet.address) || policy.is_allowed(&wallet.address))
+                        .cloned()
+                        .collect();
+                    PlanFlushState::FilterLowBalance(FilterLowBalance::new(&self.target, self.eligible.clone()))
+                }
+                PlanFlushState::FilterLowBalance(ref mut future) => {
+                    let (fees, withdrawn) = try_ready!(future.poll());
+                    self.fees = fees;
+                    self.withdrawn = withdrawn;
+                    PlanFlushState::GetLeftover(self.get_leftover())
+                }
+                PlanFlushState::GetLeftover(ref mut future) => {
+                    let (contract_balance, fee_wallet, block_hash) = try_ready!(future.poll());
+                    return Ok(Async::Ready(FlushPlan::new(
+                        self.block_number,
+                        block_hash,
+                        &self.wallets,
+                        &self.eligible,
+                        &self.withdrawn,
+                        &self.without_code,
+                        self.fees,
+                        contract_balance,
+                        fee_wallet,
+                        &self.source.flush_policy,
+                    )));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_should_offset_withdrawn_wallets_in_address_order() {
+        // arrange
+        let wallet = |address: u64, balance: u64| Wallet::new(&Address::from_low_u64_be(address), &balance.into());
+        let wallets = vec![
+            wallet(4, 500),
+            wallet(0, 10),
+            wallet(2, 1),
+            wallet(3, 50),
+            wallet(1, 300),
+        ];
+        let non_contracts = vec![wallet(4, 500), wallet(2, 1), wallet(1, 300)];
+        let withdrawn = vec![wallet(4, 500), wallet(1, 300)];
+        // act
+        let plan = FlushPlan::new(
+            100.into(),
+            H256::zero(),
+            &wallets,
+            &non_contracts,
+            &withdrawn,
+            &non_contracts.iter().map(|wallet| wallet.address).collect(),
+            5.into(),
+            1000.into(),
+            Address::from_low_u64_be(9),
+            &FlushPolicy::default(),
+        );
+        // assert
+        let plans: Vec<(WalletPlan, Option<U64>)> = plan
+            .wallets
+            .iter()
+            .map(|wallet| (wallet.plan, wallet.block_number))
+            .collect();
+        assert_eq!(
+            plans,
+            vec![
+                (WalletPlan::ZeroAddress, None),
+                (WalletPlan::Withdraw, Some(100.into())),
+                (WalletPlan::LowBalance, None),
+                (WalletPlan::Contract, None),
+                (WalletPlan::Withdraw, Some(101.into())),
+            ]
+        );
+        assert_eq!(plan.leftover, 200.into());
+        assert_eq!(plan.leftover_block_number, 103.into());
+        assert!(plan
+            .to_csv()
+            .ends_with(&format!(",200,leftover,103,{:?}\n", Address::from_low_u64_be(9))));
+    }
+
+    #[test]
+    fn new_should_follow_the_flush_policy() {
+        // arrange
+        let wallet = |address: u64, balance: u64| Wallet::new(&Address::from_low_u64_be(address), &balance.into());
+        let mut policy = FlushPolicy::default();
+        policy
+            .allow
+            .insert(Address::from_low_u64_be(2), Some(Address::from_low_u64_be(7)));
+        policy.deny.insert(Address::from_low_u64_be(3));
+        let wallets = vec![wallet(1, 300), wallet(2, 200), wallet(3, 100), wallet(4, 50)];
+        let eligible = vec![wallet(1, 300), wallet(2, 200)];
+        // act
+        let plan = FlushPlan::new(
+            100.into(),
+            H256::zero(),
+            &wallets,
+            &eligible,
+            &eligible,
+            &vec![Address::from_low_u64_be(1)].into_iter().collect(),
+            5.into(),
+            1000.into(),
+            Address::from_low_u64_be(9),
+            &policy,
+        );
+        // assert
+        let plans: Vec<(WalletPlan, Option<Address>)> = plan
+            .wallets
+            .iter()
+            .map(|wallet| (wallet.plan, wallet.destination))
+            .collect();
+        assert_eq!(
+            plans,
+            vec![
+                (WalletPlan::Withdraw, Some(Address::from_low_u64_be(1))),
+                (WalletPlan::Withdraw, Some(Address::from_low_u64_be(7))),
+                (WalletPlan::Denied, None),
+                (WalletPlan::Contract, None),
+            ]
+        );
+        let excluded: Vec<U256> = plan.excluded().iter().map(|wallet| wallet.balance).collect();
+        assert_eq!(excluded, vec![100.into(), 50.into()]);
+        assert_eq!(plan.policy_hash, policy.hash());
+    }
+
+    #[test]
+    fn new_should_offset_allowed_contracts_after_wallets_without_code() {
+        // arrange
+        let wallet = |address: u64, balance: u64| Wallet::new(&Address::from_low_u64_be(address), &balance.into());
+        let mut policy = FlushPolicy::default();
+        policy.allow.insert(Address::from_low_u64_be(1), None);
+        let wallets = vec![wallet(1, 300), wallet(2, 200), wallet(3, 100)];
+        let without_code = vec![Address::from_low_u64_be(2), Address::from_low_u64_be(3)]
+            .into_iter()
+            .collect();
+        // act
+        let plan = FlushPlan::new(
+            100.into(),
+            H256::zero(),
+            &wallets,
+            &wallets,
+            &wallets,
+            &without_code,
+            5.into(),
+            1000.into(),
+            Address::from_low_u64_be(9),
+            &policy,
+        );
+        // assert
+        let block_numbers: Vec<Option<U64>> = plan.wallets.iter().map(|wallet| wallet.block_number).collect();
+        assert_eq!(
+            block_numbers,
+            vec![Some(102.into()), Some(100.into()), Some(101.into())]
+        );
+        assert_eq!(plan.leftover_block_number, 104.into());
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use tiny_keccak::keccak256;
+use web3::types::{Address, H256};
+
+/// Which wallets a flush withdraws besides wallets without code, and where to.
+/// Every relay operator must use the same policy, since it decides the wallets withdrawn and their offsets, and so the
+/// withdrawal hashes the relays vote on. Compare hash() across operators before a flush
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlushPolicy {
+    /// Contracts withdrawn anyway, to the mapped address on the other chain if there is one, else to the same address
+    pub allow: HashMap<Address, Option<Address>>,
+    /// Wallets never withdrawn, contracts or not
+    pub deny: HashSet<Address>,
+}
+
+impl FlushPolicy {
+    /// Returns true if the wallet is withdrawn even if it has code
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the wallet
+    pub fn is_allowed(&self, address: &Address) -> bool {
+        self.allow.contains_key(address) && !self.is_denied(address)
+    }
+
+    /// Returns true if the wallet is never withdrawn
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the wallet
+    pub fn is_denied(&self, address: &Address) -> bool {
+        self.deny.contains(address)
+    }
+
+    /// Returns the address on the other chain the balance of the wallet is withdrawn to
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the wallet
+    pub fn destination(&self, address: &Address) -> Address {
+        self.allow
+            .get(address)
+            .and_then(|destination| *destination)
+            .unwrap_or(*address)
+    }
+
+    /// Returns a hash of the allowlist, with destinations, and the denylist, that does not depend on their order
+    pub fn hash(&self) -> H256 {
+        let allow: BTreeMap<&Address, &Option<Address>> = self.allow.iter().collect();
+        let deny: BTreeSet<&Address> = self.deny.iter().collect();
+        let mut bytes = Vec::new();
+        for (address, destination) in allow {
+            bytes.extend_from_slice(&address.0);
+            match destination {
+                Some(destination) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&destination.0);
+                }
+                None => bytes.push(0),
+            }
+        }
+        // Separates the lists, as an allowed address without destination could otherwise read as a denied one
+        bytes.push(2);
+        for address in deny {
+            bytes.extend_from_slice(&address.0);
+        }
+        H256(keccak256(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_should_win_over_allow() {
+        // arrange
+        let multisig = Address::from_low_u64_be(1);
+        let mapped = Address::from_low_u64_be(2);
+        let vault = Address::from_low_u64_be(3);
+        let mut policy = FlushPolicy::default();
+        policy.allow.insert(multisig, Some(mapped));
+        policy.allow.insert(vault, None);
+        policy.deny.insert(vault);
+        // act
+        let allowed = (policy.is_allowed(&multisig), policy.is_allowed(&vault));
+        let destinations = (policy.destination(&multisig), policy.destination(&vault));
+        // assert
+        assert_eq!(allowed, (true, false));
+        assert_eq!(destinations, (mapped, vault));
+    }
+
+    #[test]
+    fn hash_should_change_with_the_policy_only() {
+        // arrange
+        let mut policy = FlushPolicy::default();
+        policy.allow.insert(Address::from_low_u64_be(1), None);
+        policy
+            .allow
+            .insert(Address::from_low_u64_be(2), Some(Address::from_low_u64_be(3)));
+        policy.deny.insert(Address::from_low_u64_be(4));
+        let mut reordered = FlushPolicy::default();
+        reordered.deny.insert(Address::from_low_u64_be(4));
+        reordered
+            .allow
+            .insert(Address::from_low_u64_be(2), Some(Address::from_low_u64_be(3)));
+        reordered.allow.insert(Address::from_low_u64_be(1), None);
+        let mut remapped = policy.clone();
+        remapped
+            .allow
+            .insert(Address::from_low_u64_be(1), Some(Address::from_low_u64_be(5)));
+        // act
+        let hashes = (policy.hash(), reordered.hash(), remapped.hash());
+        // assert
+        assert_eq!(hashes.0, hashes.1);
+        assert_ne!(hashes.0, hashes.2);
+        assert_ne!(hashes.0, FlushPolicy::default().hash());
+    }
+}
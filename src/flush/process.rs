@@ -9,25 +9,27 @@ use web3::DuplexTransport;
 
 use crate::eth::transaction::SendTransaction;
 use crate::eth::Event;
-use crate::flush::{CheckBalances, FilterLowBalance, Wallet};
+use crate::flush::{FlushPlan, PlanFlush, Wallet};
+use crate::metrics;
 use crate::relay::Network;
-use crate::transfers::withdrawal::{ApproveParams, WaitForWithdrawalProcessed};
+use crate::store::flush::{FlushProgress, WithdrawalStatus};
+use crate::transfers::withdrawal::WaitForWithdrawalProcessed;
 
 enum ProcessFlushState<T: DuplexTransport + 'static> {
     Wait,
-    CheckBalances(CheckBalances<T>),
-    FilterContracts(FilterContracts),
-    FilterLowBalance(FilterLowBalance),
+    Plan(PlanFlush<T>),
     WithdrawWallets(Box<dyn Future<Item = Vec<()>, Error = ()>>),
     WithdrawLeftovers(FlushRemaining<T>),
 }
 
 /// Process Flush Log/Receipts that come across and trigger a multi step flush
-/// 1. Check all balances
-/// 1. Filter contracts out
-/// 1. Filter out balances that don't have more than the fee cost to withdraw
+/// 1. Plan the flush: check all balances, filter contracts out, and filter out balances that don't have more than the
+///    fee cost to withdraw
 /// 1. Withdraw all balances to the same wallet on target chain
 /// 1. Withdraw any leftovers in the contract after all withdrawals confirmed
+///
+/// The plan and the status of each withdrawal are kept in the flush store, so a flush interrupted by a restart resumes
+/// where it stopped instead of checking every balance again
 pub struct ProcessFlush<T: DuplexTransport + 'static> {
     state: ProcessFlushState<T>,
     source: Network<T>,
@@ -53,7 +55,8 @@ impl<T: DuplexTransport + 'static> ProcessFlush<T> {
         }
     }
 
-    fn handle_flush_event(&self, flush: Event) -> Result<Option<CheckBalances<T>>, ()> {
+    /// Returns the next state for a flush event, or None if that flush was already completed
+    fn handle_flush_event(&self, flush: Event) -> Result<Option<ProcessFlushState<T>>, ()> {
         if let Ok(mut lock) = self.source.flushed.write() {
             *lock = Some(flush.clone());
         } else {
@@ -64,77 +67,177 @@ impl<T: DuplexTransport + 'static> ProcessFlush<T> {
         info!("flush event triggered");
         let removed = flush.log.removed.unwrap_or(false);
         if removed {
-            Ok(None)
+            Ok(Some(ProcessFlushState::Wait))
         } else {
             if flush.receipt.block_hash.is_none() {
                 error!("Failed to get block hash for flush");
                 return Err(());
             }
 
-            if flush.receipt.block_number.is_none() {
-                error!("Failed to get block hash for flush");
-                return Err(());
-            }
+            let block_number = match flush.receipt.block_number {
+                Some(block_number) => block_number,
+                None => {
+                    error!("Failed to get block hash for flush");
+                    return Err(());
+                }
+            };
 
-            Ok(Some(CheckBalances::new(&self.source, flush.receipt.block_number)))
+            let progress = match self.source.flush.read() {
+                Ok(store) => store.get(&flush.receipt.transaction_hash),
+                Err(e) => {
+                    error!("error acquiring flush lock: {:?}", e);
+                    return Err(());
+                }
+            };
+
+            match progress {
+                Some(ref progress) if progress.leftover_withdrawn => Ok(None),
+                Some(progress) => {
+                    info!(
+                        "resuming flush, {} of {} wallets processed",
+                        progress.processed(),
+                        progress.withdrawals.len()
+                    );
+                    let futures = self.handle_final_wallets(&flush, &progress)?;
+                    Ok(Some(ProcessFlushState::WithdrawWallets(Box::new(futures))))
+                }
+                None => Ok(Some(ProcessFlushState::Plan(PlanFlush::new(
+                    &self.source,
+                    &self.target,
+                    block_number,
+                )))),
+            }
         }
     }
 
     fn handle_final_wallets(
         &self,
         flush_event: &Event,
-        fees: &U256,
-        wallets: &mut Vec<Wallet>,
+        progress: &FlushProgress,
     ) -> Result<impl Future<Item = Vec<()>, Error = ()>, ()> {
         let receipt = flush_event.receipt.clone();
-        if receipt.block_number.is_none() {
-            error!("no block number in transfer receipt");
-            return Err(());
-        }
-
         if receipt.block_hash.is_none() {
             error!("no block hash in transfer receipt");
             return Err(());
         }
 
         let block_hash = receipt.block_hash.unwrap();
-        let block_number = receipt.block_number.unwrap();
-        let fees = *fees;
-        wallets.sort();
+        let fees = progress.plan.fees;
+        metrics::flush_started(progress.withdrawals.len());
 
-        let futures: Vec<Box<dyn Future<Item = (), Error = ()>>> = wallets
+        let futures: Vec<Box<dyn Future<Item = (), Error = ()>>> = progress
+            .withdrawals
             .iter()
-            .enumerate()
-            .map(|(i, wallet)| {
+            .map(|withdrawal| -> Box<dyn Future<Item = (), Error = ()>> {
                 let target = self.target.clone();
                 let wait_target = self.target.clone();
+                let approved_source = self.source.clone();
+                let processed_source = self.source.clone();
                 let transaction_hash = receipt.transaction_hash;
-                let wallet = *wallet;
+                let address = withdrawal.address;
+                // Allowed contracts may be withdrawn to another address
+                let wallet = Wallet::new(&withdrawal.destination, &withdrawal.balance);
+                let block_number = withdrawal.block_number;
 
                 // Create futures
-                let transfer = wallet.get_transfer(&receipt.transaction_hash, &block_hash, block_number + i);
-                let future: Box<dyn Future<Item = (), Error = ()>> = Box::new(
-                    transfer
-                        .check_withdrawal(&target, Some(fees))
-                        .and_then(move |needs_approval| {
-                            let target = target.clone();
-                            if needs_approval {
-                                Either::A(wallet.withdraw(&target, &transaction_hash, &block_hash, block_number + i))
-                            } else {
-                                Either::B(ok(()))
-                            }
-                        })
+                let transfer = wallet.get_transfer(&receipt.transaction_hash, &block_hash, block_number);
+                let approved: Box<dyn Future<Item = (), Error = ()>> = match withdrawal.status {
+                    WithdrawalStatus::Processed => {
+                        metrics::flush_wallet_withdrawn();
+                        return Box::new(ok(()));
+                    }
+                    WithdrawalStatus::Approved => Box::new(ok(())),
+                    WithdrawalStatus::Pending => Box::new(
+                        transfer
+                            .check_withdrawal(&target, Some(fees))
+                            .and_then(move |needs_approval| {
+                                let target = target.clone();
+                                if needs_approval {
+                                    Either::A(wallet.withdraw(&target, &transaction_hash, &block_hash, block_number))
+                                } else {
+                                    Either::B(ok(()))
+                                }
+                            })
+                            .map(move |_| {
+                                set_status(&approved_source, &address, WithdrawalStatus::Approved);
+                            }),
+                    ),
+                };
+                Box::new(
+                    approved
                         .and_then(move |_| {
                             let target = wait_target.clone();
                             // Wait until it is processed before moving on
                             WaitForWithdrawalProcessed::new(&target, &transfer)
+                        })
+                        .map(move |_| {
+                            set_status(&processed_source, &address, WithdrawalStatus::Processed);
+                            metrics::flush_wallet_withdrawn()
                         }),
-                );
-                future
+                )
             })
             .collect();
         Ok(join_all(futures))
     }
+
+    /// Logs every wallet the flush left out as a contract or denied, with its balance, before the leftover that backs
+    /// those balances is sent to the fee wallet
+    fn report_excluded(&self, flush_event: &Event) {
+        let progress = match self.source.flush.read() {
+            Ok(store) => store.get(&flush_event.receipt.transaction_hash),
+            Err(e) => {
+                error!("error acquiring flush lock: {:?}", e);
+                return;
+            }
+        };
+        if let Some(progress) = progress {
+            let excluded = progress.plan.excluded();
+            let total = excluded
+                .iter()
+                .fold(U256::zero(), |total, wallet| total + wallet.balance);
+            info!(
+                "flush left out {} wallets holding {}, leftover goes to fee wallet {:?}",
+                excluded.len(),
+                total,
+                progress.plan.fee_wallet
+            );
+            for wallet in excluded {
+                warn!(
+                    "flush left out {:?} wallet {:?} holding {}",
+                    wallet.plan, wallet.address, wallet.balance
+                );
+            }
+        }
+    }
+
+    /// Stores the plan of a new flush, and returns its progress
+    fn start_flush(&self, flush_event: &Event, plan: FlushPlan) -> Result<FlushProgress, ()> {
+        let progress = FlushProgress::new(flush_event.receipt.transaction_hash, plan);
+        match self.source.flush.write() {
+            Ok(mut store) => {
+                store.start(&progress);
+                Ok(progress)
+            }
+            Err(e) => {
+                error!("error acquiring flush lock: {:?}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+/// Records where the withdrawal of a wallet is at in the flush store of the network
+///
+/// # Arguments
+///
+/// * `source` - Network being flushed
+/// * `address` - Address of the wallet
+/// * `status` - Status of its withdrawal
+fn set_status<T: DuplexTransport + 'static>(source: &Network<T>, address: &Address, status: WithdrawalStatus) {
+    match source.flush.write() {
+        Ok(mut store) => store.set_status(address, status),
+        Err(e) => error!("error acquiring flush lock: {:?}", e),
+    }
 }
 
 impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
@@ -142,7 +245,6 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let target = self.target.clone();
-        let source = self.source.clone();
         loop {
             let flush = self.flush.clone();
             let next: ProcessFlushState<T> = match self.state {
@@ -151,10 +253,13 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
                     match event {
                         Some(flush) => {
                             self.flush = Some(flush.clone());
-                            if let Some(future) = self.handle_flush_event(flush)? {
-                                ProcessFlushState::CheckBalances(future)
-                            } else {
-                                ProcessFlushState::Wait
+                            match self.handle_flush_event(flush)? {
+                                Some(state) => state,
+                                None => {
+                                    info!("flush was already completed");
+                                    metrics::flush_completed();
+                                    return Ok(Async::Ready(()));
+                                }
                             }
                         }
                         None => {
@@ -162,24 +267,18 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
                         }
                     }
                 }
-                ProcessFlushState::CheckBalances(ref mut future) => {
-                    let balances = try_ready!(future.poll());
-                    info!("found {} wallets with tokens", balances.len());
-                    let contract_future = FilterContracts::new(&source, balances);
-                    ProcessFlushState::FilterContracts(contract_future)
-                }
-                ProcessFlushState::FilterContracts(ref mut future) => {
-                    let balances = try_ready!(future.poll());
-                    info!("{} balances were not contracts", balances.len());
-                    let low_balance_future = FilterLowBalance::new(&target, balances);
-                    ProcessFlushState::FilterLowBalance(low_balance_future)
-                }
-                ProcessFlushState::FilterLowBalance(ref mut future) => {
-                    let (fees, mut balances) = try_ready!(future.poll());
-                    info!("{} wallets above minimum balances", balances.len());
+                ProcessFlushState::Plan(ref mut future) => {
+                    let plan = try_ready!(future.poll());
+                    info!(
+                        "found {} wallets with tokens, following flush policy {:?}",
+                        plan.wallets.len(),
+                        plan.policy_hash
+                    );
                     match flush {
                         Some(flush_event) => {
-                            let futures = self.handle_final_wallets(&flush_event, &fees, &mut balances)?;
+                            let progress = self.start_flush(&flush_event, plan)?;
+                            info!("{} wallets above minimum balances", progress.withdrawals.len());
+                            let futures = self.handle_final_wallets(&flush_event, &progress)?;
                             ProcessFlushState::WithdrawWallets(Box::new(futures))
                         }
                         None => {
@@ -192,6 +291,7 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
                     let withdrawals = try_ready!(future.poll());
                     info!("finished {} wallet withdrawals", withdrawals.len());
                     if let Some(flush_event) = flush {
+                        self.report_excluded(&flush_event);
                         ProcessFlushState::WithdrawLeftovers(FlushRemaining::new(
                             &target,
                             &flush_event.receipt,
@@ -205,6 +305,11 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
                 ProcessFlushState::WithdrawLeftovers(ref mut future) => {
                     try_ready!(future.poll());
                     info!("finished leftover withdrawal");
+                    match self.source.flush.write() {
+                        Ok(mut store) => store.leftover_withdrawn(),
+                        Err(e) => error!("error acquiring flush lock: {:?}", e),
+                    }
+                    metrics::flush_completed();
                     return Ok(Async::Ready(()));
                 }
             };
@@ -216,7 +321,7 @@ impl<T: DuplexTransport + 'static> Future for ProcessFlush<T> {
 enum FlushRemainingState<T: DuplexTransport + 'static> {
     GetBalance(Box<dyn Future<Item = U256, Error = ()>>),
     GetFeeWallet(Box<dyn Future<Item = Address, Error = ()>>),
-    Withdraw(SendTransaction<T, ApproveParams>),
+    Withdraw(SendTransaction<T>),
 }
 
 /// Future that gets the balance of the relay contract on target chain.
@@ -251,7 +356,7 @@ impl<T: DuplexTransport + 'static> FlushRemaining<T> {
     /// # Arguments
     ///
     /// * `target` - Network being flushed
-    fn get_balance(target: &Network<T>) -> Box<dyn Future<Item = U256, Error = ()>> {
+    pub fn get_balance(target: &Network<T>) -> Box<dyn Future<Item = U256, Error = ()>> {
         let target = target.clone();
         Box::new(
             target
@@ -273,7 +378,7 @@ impl<T: DuplexTransport + 'static> FlushRemaining<T> {
     /// # Arguments
     ///
     /// * `target` - Network being flushed
-    fn get_fee_wallet(target: &Network<T>) -> Box<dyn Future<Item = Address, Error = ()>> {
+    pub fn get_fee_wallet(target: &Network<T>) -> Box<dyn Future<Item = Address, Error = ()>> {
         let target = target.clone();
         Box::new(
             target
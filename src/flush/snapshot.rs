@@ -0,0 +1,243 @@
+use serde_derive::Serialize;
+use web3::contract::Options;
+use web3::futures::prelude::*;
+use web3::futures::stream;
+use web3::futures::try_ready;
+use web3::types::{Address, BlockNumber, U256, U64};
+use web3::DuplexTransport;
+
+use crate::flush::plan::ReportFormat;
+use crate::flush::{CheckBalances, Wallet};
+use crate::relay::Network;
+
+/// Token balance of a holder at the block of a snapshot
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HolderBalance {
+    pub address: Address,
+    /// Balance found by replaying every Transfer log up to the block
+    pub balance: U256,
+    /// Balance returned by balanceOf at the block, if the holder was checked
+    pub balance_of: Option<U256>,
+}
+
+impl HolderBalance {
+    /// Returns true if the holder was checked, and balanceOf does not match the replayed balance
+    pub fn is_mismatch(&self) -> bool {
+        self.balance_of.map_or(false, |balance_of| balance_of != self.balance)
+    }
+}
+
+/// Every holder of the token on a chain at a block, cross-checked against balanceOf
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BalanceSnapshot {
+    pub token: Address,
+    pub block_number: U64,
+    /// Sum of every replayed balance
+    pub total: U256,
+    /// Number of holders checked against balanceOf
+    pub checked: usize,
+    /// Number of checked holders whose balanceOf does not match
+    pub mismatches: usize,
+    /// Every holder with a non-zero balance, by address
+    pub holders: Vec<HolderBalance>,
+}
+
+impl BalanceSnapshot {
+    /// Returns the snapshot in the given format
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - Format of the report
+    pub fn render(&self, format: ReportFormat) -> Result<String, serde_json::Error> {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self),
+            ReportFormat::Csv => Ok(self.to_csv()),
+        }
+    }
+
+    /// Returns the snapshot as CSV, one row per holder
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("address,balance,balance_of,mismatch\n");
+        for holder in &self.holders {
+            let balance_of = holder.balance_of.map(|b| b.to_string()).unwrap_or_default();
+            csv.push_str(&format!(
+                "{:?},{},{},{}\n",
+                holder.address,
+                holder.balance,
+                balance_of,
+                holder.is_mismatch()
+            ));
+        }
+        csv
+    }
+}
+
+/// Returns the positions of the holders checked against balanceOf, spread evenly over the holders.
+/// Every holder is checked without a sample size, or if there are fewer holders than the sample size
+///
+/// # Arguments
+///
+/// * `holders` - Number of holders
+/// * `sample` - Number of holders to check, all if None
+pub fn sample_positions(holders: usize, sample: Option<usize>) -> Vec<usize> {
+    match sample {
+        Some(sample) if sample < holders => (0..sample).map(|i| i * holders / sample).collect(),
+        _ => (0..holders).collect(),
+    }
+}
+
+enum SnapshotBalancesState {
+    CheckBalances(Box<dyn Future<Item = Vec<Wallet>, Error = ()>>),
+    BalanceOf(Box<dyn Future<Item = Vec<(usize, U256)>, Error = ()>>),
+}
+
+/// Future that replays every Transfer log of a chain up to a block into the balance of each holder, as a flush does,
+/// then checks a sample of them, or all, against balanceOf at that block
+pub struct SnapshotBalances<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    block_number: U64,
+    sample: Option<usize>,
+    holders: Vec<HolderBalance>,
+    state: SnapshotBalancesState,
+}
+
+impl<T: DuplexTransport + 'static> SnapshotBalances<T> {
+    /// Returns a newly created SnapshotBalances Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network of the token
+    /// * `block_number` - Block of the snapshot
+    /// * `sample` - Number of holders to check against balanceOf, all if None
+    pub fn new(source: &Network<T>, block_number: U64, sample: Option<usize>) -> Self {
+        SnapshotBalances {
+            source: source.clone(),
+            block_number,
+            sample,
+            holders: Vec::new(),
+            state: SnapshotBalancesState::CheckBalances(Box::new(CheckBalances::new(source, Some(block_number)))),
+        }
+    }
+
+    /// Returns a Future that resolves to the balanceOf of the holders at the given positions, with their positions.
+    /// Queries are sent up to the scan concurrency of the network at once
+    ///
+    /// # Arguments
+    ///
+    /// * `positions` - Positions of the holders to check
+    fn balance_of(&self, positions: Vec<usize>) -> Box<dyn Future<Item = Vec<(usize, U256)>, Error = ()>> {
+        let source = self.source.clone();
+        let block_number = self.block_number;
+        let network_type = self.source.network_type;
+        let addresses: Vec<(usize, Address)> = positions
+            .into_iter()
+            .map(|position| (position, self.holders[position].address))
+            .collect();
+        let future = stream::iter_ok(addresses)
+            .map(move |(position, address)| {
+                source
+                    .token
+                    .query(
+                        "balanceOf",
+                        address,
+                        None,
+                        Options::default(),
+                        BlockNumber::Number(block_number),
+                    )
+                    .map(move |balance: U256| (position, balance))
+                    .map_err(move |e| {
+                        error!(
+                            "error getting balance of {:?} at {} on {:?}: {:?}",
+                            address, block_number, network_type, e
+                        );
+                    })
+            })
+            .buffered(self.source.scan.concurrency)
+            .collect();
+        Box::new(future)
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for SnapshotBalances<T> {
+    type Item = BalanceSnapshot;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                SnapshotBalancesState::CheckBalances(ref mut future) => {
+                    let mut wallets = try_ready!(future.poll());
+                    // Tokens at the zero address are burnt, as in FlushPlan
+                    wallets.retain(|wallet| !wallet.balance.is_zero() && wallet.address != Address::zero());
+                    wallets.sort();
+                    self.holders = wallets
+                        .iter()
+                        .map(|wallet| HolderBalance {
+                            address: wallet.address,
+                            balance: wallet.balance,
+                            balance_of: None,
+                        })
+                        .collect();
+                    info!(
+                        "found {} holders at {} on {:?}",
+                        self.holders.len(),
+                        self.block_number,
+                        self.source.network_type
+                    );
+                    let positions = sample_positions(self.holders.len(), self.sample);
+                    SnapshotBalancesState::BalanceOf(self.balance_of(positions))
+                }
+                SnapshotBalancesState::BalanceOf(ref mut future) => {
+                    let balances = try_ready!(future.poll());
+                    for (position, balance) in &balances {
+                        self.holders[*position].balance_of = Some(*balance);
+                    }
+                    let mismatched: Vec<&HolderBalance> =
+                        self.holders.iter().filter(|holder| holder.is_mismatch()).collect();
+                    for holder in &mismatched {
+                        warn!(
+                            "balance of {:?} at {} on {:?} is {:?} on chain, but {} from transfers",
+                            holder.address,
+                            self.block_number,
+                            self.source.network_type,
+                            holder.balance_of,
+                            holder.balance
+                        );
+                    }
+                    let total = self
+                        .holders
+                        .iter()
+                        .fold(U256::zero(), |total, holder| total + holder.balance);
+                    return Ok(Async::Ready(BalanceSnapshot {
+                        token: self.source.token.address(),
+                        block_number: self.block_number,
+                        total,
+                        checked: balances.len(),
+                        mismatches: mismatched.len(),
+                        holders: self.holders.clone(),
+                    }));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_positions_should_spread_over_holders() {
+        // arrange
+        let holders = 10;
+        // act
+        let sampled = sample_positions(holders, Some(4));
+        let everyone = sample_positions(holders, None);
+        let oversampled = sample_positions(holders, Some(20));
+        // assert
+        assert_eq!(sampled, vec![0, 2, 5, 7]);
+        assert_eq!(everyone, (0..10).collect::<Vec<usize>>());
+        assert_eq!(oversampled, everyone);
+    }
+}
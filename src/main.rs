@@ -11,41 +11,63 @@ pub mod errors;
 pub mod eth;
 pub mod extensions;
 pub mod flush;
+pub mod metrics;
 #[cfg(test)]
 mod mock;
 pub mod relay;
 pub mod relay_config;
+pub mod reload;
 pub mod server;
+pub mod store;
 pub mod transfers;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use failure::{Error, SyncFailure};
-use serde_json;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio_core::reactor;
 use web3::futures::sync::mpsc;
 use web3::futures::Future;
+use web3::types::Address;
 use web3::Web3;
 
 use crate::endpoint::{Endpoint, RequestType};
-use crate::errors::{ConfigError, OperationError};
-use crate::relay::{Network, Relay};
-use crate::relay_config::{consul_configs, logger, settings};
+use crate::errors::OperationError;
+use crate::eth::gas::GasStrategy;
+use crate::eth::polling::PollingTransport;
+use crate::eth::transport::ChainTransport;
+use crate::eth::websocket::{Backoff, Health, ReconnectingWebSocket};
+use crate::extensions::shutdown::Shutdown;
+use crate::flush::plan::{PlanFlush, ReportFormat};
+use crate::flush::snapshot::SnapshotBalances;
+use crate::relay::{Network, NetworkConfig};
+use crate::relay_config::sources::ContractsConfig;
+use crate::relay_config::{consul_configs, logger, settings, sources};
+use crate::reload::ReloadRelay;
 use crate::server::endpoint;
-use crate::settings::Settings;
+use crate::settings::{ConfigSourceKind, RpcEndpoint, Settings, TransportKind};
+use crate::store::transfers::PersistTransfers;
+use crate::store::{AuditLog, PauseStore};
 
 use log::Level;
 
+/// Extra time given to the relay to wind down after the shutdown timeout, before exiting regardless
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 fn main() -> Result<(), Error> {
-    // Set up ctrl-c handler
-    let running = Arc::new(AtomicBool::new(true));
+    // Set up SIGINT and SIGTERM handler, a second signal exits right away
+    let shutdown = Shutdown::new();
 
-    let running_ = running.clone();
+    let shutdown_ = shutdown.clone();
     ctrlc::set_handler(move || {
-        info!("ctrl-c caught, exiting...");
-        running_.store(false, Ordering::SeqCst);
+        if shutdown_.is_triggered() {
+            info!("second shutdown signal caught, exiting...");
+            process::exit(1);
+        }
+        info!("shutdown signal caught, shutting down...");
+        shutdown_.trigger();
     })?;
 
     // Parse options
@@ -69,6 +91,61 @@ fn main() -> Result<(), Error> {
                 .help("Specifies the logging severity level")
                 .takes_value(true),
         )
+        .subcommand(
+            SubCommand::with_name("flush-plan")
+                .about("Prints what a flush at a sidechain block would do, without sending any transaction")
+                .arg(
+                    Arg::with_name("block")
+                        .long("block")
+                        .value_name("Sidechain block number")
+                        .help("Block the flush happens at")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the plan")
+                        .possible_values(&["json", "csv"])
+                        .default_value("json")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("balances")
+                .about("Prints the token balance of every holder on a chain at a block, checked against balanceOf")
+                .arg(
+                    Arg::with_name("chain")
+                        .long("chain")
+                        .help("Chain of the token")
+                        .possible_values(&["home", "side"])
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("block")
+                        .long("block")
+                        .value_name("Block number")
+                        .help("Block of the snapshot")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .value_name("Number of holders")
+                        .help("Checks this many holders against balanceOf instead of all of them")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the snapshot")
+                        .possible_values(&["json", "csv"])
+                        .default_value("json")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
     let settings = Settings::new(matches.value_of("config"))?;
@@ -84,69 +161,360 @@ fn main() -> Result<(), Error> {
 
     logger::init_logger(&settings.logging, "relay", log_severity).expect("problem initializing relay logger");
 
-    let consul_config = consul_configs::ConsulConfig::new(
-        &settings.relay.consul,
-        &settings.relay.consul_token,
-        &settings.relay.community,
-    );
-
-    let homechain_config = consul_config.wait_or_get("homechain").map_err(|e| {
-        error!("error fetchign consul configuration {:?}", e);
-        ConfigError::ConsulError
-    })?;
-    let sidechain_config = consul_config.wait_or_get("sidechain").map_err(|e| {
-        error!("error fetchign consul configuration {:?}", e);
-        ConfigError::ConsulError
-    })?;
+    let contracts = sources::from_settings(&settings).load()?;
 
-    let nectar_token_abi = consul_config.create_contract_abi("NectarToken")?;
-    let erc20_relay_abi = consul_config.create_contract_abi("ERC20Relay")?;
-
-    consul_config.watch_for_config_deletion();
-
-    // Set up our two websocket connections on the same event loop
+    // Set up our two chain connections on the same event loop
     let mut eloop = tokio_core::reactor::Core::new()?;
     let handle = eloop.handle();
 
-    let home_ws = web3::transports::WebSocket::with_event_loop(&settings.relay.homechain.wsuri, &handle)
-        .map_err(SyncFailure::new)?;
+    let home_ws = chain_transport(
+        &settings,
+        &settings.relay.homechain,
+        &handle,
+        contracts.homechain.chain_id,
+    )?;
+    let side_ws = chain_transport(
+        &settings,
+        &settings.relay.sidechain,
+        &handle,
+        contracts.sidechain.chain_id,
+    )?;
+
+    if let Some(matches) = matches.subcommand_matches("flush-plan") {
+        return flush_plan(&mut eloop, &settings, home_ws, side_ws, &contracts, matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("balances") {
+        return balances(&mut eloop, &settings, home_ws, side_ws, &contracts, matches);
+    }
 
-    let side_ws = web3::transports::WebSocket::with_event_loop(&settings.relay.sidechain.wsuri, &handle)
-        .map_err(SyncFailure::new)?;
+    // Without a watcher nothing is ever sent, and the relay keeps the config it started with
+    let (changes_tx, changes_rx) = mpsc::unbounded();
+    if settings.relay.source == ConfigSourceKind::Consul {
+        consul_configs::ConsulConfig::new(
+            &settings.relay.consul,
+            &settings.relay.consul_token,
+            &settings.relay.community,
+        )
+        .watch_for_changes(contracts.clone(), changes_tx);
+    }
 
     let (tx, rx) = mpsc::unbounded();
-    let endpoint = Endpoint::new(tx, settings.endpoint.port);
-    endpoint.start_server();
+    let audit = Arc::new(AuditLog::open(
+        &Path::new(&settings.relay.state_dir).join("audit.jsonl"),
+        settings.relay.audit_max_bytes,
+        settings.relay.audit_max_files,
+    )?);
+    let endpoint = Endpoint::new(
+        tx,
+        settings.endpoint.port,
+        audit.clone(),
+        settings.endpoint.admin_token.clone(),
+    )
+    .with_admin_tls(settings.endpoint.admin_tls.clone());
+    endpoint.start_server()?;
+    let shutdown_timeout = Duration::from_secs(settings.relay.shutdown_timeout) + SHUTDOWN_GRACE;
     // Run the relay
     handle.spawn(run(
         handle.clone(),
         rx,
         settings,
         home_ws,
-        homechain_config,
         side_ws,
-        sidechain_config,
-        nectar_token_abi,
-        erc20_relay_abi,
+        contracts,
+        changes_rx,
+        shutdown.clone(),
+        audit,
     ));
 
-    while running.load(Ordering::SeqCst) {
+    let mut deadline = None;
+    while !shutdown.is_completed() {
         eloop.turn(Some(Duration::from_secs(1)));
+        if shutdown.is_triggered() {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + shutdown_timeout);
+            if Instant::now() >= deadline {
+                warn!("relay did not shut down in time, exiting");
+                break;
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Prints the plan of a flush at a sidechain block, without sending any transaction
+///
+/// # Arguments
+///
+/// * `eloop` - Event loop to run the plan on
+/// * `settings` - Settings of the relay
+/// * `home_ws` - Transport of the homechain
+/// * `side_ws` - Transport of the sidechain
+/// * `contracts` - Chain IDs, contract addresses and ABIs
+/// * `matches` - Arguments of the flush-plan subcommand
+fn flush_plan(
+    eloop: &mut reactor::Core,
+    settings: &Settings,
+    home_ws: ChainTransport,
+    side_ws: ChainTransport,
+    contracts: &ContractsConfig,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
+    let block: u64 = matches.value_of("block").unwrap_or_default().parse()?;
+    let format: ReportFormat = matches
+        .value_of("format")
+        .unwrap_or("json")
+        .parse()
+        .map_err(|format| failure::err_msg(format!("invalid report format {}", format)))?;
+    // Nothing is sent, and nothing is written to the state directory
+    let (home_config, side_config) = network_configs(settings, contracts).map_err(failure::err_msg)?;
+    let (homechain, sidechain) = networks(home_ws, side_ws, home_config, side_config).map_err(failure::err_msg)?;
+    let plan = eloop
+        .run(PlanFlush::new(&sidechain, &homechain, block.into()))
+        .map_err(|_| failure::err_msg("unable to plan the flush"))?;
+    println!("{}", plan.render(format)?);
+    Ok(())
+}
+
+/// Prints the token balance of every holder on a chain at a block, and fails if any checked balance does not match
+/// balanceOf
+///
+/// # Arguments
+///
+/// * `eloop` - Event loop to run the snapshot on
+/// * `settings` - Settings of the relay
+/// * `home_ws` - Transport of the homechain
+/// * `side_ws` - Transport of the sidechain
+/// * `contracts` - Chain IDs, contract addresses and ABIs
+/// * `matches` - Arguments of the balances subcommand
+fn balances(
+    eloop: &mut reactor::Core,
+    settings: &Settings,
+    home_ws: ChainTransport,
+    side_ws: ChainTransport,
+    contracts: &ContractsConfig,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
+    let block: u64 = matches.value_of("block").unwrap_or_default().parse()?;
+    let sample: Option<usize> = matches.value_of("sample").map(str::parse::<usize>).transpose()?;
+    let format: ReportFormat = matches
+        .value_of("format")
+        .unwrap_or("json")
+        .parse()
+        .map_err(|format| failure::err_msg(format!("invalid report format {}", format)))?;
+    // Nothing is sent, and nothing is written to the state directory
+    let (home_config, side_config) = network_configs(settings, contracts).map_err(failure::err_msg)?;
+    let (homechain, sidechain) = networks(home_ws, side_ws, home_config, side_config).map_err(failure::err_msg)?;
+    let source = match matches.value_of("chain") {
+        Some("home") => homechain,
+        _ => sidechain,
+    };
+    let snapshot = eloop
+        .run(SnapshotBalances::new(&source, block.into(), sample))
+        .map_err(|_| failure::err_msg("unable to get the balance snapshot"))?;
+    println!("{}", snapshot.render(format)?);
+    if snapshot.mismatches > 0 {
+        return Err(failure::err_msg(format!(
+            "{} of {} checked balances do not match balanceOf",
+            snapshot.mismatches, snapshot.checked
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the transport of a chain picked in the settings, with its health checks running on the event loop
+///
+/// # Arguments
+///
+/// * `settings` - Settings of the relay
+/// * `network` - Settings of the chain
+/// * `handle` - Handle to the event loop
+/// * `chain_id` - Chain ID the endpoints have to report
+fn chain_transport(
+    settings: &Settings,
+    network: &settings::Network,
+    handle: &reactor::Handle,
+    chain_id: u64,
+) -> Result<ChainTransport, Error> {
+    let transport = match network.transport {
+        TransportKind::Websocket => ChainTransport::WebSocket(
+            ReconnectingWebSocket::new(
+                network.rpc_endpoints(),
+                handle,
+                Duration::from_secs(settings.relay.request_timeout),
+                reconnect_backoff(settings),
+                health(settings, chain_id),
+            )
+            .map_err(SyncFailure::new)?,
+        ),
+        TransportKind::Http => {
+            let endpoint = RpcEndpoint {
+                uri: network.httpuri.clone().unwrap_or_default(),
+                priority: 0,
+                name: None,
+            };
+            ChainTransport::Polling(
+                PollingTransport::new(
+                    &endpoint,
+                    handle,
+                    Duration::from_secs(network.poll_interval),
+                    settings.relay.confirmations,
+                )
+                .map_err(SyncFailure::new)?,
+            )
+        }
+    };
+    if let Some(check) = transport.check_health() {
+        handle.spawn(check);
+    }
+    Ok(transport)
+}
+
+/// Returns the backoff between reconnection attempts set in the settings
+fn reconnect_backoff(settings: &Settings) -> Backoff {
+    Backoff::new(
+        Duration::from_secs(settings.relay.reconnect_initial_delay),
+        Duration::from_secs(settings.relay.reconnect_max_delay),
+    )
+}
+
+/// Returns what RPC endpoints of the chain have to meet to be used
+fn health(settings: &Settings, chain_id: u64) -> Health {
+    Health {
+        interval: Duration::from_secs(settings.relay.health_check_interval),
+        max_head_age: settings.relay.max_head_age,
+        min_peers: settings.relay.min_peers,
+        chain_id,
+    }
+}
+
+/// Returns the configs of the homechain and the sidechain, with the contracts and settings of the relay. Nothing is
+/// persisted, the nonces are zero, and the shared stores are in memory until the caller sets them
+///
+/// # Arguments
+///
+/// * `settings` - Settings of the relay
+/// * `contracts` - Chain IDs, contract addresses and ABIs
+fn network_configs(settings: &Settings, contracts: &ContractsConfig) -> Result<(NetworkConfig, NetworkConfig), String> {
+    let scan = settings
+        .relay
+        .scan
+        .config()
+        .map_err(|e| format!("error reading scan settings {}", e))?;
+    let shared = NetworkConfig {
+        account: settings.relay.account.clone(),
+        token_abi: contracts.token_abi.clone(),
+        relay_abi: contracts.relay_abi.clone(),
+        confirmations: settings.relay.confirmations,
+        keydir: settings.relay.keydir.clone(),
+        password: settings.relay.password.clone(),
+        retries: settings.relay.retries,
+        scan,
+        ..NetworkConfig::default()
+    };
+    let homechain = NetworkConfig {
+        token: contracts.homechain.token.clone(),
+        relay: contracts.homechain.relay.clone(),
+        gas: GasStrategy::from_settings(settings.relay.homechain.free, &settings.relay.homechain.gas),
+        interval: settings.relay.homechain.interval,
+        timeout: settings.relay.homechain.timeout,
+        chain_id: contracts.homechain.chain_id,
+        limits: settings
+            .relay
+            .homechain
+            .limits
+            .config()
+            .map_err(|e| format!("error reading homechain limits {}", e))?,
+        ..shared.clone()
+    };
+    let sidechain = NetworkConfig {
+        token: contracts.sidechain.token.clone(),
+        relay: contracts.sidechain.relay.clone(),
+        gas: GasStrategy::from_settings(settings.relay.sidechain.free, &settings.relay.sidechain.gas),
+        anchor_frequency: settings.relay.anchor_frequency,
+        interval: settings.relay.sidechain.interval,
+        timeout: settings.relay.sidechain.timeout,
+        chain_id: contracts.sidechain.chain_id,
+        limits: settings
+            .relay
+            .sidechain
+            .limits
+            .config()
+            .map_err(|e| format!("error reading sidechain limits {}", e))?,
+        flush_policy: settings
+            .relay
+            .flush
+            .policy()
+            .map_err(|e| format!("error reading flush settings {}", e))?,
+        anchor_leaders: anchor_leaders(settings),
+        ..shared
+    };
+    Ok((homechain, sidechain))
+}
+
+/// Returns the homechain and the sidechain
+///
+/// # Arguments
+///
+/// * `home_ws` - Transport of the homechain
+/// * `side_ws` - Transport of the sidechain
+/// * `home_config` - Config of the homechain
+/// * `side_config` - Config of the sidechain
+fn networks(
+    home_ws: ChainTransport,
+    side_ws: ChainTransport,
+    home_config: NetworkConfig,
+    side_config: NetworkConfig,
+) -> Result<(Network<ChainTransport>, Network<ChainTransport>), String> {
+    let homechain =
+        Network::homechain(home_ws, home_config).map_err(|e| format!("error initializing homechain {}", e))?;
+    let sidechain =
+        Network::sidechain(side_ws, side_config).map_err(|e| format!("error initializing sidechain {}", e))?;
+    Ok((homechain, sidechain))
+}
+
+/// Returns the verifiers taking turns to post anchors, sorted so every relay agrees on the turns
+///
+/// # Arguments
+///
+/// * `settings` - Settings with the anchor leaders, already validated
+fn anchor_leaders(settings: &Settings) -> Vec<Address> {
+    let mut leaders: Vec<Address> = settings
+        .relay
+        .anchor_leaders
+        .iter()
+        .filter_map(|leader| eth::utils::clean_0x(leader).parse().ok())
+        .collect();
+    leaders.sort();
+    leaders.dedup();
+    leaders
+}
+
+/// Opens the store of paused targets and their backlogs in the state directory, and pauses the targets in the settings
+///
+/// # Arguments
+///
+/// * `settings` - Relay settings
+fn pause_store(settings: &Settings) -> Result<Arc<RwLock<PauseStore>>, Error> {
+    let mut store = PauseStore::open(&Path::new(&settings.relay.state_dir).join("pause.json"))?;
+    for target in &settings.relay.paused {
+        if store.pause(*target) {
+            info!("paused {} from the settings", target);
+        }
+    }
+    metrics::paused(&store.status());
+    Ok(Arc::new(RwLock::new(store)))
+}
+
 fn run(
     handle: reactor::Handle,
     hash_rx: mpsc::UnboundedReceiver<RequestType>,
     settings: Settings,
-    home_ws: web3::transports::WebSocket,
-    home_config: serde_json::Value,
-    side_ws: web3::transports::WebSocket,
-    side_config: serde_json::Value,
-    nectar_token_abi: String,
-    erc20_relay_abi: String,
+    home_ws: ChainTransport,
+    side_ws: ChainTransport,
+    contracts: ContractsConfig,
+    changes_rx: mpsc::UnboundedReceiver<ContractsConfig>,
+    shutdown: Shutdown,
+    audit: Arc<AuditLog>,
 ) -> impl Future<Item = (), Error = ()> {
     let account = eth::utils::clean_0x(&settings.relay.account)
         .parse()
@@ -164,79 +532,43 @@ fn run(
                 .eth()
                 .transaction_count(account, None)
                 .and_then(move |side_nonce| {
-                    let mut _home_nonce = AtomicUsize::new(home_nonce.as_u64() as usize);
-                    let mut _side_nonce = AtomicUsize::new(side_nonce.as_u64() as usize);
-
-                    let mut key = "chain_id";
-                    let home_chain_id = home_config[key]
-                        .as_u64()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-                    let side_chain_id = side_config[key]
-                        .as_u64()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-
-                    key = "nectar_token_address";
-                    let homechain_nectar_token_address = home_config[key]
-                        .as_str()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-                    let sidechain_nectar_token_address = side_config[key]
-                        .as_str()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-
-                    key = "erc20_relay_address";
-                    let homechain_erc20_relay_address = home_config[key]
-                        .as_str()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-                    let sidechain_erc20_relay_address = side_config[key]
-                        .as_str()
-                        .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
-                        .map_err(|e| e.to_string())?;
-
-                    let relay = Relay::new(
-                        Network::homechain(
-                            home_ws.clone(),
-                            &settings.relay.account,
-                            &homechain_nectar_token_address,
-                            &nectar_token_abi,
-                            &homechain_erc20_relay_address,
-                            &erc20_relay_abi,
-                            settings.relay.homechain.free,
-                            settings.relay.confirmations,
-                            settings.relay.homechain.interval,
-                            settings.relay.homechain.timeout,
-                            home_chain_id,
-                            &settings.relay.keydir,
-                            &settings.relay.password,
-                            _home_nonce,
-                            settings.relay.retries,
-                        )
-                        .map_err(|e| format!("error initializing homechain {}", e))?,
-                        Network::sidechain(
-                            side_ws.clone(),
-                            &settings.relay.account,
-                            &sidechain_nectar_token_address,
-                            &nectar_token_abi,
-                            &sidechain_erc20_relay_address,
-                            &erc20_relay_abi,
-                            settings.relay.sidechain.free,
-                            settings.relay.confirmations,
-                            settings.relay.anchor_frequency,
-                            settings.relay.sidechain.interval,
-                            settings.relay.sidechain.timeout,
-                            side_chain_id,
-                            &settings.relay.keydir,
-                            &settings.relay.password,
-                            _side_nonce,
-                            settings.relay.retries,
-                        )
-                        .map_err(|e| format!("error initializing sidechain {}", e))?,
-                    );
-                    handle.spawn(relay.run(hash_rx, &handle));
+                    let (home_config, side_config) = network_configs(&settings, &contracts)?;
+                    // Operators compare this to make sure their flushes withdraw the same wallets
+                    info!("flush policy hash {:?}", side_config.flush_policy.hash());
+                    let pause = pause_store(&settings).map_err(|e| format!("error opening pause state {}", e))?;
+                    let state_dir = Some(settings.relay.state_dir.clone());
+                    let home_config = NetworkConfig {
+                        nonce: home_nonce,
+                        state_dir: state_dir.clone(),
+                        endpoint: home_ws.active_endpoint(),
+                        audit: audit.clone(),
+                        pause: pause.clone(),
+                        ..home_config
+                    };
+                    let side_config = NetworkConfig {
+                        nonce: side_nonce,
+                        state_dir,
+                        endpoint: side_ws.active_endpoint(),
+                        audit,
+                        pause,
+                        ..side_config
+                    };
+                    let (homechain, sidechain) = networks(home_ws.clone(), side_ws.clone(), home_config, side_config)?;
+                    // Reloads and reconnects keep the stores, so they are written by the same tasks throughout
+                    handle.spawn(PersistTransfers::new(&homechain.pending, &shutdown, &handle));
+                    handle.spawn(PersistTransfers::new(&sidechain.pending, &shutdown, &handle));
+                    handle.spawn(ReloadRelay::new(
+                        &homechain,
+                        &sidechain,
+                        contracts,
+                        changes_rx,
+                        hash_rx,
+                        &handle,
+                        Duration::from_secs(settings.relay.reload_drain_timeout),
+                        &shutdown,
+                        Duration::from_secs(settings.relay.shutdown_timeout),
+                        reconnect_backoff(&settings),
+                    ));
                     Ok(())
                 })
         })
@@ -0,0 +1,278 @@
+use lazy_static::lazy_static;
+use prometheus::{Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use web3::types::U256;
+
+use crate::relay::NetworkType;
+use crate::store::pause::{PauseState, PauseStatus};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref TRANSFERS_SEEN: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("relay_transfers_seen_total", "Transfers to the relay contract seen on each chain"),
+        &["chain"],
+    ));
+    static ref TRANSACTIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "relay_transactions_total",
+            "Relay contract transactions by chain, function (approveWithdrawal, unapproveWithdrawal, anchor) and status",
+        ),
+        &["chain", "function", "status"],
+    ));
+    static ref NONCE_RESYNCS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("relay_nonce_resyncs_total", "Nonce resyncs after a nonce too low error"),
+        &["chain"],
+    ));
+    static ref TRANSACTION_QUEUE_DEPTH: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("relay_transaction_queue_depth", "Contract calls waiting to be sent on each chain"),
+        &["chain"],
+    ));
+    static ref CONFIG_RELOADS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "relay_config_reloads_total",
+            "Contract config changes by result (applied, rejected, unsafe, failed)",
+        ),
+        &["result"],
+    ));
+    static ref SUBSCRIPTION_TIMEOUTS: IntCounter = register(IntCounter::new(
+        "relay_subscription_timeouts_total",
+        "Subscriptions that timed out waiting for a message",
+    ));
+    static ref FLUSH_WALLETS: IntGauge = register(IntGauge::new(
+        "relay_flush_wallets",
+        "Wallets to be withdrawn by the current flush",
+    ));
+    static ref FLUSH_WALLETS_WITHDRAWN: IntGauge = register(IntGauge::new(
+        "relay_flush_wallets_withdrawn",
+        "Wallets withdrawn so far by the current flush",
+    ));
+    static ref FLUSH_COMPLETE: IntGauge = register(IntGauge::new(
+        "relay_flush_complete",
+        "1 once the flush has withdrawn every wallet and the leftover balance",
+    ));
+    static ref TRANSFERS_HELD: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("relay_transfers_held_total", "Transfers held over a value limit, by source chain"),
+        &["chain"],
+    ));
+    static ref HELD_TRANSFERS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "relay_held_transfers",
+            "Transfers waiting for an operator to release them, by source chain",
+        ),
+        &["chain"],
+    ));
+    static ref PAUSED: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "relay_paused",
+            "1 while a target (home-to-side, side-to-home, anchors) is paused",
+        ),
+        &["target"],
+    ));
+    static ref PAUSE_BUFFERED: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "relay_pause_buffered",
+            "Transfers or anchors buffered while a target is paused",
+        ),
+        &["target"],
+    ));
+    static ref ANCHOR_MISMATCHES: IntCounter = register(IntCounter::new(
+        "relay_anchor_mismatches_total",
+        "Anchors on the homechain that do not match the sidechain block at their height",
+    ));
+    static ref LATEST_ANCHOR: IntGauge = register(IntGauge::new(
+        "relay_latest_anchor_block",
+        "Sidechain block number of the latest anchor on the homechain",
+    ));
+    static ref LAST_BLOCK: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("relay_last_block", "Last block seen on each chain"),
+        &["chain"],
+    ));
+    static ref ETH_BALANCE: GaugeVec = register(GaugeVec::new(
+        Opts::new("relay_eth_balance_wei", "ETH balance of the relay account on each chain"),
+        &["chain"],
+    ));
+    static ref NCT_BALANCE: GaugeVec = register(GaugeVec::new(
+        Opts::new(
+            "relay_contract_nct_balance",
+            "NCT balance of the relay contract on each chain, in base units",
+        ),
+        &["chain"],
+    ));
+}
+
+/// Registers a collector with the relay registry
+fn register<C: prometheus::core::Collector + Clone + 'static>(collector: prometheus::Result<C>) -> C {
+    let collector = collector.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric registered twice");
+    collector
+}
+
+/// Returns the label used for the given chain
+fn chain(network_type: NetworkType) -> &'static str {
+    match network_type {
+        NetworkType::Home => "home",
+        NetworkType::Side => "side",
+    }
+}
+
+/// Returns the value as an f64, for gauges that hold token amounts
+fn as_f64(value: U256) -> f64 {
+    value.to_string().parse().unwrap_or(std::f64::NAN)
+}
+
+/// Counts a new transfer to the relay contract
+pub fn transfer_seen(network_type: NetworkType) {
+    TRANSFERS_SEEN.with_label_values(&[chain(network_type)]).inc();
+}
+
+/// Counts a transaction sent to the relay contract
+pub fn transaction_sent(network_type: NetworkType, function: &str) {
+    TRANSACTIONS
+        .with_label_values(&[chain(network_type), function, "sent"])
+        .inc();
+}
+
+/// Counts a relay contract transaction that was mined successfully
+pub fn transaction_succeeded(network_type: NetworkType, function: &str) {
+    TRANSACTIONS
+        .with_label_values(&[chain(network_type), function, "succeeded"])
+        .inc();
+}
+
+/// Counts a relay contract transaction that failed to send or reverted
+pub fn transaction_failed(network_type: NetworkType, function: &str) {
+    TRANSACTIONS
+        .with_label_values(&[chain(network_type), function, "failed"])
+        .inc();
+}
+
+/// Counts a relay contract transaction replaced with a higher gas price
+pub fn transaction_replaced(network_type: NetworkType, function: &str) {
+    TRANSACTIONS
+        .with_label_values(&[chain(network_type), function, "replaced"])
+        .inc();
+}
+
+/// Counts a nonce resync
+pub fn nonce_resynced(network_type: NetworkType) {
+    NONCE_RESYNCS.with_label_values(&[chain(network_type)]).inc();
+}
+
+/// Records the number of contract calls waiting in the transaction queue of a chain
+pub fn transaction_queue_depth(network_type: NetworkType, depth: usize) {
+    TRANSACTION_QUEUE_DEPTH
+        .with_label_values(&[chain(network_type)])
+        .set(depth as i64);
+}
+
+/// Counts a contract config change, by how it was handled
+pub fn config_reloaded(result: &str) {
+    CONFIG_RELOADS.with_label_values(&[result]).inc();
+}
+
+/// Counts a subscription timeout
+pub fn subscription_timed_out() {
+    SUBSCRIPTION_TIMEOUTS.inc();
+}
+
+/// Resets flush progress once the wallets to withdraw are known
+pub fn flush_started(wallets: usize) {
+    FLUSH_WALLETS.set(wallets as i64);
+    FLUSH_WALLETS_WITHDRAWN.set(0);
+    FLUSH_COMPLETE.set(0);
+}
+
+/// Counts a wallet withdrawn by the flush
+pub fn flush_wallet_withdrawn() {
+    FLUSH_WALLETS_WITHDRAWN.inc();
+}
+
+/// Marks the flush as complete
+pub fn flush_completed() {
+    FLUSH_COMPLETE.set(1);
+}
+
+/// Counts a transfer held over a value limit, and records how many are held on its chain
+pub fn transfer_held(network_type: NetworkType, held: usize) {
+    TRANSFERS_HELD.with_label_values(&[chain(network_type)]).inc();
+    held_transfers(network_type, held);
+}
+
+/// Records the number of transfers held on a chain
+pub fn held_transfers(network_type: NetworkType, held: usize) {
+    HELD_TRANSFERS
+        .with_label_values(&[chain(network_type)])
+        .set(held as i64);
+}
+
+/// Records what is paused, and how much each target buffered
+pub fn paused(status: &PauseStatus) {
+    let targets = [
+        ("home-to-side", status.home_to_side),
+        ("side-to-home", status.side_to_home),
+        ("anchors", status.anchors),
+    ];
+    for (target, state) in targets.iter() {
+        let PauseState { paused, buffered } = *state;
+        PAUSED.with_label_values(&[target]).set(paused as i64);
+        PAUSE_BUFFERED.with_label_values(&[target]).set(buffered as i64);
+    }
+}
+
+/// Counts an anchor that does not match the sidechain
+pub fn anchor_mismatch() {
+    ANCHOR_MISMATCHES.inc();
+}
+
+/// Records the sidechain block number of the latest anchor
+pub fn latest_anchor(block: u64) {
+    LATEST_ANCHOR.set(block as i64);
+}
+
+/// Records the latest block on a chain
+pub fn last_block(network_type: NetworkType, block: u64) {
+    LAST_BLOCK.with_label_values(&[chain(network_type)]).set(block as i64);
+}
+
+/// Records the relay account ETH balance and the relay contract NCT balance on a chain
+pub fn balances(network_type: NetworkType, eth_balance: Option<U256>, nct_balance: Option<U256>) {
+    if let Some(balance) = eth_balance {
+        ETH_BALANCE
+            .with_label_values(&[chain(network_type)])
+            .set(as_f64(balance));
+    }
+    if let Some(balance) = nct_balance {
+        NCT_BALANCE
+            .with_label_values(&[chain(network_type)])
+            .set(as_f64(balance));
+    }
+}
+
+/// Returns every metric in the Prometheus text format
+pub fn gather() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gather_should_include_labelled_metrics() {
+        // arrange
+        transfer_seen(NetworkType::Home);
+        transaction_sent(NetworkType::Side, "approveWithdrawal");
+        last_block(NetworkType::Side, 1234);
+        // act
+        let text = gather().unwrap();
+        // assert
+        assert!(text.contains("relay_transfers_seen_total{chain=\"home\"}"));
+        assert!(
+            text.contains("relay_transactions_total{chain=\"side\",function=\"approveWithdrawal\",status=\"sent\"}")
+        );
+        assert!(text.contains("relay_last_block{chain=\"side\"} 1234"));
+    }
+}
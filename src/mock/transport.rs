@@ -2,7 +2,7 @@ use jsonrpc_core as rpc;
 use serde_json;
 use std::cell::RefCell;
 use std::collections::vec_deque::VecDeque;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio_core::reactor;
@@ -15,7 +15,8 @@ use web3::types::{BlockHeader, Log, H160, H2048, H256, U256};
 use web3::{BatchTransport, DuplexTransport, Error, RequestId, Transport};
 
 use crate::errors::OperationError;
-use crate::relay::{Network, NetworkType};
+use crate::eth::gas::GasStrategy;
+use crate::relay::{Network, NetworkConfig, NetworkType};
 
 // Result from a MockTask
 pub type MockTask<T> = Box<dyn Future<Item = T, Error = Error>>;
@@ -27,6 +28,10 @@ type Subscription = mpsc::UnboundedSender<rpc::Value>;
 pub struct MockTransport {
     id: Rc<AtomicUsize>,
     responses: Rc<RefCell<VecDeque<rpc::Value>>>,
+    // Responses to a single method, used before the shared responses
+    method_responses: Rc<RefCell<HashMap<String, VecDeque<Result<rpc::Value>>>>>,
+    // Method and params of every request, in order
+    requests: Rc<RefCell<Vec<(String, Vec<rpc::Value>)>>>,
     subscriptions: Rc<RefCell<BTreeMap<SubscriptionId, Subscription>>>,
 }
 
@@ -38,6 +43,8 @@ impl MockTransport {
         MockTransport {
             id,
             responses,
+            method_responses: Default::default(),
+            requests: Default::default(),
             subscriptions,
         }
     }
@@ -46,7 +53,7 @@ impl MockTransport {
         &self,
         network_type: NetworkType,
     ) -> std::result::Result<Network<MockTransport>, OperationError> {
-        let tx_count = AtomicUsize::new(0);
+        let tx_count = U256::zero();
         let mock_abi = r#"[
             {
               "constant": true,
@@ -73,21 +80,24 @@ impl MockTransport {
         Network::new(
             network_type,
             self.clone(),
-            "0x5af8bcc6127afde967279dc04661f599a5c0cafa",
-            "0x7e7087c25df885f97aeacbfae84ea12016799eee",
-            &mock_abi,
-            "0x7e7087c25df885f97aeacbfae84ea12016799eee",
-            &mock_abi,
-            true,
-            0,
-            0,
-            30,
-            30,
-            1338,
-            "../",
-            "password",
-            tx_count,
-            0,
+            NetworkConfig {
+                account: "0x5af8bcc6127afde967279dc04661f599a5c0cafa".to_string(),
+                token: "0x7e7087c25df885f97aeacbfae84ea12016799eee".to_string(),
+                token_abi: mock_abi.clone(),
+                relay: "0x7e7087c25df885f97aeacbfae84ea12016799eee".to_string(),
+                relay_abi: mock_abi,
+                gas: GasStrategy {
+                    free: true,
+                    ..GasStrategy::default()
+                },
+                interval: 30,
+                timeout: 30,
+                chain_id: 1338,
+                keydir: "../".to_string(),
+                password: "password".to_string(),
+                nonce: tx_count,
+                ..NetworkConfig::default()
+            },
         )
     }
 
@@ -115,6 +125,31 @@ impl MockTransport {
         }
     }
 
+    pub fn add_method_response(&mut self, method: &str, response: rpc::Value) {
+        self.method_responses
+            .borrow_mut()
+            .entry(method.to_string())
+            .or_default()
+            .push_back(Ok(response))
+    }
+
+    pub fn add_method_error(&mut self, method: &str, error: Error) {
+        self.method_responses
+            .borrow_mut()
+            .entry(method.to_string())
+            .or_default()
+            .push_back(Err(error))
+    }
+
+    pub fn requests(&self, method: &str) -> Vec<Vec<rpc::Value>> {
+        self.requests
+            .borrow()
+            .iter()
+            .filter(|(requested, _)| requested == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
     pub fn clear_rpc(&mut self) {
         *self.responses.borrow_mut() = VecDeque::new();
     }
@@ -125,13 +160,23 @@ impl Transport for MockTransport {
 
     fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
         let id = self.id.fetch_add(1, Ordering::AcqRel);
+        self.requests.borrow_mut().push((method.to_string(), params.clone()));
         let call = helpers::build_request(id, method, params);
         (id, call)
     }
 
-    fn send(&self, _id: RequestId, _request: rpc::Call) -> Self::Out {
-        match self.responses.borrow_mut().pop_front() {
-            Some(v) => Box::new(future::finished(v)),
+    fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+        let method_response = match request {
+            rpc::Call::MethodCall(call) => self
+                .method_responses
+                .borrow_mut()
+                .get_mut(&call.method)
+                .and_then(|responses| responses.pop_front()),
+            _ => None,
+        };
+        match method_response.or_else(|| self.responses.borrow_mut().pop_front().map(Ok)) {
+            Some(Ok(v)) => Box::new(future::finished(v)),
+            Some(Err(e)) => Box::new(future::failed(e)),
             None => Box::new(future::failed(Error::Unreachable.into())),
         }
     }
@@ -177,7 +222,7 @@ mod tests {
 
     #[test]
     fn should_build_network_with_mock() {
-        let tx_count = AtomicUsize::new(0);
+        let tx_count = U256::zero();
         let mock_abi = r#"[
             {
               "constant": true,
@@ -204,21 +249,24 @@ mod tests {
         Network::new(
             NetworkType::Home,
             MockTransport::new(),
-            "0x5af8bcc6127afde967279dc04661f599a5c0cafa",
-            "0x7e7087c25df885f97aeacbfae84ea12016799eee",
-            &mock_abi,
-            "0x7e7087c25df885f97aeacbfae84ea12016799eee",
-            &mock_abi,
-            true,
-            0,
-            0,
-            30,
-            30,
-            1338,
-            "../",
-            "password",
-            tx_count,
-            0,
+            NetworkConfig {
+                account: "0x5af8bcc6127afde967279dc04661f599a5c0cafa".to_string(),
+                token: "0x7e7087c25df885f97aeacbfae84ea12016799eee".to_string(),
+                token_abi: mock_abi.clone(),
+                relay: "0x7e7087c25df885f97aeacbfae84ea12016799eee".to_string(),
+                relay_abi: mock_abi,
+                gas: GasStrategy {
+                    free: true,
+                    ..GasStrategy::default()
+                },
+                interval: 30,
+                timeout: 30,
+                chain_id: 1338,
+                keydir: "../".to_string(),
+                password: "password".to_string(),
+                nonce: tx_count,
+                ..NetworkConfig::default()
+            },
         )
         .unwrap();
     }
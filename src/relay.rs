@@ -1,34 +1,41 @@
 use failure::{Error, SyncFailure};
-use lru::LruCache;
-use std::sync::atomic::AtomicUsize;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::{Arc, RwLock};
-use std::{process, time};
+use std::time;
 use tokio_core::reactor;
 use web3::confirm::{wait_for_transaction_confirmation, SendTransactionWithConfirmation};
 use web3::contract::Contract;
 use web3::futures::future::{err, Either};
 use web3::futures::sync::mpsc;
-use web3::futures::Future;
+use web3::futures::{stream, Future, Stream};
 use web3::types::{Address, FilterBuilder, TransactionReceipt, H256, U256};
 use web3::{DuplexTransport, Web3};
 
 use super::anchors::anchor::ProcessAnchors;
 use super::errors::OperationError;
 use super::eth::contracts::{FLUSH_EVENT_SIGNATURE, TRANSFER_EVENT_SIGNATURE};
+use super::eth::gas::GasStrategy;
+use super::eth::queue::TransactionQueue;
+use super::eth::scan::ScanConfig;
 use super::eth::utils::clean_0x;
 use super::extensions::removed::{CancelRemoved, ExitOnLogRemoved};
-use super::flush::{CheckForPastFlush, ProcessFlush};
-use super::server::{HandleRequests, RequestType};
+use super::extensions::shutdown::Shutdown;
+use super::flush::{CheckForPastFlush, FlushPolicy, ProcessFlush};
+use super::server::{HandleRequests, RefreshMetrics, RequestType};
+use super::store::limits::LimitConfig;
+use super::store::pause::PauseTarget;
+use super::store::{AuditLog, CheckpointStore, FlushStore, LimitStore, PauseStore, TransferStore};
+use super::transfers::backfill::BackfillTransfers;
 use super::transfers::live::ProcessTransfer;
 use super::transfers::live::WatchLiveLogs;
-use super::transfers::past::ProcessPastTransfers;
+use super::transfers::past::{ProcessPastTransfers, ValidateAndApproveTransfer};
+use super::transfers::transfer::Transfer;
 use crate::anchors::anchor::WatchAnchors;
 use crate::eth::Event;
+use crate::metrics;
 use crate::transfers::past::WatchPastTransfers;
 
-const FREE_GAS_PRICE: u64 = 0;
-const GAS_LIMIT: u64 = 200_000;
-
 /// Add CheckRemoved trait to SendTransactionWithConfirmation, which is called by Transfer::approve_withdrawal
 impl<T> CancelRemoved<T, TransactionReceipt, web3::Error> for SendTransactionWithConfirmation<T>
 where
@@ -37,9 +44,9 @@ where
     fn cancel_removed(
         self,
         target: &Network<T>,
-        tx_hash: H256,
+        withdrawal_hash: H256,
     ) -> ExitOnLogRemoved<T, TransactionReceipt, web3::Error> {
-        ExitOnLogRemoved::new(target, tx_hash, Box::new(self))
+        ExitOnLogRemoved::new(target, withdrawal_hash, Box::new(self))
     }
 }
 
@@ -77,7 +84,8 @@ impl<T: DuplexTransport + 'static> Relay<T> {
     }
 
     /// Returns a Future representing the operation of the token relay, including forwarding
-    /// Transfer events and anchoring sidechain blocks onto the homechain
+    /// Transfer events and anchoring sidechain blocks onto the homechain.
+    /// Fails if any of the subscriptions fail or time out
     ///
     /// # Arguments
     ///
@@ -90,9 +98,18 @@ impl<T: DuplexTransport + 'static> Relay<T> {
         let sidechain = self.sidechain.clone();
         let homechain = self.homechain.clone();
         let handle = handle.clone();
+        // Every contract call on a chain goes through its queue, so it has to be running before anything else
+        handle.spawn(homechain.transactions.process(&homechain));
+        handle.spawn(sidechain.transactions.process(&sidechain));
+        // Requests are answered from the start, as the backfill can take a while after a long downtime
+        let requests = Relay::handle_requests(&homechain, &sidechain, rx, &handle);
+        let refresh_metrics = RefreshMetrics::new(&homechain, &sidechain, &handle);
+        let (backfill_sidechain, backfill_homechain, backfill_handle) =
+            (sidechain.clone(), homechain.clone(), handle.clone());
         sidechain
             .check_flush_block()
             .and_then(move |flush_option| {
+                let (sidechain, homechain, handle) = (backfill_sidechain, backfill_homechain, backfill_handle);
                 if let Ok(mut lock) = sidechain.flushed.write() {
                     *lock = flush_option.clone();
                 } else {
@@ -100,35 +117,97 @@ impl<T: DuplexTransport + 'static> Relay<T> {
                     return Either::B(err(()));
                 }
 
+                // Backfills retry until they complete, as the lookbacks only move the checkpoint on from where they end
+                let backfill = homechain
+                    .backfill_transfer_logs(&sidechain, &handle)
+                    .join(sidechain.backfill_transfer_logs(&homechain, &handle));
+                Either::A(backfill.map(move |_| flush_option))
+            })
+            .and_then(move |flush_option| {
                 let (watch_anchors, process_anchors) = sidechain.handle_anchors(&homechain, &handle);
                 let (watch_side_past, process_side_past) = sidechain.recheck_past_transfer_logs(&homechain, &handle);
                 let (watch_home_past, process_home_past) = homechain.recheck_past_transfer_logs(&sidechain, &handle);
+                let (watch_home_logs, process_home_logs) = homechain.watch_transfer_logs(&sidechain, &handle);
+                let (watch_side_logs, process_side_logs) = sidechain.watch_transfer_logs(&homechain, &handle);
+                let (watch_flush, process_flush) = sidechain.watch_flush_logs(&homechain, flush_option, &handle);
+                // Buffered transfers whose approval a restart interrupted, after approvals were resumed
+                let home_backlog = homechain.approve_backlog(&sidechain, &handle);
+                let side_backlog = sidechain.approve_backlog(&homechain, &handle);
+                // Logs are processed in separately spawned tasks, because confirmations take many blocks
+                let watch_logs = watch_home_logs
+                    .join(watch_side_logs)
+                    .join(watch_flush)
+                    .map_err(|e| error!("error watching transaction logs {:?}", e));
 
-                Either::A(
-                    watch_anchors
-                        .join(process_anchors)
-                        .join(watch_side_past)
-                        .join(process_side_past)
-                        .join(watch_home_past)
-                        .join(process_home_past)
-                        .join(homechain.watch_transfer_logs(&sidechain, &handle))
-                        .join(sidechain.watch_transfer_logs(&homechain, &handle))
-                        .join(sidechain.watch_flush_logs(&homechain, flush_option, &handle))
-                        .join(Relay::handle_requests(&homechain, &sidechain, rx, &handle))
-                        .and_then(|_| Ok(())),
-                )
+                watch_anchors
+                    .join(process_anchors)
+                    .join(watch_side_past)
+                    .join(process_side_past)
+                    .join(watch_home_past)
+                    .join(process_home_past)
+                    .join(watch_logs)
+                    .join(process_home_logs)
+                    .join(process_side_logs)
+                    .join(process_flush)
+                    .join(home_backlog)
+                    .join(side_backlog)
+                    .and_then(|_| Ok(()))
             })
-            .map_err(|e| {
+            .join(requests)
+            .and_then(|_| Ok(()))
+            // Metrics are refreshed for as long as the relay runs
+            .select(refresh_metrics)
+            .map(|_| ())
+            .map_err(|(e, _)| {
+                // Usually a dropped websocket, the caller starts the relay again once the chains are reachable
                 error!("error at top level: {:?}", e);
-                process::exit(-1);
             })
     }
 }
 
-#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+/// Lifecycle of a transfer, from the first sighting of its log to the approval (or unapproval) on the target chain
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TransferApprovalState {
+    /// Log seen, waiting for confirmations
+    Seen,
+    /// Log has enough confirmations
+    Confirmed,
+    /// Approval transaction sent to the target chain
     Sent,
+    /// Approval transaction mined on the target chain
+    Mined,
+    /// Log was removed from the source chain
     Removed,
+    /// Approval was reverted on the target chain after the log was removed
+    Unapproved,
+    /// Over a value limit, waiting for an operator to release it
+    Held,
+    /// Buffered while approvals from its chain are paused, approved on resume
+    Paused,
+}
+
+impl TransferApprovalState {
+    /// Returns true if nothing is left to do for a transfer in this state
+    pub fn is_settled(self) -> bool {
+        match self {
+            TransferApprovalState::Seen
+            | TransferApprovalState::Confirmed
+            | TransferApprovalState::Sent
+            | TransferApprovalState::Held
+            | TransferApprovalState::Paused => false,
+            TransferApprovalState::Mined | TransferApprovalState::Removed | TransferApprovalState::Unapproved => true,
+        }
+    }
+
+    /// Returns the state a transfer moves to once the future waiting on it completes
+    pub fn completed(self) -> Self {
+        match self {
+            TransferApprovalState::Seen => TransferApprovalState::Confirmed,
+            TransferApprovalState::Sent => TransferApprovalState::Mined,
+            state => state,
+        }
+    }
 }
 
 /// Networks are considered either the homechain or the sidechain for the purposes of relaying
@@ -149,7 +228,7 @@ pub struct Network<T: DuplexTransport + 'static> {
     pub account: Address,
     pub token: Arc<Contract<T>>,
     pub relay: Arc<Contract<T>>,
-    pub free: bool,
+    pub gas: GasStrategy,
     pub confirmations: u64,
     pub anchor_frequency: u64,
     pub interval: u64,
@@ -157,10 +236,102 @@ pub struct Network<T: DuplexTransport + 'static> {
     pub chain_id: u64,
     pub keydir: String,
     pub password: String,
-    pub nonce: Arc<AtomicUsize>,
-    pub pending: Arc<RwLock<LruCache<H256, TransferApprovalState>>>,
+    pub transactions: TransactionQueue,
+    pub pending: Arc<RwLock<TransferStore>>,
+    pub checkpoint: Arc<RwLock<CheckpointStore>>,
     pub retries: u64,
     pub flushed: Arc<RwLock<Option<Event>>>,
+    pub shutdown: Shutdown,
+    /// Name of the RPC endpoint in use, if the transport has several
+    pub endpoint: Arc<RwLock<Option<String>>>,
+    /// Log of every relay contract transaction sent for a transfer, shared by both networks
+    pub audit: Arc<AuditLog>,
+    /// Value limits on transfers from this network, and the transfers held over them
+    pub limits: Arc<RwLock<LimitStore>>,
+    /// What is paused, and what was buffered while paused, shared by both networks
+    pub pause: Arc<RwLock<PauseStore>>,
+    /// Verifiers taking turns to post anchors of this network, sorted. Empty if every relay posts every anchor
+    pub anchor_leaders: Vec<Address>,
+    /// Plan and progress of the flush of this network, so it resumes after a restart
+    pub flush: Arc<RwLock<FlushStore>>,
+    /// Wallets a flush of this network withdraws or skips whether they have code or not
+    pub flush_policy: FlushPolicy,
+    /// How log scans over many blocks split their range into queries
+    pub scan: ScanConfig,
+}
+
+/// Everything a network is constructed from, besides its transport
+#[derive(Clone)]
+pub struct NetworkConfig {
+    /// Address of the relay account
+    pub account: String,
+    /// Address of the ERC20 token contract to use
+    pub token: String,
+    pub token_abi: String,
+    /// Address of the ERC20Relay contract to use
+    pub relay: String,
+    pub relay_abi: String,
+    /// How gas prices and gas limits are chosen for transactions on this network
+    pub gas: GasStrategy,
+    /// Number of blocks to wait for confirmation
+    pub confirmations: u64,
+    /// Frequency of sidechain anchor blocks
+    pub anchor_frequency: u64,
+    /// Number of seconds between each lookback attempt
+    pub interval: u64,
+    pub timeout: u64,
+    pub chain_id: u64,
+    pub keydir: String,
+    pub password: String,
+    /// Next unused nonce of the relay account, handed to the transaction queue
+    pub nonce: U256,
+    pub retries: u64,
+    /// Directory where transfer states, checkpoints, limits and flush progress are persisted, kept in memory if None
+    pub state_dir: Option<String>,
+    /// Value limits on transfers from this network
+    pub limits: LimitConfig,
+    /// How log scans over many blocks split their range into queries
+    pub scan: ScanConfig,
+    /// Wallets a flush of this network withdraws or skips whether they have code or not
+    pub flush_policy: FlushPolicy,
+    /// Verifiers taking turns to post anchors of this network, sorted. Empty if every relay posts every anchor
+    pub anchor_leaders: Vec<Address>,
+    /// Name of the RPC endpoint in use, shared with the transport
+    pub endpoint: Arc<RwLock<Option<String>>>,
+    /// Log of every relay contract transaction sent for a transfer, shared by both networks
+    pub audit: Arc<AuditLog>,
+    /// What is paused, and what was buffered while paused, shared by both networks
+    pub pause: Arc<RwLock<PauseStore>>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            account: String::new(),
+            token: String::new(),
+            token_abi: String::new(),
+            relay: String::new(),
+            relay_abi: String::new(),
+            gas: GasStrategy::default(),
+            confirmations: 0,
+            anchor_frequency: 0,
+            interval: 0,
+            timeout: 0,
+            chain_id: 0,
+            keydir: String::new(),
+            password: String::new(),
+            nonce: U256::zero(),
+            retries: 0,
+            state_dir: None,
+            limits: LimitConfig::default(),
+            scan: ScanConfig::default(),
+            flush_policy: FlushPolicy::default(),
+            anchor_leaders: Vec::new(),
+            endpoint: Arc::new(RwLock::new(None)),
+            audit: Arc::new(AuditLog::disabled()),
+            pause: Arc::new(RwLock::new(PauseStore::in_memory())),
+        }
+    }
 }
 
 impl<T: DuplexTransport + 'static> Network<T> {
@@ -170,71 +341,85 @@ impl<T: DuplexTransport + 'static> Network<T> {
     ///
     /// * `network_type` - The type of the network (homechain or sidechain)
     /// * `transport` - The transport to use for interacting with the network
-    /// * `token` - Address of the ERC20 token contract to use
-    /// * `relay` - Address of the ERC20Relay contract to use
-    /// * `confirmations` - Number of blocks to wait for confirmation
-    /// * `anchor_frequency` - Frequency of sidechain anchor blocks
-    /// * `interval` - Number of seconds between each lookback attempt
-    pub fn new(
-        network_type: NetworkType,
-        transport: T,
-        account: &str,
-        token: &str,
-        token_abi: &str,
-        relay: &str,
-        relay_abi: &str,
-        free: bool,
-        confirmations: u64,
-        anchor_frequency: u64,
-        interval: u64,
-        timeout: u64,
-        chain_id: u64,
-        keydir: &str,
-        password: &str,
-        nonce: AtomicUsize,
-        retries: u64,
-    ) -> Result<Self, OperationError> {
+    /// * `config` - Contracts, settings and shared stores of the network
+    pub fn new(network_type: NetworkType, transport: T, config: NetworkConfig) -> Result<Self, OperationError> {
         let web3 = Web3::new(transport);
-        let account = clean_0x(account)
+        let account = clean_0x(&config.account)
             .parse()
-            .or_else(|_| Err(OperationError::InvalidAddress(account.into())))?;
+            .or_else(|_| Err(OperationError::InvalidAddress(config.account.clone())))?;
 
-        let token_address: Address = clean_0x(token)
+        let token_address: Address = clean_0x(&config.token)
             .parse()
-            .or_else(|_| Err(OperationError::InvalidAddress(token.into())))?;
+            .or_else(|_| Err(OperationError::InvalidAddress(config.token.clone())))?;
 
-        let relay_address: Address = clean_0x(relay)
+        let relay_address: Address = clean_0x(&config.relay)
             .parse()
-            .or_else(|_| Err(OperationError::InvalidAddress(relay.into())))?;
+            .or_else(|_| Err(OperationError::InvalidAddress(config.relay.clone())))?;
 
         let token = Arc::new(
-            Contract::from_json(web3.eth(), token_address, token_abi.as_bytes())
+            Contract::from_json(web3.eth(), token_address, config.token_abi.as_bytes())
                 .or(Err(OperationError::InvalidContractAbi))?,
         );
 
         let relay = Arc::new(
-            Contract::from_json(web3.eth(), relay_address, relay_abi.as_bytes())
+            Contract::from_json(web3.eth(), relay_address, config.relay_abi.as_bytes())
                 .or(Err(OperationError::InvalidContractAbi))?,
         );
 
+        let (pending, checkpoint, limits, flush) = match config.state_dir {
+            Some(ref dir) => {
+                let prefix = format!("{:?}", network_type).to_lowercase();
+                let dir = Path::new(dir);
+                let pending = TransferStore::open(&dir.join(format!("{}_transfers.json", prefix)))
+                    .map_err(|e| OperationError::CouldNotOpenStateStore(e.to_string()))?;
+                let checkpoint = CheckpointStore::open(&dir.join(format!("{}_checkpoint.json", prefix)))
+                    .map_err(|e| OperationError::CouldNotOpenStateStore(e.to_string()))?;
+                let limits = LimitStore::open(&dir.join(format!("{}_limits.json", prefix)), config.limits)
+                    .map_err(|e| OperationError::CouldNotOpenStateStore(e.to_string()))?;
+                // Only the sidechain is ever flushed
+                let flush = match network_type {
+                    NetworkType::Side => FlushStore::open(&dir.join("flush.json"))
+                        .map_err(|e| OperationError::CouldNotOpenStateStore(e.to_string()))?,
+                    NetworkType::Home => FlushStore::in_memory(),
+                };
+                (pending, checkpoint, limits, flush)
+            }
+            None => (
+                TransferStore::in_memory(),
+                CheckpointStore::in_memory(),
+                LimitStore::in_memory(config.limits),
+                FlushStore::in_memory(),
+            ),
+        };
+
         Ok(Self {
             network_type,
             web3,
             account,
             token,
             relay,
-            free,
-            confirmations,
-            anchor_frequency,
-            interval,
-            timeout,
-            chain_id,
-            keydir: keydir.to_string(),
-            password: password.to_string(),
-            nonce: Arc::new(nonce),
-            pending: Arc::new(RwLock::new(LruCache::new(4096))),
-            retries,
+            gas: config.gas,
+            confirmations: config.confirmations,
+            anchor_frequency: config.anchor_frequency,
+            interval: config.interval,
+            timeout: config.timeout,
+            chain_id: config.chain_id,
+            keydir: config.keydir,
+            password: config.password,
+            transactions: TransactionQueue::new(config.nonce),
+            pending: Arc::new(RwLock::new(pending)),
+            checkpoint: Arc::new(RwLock::new(checkpoint)),
+            retries: config.retries,
             flushed: Arc::new(RwLock::new(None)),
+            shutdown: Shutdown::new(),
+            endpoint: config.endpoint,
+            audit: config.audit,
+            limits: Arc::new(RwLock::new(limits)),
+            pause: config.pause,
+            anchor_leaders: config.anchor_leaders,
+            flush: Arc::new(RwLock::new(flush)),
+            flush_policy: config.flush_policy,
+            scan: config.scan,
         })
     }
 
@@ -243,44 +428,15 @@ impl<T: DuplexTransport + 'static> Network<T> {
     /// # Arguments
     ///
     /// * `transport` - The transport to use for interacting with the network
-    /// * `token` - Address of the ERC20 token contract to use
-    /// * `relay` - Address of the ERC20Relay contract to use
-    /// * `confirmations` - Number of blocks to wait for confirmation
-    pub fn homechain(
-        transport: T,
-        account: &str,
-        token: &str,
-        token_abi: &str,
-        relay: &str,
-        relay_abi: &str,
-        free: bool,
-        confirmations: u64,
-        interval: u64,
-        timeout: u64,
-        chain_id: u64,
-        keydir: &str,
-        password: &str,
-        nonce: AtomicUsize,
-        retries: u64,
-    ) -> Result<Self, OperationError> {
+    /// * `config` - Contracts, settings and shared stores of the network
+    pub fn homechain(transport: T, config: NetworkConfig) -> Result<Self, OperationError> {
         Self::new(
             NetworkType::Home,
             transport,
-            account,
-            token,
-            token_abi,
-            relay,
-            relay_abi,
-            free,
-            confirmations,
-            0,
-            interval,
-            timeout,
-            chain_id,
-            keydir,
-            password,
-            nonce,
-            retries,
+            NetworkConfig {
+                anchor_frequency: 0,
+                ..config
+            },
         )
     }
 
@@ -289,47 +445,46 @@ impl<T: DuplexTransport + 'static> Network<T> {
     /// # Arguments
     ///
     /// * `transport` - The transport to use for interacting with the network
+    /// * `config` - Contracts, settings and shared stores of the network
+    pub fn sidechain(transport: T, config: NetworkConfig) -> Result<Self, OperationError> {
+        Self::new(NetworkType::Side, transport, config)
+    }
+
+    /// Returns a copy of this network using different token and relay contracts.
+    /// The copy shares the transaction queue and the state stores with this network
+    ///
+    /// # Arguments
+    ///
     /// * `token` - Address of the ERC20 token contract to use
+    /// * `token_abi` - ABI of the ERC20 token contract
     /// * `relay` - Address of the ERC20Relay contract to use
-    /// * `confirmations` - Number of blocks to wait for confirmation
-    /// * `anchor_frequency` - Frequency of sidechain anchor blocks
-    pub fn sidechain(
-        transport: T,
-        account: &str,
+    /// * `relay_abi` - ABI of the ERC20Relay contract
+    pub fn with_contracts(
+        &self,
         token: &str,
         token_abi: &str,
         relay: &str,
         relay_abi: &str,
-        free: bool,
-        confirmations: u64,
-        anchor_frequency: u64,
-        interval: u64,
-        timeout: u64,
-        chain_id: u64,
-        keydir: &str,
-        password: &str,
-        nonce: AtomicUsize,
-        retries: u64,
     ) -> Result<Self, OperationError> {
-        Self::new(
-            NetworkType::Side,
-            transport,
-            account,
-            token,
-            token_abi,
-            relay,
-            relay_abi,
-            free,
-            confirmations,
-            anchor_frequency,
-            interval,
-            timeout,
-            chain_id,
-            keydir,
-            password,
-            nonce,
-            retries,
-        )
+        let token_address: Address = clean_0x(token)
+            .parse()
+            .or_else(|_| Err(OperationError::InvalidAddress(token.into())))?;
+
+        let relay_address: Address = clean_0x(relay)
+            .parse()
+            .or_else(|_| Err(OperationError::InvalidAddress(relay.into())))?;
+
+        let token = Contract::from_json(self.web3.eth(), token_address, token_abi.as_bytes())
+            .or(Err(OperationError::InvalidContractAbi))?;
+
+        let relay = Contract::from_json(self.web3.eth(), relay_address, relay_abi.as_bytes())
+            .or(Err(OperationError::InvalidContractAbi))?;
+
+        Ok(Self {
+            token: Arc::new(token),
+            relay: Arc::new(relay),
+            ..self.clone()
+        })
     }
 
     /// Unlock an account with a password
@@ -352,11 +507,100 @@ impl<T: DuplexTransport + 'static> Network<T> {
             })
     }
 
+    /// Buffers a transfer from this network instead of approving it, if approvals from this network are paused.
+    /// Returns true if the transfer is buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - Transfer about to be approved
+    pub fn buffer_if_paused(&self, transfer: &Transfer) -> bool {
+        let buffered = match self.pause.write() {
+            Ok(mut pause) => {
+                let buffered = pause.buffer_transfer(self.network_type, transfer);
+                if buffered {
+                    let target = PauseTarget::transfers_from(self.network_type);
+                    info!("{} is paused, buffering transfer: {}", target, transfer);
+                    metrics::paused(&pause.status());
+                }
+                buffered
+            }
+            Err(e) => {
+                error!("error acquiring pause lock on {:?}: {:?}", self.network_type, e);
+                false
+            }
+        };
+        if buffered {
+            match self.pending.write() {
+                Ok(mut pending) => pending.put(transfer.get_withdrawal_hash(), TransferApprovalState::Paused),
+                Err(e) => error!("error acquiring pending lock on {:?}: {:?}", self.network_type, e),
+            }
+        }
+        buffered
+    }
+
+    /// Returns a Future that approves the transfers buffered while approvals from this network were paused, once they
+    /// are resumed, one at a time in the order they were buffered. Each leaves the backlog once its approval is done,
+    /// so the ones a restart interrupts are approved on the next start
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the withdrawals are approved
+    /// * `handle` - Handle to spawn new futures
+    pub fn approve_backlog(
+        &self,
+        target: &Network<T>,
+        handle: &reactor::Handle,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let network_type = self.network_type;
+        let backlog = match self.pause.write() {
+            Ok(mut pause) => pause.resumed_transfers(network_type),
+            Err(e) => {
+                error!("error acquiring pause lock on {:?}: {:?}", network_type, e);
+                return Box::new(err(()));
+            }
+        };
+        if !backlog.is_empty() {
+            info!("approving {} buffered transfers from {:?}", backlog.len(), network_type);
+        }
+        if let Ok(mut pending) = self.pending.write() {
+            for transfer in &backlog {
+                pending.put(transfer.get_withdrawal_hash(), TransferApprovalState::Confirmed);
+            }
+        }
+        let (source, target, handle) = (self.clone(), target.clone(), handle.clone());
+        Box::new(stream::iter_ok(backlog).for_each(move |transfer| {
+            let pause = source.pause.clone();
+            ValidateAndApproveTransfer::new(&source, &target, &handle, &transfer)
+                .waiting_for_approval()
+                .then(move |result| {
+                    // Errors are logged in the future, and should not stop the rest of the backlog
+                    if result.is_err() {
+                        warn!(
+                            "buffered transfer not approved on {:?}, retrying on the next start: {}",
+                            network_type, transfer
+                        );
+                        return Ok(());
+                    }
+                    match pause.write() {
+                        Ok(mut pause) => {
+                            // Paused again in between, so the transfer was buffered again rather than approved
+                            if !pause.is_paused(PauseTarget::transfers_from(network_type)) {
+                                pause.discard_transfer(network_type, &transfer.get_withdrawal_hash());
+                                metrics::paused(&pause.status());
+                            }
+                        }
+                        Err(e) => error!("error acquiring pause lock on {:?}: {:?}", network_type, e),
+                    }
+                    Ok(())
+                })
+        }))
+    }
+
     pub fn check_flush_block(&self) -> CheckForPastFlush<T> {
         CheckForPastFlush::new(self)
     }
 
-    /// Returns a WatchFlush Future for this chain.
+    /// Returns a tuple with WatchLiveLogs and ProcessFlush Futures for this chain.
     /// Watches the self (which should only be sidechain), and sends to the target, which should be
     /// homechain
     ///
@@ -369,7 +613,7 @@ impl<T: DuplexTransport + 'static> Network<T> {
         target: &Network<T>,
         flush_option: Option<Event>,
         handle: &reactor::Handle,
-    ) -> ProcessFlush<T> {
+    ) -> (WatchLiveLogs<T>, ProcessFlush<T>) {
         let (tx, rx) = mpsc::unbounded();
         if let Some(flush) = flush_option {
             tx.unbounded_send(flush).unwrap();
@@ -378,22 +622,23 @@ impl<T: DuplexTransport + 'static> Network<T> {
             .address(vec![self.relay.address()])
             .topics(Some(vec![FLUSH_EVENT_SIGNATURE.into()]), None, None, None)
             .build();
-        // This on just watches right inside, no tx to send to
-        let watch = WatchLiveLogs::new(self, &filter, &tx, handle)
-            .map_err(move |e| error!("error watching transaction logs {:?}", e));
-        // We do this in a separately spawned task because we have to wait 20 blocks per
-        handle.spawn(watch);
-        ProcessFlush::new(self, target, rx)
+        let watch = WatchLiveLogs::new(self, &filter, &tx, handle);
+        let process = ProcessFlush::new(self, target, rx);
+        (watch, process)
     }
 
-    /// Returns a ProcessTransfer Future for this chain.
+    /// Returns a tuple with WatchLiveLogs and ProcessTransfer Futures for this chain.
     /// Watches the self network, and sends transactions to the target network
     ///
     /// # Arguments
     ///
     /// * `target` - Network where to anchor the block headers
     /// * `handle` - Handle to spawn new tasks
-    pub fn watch_transfer_logs(&self, target: &Network<T>, handle: &reactor::Handle) -> ProcessTransfer<T> {
+    pub fn watch_transfer_logs(
+        &self,
+        target: &Network<T>,
+        handle: &reactor::Handle,
+    ) -> (WatchLiveLogs<T>, ProcessTransfer<T>) {
         let (tx, rx) = mpsc::unbounded();
         let filter = FilterBuilder::default()
             .address(vec![self.token.address()])
@@ -404,11 +649,20 @@ impl<T: DuplexTransport + 'static> Network<T> {
                 None,
             )
             .build();
-        let watch = WatchLiveLogs::new(self, &filter, &tx, handle)
-            .map_err(move |e| error!("error watching transaction logs {:?}", e));
-        // We do this in a separately spawned task because we have to wait 20 blocks per
-        handle.spawn(watch);
-        ProcessTransfer::new(self, target, rx, handle)
+        let watch = WatchLiveLogs::new(self, &filter, &tx, handle);
+        let process = ProcessTransfer::new(self, target, rx, handle);
+        (watch, process)
+    }
+
+    /// Returns a BackfillTransfers Future for this chain.
+    /// Will approve Transfers found in this network since the last checkpoint, to the target network.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the transfers will be approved
+    /// * `handle` - Handle to spawn new tasks
+    pub fn backfill_transfer_logs(&self, target: &Network<T>, handle: &reactor::Handle) -> BackfillTransfers<T> {
+        BackfillTransfers::new(self, target, handle)
     }
 
     /// Returns a RecheckPastTransferLogs Future for this chain.
@@ -442,31 +696,20 @@ impl<T: DuplexTransport + 'static> Network<T> {
         handle: &reactor::Handle,
     ) -> (WatchAnchors<T>, ProcessAnchors<T>) {
         let (tx, rx) = mpsc::unbounded();
-        let watch = WatchAnchors::new(self, tx, handle);
+        let watch = WatchAnchors::new(self, target, tx, handle);
         let process = ProcessAnchors::new(self, target, rx, handle);
+        // Post the anchors missed since the last run
+        handle.spawn(watch.backfill());
         (watch, process)
     }
 
-    /// Returns the gas limit for the network as a U256
-    pub fn get_gas_limit(&self) -> U256 {
-        GAS_LIMIT.into()
-    }
-
-    /// Takes in an estimated price, per the server.
-    /// Change gas price to 0 if free is set in config
-    pub fn finalize_gas_price(&self, potential_gas_price: U256) -> U256 {
-        if self.free {
-            return FREE_GAS_PRICE.into();
-        }
-        potential_gas_price
-    }
-
     ///Returns a transaction receipt after waiting if not removed
-    ///The state of the transaction is stored on the target chain
+    ///The state of the transaction is stored on the target chain, under the withdrawal hash
     pub fn get_receipt(
         &self,
         removed: bool,
         transaction_hash: H256,
+        withdrawal_hash: H256,
     ) -> Box<dyn Future<Item = Option<TransactionReceipt>, Error = ()>> {
         let source = self.clone();
         let web3 = self.web3.clone();
@@ -487,7 +730,7 @@ impl<T: DuplexTransport + 'static> Network<T> {
                     time::Duration::from_secs(1),
                     confirmations,
                 )
-                .cancel_removed(&source, transaction_hash),
+                .cancel_removed(&source, withdrawal_hash),
             )
         }
         .map_err(move |_| {
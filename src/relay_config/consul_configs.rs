@@ -1,10 +1,15 @@
 use crate::errors::OperationError;
 use base64::decode;
-use consul::{kv::KV, Client, Config};
+use consul::{kv::KV, Client, Config, QueryOptions};
 use failure::Error;
 use serde_json;
-use std::collections::HashMap;
-use std::{process, thread, time};
+use std::{thread, time};
+use web3::futures::sync::mpsc;
+
+use super::sources::{ConfigSource, ContractsConfig};
+
+/// Seconds a consul blocking query waits for a change before returning
+const WATCH_WAIT_SECS: u64 = 300;
 
 #[derive(Debug, Clone)]
 pub struct ConsulConfig {
@@ -66,35 +71,56 @@ impl ConsulConfig {
         }
     }
 
-    pub fn watch_for_config_deletion(&self) {
-        let url = self.consul_url.clone();
-        let token = self.consul_token.clone();
-        let community = self.community.clone();
-        let chains = vec!["homechain", "sidechain"];
+    /// Watches the chain keys of the community with consul blocking queries, and sends the new contracts config every
+    /// time it changes
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - Contracts config in use
+    /// * `tx` - Sender for updated contracts configs
+    pub fn watch_for_changes(&self, current: ContractsConfig, tx: mpsc::UnboundedSender<ContractsConfig>) {
+        let consul_config = self.clone();
         thread::spawn(move || {
-            let config = Config::new(Some(&url), Some(&token)).unwrap();
+            let config = Config::new(Some(&consul_config.consul_url), Some(&consul_config.consul_token)).unwrap();
             let client = Client::new(config);
+            let prefix = format!("chain/{}/", &consul_config.community);
             let one_sec = time::Duration::from_secs(1);
-            let mut contract_addresses = HashMap::new();
+            let mut current = current;
+            let mut index = None;
 
             loop {
-                for chain in chains.iter() {
-                    let keyname = format!("chain/{}/{}", &community, &chain);
-
-                    if let Ok((Some(keypair), _)) = client.get(&keyname, None) {
-                        contract_addresses.entry(chain).or_insert_with(|| keypair.Value.clone());
-                        let val = &contract_addresses[chain];
+                let options = QueryOptions {
+                    wait_index: index,
+                    wait_time: Some(time::Duration::from_secs(WATCH_WAIT_SECS)),
+                    ..QueryOptions::default()
+                };
+                let last_index = match client.list(&prefix, Some(&options)) {
+                    Ok((_, meta)) => meta.last_index,
+                    Err(e) => {
+                        warn!("error watching consul config: {}", e);
+                        thread::sleep(one_sec);
+                        continue;
+                    }
+                };
+                // The first query only gets the index to wait on, and the query times out without an index change
+                let first = index.is_none();
+                let changed = last_index != index;
+                index = last_index;
+                if first || !changed {
+                    continue;
+                }
 
-                        if &keypair.Value != val {
-                            info!("config change detected, exiting...");
-                            process::exit(1);
-                        } else {
-                            thread::sleep(one_sec);
+                info!("consul config change detected");
+                match consul_config.load() {
+                    Ok(contracts) => {
+                        if contracts != current {
+                            current = contracts.clone();
+                            if tx.unbounded_send(contracts).is_err() {
+                                return;
+                            }
                         }
-                    } else {
-                        info!("config change detected, exiting...");
-                        process::exit(1);
                     }
+                    Err(e) => error!("error loading changed consul config: {}", e),
                 }
             }
         });
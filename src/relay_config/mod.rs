@@ -1,3 +1,4 @@
 pub mod consul_configs;
 pub mod logger;
 pub mod settings;
+pub mod sources;
@@ -4,8 +4,14 @@ use serde_derive::Deserialize;
 use std::env;
 use std::ffi::OsString;
 use std::path::Path;
+use web3::types::{Address, U256};
 
 use crate::errors::ConfigError;
+use crate::eth::scan::ScanConfig;
+use crate::eth::utils;
+use crate::flush::FlushPolicy;
+use crate::store::limits::LimitConfig;
+use crate::store::pause::PauseTarget;
 use crate::transfers::past::{LOOKBACK_LEEWAY, LOOKBACK_RANGE};
 
 /// Settings for the application
@@ -33,6 +39,23 @@ pub enum Logging {
 pub struct Endpoint {
     /// The port to listen on
     pub port: u16,
+    /// Bearer token for operator routes, which are disabled without one
+    pub admin_token: Option<String>,
+    /// Listener for operator routes that authenticates clients by certificate
+    pub admin_tls: Option<AdminTls>,
+}
+
+/// Mutual TLS listener for operator routes
+#[derive(Clone, Debug, Deserialize)]
+pub struct AdminTls {
+    /// The port to listen on
+    pub port: u16,
+    /// PEM certificate chain of the server
+    pub cert: String,
+    /// PEM private key of the server
+    pub key: String,
+    /// PEM certificates of the CAs that sign client certificates
+    pub client_ca: String,
 }
 
 /// Relay settings
@@ -46,10 +69,15 @@ pub struct Relay {
     pub confirmations: u64,
     /// Frequency of sidechain anchor blocks
     pub anchor_frequency: u64,
+    /// Verifier addresses taking turns to post anchors, one per anchor height. Empty if every relay posts every anchor
+    #[serde(default)]
+    pub anchor_leaders: Vec<String>,
     /// Network to use as the homechain
     pub homechain: Network,
     /// Network to use as the sidechain
     pub sidechain: Network,
+    /// Where chain IDs, contract addresses and ABIs are loaded from
+    pub source: ConfigSourceKind,
     /// consul url to grab contracts from
     pub consul: String,
     /// consul token used to access consul
@@ -60,19 +88,355 @@ pub struct Relay {
     pub keydir: String,
     /// The amount of attempts to resync the account nonce when `nonce too low` errors occur
     pub retries: u64,
+    /// Directory where transfer states are persisted across restarts
+    pub state_dir: String,
+    /// Size in bytes the audit log in state_dir grows to before it is rotated
+    pub audit_max_bytes: u64,
+    /// Number of rotated audit log files to keep
+    pub audit_max_files: usize,
+    /// Targets to pause at startup, on top of any paused through the endpoint before the restart
+    #[serde(default)]
+    pub paused: Vec<PauseTarget>,
+    /// Contracts a flush withdraws anyway, and wallets it never withdraws
+    #[serde(default)]
+    pub flush: Flush,
+    /// How balance checks and lookbacks split their block range into log queries
+    pub scan: Scan,
+    /// Seconds to wait for sent transactions to be mined before applying a config change anyway
+    pub reload_drain_timeout: u64,
+    /// Seconds to wait for sent transactions to be mined when shutting down
+    pub shutdown_timeout: u64,
+    /// Seconds to wait for a response before considering a websocket dropped
+    pub request_timeout: u64,
+    /// Seconds to wait before the first attempt to reconnect a dropped websocket
+    pub reconnect_initial_delay: u64,
+    /// Longest wait in seconds between attempts to reconnect, the wait doubles after each failed attempt
+    pub reconnect_max_delay: u64,
+    /// Seconds between health checks of every endpoint
+    pub health_check_interval: u64,
+    /// Oldest the latest block of a healthy endpoint can be, in seconds
+    pub max_head_age: u64,
+    /// Fewest peers a healthy endpoint can have
+    pub min_peers: u64,
+    /// Path to the JSON artifact of the token contract, for the file source
+    pub token_abi: Option<String>,
+    /// Path to the JSON artifact of the relay contract, for the file source
+    pub relay_abi: Option<String>,
+}
+
+impl Relay {
+    /// Returns why the anchor leaders are invalid, if they are
+    fn invalid_anchor_leaders(&self) -> Option<String> {
+        if self.anchor_leaders.is_empty() {
+            return None;
+        }
+        let parse = |address: &str| utils::clean_0x(address).parse::<Address>().ok();
+        if let Some(leader) = self.anchor_leaders.iter().find(|leader| parse(leader).is_none()) {
+            return Some(format!("{} is not an address", leader));
+        }
+        let account = parse(&self.account);
+        if !self.anchor_leaders.iter().any(|leader| parse(leader) == account) {
+            return Some(format!("relay.account {} is not one of them", self.account));
+        }
+        None
+    }
+}
+
+/// Flush policy settings. Without them, a flush withdraws every wallet without code
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Flush {
+    /// Contracts withdrawn even though they have code
+    #[serde(default)]
+    pub allow: Vec<AllowedContract>,
+    /// Addresses never withdrawn, contracts or not
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Contract a flush withdraws even though it has code
+#[derive(Clone, Debug, Deserialize)]
+pub struct AllowedContract {
+    /// Address of the contract on the sidechain
+    pub address: String,
+    /// Address on the homechain the balance is withdrawn to, defaults to the same address
+    pub destination: Option<String>,
+}
+
+impl Flush {
+    /// Returns the flush policy with parsed addresses
+    pub fn policy(&self) -> Result<FlushPolicy, ConfigError> {
+        let parse = |address: &str| {
+            utils::clean_0x(address)
+                .parse::<Address>()
+                .map_err(|_| ConfigError::InvalidFlushPolicy(format!("{} is not an address", address)))
+        };
+        let mut policy = FlushPolicy::default();
+        for contract in &self.allow {
+            let destination = contract.destination.as_ref().map(|d| parse(d)).transpose()?;
+            if destination == Some(Address::zero()) {
+                return Err(ConfigError::InvalidFlushPolicy(format!(
+                    "{} is withdrawn to the zero address",
+                    contract.address
+                )));
+            }
+            policy.allow.insert(parse(&contract.address)?, destination);
+        }
+        for address in &self.deny {
+            policy.deny.insert(parse(address)?);
+        }
+        Ok(policy)
+    }
+}
+
+/// Log scan settings
+#[derive(Clone, Debug, Deserialize)]
+pub struct Scan {
+    /// Most log queries in flight at once
+    pub concurrency: usize,
+    /// Blocks in the first query, halved when the node refuses a query and doubled while queries are sparse
+    pub initial_window: u64,
+    /// Fewest blocks in a query
+    pub min_window: u64,
+    /// Most blocks in a query
+    pub max_window: u64,
+    /// Queries with fewer logs than this are sparse
+    pub sparse_logs: usize,
+}
+
+impl Scan {
+    /// Returns the scan settings, if they are valid
+    pub fn config(&self) -> Result<ScanConfig, ConfigError> {
+        if self.concurrency == 0 {
+            Err(ConfigError::InvalidScan("concurrency must be non-zero".to_string()))
+        } else if self.min_window == 0 {
+            Err(ConfigError::InvalidScan("min_window must be non-zero".to_string()))
+        } else if self.min_window > self.initial_window || self.initial_window > self.max_window {
+            Err(ConfigError::InvalidScan(
+                "initial_window must be between min_window and max_window".to_string(),
+            ))
+        } else {
+            Ok(ScanConfig {
+                concurrency: self.concurrency,
+                initial_window: self.initial_window,
+                min_window: self.min_window,
+                max_window: self.max_window,
+                sparse_logs: self.sparse_logs,
+            })
+        }
+    }
+}
+
+/// Where chain IDs, contract addresses and ABIs are loaded from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigSourceKind {
+    /// Wait for them in consul, under `chain/{community}`
+    Consul,
+    /// Read them from this file, with ABIs from local JSON artifacts
+    File,
 }
 
 /// Per-network settings
 #[derive(Debug, Deserialize)]
 pub struct Network {
-    /// URI for the Websocket RPC endpoint for an Ethereum client
-    pub wsuri: String,
+    /// How heads and logs are received from the chain
+    pub transport: TransportKind,
+    /// URI for the HTTP RPC endpoint, for the http transport
+    pub httpuri: Option<String>,
+    /// seconds between polls for new blocks, for the http transport
+    pub poll_interval: u64,
+    /// URI for the Websocket RPC endpoint for an Ethereum client, used before any of `endpoints`
+    pub wsuri: Option<String>,
+    /// More Websocket RPC endpoints for the same chain, to fail over to
+    #[serde(default)]
+    pub endpoints: Vec<RpcEndpoint>,
     /// Whether or not the transactions should be free
     pub free: bool,
     /// seconds between checks for missed transactions
     pub interval: u64,
     /// seconds until timeout on block events
     pub timeout: u64,
+    /// Gas price and gas limit settings
+    pub gas: Gas,
+    /// Value limits on transfers from this network
+    pub limits: Limits,
+    /// Chain ID, for the file source
+    pub chain_id: Option<u64>,
+    /// Token contract address, for the file source
+    pub token: Option<String>,
+    /// Relay contract address, for the file source
+    pub relay: Option<String>,
+}
+
+impl Network {
+    /// Returns every RPC endpoint of the network, with `wsuri` first
+    pub fn rpc_endpoints(&self) -> Vec<RpcEndpoint> {
+        let wsuri = self.wsuri.iter().map(|uri| RpcEndpoint {
+            uri: uri.clone(),
+            priority: 0,
+            name: None,
+        });
+        wsuri.chain(self.endpoints.iter().cloned()).collect()
+    }
+
+    /// Returns the endpoint setting the transport of the network needs but is missing, if any
+    fn missing_endpoint(&self) -> Option<&'static str> {
+        match self.transport {
+            TransportKind::Websocket if self.rpc_endpoints().is_empty() => Some("wsuri"),
+            TransportKind::Http if self.httpuri.is_none() => Some("httpuri"),
+            _ => None,
+        }
+    }
+}
+
+/// How a network receives new heads and logs
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Websocket subscriptions, failing over between `wsuri` and `endpoints`
+    Websocket,
+    /// Polling `httpuri`, for nodes without websocket subscriptions
+    Http,
+}
+
+/// Websocket RPC endpoint of a network
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RpcEndpoint {
+    /// URI of the endpoint
+    pub uri: String,
+    /// Endpoints with lower priorities are used first
+    #[serde(default)]
+    pub priority: u32,
+    /// Name shown in the status, defaults to the host in the URI
+    pub name: Option<String>,
+}
+
+impl RpcEndpoint {
+    /// Returns the name of the endpoint. The host leaves out credentials and API keys in the URI
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let address = self.uri.splitn(2, "://").last().unwrap_or("");
+            let authority = address.split('/').next().unwrap_or("");
+            authority.rsplit('@').next().unwrap_or("").to_string()
+        })
+    }
+}
+
+/// Per-network value limits on transfers, amounts in token base units.
+/// Amounts are strings, as they do not fit in a TOML integer
+#[derive(Clone, Debug, Deserialize)]
+pub struct Limits {
+    /// Largest single transfer
+    pub max_transfer: Option<String>,
+    /// Most value approved within the window
+    pub max_window_total: Option<String>,
+    /// Most value approved to a single destination within the window
+    pub max_destination_total: Option<String>,
+    /// Seconds covered by the rolling window
+    pub window: u64,
+}
+
+impl Limits {
+    /// Returns the limits with parsed amounts
+    pub fn config(&self) -> Result<LimitConfig, ConfigError> {
+        let parse = |name: &str, amount: &Option<String>| {
+            amount
+                .as_ref()
+                .map(|amount| {
+                    U256::from_dec_str(amount)
+                        .map_err(|_| ConfigError::InvalidLimit(format!("{} is not an amount: {}", name, amount)))
+                })
+                .transpose()
+        };
+        if self.window == 0 {
+            return Err(ConfigError::InvalidLimit("window must be non-zero".to_string()));
+        }
+        Ok(LimitConfig {
+            max_transfer: parse("max_transfer", &self.max_transfer)?,
+            max_window_total: parse("max_window_total", &self.max_window_total)?,
+            max_destination_total: parse("max_destination_total", &self.max_destination_total)?,
+            window: self.window,
+        })
+    }
+}
+
+/// Nodes reject replacement transactions that do not raise the gas price by at least this percent
+const MIN_BUMP_PERCENT: u64 = 10;
+
+/// How the gas price is chosen for a network
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GasPriceKind {
+    /// Always use `price`
+    Fixed,
+    /// Use eth_gasPrice times `multiplier`
+    Node,
+    /// Send type-2 transactions with fees from eth_feeHistory
+    Eip1559,
+}
+
+/// Per-network gas settings
+#[derive(Clone, Debug, Deserialize)]
+pub struct Gas {
+    /// How the gas price is chosen
+    pub strategy: GasPriceKind,
+    /// Gas price in wei for the fixed strategy
+    pub price: Option<u64>,
+    /// Multiplier on eth_gasPrice for the node strategy
+    pub multiplier: f64,
+    /// Lowest gas price (or max fee) in wei
+    pub min_price: Option<u64>,
+    /// Highest gas price (or max fee) in wei
+    pub max_price: Option<u64>,
+    /// Multiplier on eth_estimateGas for the gas limit
+    pub limit_margin: f64,
+    /// Highest gas limit
+    pub limit_cap: u64,
+    /// Number of blocks of fee history for the eip1559 strategy
+    pub fee_history_blocks: u64,
+    /// Percentile of past priority fees to pay for the eip1559 strategy
+    pub priority_fee_percentile: f64,
+    /// Multiplier on the next base fee for the eip1559 max fee
+    pub base_fee_multiplier: f64,
+    /// Blocks to wait for a transaction to be mined before replacing it with a higher gas price, 0 disables
+    pub stuck_blocks: u64,
+    /// Percent to raise the gas price by on each replacement, at least 10
+    pub bump_percent: u64,
+    /// Highest gas price (or max fee) in wei for replacements, defaults to max_price
+    pub bump_ceiling: Option<u64>,
+}
+
+impl Gas {
+    fn validated(&self) -> Result<(), ConfigError> {
+        if self.strategy == GasPriceKind::Fixed && self.price.is_none() {
+            Err(ConfigError::InvalidGas("fixed strategy requires a price".to_string()))
+        } else if self.multiplier <= 0.0 || self.base_fee_multiplier <= 0.0 {
+            Err(ConfigError::InvalidGas("multipliers must be positive".to_string()))
+        } else if self.limit_margin < 1.0 {
+            Err(ConfigError::InvalidGas("limit_margin must be at least 1".to_string()))
+        } else if self.limit_cap == 0 {
+            Err(ConfigError::InvalidGas("limit_cap must be non-zero".to_string()))
+        } else if self.min_price.unwrap_or(0) > self.max_price.unwrap_or(u64::max_value()) {
+            Err(ConfigError::InvalidGas(
+                "min_price must not be above max_price".to_string(),
+            ))
+        } else if self.fee_history_blocks == 0 {
+            Err(ConfigError::InvalidGas(
+                "fee_history_blocks must be non-zero".to_string(),
+            ))
+        } else if self.bump_percent < MIN_BUMP_PERCENT {
+            Err(ConfigError::InvalidGas(format!(
+                "bump_percent must be at least {}",
+                MIN_BUMP_PERCENT
+            )))
+        } else if self.priority_fee_percentile < 0.0 || self.priority_fee_percentile > 100.0 {
+            Err(ConfigError::InvalidGas(
+                "priority_fee_percentile must be between 0 and 100".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl Settings {
@@ -90,12 +454,46 @@ impl Settings {
         c.set_default("endpoint.port", 12344)?;
         c.set_default("relay.confirmations", 12)?;
         c.set_default("relay.anchor_frequency", 100)?;
+        c.set_default("relay.source", "consul")?;
+        c.set_default("relay.consul", "")?;
         c.set_default("relay.community", "")?;
         c.set_default("relay.homechain.interval", 30)?;
         c.set_default("relay.sidechain.interval", 30)?;
         c.set_default("relay.homechain.timeout", 30)?;
         c.set_default("relay.sidechain.timeout", 30)?;
+        c.set_default("relay.homechain.transport", "websocket")?;
+        c.set_default("relay.sidechain.transport", "websocket")?;
+        c.set_default("relay.homechain.poll_interval", 5)?;
+        c.set_default("relay.sidechain.poll_interval", 5)?;
         c.set_default("relay.retries", 10)?;
+        c.set_default("relay.state_dir", "./state")?;
+        c.set_default("relay.audit_max_bytes", 10_485_760)?;
+        c.set_default("relay.audit_max_files", 5)?;
+        c.set_default("relay.reload_drain_timeout", 300)?;
+        c.set_default("relay.shutdown_timeout", 60)?;
+        c.set_default("relay.request_timeout", 120)?;
+        c.set_default("relay.reconnect_initial_delay", 1)?;
+        c.set_default("relay.reconnect_max_delay", 60)?;
+        c.set_default("relay.health_check_interval", 30)?;
+        c.set_default("relay.max_head_age", 300)?;
+        c.set_default("relay.min_peers", 1)?;
+        c.set_default("relay.scan.concurrency", 4)?;
+        c.set_default("relay.scan.initial_window", 1000)?;
+        c.set_default("relay.scan.min_window", 10)?;
+        c.set_default("relay.scan.max_window", 100_000)?;
+        c.set_default("relay.scan.sparse_logs", 1000)?;
+        for chain in &["homechain", "sidechain"] {
+            c.set_default(&format!("relay.{}.limits.window", chain), 86400)?;
+            c.set_default(&format!("relay.{}.gas.strategy", chain), "node")?;
+            c.set_default(&format!("relay.{}.gas.multiplier", chain), 1.0)?;
+            c.set_default(&format!("relay.{}.gas.limit_margin", chain), 1.2)?;
+            c.set_default(&format!("relay.{}.gas.limit_cap", chain), 500_000)?;
+            c.set_default(&format!("relay.{}.gas.fee_history_blocks", chain), 10)?;
+            c.set_default(&format!("relay.{}.gas.priority_fee_percentile", chain), 50.0)?;
+            c.set_default(&format!("relay.{}.gas.base_fee_multiplier", chain), 2.0)?;
+            c.set_default(&format!("relay.{}.gas.stuck_blocks", chain), 20)?;
+            c.set_default(&format!("relay.{}.gas.bump_percent", chain), 15)?;
+        }
 
         // XXX: Get default from the CONSUL_TOKEN environment variable, look into naming such that
         // below Environment override does this for us
@@ -123,17 +521,56 @@ impl Settings {
         let lookback_combined = LOOKBACK_RANGE + LOOKBACK_LEEWAY;
         if self.endpoint.port == 0 {
             Err(ConfigError::InvalidPort)
+        } else if self
+            .endpoint
+            .admin_tls
+            .as_ref()
+            .map_or(false, |tls| tls.port == 0 || tls.port == self.endpoint.port)
+        {
+            Err(ConfigError::InvalidPort)
         } else if self.relay.anchor_frequency == 0 {
             Err(ConfigError::InvalidAnchorFrequency)
         } else if self.relay.confirmations >= self.relay.anchor_frequency {
             Err(ConfigError::InvalidConfirmations)
+        } else if let Some(reason) = self.relay.invalid_anchor_leaders() {
+            Err(ConfigError::InvalidAnchorLeaders(reason))
         } else if self.relay.homechain.interval >= lookback_combined
             || self.relay.sidechain.interval >= lookback_combined
         {
             Err(ConfigError::InvalidLookbackInterval(lookback_combined))
         } else if !Path::new(&self.relay.keydir).exists() {
             Err(ConfigError::InvalidKeydir)
+        } else if self.relay.source == ConfigSourceKind::Consul && self.relay.consul.is_empty() {
+            Err(ConfigError::MissingSetting("relay.consul".to_string()))
+        } else if let Some(setting) = self.relay.homechain.missing_endpoint() {
+            Err(ConfigError::MissingSetting(format!("relay.homechain.{}", setting)))
+        } else if let Some(setting) = self.relay.sidechain.missing_endpoint() {
+            Err(ConfigError::MissingSetting(format!("relay.sidechain.{}", setting)))
+        } else if self.relay.homechain.poll_interval == 0 || self.relay.sidechain.poll_interval == 0 {
+            Err(ConfigError::InvalidTimeout(
+                "poll_interval must be non-zero".to_string(),
+            ))
+        } else if self.relay.health_check_interval == 0 {
+            Err(ConfigError::InvalidTimeout(
+                "health_check_interval must be non-zero".to_string(),
+            ))
+        } else if self.relay.request_timeout == 0 {
+            Err(ConfigError::InvalidTimeout(
+                "request_timeout must be non-zero".to_string(),
+            ))
+        } else if self.relay.reconnect_initial_delay == 0
+            || self.relay.reconnect_initial_delay > self.relay.reconnect_max_delay
+        {
+            Err(ConfigError::InvalidTimeout(
+                "reconnect_initial_delay must be non-zero and at most reconnect_max_delay".to_string(),
+            ))
         } else {
+            self.relay.homechain.gas.validated()?;
+            self.relay.sidechain.gas.validated()?;
+            self.relay.homechain.limits.config()?;
+            self.relay.sidechain.limits.config()?;
+            self.relay.flush.policy()?;
+            self.relay.scan.config()?;
             Ok(self)
         }
     }
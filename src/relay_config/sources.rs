@@ -0,0 +1,311 @@
+use failure::Error;
+use serde_json;
+use std::fs;
+
+use super::consul_configs::ConsulConfig;
+use super::settings::{ConfigSourceKind, Network, Settings};
+use crate::errors::{ConfigError, OperationError};
+
+/// Chain ID and contract addresses of a single network
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    pub token: String,
+    pub relay: String,
+}
+
+/// Everything the relay needs to know about the contracts on both networks
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContractsConfig {
+    pub homechain: ChainConfig,
+    pub sidechain: ChainConfig,
+    pub token_abi: String,
+    pub relay_abi: String,
+}
+
+/// How a new contracts config differs from the one in use
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// Nothing changed
+    None,
+    /// Contract addresses or ABIs changed, the flags tell which networks have to be rebuilt
+    Live { homechain: bool, sidechain: bool },
+    /// The change cannot be applied while running
+    Unsafe(String),
+}
+
+impl ContractsConfig {
+    /// Returns how the given config differs from this one
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - The updated config
+    pub fn change(&self, new: &ContractsConfig) -> ConfigChange {
+        // Nonces, checkpoints and transfer states all belong to a single chain, they cannot move to another one
+        if self.homechain.chain_id != new.homechain.chain_id {
+            return ConfigChange::Unsafe(format!(
+                "homechain chain id changed from {} to {}",
+                self.homechain.chain_id, new.homechain.chain_id
+            ));
+        }
+        if self.sidechain.chain_id != new.sidechain.chain_id {
+            return ConfigChange::Unsafe(format!(
+                "sidechain chain id changed from {} to {}",
+                self.sidechain.chain_id, new.sidechain.chain_id
+            ));
+        }
+        let abis = self.token_abi != new.token_abi || self.relay_abi != new.relay_abi;
+        let homechain = abis || self.homechain != new.homechain;
+        let sidechain = abis || self.sidechain != new.sidechain;
+        if homechain || sidechain {
+            ConfigChange::Live { homechain, sidechain }
+        } else {
+            ConfigChange::None
+        }
+    }
+}
+
+/// Somewhere chain IDs, contract addresses and contract ABIs can be loaded from
+pub trait ConfigSource {
+    /// Returns the contracts config, blocking until it is available
+    fn load(&self) -> Result<ContractsConfig, Error>;
+}
+
+/// Returns the config source selected in the settings
+///
+/// # Arguments
+///
+/// * `settings` - Relay settings
+pub fn from_settings(settings: &Settings) -> Box<dyn ConfigSource> {
+    match settings.relay.source {
+        ConfigSourceKind::Consul => Box::new(ConsulConfig::new(
+            &settings.relay.consul,
+            &settings.relay.consul_token,
+            &settings.relay.community,
+        )),
+        ConfigSourceKind::File => Box::new(FileConfig::new(settings)),
+    }
+}
+
+impl ConfigSource for ConsulConfig {
+    fn load(&self) -> Result<ContractsConfig, Error> {
+        let homechain = self.wait_or_get("homechain").map_err(|e| {
+            error!("error fetching consul configuration {:?}", e);
+            ConfigError::ConsulError
+        })?;
+        let sidechain = self.wait_or_get("sidechain").map_err(|e| {
+            error!("error fetching consul configuration {:?}", e);
+            ConfigError::ConsulError
+        })?;
+        Ok(ContractsConfig {
+            homechain: chain_from_consul(&homechain)?,
+            sidechain: chain_from_consul(&sidechain)?,
+            token_abi: self.create_contract_abi("NectarToken")?,
+            relay_abi: self.create_contract_abi("ERC20Relay")?,
+        })
+    }
+}
+
+/// Returns the ChainConfig out of the chain json stored in consul
+///
+/// # Arguments
+///
+/// * `config` - Value of the `chain/{community}/{chain}` key
+fn chain_from_consul(config: &serde_json::Value) -> Result<ChainConfig, OperationError> {
+    let get_str = |key: &str| {
+        config[key]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| OperationError::CouldNotGetConsulKey(key.to_string()))
+    };
+    let chain_id = config["chain_id"]
+        .as_u64()
+        .ok_or_else(|| OperationError::CouldNotGetConsulKey("chain_id".to_string()))?;
+    Ok(ChainConfig {
+        chain_id,
+        token: get_str("nectar_token_address")?,
+        relay: get_str("erc20_relay_address")?,
+    })
+}
+
+/// Chain ID and contract addresses of a network as set in the TOML settings, where all of them are optional
+#[derive(Clone, Debug)]
+struct FileChain {
+    name: &'static str,
+    chain_id: Option<u64>,
+    token: Option<String>,
+    relay: Option<String>,
+}
+
+impl FileChain {
+    fn new(name: &'static str, network: &Network) -> Self {
+        FileChain {
+            name,
+            chain_id: network.chain_id,
+            token: network.token.clone(),
+            relay: network.relay.clone(),
+        }
+    }
+
+    fn chain_config(&self) -> Result<ChainConfig, ConfigError> {
+        let missing = |key: &str| ConfigError::MissingSetting(format!("relay.{}.{}", self.name, key));
+        Ok(ChainConfig {
+            chain_id: self.chain_id.ok_or_else(|| missing("chain_id"))?,
+            token: self.token.clone().ok_or_else(|| missing("token"))?,
+            relay: self.relay.clone().ok_or_else(|| missing("relay"))?,
+        })
+    }
+}
+
+/// Config source that reads everything from the TOML settings, and the ABIs from local JSON artifacts
+#[derive(Clone, Debug)]
+pub struct FileConfig {
+    homechain: FileChain,
+    sidechain: FileChain,
+    token_abi: Option<String>,
+    relay_abi: Option<String>,
+}
+
+impl FileConfig {
+    /// Returns a FileConfig from the relay settings
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - Relay settings
+    pub fn new(settings: &Settings) -> Self {
+        FileConfig {
+            homechain: FileChain::new("homechain", &settings.relay.homechain),
+            sidechain: FileChain::new("sidechain", &settings.relay.sidechain),
+            token_abi: settings.relay.token_abi.clone(),
+            relay_abi: settings.relay.relay_abi.clone(),
+        }
+    }
+}
+
+impl ConfigSource for FileConfig {
+    fn load(&self) -> Result<ContractsConfig, Error> {
+        let token_abi = self
+            .token_abi
+            .as_ref()
+            .ok_or_else(|| ConfigError::MissingSetting("relay.token_abi".to_string()))?;
+        let relay_abi = self
+            .relay_abi
+            .as_ref()
+            .ok_or_else(|| ConfigError::MissingSetting("relay.relay_abi".to_string()))?;
+        Ok(ContractsConfig {
+            homechain: self.homechain.chain_config()?,
+            sidechain: self.sidechain.chain_config()?,
+            token_abi: read_abi(token_abi)?,
+            relay_abi: read_abi(relay_abi)?,
+        })
+    }
+}
+
+/// Returns the ABI in a JSON file, either a contract artifact with an `abi` key or a bare ABI array
+///
+/// # Arguments
+///
+/// * `path` - Path to the JSON file
+pub fn read_abi(path: &str) -> Result<String, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::InvalidAbiFile(format!("{}: {}", path, e)))?;
+    parse_abi(&contents).ok_or_else(|| ConfigError::InvalidAbiFile(format!("{}: no abi found", path)))
+}
+
+fn parse_abi(contents: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(contents).ok()?;
+    let abi = if json.is_array() { &json } else { json.get("abi")? };
+    if abi.is_array() {
+        serde_json::to_string(abi).ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_abi_should_accept_artifact_or_bare_abi() {
+        // arrange
+        let abi = json!([{"type": "function", "name": "anchor", "inputs": [], "outputs": []}]);
+        let artifact = json!({"contractName": "ERC20Relay", "abi": abi.clone()}).to_string();
+        let bare = abi.to_string();
+        // act
+        let from_artifact = parse_abi(&artifact);
+        let from_bare = parse_abi(&bare);
+        let from_other = parse_abi(&json!({"contractName": "ERC20Relay"}).to_string());
+        // assert
+        assert_eq!(from_artifact, Some(abi.to_string()));
+        assert_eq!(from_bare, Some(abi.to_string()));
+        assert_eq!(from_other, None);
+    }
+
+    #[test]
+    fn change_should_rebuild_changed_networks_and_refuse_chain_id_changes() {
+        // arrange
+        let chain = |chain_id: u64, relay: &str| ChainConfig {
+            chain_id,
+            token: "0x273f0a1f19478605e8ee50fa309a933f2a04584b".to_string(),
+            relay: relay.to_string(),
+        };
+        let config = ContractsConfig {
+            homechain: chain(1, "0x11c60f7c6a3b5d01fb051a81cdcbb144280da8e4"),
+            sidechain: chain(2, "0x11c60f7c6a3b5d01fb051a81cdcbb144280da8e4"),
+            token_abi: "[]".to_string(),
+            relay_abi: "[]".to_string(),
+        };
+        let moved_relay = ContractsConfig {
+            sidechain: chain(2, "0x0000000000000000000000000000000000000001"),
+            ..config.clone()
+        };
+        let moved_chain = ContractsConfig {
+            homechain: chain(3, "0x11c60f7c6a3b5d01fb051a81cdcbb144280da8e4"),
+            ..config.clone()
+        };
+        // act
+        let unchanged = config.change(&config.clone());
+        let live = config.change(&moved_relay);
+        let unsafe_change = config.change(&moved_chain);
+        // assert
+        assert_eq!(unchanged, ConfigChange::None);
+        assert_eq!(
+            live,
+            ConfigChange::Live {
+                homechain: false,
+                sidechain: true
+            }
+        );
+        assert_eq!(
+            unsafe_change,
+            ConfigChange::Unsafe("homechain chain id changed from 1 to 3".to_string())
+        );
+    }
+
+    #[test]
+    fn chain_from_consul_should_read_chain_id_and_addresses() {
+        // arrange
+        let config = json!({
+            "chain_id": 1337,
+            "nectar_token_address": "0x273f0a1f19478605e8ee50fa309a933f2a04584b",
+            "erc20_relay_address": "0x11c60f7c6a3b5d01fb051a81cdcbb144280da8e4"
+        });
+        // act
+        let chain = chain_from_consul(&config);
+        let missing = chain_from_consul(&json!({"chain_id": 1337}));
+        // assert
+        assert_eq!(
+            chain,
+            Ok(ChainConfig {
+                chain_id: 1337,
+                token: "0x273f0a1f19478605e8ee50fa309a933f2a04584b".to_string(),
+                relay: "0x11c60f7c6a3b5d01fb051a81cdcbb144280da8e4".to_string(),
+            })
+        );
+        assert_eq!(
+            missing,
+            Err(OperationError::CouldNotGetConsulKey("nectar_token_address".to_string()))
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::{mem, process};
+use tokio_core::reactor;
+use web3::futures::future;
+use web3::futures::prelude::*;
+use web3::futures::sync::{mpsc, oneshot};
+use web3::futures::try_ready;
+use web3::types::{BlockNumber, U256};
+use web3::DuplexTransport;
+
+use crate::errors::OperationError;
+use crate::eth::queue::TransactionQueue;
+use crate::eth::websocket::Backoff;
+use crate::extensions::shutdown::Shutdown;
+use crate::metrics;
+use crate::relay::{Network, Relay};
+use crate::relay_config::sources::{ConfigChange, ContractsConfig};
+use crate::server::RequestType;
+
+/// How often to check whether the networks being rebuilt are done with their transactions
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Most requests waiting while the relay is reloading, later ones are dropped
+const MAX_WAITING_REQUESTS: usize = 1_000;
+
+enum ReloadState<T: DuplexTransport + 'static> {
+    Start,
+    Running(oneshot::Sender<()>, Option<oneshot::Receiver<()>>),
+    Drain(
+        Option<Network<T>>,
+        Option<Network<T>>,
+        ContractsConfig,
+        Instant,
+        reactor::Timeout,
+    ),
+    Rebuild(
+        Option<Network<T>>,
+        Option<Network<T>>,
+        ContractsConfig,
+        Box<dyn Future<Item = (Option<U256>, Option<U256>), Error = ()>>,
+    ),
+    Reconnect(
+        reactor::Timeout,
+        Option<Box<dyn Future<Item = (Option<U256>, Option<U256>), Error = ()>>>,
+    ),
+    Stop(Option<oneshot::Sender<()>>, Instant, reactor::Timeout),
+}
+
+/// Future that runs the relay, and applies contract config changes while running.
+/// On a change, it stops the relay, waits for the transactions on the affected networks to be mined, rebuilds those
+/// networks with the new contracts, and starts the relay again. Changes that cannot be applied live exit the process.
+/// When the relay fails, usually because a websocket dropped, it waits until both chains answer again and starts the
+/// relay again, which backfills the blocks missed in between and subscribes again.
+/// Requests from the http endpoint wait while the relay is not running, and are passed on once it starts again.
+/// On shutdown, it stops taking new logs and requests, and resolves once sent transactions are mined or the shutdown
+/// timeout passes
+pub struct ReloadRelay<T: DuplexTransport + 'static> {
+    homechain: Network<T>,
+    sidechain: Network<T>,
+    contracts: ContractsConfig,
+    changes: Option<mpsc::UnboundedReceiver<ContractsConfig>>,
+    requests: mpsc::UnboundedReceiver<RequestType>,
+    relay_requests: Option<mpsc::UnboundedSender<RequestType>>,
+    waiting_requests: VecDeque<RequestType>,
+    handle: reactor::Handle,
+    drain_timeout: Duration,
+    shutdown: Shutdown,
+    shutdown_timeout: Duration,
+    backoff: Backoff,
+    state: ReloadState<T>,
+}
+
+impl<T: DuplexTransport + 'static> ReloadRelay<T> {
+    /// Returns a newly created ReloadRelay Future
+    ///
+    /// # Arguments
+    ///
+    /// * `homechain` - Network to be used as the home chain
+    /// * `sidechain` - Network to be used as the side chain
+    /// * `contracts` - Contracts config the networks were built with
+    /// * `changes` - Receiver for updated contracts configs
+    /// * `requests` - Receiver for requests from the http endpoint
+    /// * `handle` - Handle to spawn new futures
+    /// * `drain_timeout` - How long to wait for sent transactions to be mined before applying a change anyway
+    /// * `shutdown` - Shutdown signal of the process
+    /// * `shutdown_timeout` - How long to wait for sent transactions to be mined when shutting down
+    /// * `backoff` - Delays between attempts to restart the relay after it fails
+    pub fn new(
+        homechain: &Network<T>,
+        sidechain: &Network<T>,
+        contracts: ContractsConfig,
+        changes: mpsc::UnboundedReceiver<ContractsConfig>,
+        requests: mpsc::UnboundedReceiver<RequestType>,
+        handle: &reactor::Handle,
+        drain_timeout: Duration,
+        shutdown: &Shutdown,
+        shutdown_timeout: Duration,
+        backoff: Backoff,
+    ) -> Self {
+        ReloadRelay {
+            homechain: homechain.clone(),
+            sidechain: sidechain.clone(),
+            contracts,
+            changes: Some(changes),
+            requests,
+            relay_requests: None,
+            waiting_requests: VecDeque::new(),
+            handle: handle.clone(),
+            drain_timeout,
+            shutdown: shutdown.clone(),
+            shutdown_timeout,
+            backoff,
+            state: ReloadState::Start,
+        }
+    }
+
+    /// Passes requests from the http endpoint to the running relay. Requests that come in while reloading wait
+    /// until the relay starts again, up to MAX_WAITING_REQUESTS
+    fn forward_requests(&mut self) {
+        while let Ok(Async::Ready(Some(request))) = self.requests.poll() {
+            match self.relay_requests {
+                Some(ref tx) => {
+                    if tx.unbounded_send(request).is_err() {
+                        error!("error passing request to the relay");
+                    }
+                }
+                None if self.waiting_requests.len() < MAX_WAITING_REQUESTS => self.waiting_requests.push_back(request),
+                None => warn!(
+                    "relay is reloading with {} requests waiting, dropping request",
+                    self.waiting_requests.len()
+                ),
+            }
+        }
+    }
+
+    /// Spawns the relay with the current networks, and returns the Running state that stops it when dropped
+    fn start(&mut self) -> ReloadState<T> {
+        let (tx, rx) = mpsc::unbounded();
+        if !self.waiting_requests.is_empty() {
+            info!(
+                "passing {} requests that waited for the reload to the relay",
+                self.waiting_requests.len()
+            );
+        }
+        for request in self.waiting_requests.drain(..) {
+            if tx.unbounded_send(request).is_err() {
+                error!("error passing request to the relay");
+            }
+        }
+        self.relay_requests = Some(tx);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (failed_tx, failed_rx) = oneshot::channel();
+        let relay = Relay::new(self.homechain.clone(), self.sidechain.clone());
+        self.handle.spawn(
+            relay
+                .run(rx, &self.handle)
+                .select(stop_rx.then(|_| Ok::<(), ()>(())))
+                .then(move |result| {
+                    if result.is_err() {
+                        let _ = failed_tx.send(());
+                    }
+                    Ok(())
+                }),
+        );
+        ReloadState::Running(stop_tx, Some(failed_rx))
+    }
+
+    /// Returns the Reconnect state that starts the relay again once both chains answer
+    fn reconnect(&mut self) -> ReloadState<T> {
+        self.relay_requests = None;
+        // Calls still queued were meant for the dropped websocket. Their transfers stay in the store, and are
+        // picked up again by the backfill or the past transfer watchers once the relay is back
+        self.homechain.transactions.close();
+        self.sidechain.transactions.close();
+        let delay = self.backoff.delay();
+        warn!("relay stopped, restarting in {}s", delay.as_secs());
+        let timeout = reactor::Timeout::new(delay, &self.handle).expect("error creating timeout");
+        ReloadState::Reconnect(timeout, None)
+    }
+
+    /// Starts the shutdown, and returns the Stop state that keeps the relay running while its subscriptions close
+    fn stop(&mut self) -> ReloadState<T> {
+        info!(
+            "shutting down, waiting up to {}s for sent transactions",
+            self.shutdown_timeout.as_secs()
+        );
+        self.homechain.shutdown.trigger();
+        self.sidechain.shutdown.trigger();
+        self.relay_requests = None;
+        // Dropping the waiting requests closes their replies, so the endpoint answers them with an error
+        self.waiting_requests.clear();
+        self.requests.close();
+        let relay = match mem::replace(&mut self.state, ReloadState::Start) {
+            ReloadState::Running(stop, _) => Some(stop),
+            _ => None,
+        };
+        let timeout = reactor::Timeout::new(Duration::from_secs(0), &self.handle).expect("error creating timeout");
+        ReloadState::Stop(relay, Instant::now() + self.shutdown_timeout, timeout)
+    }
+
+    /// Returns the networks rebuilt with the contracts in the new config, None for networks that did not change
+    ///
+    /// # Arguments
+    ///
+    /// * `contracts` - The updated config
+    /// * `homechain` - Whether the homechain changed
+    /// * `sidechain` - Whether the sidechain changed
+    fn rebuild(
+        &self,
+        contracts: &ContractsConfig,
+        homechain: bool,
+        sidechain: bool,
+    ) -> Result<(Option<Network<T>>, Option<Network<T>>), OperationError> {
+        let rebuild = |network: &Network<T>, changed: bool, chain: &str, relay: &str| {
+            if changed {
+                network
+                    .with_contracts(chain, &contracts.token_abi, relay, &contracts.relay_abi)
+                    .map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        Ok((
+            rebuild(
+                &self.homechain,
+                homechain,
+                &contracts.homechain.token,
+                &contracts.homechain.relay,
+            )?,
+            rebuild(
+                &self.sidechain,
+                sidechain,
+                &contracts.sidechain.token,
+                &contracts.sidechain.relay,
+            )?,
+        ))
+    }
+
+    /// Returns the state to move to for a new contracts config, or None to keep running as is
+    ///
+    /// # Arguments
+    ///
+    /// * `contracts` - The updated config
+    fn reload(&mut self, contracts: ContractsConfig) -> Option<ReloadState<T>> {
+        match self.contracts.change(&contracts) {
+            ConfigChange::None => None,
+            ConfigChange::Unsafe(reason) => {
+                error!("config change cannot be applied live, exiting: {}", reason);
+                metrics::config_reloaded("unsafe");
+                process::exit(1);
+            }
+            ConfigChange::Live { homechain, sidechain } => match self.rebuild(&contracts, homechain, sidechain) {
+                Ok((homechain, sidechain)) => {
+                    info!(
+                        "config changed, reloading homechain: {}, sidechain: {}",
+                        homechain.is_some(),
+                        sidechain.is_some()
+                    );
+                    self.relay_requests = None;
+                    let timeout =
+                        reactor::Timeout::new(Duration::from_secs(0), &self.handle).expect("error creating timeout");
+                    let deadline = Instant::now() + self.drain_timeout;
+                    Some(ReloadState::Drain(homechain, sidechain, contracts, deadline, timeout))
+                }
+                Err(e) => {
+                    error!("rejected config change: {}", e);
+                    metrics::config_reloaded("rejected");
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// Returns a Future that resolves to the pending transaction count of the relay account, if the network is set
+fn next_nonce<T: DuplexTransport + 'static>(
+    network: &Option<Network<T>>,
+) -> Box<dyn Future<Item = Option<U256>, Error = ()>> {
+    match network {
+        Some(network) => {
+            let network_type = network.network_type;
+            Box::new(
+                network
+                    .web3
+                    .eth()
+                    .transaction_count(network.account, Some(BlockNumber::Pending))
+                    .map(Some)
+                    .map_err(move |e| {
+                        error!("error getting transaction count on {:?}: {:?}", network_type, e);
+                    }),
+            )
+        }
+        None => Box::new(future::ok(None)),
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for ReloadRelay<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.forward_requests();
+        let stopping = match self.state {
+            ReloadState::Stop(..) => true,
+            _ => false,
+        };
+        if !stopping && self.shutdown.poll_triggered() {
+            self.state = self.stop();
+        }
+        loop {
+            let next = match self.state {
+                ReloadState::Start => self.start(),
+                ReloadState::Running(_, ref mut failed) => {
+                    let relay_failed = match failed {
+                        Some(ref mut rx) => match rx.poll() {
+                            Ok(Async::Ready(())) => true,
+                            Ok(Async::NotReady) => false,
+                            Err(_) => {
+                                // The relay finished without failing, after a flush
+                                *failed = None;
+                                false
+                            }
+                        },
+                        None => false,
+                    };
+                    if relay_failed {
+                        self.reconnect()
+                    } else {
+                        let polled = match self.changes {
+                            Some(ref mut changes) => changes.poll()?,
+                            None => return Ok(Async::NotReady),
+                        };
+                        match polled {
+                            Async::Ready(Some(contracts)) => match self.reload(contracts) {
+                                Some(state) => state,
+                                None => continue,
+                            },
+                            Async::Ready(None) => {
+                                // Nothing watches the config, keep forwarding requests
+                                self.changes = None;
+                                return Ok(Async::NotReady);
+                            }
+                            Async::NotReady => return Ok(Async::NotReady),
+                        }
+                    }
+                }
+                ReloadState::Drain(ref mut homechain, ref mut sidechain, ref contracts, deadline, ref mut timeout) => {
+                    try_ready!(timeout.poll().map_err(|e| {
+                        error!("error waiting for transactions to drain: {:?}", e);
+                    }));
+                    let drained = homechain
+                        .iter()
+                        .chain(sidechain.iter())
+                        .all(|network| network.transactions.is_drained());
+                    if !drained && Instant::now() < deadline {
+                        *timeout =
+                            reactor::Timeout::new(DRAIN_POLL_INTERVAL, &self.handle).expect("error creating timeout");
+                        continue;
+                    }
+                    if !drained {
+                        warn!("transactions still pending after the drain timeout, reloading anyway");
+                    }
+                    for network in homechain.iter().chain(sidechain.iter()) {
+                        network.transactions.close();
+                    }
+                    let future = next_nonce(homechain).join(next_nonce(sidechain));
+                    ReloadState::Rebuild(homechain.take(), sidechain.take(), contracts.clone(), Box::new(future))
+                }
+                ReloadState::Rebuild(ref mut homechain, ref mut sidechain, ref contracts, ref mut future) => {
+                    let (home_nonce, side_nonce) = try_ready!(future.poll().map_err(|_| {
+                        error!("unable to reload config, exiting");
+                        metrics::config_reloaded("failed");
+                        process::exit(1);
+                    }));
+                    if let (Some(mut network), Some(nonce)) = (homechain.take(), home_nonce) {
+                        network.transactions = TransactionQueue::new(nonce);
+                        self.homechain = network;
+                    }
+                    if let (Some(mut network), Some(nonce)) = (sidechain.take(), side_nonce) {
+                        network.transactions = TransactionQueue::new(nonce);
+                        self.sidechain = network;
+                    }
+                    self.contracts = contracts.clone();
+                    info!("config reloaded");
+                    metrics::config_reloaded("applied");
+                    ReloadState::Start
+                }
+                ReloadState::Reconnect(ref mut timeout, ref mut probe) => {
+                    if probe.is_none() {
+                        try_ready!(timeout.poll().map_err(|e| {
+                            error!("error waiting to restart the relay: {:?}", e);
+                        }));
+                        // The pending nonces are needed for the new queues, and tell both chains answer again
+                        let homechain = Some(self.homechain.clone());
+                        let sidechain = Some(self.sidechain.clone());
+                        *probe = Some(Box::new(next_nonce(&homechain).join(next_nonce(&sidechain))));
+                    }
+                    match probe.as_mut().map(|future| future.poll()) {
+                        Some(Ok(Async::Ready((Some(home_nonce), Some(side_nonce))))) => {
+                            self.homechain.transactions = TransactionQueue::new(home_nonce);
+                            self.sidechain.transactions = TransactionQueue::new(side_nonce);
+                            self.backoff.reset();
+                            info!("both chains reachable, restarting the relay");
+                            ReloadState::Start
+                        }
+                        Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+                        _ => {
+                            let delay = self.backoff.delay();
+                            warn!("chains still unreachable, retrying in {}s", delay.as_secs());
+                            *timeout = reactor::Timeout::new(delay, &self.handle).expect("error creating timeout");
+                            *probe = None;
+                            continue;
+                        }
+                    }
+                }
+                ReloadState::Stop(_, deadline, ref mut timeout) => {
+                    try_ready!(timeout.poll().map_err(|e| {
+                        error!("error waiting for transactions before shutdown: {:?}", e);
+                    }));
+                    let networks = [&self.homechain, &self.sidechain];
+                    let drained = networks.iter().all(|network| network.transactions.is_drained());
+                    if !drained && Instant::now() < deadline {
+                        *timeout =
+                            reactor::Timeout::new(DRAIN_POLL_INTERVAL, &self.handle).expect("error creating timeout");
+                        continue;
+                    }
+                    for network in networks.iter() {
+                        for (nonce, hash) in network.transactions.pending_transactions() {
+                            // The transfer stays in the sent state, so the next start picks it up again
+                            warn!(
+                                "transaction {:?} with nonce {} on {:?} not mined before shutdown",
+                                hash, nonce, network.network_type
+                            );
+                        }
+                        network.transactions.close();
+                        match network.pending.write() {
+                            Ok(mut pending) => pending.flush(),
+                            Err(e) => error!("error acquiring pending lock on {:?}: {:?}", network.network_type, e),
+                        }
+                    }
+                    info!("shutdown complete");
+                    self.shutdown.complete();
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
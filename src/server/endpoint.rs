@@ -1,16 +1,29 @@
+use actix_web::http::header;
 use actix_web::http::StatusCode;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use failure::Error;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
 use serde_derive::{Deserialize, Serialize};
+use std::net::TcpListener;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use std::thread;
 use web3::futures::future;
 use web3::futures::prelude::*;
 use web3::futures::sync::mpsc;
-use web3::types::{H256, U256};
+use web3::types::{Address, H256, U256};
 
 use crate::errors::EndpointError;
 use crate::eth::utils;
+use crate::flush::plan::{FlushPlan, ReportFormat};
+use crate::flush::snapshot::BalanceSnapshot;
+use crate::metrics;
 use crate::relay::NetworkType;
+use crate::relay_config::settings::AdminTls;
+use crate::store::audit::{AuditLog, AuditQuery};
+use crate::store::limits::HeldTransfer;
+use crate::store::pause::{PauseStatus, PauseTarget};
+use crate::transfers::rescan::{RescanJobs, RescanProgress};
 
 pub const HOME: &str = "HOME";
 pub const SIDE: &str = "SIDE";
@@ -19,6 +32,33 @@ pub const SIDE: &str = "SIDE";
 pub enum RequestType {
     Hash(NetworkType, H256),
     Status(mpsc::UnboundedSender<Result<StatusResponse, ()>>),
+    Withdrawals(
+        NetworkType,
+        H256,
+        mpsc::UnboundedSender<Result<Vec<WithdrawalStatus>, ()>>,
+    ),
+    Held(mpsc::UnboundedSender<Result<HeldResponse, ()>>),
+    Release(H256, mpsc::UnboundedSender<Result<bool, ()>>),
+    Anchor(u64, mpsc::UnboundedSender<Result<Option<AnchorResponse>, ()>>),
+    Unapprove(NetworkType, H256, mpsc::UnboundedSender<Result<Vec<H256>, ()>>),
+    Queue(mpsc::UnboundedSender<Result<QueueResponse, ()>>),
+    Pause(PauseTarget, mpsc::UnboundedSender<Result<PauseStatus, ()>>),
+    Resume(PauseTarget, mpsc::UnboundedSender<Result<PauseStatus, ()>>),
+    Paused(mpsc::UnboundedSender<Result<PauseStatus, ()>>),
+    Rescan(
+        NetworkType,
+        u64,
+        u64,
+        Arc<RwLock<RescanJobs>>,
+        mpsc::UnboundedSender<Result<RescanProgress, ()>>,
+    ),
+    FlushPlan(u64, mpsc::UnboundedSender<Result<FlushPlan, ()>>),
+    Balances(
+        NetworkType,
+        u64,
+        Option<usize>,
+        mpsc::UnboundedSender<Result<BalanceSnapshot, ()>>,
+    ),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -38,6 +78,7 @@ pub struct NetworkStatus {
     relay_eth_balance: Option<String>,
     relay_last_block: Option<String>,
     contract_nct_balance: Option<String>,
+    endpoint: Option<String>,
 }
 
 impl NetworkStatus {
@@ -45,20 +86,186 @@ impl NetworkStatus {
         relay_eth_balance: Option<U256>,
         relay_last_block: Option<U256>,
         contract_nct_balance: Option<U256>,
+        endpoint: Option<String>,
     ) -> Self {
         NetworkStatus {
             relay_eth_balance: relay_eth_balance.map(|v| v.to_string()),
             relay_last_block: relay_last_block.map(|v| v.to_string()),
             contract_nct_balance: contract_nct_balance.map(|v| v.to_string()),
+            endpoint,
         }
     }
 }
 
+/// State on the target contract of the withdrawal for one transfer
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WithdrawalStatus {
+    withdrawal_hash: String,
+    transfer_destination: String,
+    transfer_amount: String,
+    destination: String,
+    amount: String,
+    approvals: Vec<String>,
+    approved: bool,
+    processed: bool,
+}
+
+impl WithdrawalStatus {
+    /// Returns a WithdrawalStatus for a transfer
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    /// * `transfer` - Destination and amount of the transfer on the source chain
+    /// * `withdrawal` - Destination, amount and processed flag recorded on the target contract
+    /// * `approvals` - Addresses that approved the withdrawal
+    /// * `account` - Address of this relay
+    pub fn new(
+        withdrawal_hash: H256,
+        transfer: (Address, U256),
+        withdrawal: (Address, U256, bool),
+        approvals: &[Address],
+        account: Address,
+    ) -> Self {
+        WithdrawalStatus {
+            withdrawal_hash: format!("{:?}", withdrawal_hash),
+            transfer_destination: format!("{:?}", transfer.0),
+            transfer_amount: transfer.1.to_string(),
+            destination: format!("{:?}", withdrawal.0),
+            amount: withdrawal.1.to_string(),
+            approvals: approvals.iter().map(|approver| format!("{:?}", approver)).collect(),
+            approved: approvals.contains(&account),
+            processed: withdrawal.2,
+        }
+    }
+}
+
+/// Transfers held over a value limit, by source chain
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HeldResponse {
+    home: Vec<HeldTransfer>,
+    side: Vec<HeldTransfer>,
+}
+
+impl HeldResponse {
+    pub fn new(home: &[HeldTransfer], side: &[HeldTransfer]) -> Self {
+        HeldResponse {
+            home: home.to_vec(),
+            side: side.to_vec(),
+        }
+    }
+}
+
+/// Sidechain block posted again as an anchor on the homechain
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AnchorResponse {
+    block_number: String,
+    block_hash: String,
+}
+
+impl AnchorResponse {
+    pub fn new(block_number: u64, block_hash: H256) -> Self {
+        AnchorResponse {
+            block_number: block_number.to_string(),
+            block_hash: format!("{:?}", block_hash),
+        }
+    }
+}
+
+/// Transaction sent by the relay and not mined yet
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingTransaction {
+    nonce: String,
+    hash: String,
+}
+
+/// Nonces and transactions of the transaction queue of one network
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueueStatus {
+    next_nonce: String,
+    released_nonces: Vec<String>,
+    pending: Vec<PendingTransaction>,
+    queued: usize,
+}
+
+impl QueueStatus {
+    /// Returns a QueueStatus for a network
+    ///
+    /// # Arguments
+    ///
+    /// * `next_nonce` - Nonce handed out once every released nonce is used
+    /// * `released_nonces` - Nonces given back by failed sends, that are handed out first
+    /// * `pending` - Nonce and latest hash of every transaction sent but not mined yet
+    /// * `queued` - Number of contract calls waiting to be sent
+    pub fn new(next_nonce: U256, released_nonces: &[U256], pending: &[(U256, H256)], queued: usize) -> Self {
+        QueueStatus {
+            next_nonce: next_nonce.to_string(),
+            released_nonces: released_nonces.iter().map(|nonce| nonce.to_string()).collect(),
+            pending: pending
+                .iter()
+                .map(|(nonce, hash)| PendingTransaction {
+                    nonce: nonce.to_string(),
+                    hash: format!("{:?}", hash),
+                })
+                .collect(),
+            queued,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueueResponse {
+    home: QueueStatus,
+    side: QueueStatus,
+}
+
+impl QueueResponse {
+    pub fn new(home: QueueStatus, side: QueueStatus) -> Self {
+        QueueResponse { home, side }
+    }
+}
+
+/// Block range of a rescan
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RescanQuery {
+    from: u64,
+    to: u64,
+}
+
+/// Sidechain block to plan a flush at, and the format of the plan, json (default) or csv
+#[derive(Clone, Debug, Deserialize)]
+pub struct FlushPlanQuery {
+    block: u64,
+    format: Option<String>,
+}
+
+/// Block of a balance snapshot, the number of holders to check against balanceOf (all by default), and the format of the
+/// snapshot, json (default) or csv
+#[derive(Clone, Debug, Deserialize)]
+pub struct BalancesQuery {
+    block: u64,
+    sample: Option<usize>,
+    format: Option<String>,
+}
+
+/// How a listener authenticates requests to operator routes
+#[derive(Clone, Debug)]
+enum AdminAuth {
+    /// Requests carry the bearer token, and are refused if none is configured
+    Token(Option<String>),
+    /// Clients presented a certificate signed by the configured CA when they connected
+    ClientCertificate,
+}
+
 /// This defines the http endpoint used to request a look at a specific transaction hash
 #[derive(Clone)]
 pub struct Endpoint {
     tx: mpsc::UnboundedSender<RequestType>,
     port: String,
+    audit: Arc<AuditLog>,
+    admin_token: Option<String>,
+    admin_tls: Option<AdminTls>,
+    rescans: Arc<RwLock<RescanJobs>>,
 }
 
 impl Endpoint {
@@ -68,39 +275,209 @@ impl Endpoint {
     ///
     /// * `tx` - Sender to report new queries
     /// * `port` - Handle to spawn new futures
-    pub fn new(tx: mpsc::UnboundedSender<RequestType>, port: u16) -> Self {
+    /// * `audit` - Audit log of relayed transfers, read directly so it is available while the relay restarts
+    /// * `admin_token` - Bearer token for operator routes, which are disabled without one
+    pub fn new(
+        tx: mpsc::UnboundedSender<RequestType>,
+        port: u16,
+        audit: Arc<AuditLog>,
+        admin_token: Option<String>,
+    ) -> Self {
         Self {
             tx,
             port: port.to_string(),
+            audit,
+            admin_token,
+            admin_tls: None,
+            rescans: Arc::new(RwLock::new(RescanJobs::default())),
         }
     }
 
-    /// Start listening on the given port for messages at /chain/tx_hash
-    pub fn start_server(self) {
-        let port = self.port.clone();
+    /// Serves the operator routes on a second listener, that only takes clients with a certificate signed by the CA
+    ///
+    /// # Arguments
+    ///
+    /// * `admin_tls` - Port, server certificate and client CA of the listener, not started if None
+    pub fn with_admin_tls(self, admin_tls: Option<AdminTls>) -> Self {
+        Self { admin_tls, ..self }
+    }
+
+    /// Start listening on the given port for messages at /chain/tx_hash, and on the admin tls port if configured
+    pub fn start_server(self) -> Result<(), Error> {
+        // Bind before spawning, so a port in use stops the relay instead of a thread that nothing watches
+        if let Some(tls) = self.admin_tls.clone() {
+            let acceptor = admin_acceptor(&tls)?;
+            let listener = TcpListener::bind(format!("0.0.0.0:{}", tls.port))?;
+            let endpoint = self.clone();
+            thread::spawn(move || {
+                let result = HttpServer::new(move || {
+                    App::new()
+                        .wrap(middleware::Logger::default())
+                        .configure(|cfg| endpoint.admin_routes(cfg, &AdminAuth::ClientCertificate))
+                })
+                .listen_ssl(listener, acceptor)
+                .and_then(|server| server.run());
+                if let Err(e) = result {
+                    error!("error running admin tls server: {:?}", e);
+                }
+            });
+        }
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))?;
         thread::spawn(move || {
-            HttpServer::new(move || {
-                let status_tx = self.tx.clone();
-                let hash_tx = self.tx.clone();
+            let result = HttpServer::new(move || {
+                let auth = AdminAuth::Token(self.admin_token.clone());
                 App::new()
                     .wrap(middleware::Logger::default())
-                    .service(web::resource("/status").route(web::get().to(move || {
-                        let tx = status_tx.clone();
-                        status(&tx)
-                    })))
-                    .service(web::resource("/{chain}/{tx_hash}").route(web::post().to(
-                        move |info: web::Path<(String, String)>| {
-                            let tx = hash_tx.clone();
-                            search(&tx, &info)
-                        },
-                    )))
+                    .configure(|cfg| self.public_routes(cfg))
+                    .configure(|cfg| self.admin_routes(cfg, &auth))
             })
-            .bind(format!("0.0.0.0:{}", port))
-            .unwrap()
-            .run()
-            .unwrap();
+            .listen(listener)
+            .and_then(|server| server.run());
+            if let Err(e) = result {
+                error!("error running server: {:?}", e);
+            }
         });
+        Ok(())
+    }
+
+    /// Adds the read-only routes, open to anyone who can reach the port
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Config of the app to add the routes to
+    fn public_routes(&self, cfg: &mut web::ServiceConfig) {
+        let status_tx = self.tx.clone();
+        let withdrawals_tx = self.tx.clone();
+        let audit = self.audit.clone();
+        let held_tx = self.tx.clone();
+        let paused_tx = self.tx.clone();
+        cfg.service(web::resource("/status").route(web::get().to(move || {
+            let tx = status_tx.clone();
+            status(&tx)
+        })))
+        .service(web::resource("/metrics").route(web::get().to(export_metrics)))
+        .service(
+            web::resource("/transfers")
+                .route(web::get().to(move |query: web::Query<AuditQuery>| transfers(&audit, &query))),
+        )
+        .service(web::resource("/held").route(web::get().to(move || {
+            let tx = held_tx.clone();
+            held(&tx)
+        })))
+        .service(web::resource("/paused").route(web::get().to(move || {
+            let tx = paused_tx.clone();
+            paused(&tx)
+        })))
+        .service(web::resource("/withdrawals/{chain}/{tx_hash}").route(web::get().to(
+            move |info: web::Path<(String, String)>| {
+                let tx = withdrawals_tx.clone();
+                withdrawals(&tx, &info)
+            },
+        )));
     }
+
+    /// Adds the operator routes, that change what the relay does or show its internals
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Config of the app to add the routes to
+    /// * `auth` - How the listener authenticates requests
+    fn admin_routes(&self, cfg: &mut web::ServiceConfig, auth: &AdminAuth) {
+        let release_tx = self.tx.clone();
+        let anchor_tx = self.tx.clone();
+        let queue_tx = self.tx.clone();
+        let unapprove_tx = self.tx.clone();
+        let hash_tx = self.tx.clone();
+        let pause_tx = self.tx.clone();
+        let resume_tx = self.tx.clone();
+        let rescan_tx = self.tx.clone();
+        let flush_plan_tx = self.tx.clone();
+        let balances_tx = self.tx.clone();
+        let (rescans, rescan_jobs) = (self.rescans.clone(), self.rescans.clone());
+        let (release_auth, anchor_auth, queue_auth, unapprove_auth, hash_auth) =
+            (auth.clone(), auth.clone(), auth.clone(), auth.clone(), auth.clone());
+        let (pause_auth, resume_auth, rescan_auth, rescans_auth, flush_plan_auth) =
+            (auth.clone(), auth.clone(), auth.clone(), auth.clone(), auth.clone());
+        let balances_auth = auth.clone();
+        cfg.service(web::resource("/held/{withdrawal_hash}/release").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<String>| {
+                let tx = release_tx.clone();
+                release(&tx, &request, &release_auth, &info)
+            },
+        )))
+        .service(
+            web::resource("/queue").route(web::get().to(move |request: HttpRequest| {
+                let tx = queue_tx.clone();
+                queue(&tx, &request, &queue_auth)
+            })),
+        )
+        .service(web::resource("/flush/plan").route(web::get().to(
+            move |request: HttpRequest, query: web::Query<FlushPlanQuery>| {
+                let tx = flush_plan_tx.clone();
+                flush_plan(&tx, &request, &flush_plan_auth, &query)
+            },
+        )))
+        .service(web::resource("/balances/{chain}").route(web::get().to(
+            move |request: HttpRequest, info: web::Path<String>, query: web::Query<BalancesQuery>| {
+                let tx = balances_tx.clone();
+                balances(&tx, &request, &balances_auth, &info, &query)
+            },
+        )))
+        .service(web::resource("/rescans/{id}").route(web::get().to(
+            move |request: HttpRequest, info: web::Path<u64>| rescan_job(&rescan_jobs, &request, &rescans_auth, *info),
+        )))
+        // Registered before /{chain}/{tx_hash}, which also matches /anchors, /pause, /resume and /{chain}/rescan
+        .service(web::resource("/{chain}/rescan").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<String>, query: web::Query<RescanQuery>| {
+                let tx = rescan_tx.clone();
+                rescan(&tx, &rescans, &request, &rescan_auth, &info, &query)
+            },
+        )))
+        .service(web::resource("/anchors/{block_number}").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<u64>| {
+                let tx = anchor_tx.clone();
+                anchor(&tx, &request, &anchor_auth, *info)
+            },
+        )))
+        .service(web::resource("/pause/{target}").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<String>| {
+                let tx = pause_tx.clone();
+                pause(&tx, &request, &pause_auth, &info, true)
+            },
+        )))
+        .service(web::resource("/resume/{target}").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<String>| {
+                let tx = resume_tx.clone();
+                pause(&tx, &request, &resume_auth, &info, false)
+            },
+        )))
+        .service(web::resource("/{chain}/{tx_hash}/unapprove").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<(String, String)>| {
+                let tx = unapprove_tx.clone();
+                unapprove(&tx, &request, &unapprove_auth, &info)
+            },
+        )))
+        .service(web::resource("/{chain}/{tx_hash}").route(web::post().to(
+            move |request: HttpRequest, info: web::Path<(String, String)>| {
+                let tx = hash_tx.clone();
+                search(&tx, &request, &hash_auth, &info)
+            },
+        )));
+    }
+}
+
+/// Returns the tls acceptor of the admin listener, which refuses clients without a certificate signed by the CA
+///
+/// # Arguments
+///
+/// * `tls` - Server certificate, key and client CA files
+fn admin_acceptor(tls: &AdminTls) -> Result<SslAcceptorBuilder, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&tls.key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&tls.cert)?;
+    builder.set_ca_file(&tls.client_ca)?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    Ok(builder)
 }
 
 /// Return an HttpResponse that contains the status of this relay
@@ -147,28 +524,654 @@ fn status(tx: &mpsc::UnboundedSender<RequestType>) -> Box<dyn Future<Item = Http
     Box::new(request_future)
 }
 
-/// Return an HttpResponse given the success of sending the txhash and chain to be scanned
+/// Return an HttpResponse with all relay metrics in the Prometheus text format.
+/// Balances and last blocks are as of the latest RefreshMetrics check, so scrapes make no calls to the chains
+fn export_metrics() -> Result<HttpResponse, EndpointError> {
+    let body = metrics::gather().map_err(|e| {
+        error!("error encoding metrics: {:?}", e);
+        EndpointError::UnableToGetMetrics
+    })?;
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body))
+}
+
+/// Return an HttpResponse with a page of the audit log of relayed transfers
+///
+/// # Arguments
+///
+/// * `audit` - Audit log to read
+/// * `query` - Filters and page to return
+fn transfers(audit: &AuditLog, query: &AuditQuery) -> Result<HttpResponse, EndpointError> {
+    let page = audit.query(query).map_err(|e| {
+        error!("error reading audit log: {:?}", e);
+        EndpointError::UnableToGetTransfers
+    })?;
+    let body = serde_json::to_string(&page).map_err(|e| {
+        error!("error parsing response: {:?}", e);
+        EndpointError::UnableToGetTransfers
+    })?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+}
+
+/// Return an HttpResponse with the withdrawal of every transfer in a transaction, as recorded on the target contract
 ///
 /// # Arguments
 ///
 /// * `tx` - Sender to report new requests
 /// * `info` - Tuple of two strings. The chain and tx hash.
-fn search(
+fn withdrawals(
     tx: &mpsc::UnboundedSender<RequestType>,
     info: &web::Path<(String, String)>,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    let (tx_hash, chain) = match parse_transaction(info) {
+        Ok(parsed) => parsed,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let (withdrawals_tx, withdrawals_rx) = mpsc::unbounded();
+    let request = RequestType::Withdrawals(chain, tx_hash, withdrawals_tx);
+    let send_result = tx.unbounded_send(request);
+    if send_result.is_err() {
+        error!("error sending withdrawals request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    let hash = info.1.clone();
+    Box::new(
+        withdrawals_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToGetWithdrawals)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(withdrawals)) => {
+                    let body = serde_json::to_string(&withdrawals).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToGetWithdrawals
+                    })?;
+                    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+                }
+                Some(Err(())) => Err(EndpointError::TransfersNotFound(hash)),
+                None => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToGetWithdrawals)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with every transfer held over a value limit
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+fn held(tx: &mpsc::UnboundedSender<RequestType>) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    let (held_tx, held_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Held(held_tx));
+    if send_result.is_err() {
+        error!("error sending held request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        held_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToGetHeld)
+            .and_then(|(message, _)| match message {
+                Some(Ok(held)) => {
+                    let body = serde_json::to_string(&held).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToGetHeld
+                    })?;
+                    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+                }
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToGetHeld)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse given the success of releasing a held transfer, which is then approved regardless of limits
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Withdrawal hash of the held transfer
+fn release(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<String>,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let hash = info.to_string();
+    let withdrawal_hash = match H256::from_str(utils::clean_0x(&hash)) {
+        Ok(withdrawal_hash) => withdrawal_hash,
+        Err(_) => return Box::new(future::err(EndpointError::BadWithdrawalHash(hash))),
+    };
+    let (release_tx, release_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Release(withdrawal_hash, release_tx));
+    if send_result.is_err() {
+        error!("error sending release request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        release_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToSend)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(true)) => {
+                    info!("operator released held transfer {}", hash);
+                    Ok(HttpResponse::new(StatusCode::OK))
+                }
+                Some(Ok(false)) => Err(EndpointError::HeldTransferNotFound(hash)),
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToSend)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with what is paused, and how much each paused target buffered
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+fn paused(tx: &mpsc::UnboundedSender<RequestType>) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    let (paused_tx, paused_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Paused(paused_tx));
+    if send_result.is_err() {
+        error!("error sending pause status request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+    Box::new(pause_response(paused_rx))
+}
+
+/// Return an HttpResponse with the pause state, after pausing or resuming a target.
+/// Resuming approves the transfers, or posts the anchors, buffered while it was paused
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Target to pause or resume: home-to-side, side-to-home or anchors
+/// * `paused` - True to pause the target, false to resume it
+fn pause(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<String>,
+    paused: bool,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let target = match PauseTarget::from_str(info.as_str()) {
+        Ok(target) => target,
+        Err(target) => return Box::new(future::err(EndpointError::BadPauseTarget(target))),
+    };
+    let (pause_tx, pause_rx) = mpsc::unbounded();
+    let request = if paused {
+        RequestType::Pause(target, pause_tx)
+    } else {
+        RequestType::Resume(target, pause_tx)
+    };
+    let send_result = tx.unbounded_send(request);
+    if send_result.is_err() {
+        error!("error sending pause request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+    info!("operator {} {}", if paused { "paused" } else { "resumed" }, target);
+    Box::new(pause_response(pause_rx))
+}
+
+/// Returns a Future with an HttpResponse of the pause state received
+///
+/// # Arguments
+///
+/// * `rx` - Receiver of the pause state
+fn pause_response(
+    rx: mpsc::UnboundedReceiver<Result<PauseStatus, ()>>,
+) -> impl Future<Item = HttpResponse, Error = EndpointError> {
+    rx.into_future()
+        .map_err(|_| EndpointError::UnableToGetPaused)
+        .and_then(|(message, _)| match message {
+            Some(Ok(status)) => {
+                let body = serde_json::to_string(&status).map_err(|e| {
+                    error!("error parsing response: {:?}", e);
+                    EndpointError::UnableToGetPaused
+                })?;
+                Ok(HttpResponse::Ok().content_type("application/json").body(body))
+            }
+            _ => {
+                error!("error receiving message");
+                Err(EndpointError::UnableToGetPaused)
+            }
+        })
+}
+
+/// Return an HttpResponse with the progress of a new job that rescans a block range for missed transfers, and approves
+/// them. The relay registers the job once it takes the request, so no job is left running if the request is dropped
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `rescans` - Rescan jobs, where the relay registers the new job
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Chain to rescan
+/// * `query` - First and last block to rescan
+fn rescan(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    rescans: &Arc<RwLock<RescanJobs>>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<String>,
+    query: &RescanQuery,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let chain = match parse_chain(info.as_str()) {
+        Ok(chain) => chain,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    if query.from > query.to {
+        return Box::new(future::err(EndpointError::BadRescanRange(query.from, query.to)));
+    }
+    let (rescan_tx, rescan_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Rescan(
+        chain,
+        query.from,
+        query.to,
+        rescans.clone(),
+        rescan_tx,
+    ));
+    if send_result.is_err() {
+        error!("error sending rescan request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    let (from, to) = (query.from, query.to);
+    Box::new(
+        rescan_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToGetRescan)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(progress)) => {
+                    info!(
+                        "operator started rescan {} from {} to {} on {:?}",
+                        progress.id, from, to, chain
+                    );
+                    let body = serde_json::to_string(&progress).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToGetRescan
+                    })?;
+                    Ok(HttpResponse::Accepted().content_type("application/json").body(body))
+                }
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToGetRescan)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with the progress of a rescan job
+///
+/// # Arguments
+///
+/// * `rescans` - Rescan jobs
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `id` - Id of the job
+fn rescan_job(
+    rescans: &RwLock<RescanJobs>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    id: u64,
 ) -> Result<HttpResponse, EndpointError> {
+    authorize(request, auth)?;
+    let progress = rescans
+        .read()
+        .map_err(|e| {
+            error!("error acquiring rescan jobs lock: {:?}", e);
+            EndpointError::UnableToGetRescan
+        })?
+        .get(id)
+        .ok_or(EndpointError::RescanNotFound(id))?;
+    let body = serde_json::to_string(&progress).map_err(|e| {
+        error!("error parsing response: {:?}", e);
+        EndpointError::UnableToGetRescan
+    })?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+}
+
+/// Return an HttpResponse with the plan of a flush at a sidechain block, without flushing
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `query` - Block of the flush and format of the plan
+fn flush_plan(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    query: &FlushPlanQuery,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let format = match query.format.as_ref().map(|format| ReportFormat::from_str(format)) {
+        None => ReportFormat::Json,
+        Some(Ok(format)) => format,
+        Some(Err(format)) => return Box::new(future::err(EndpointError::BadFormat(format))),
+    };
+    let (plan_tx, plan_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::FlushPlan(query.block, plan_tx));
+    if send_result.is_err() {
+        error!("error sending flush plan request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        plan_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToPlanFlush)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(plan)) => {
+                    let body = plan.render(format).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToPlanFlush
+                    })?;
+                    Ok(HttpResponse::Ok().content_type(format.content_type()).body(body))
+                }
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToPlanFlush)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with the token balance of every holder on a chain at a block, checked against balanceOf
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Chain of the token
+/// * `query` - Block of the snapshot, holders to check and format of the snapshot
+fn balances(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<String>,
+    query: &BalancesQuery,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let chain = match parse_chain(info.as_str()) {
+        Ok(chain) => chain,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let format = match query.format.as_ref().map(|format| ReportFormat::from_str(format)) {
+        None => ReportFormat::Json,
+        Some(Ok(format)) => format,
+        Some(Err(format)) => return Box::new(future::err(EndpointError::BadFormat(format))),
+    };
+    let (snapshot_tx, snapshot_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Balances(chain, query.block, query.sample, snapshot_tx));
+    if send_result.is_err() {
+        error!("error sending balances request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        snapshot_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToSnapshotBalances)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(snapshot)) => {
+                    let body = snapshot.render(format).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToSnapshotBalances
+                    })?;
+                    Ok(HttpResponse::Ok().content_type(format.content_type()).body(body))
+                }
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToSnapshotBalances)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with the nonces and pending transactions of the transaction queue of each network
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+fn queue(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let (queue_tx, queue_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Queue(queue_tx));
+    if send_result.is_err() {
+        error!("error sending queue request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        queue_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToGetQueue)
+            .and_then(|(message, _)| match message {
+                Some(Ok(queue)) => {
+                    let body = serde_json::to_string(&queue).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToGetQueue
+                    })?;
+                    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+                }
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToGetQueue)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with the sidechain block posted again as an anchor on the homechain
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `block_number` - Sidechain block to anchor
+fn anchor(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    block_number: u64,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let (anchor_tx, anchor_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Anchor(block_number, anchor_tx));
+    if send_result.is_err() {
+        error!("error sending anchor request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    Box::new(
+        anchor_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToAnchor)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(Some(anchor))) => {
+                    info!("operator anchored sidechain block {}", block_number);
+                    let body = serde_json::to_string(&anchor).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToAnchor
+                    })?;
+                    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+                }
+                Some(Ok(None)) => Err(EndpointError::AnchorBlockNotFound(block_number)),
+                _ => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToAnchor)
+                }
+            }),
+    )
+}
+
+/// Return an HttpResponse with the withdrawal hash of every transfer in a transaction that is being unapproved
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Tuple of two strings. The chain and tx hash.
+fn unapprove(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<(String, String)>,
+) -> Box<dyn Future<Item = HttpResponse, Error = EndpointError>> {
+    if let Err(e) = authorize(request, auth) {
+        return Box::new(future::err(e));
+    }
+    let (tx_hash, chain) = match parse_transaction(info) {
+        Ok(parsed) => parsed,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let (unapprove_tx, unapprove_rx) = mpsc::unbounded();
+    let send_result = tx.unbounded_send(RequestType::Unapprove(chain, tx_hash, unapprove_tx));
+    if send_result.is_err() {
+        error!("error sending unapprove request: {:?}", send_result.err());
+        return Box::new(future::err(EndpointError::UnableToSend));
+    }
+
+    let hash = info.1.clone();
+    Box::new(
+        unapprove_rx
+            .into_future()
+            .map_err(|_| EndpointError::UnableToSend)
+            .and_then(move |(message, _)| match message {
+                Some(Ok(withdrawal_hashes)) => {
+                    info!("operator unapproved transfers in {} on {:?}", hash, chain);
+                    let hashes: Vec<String> = withdrawal_hashes.iter().map(|h| format!("{:?}", h)).collect();
+                    let body = serde_json::to_string(&hashes).map_err(|e| {
+                        error!("error parsing response: {:?}", e);
+                        EndpointError::UnableToSend
+                    })?;
+                    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+                }
+                Some(Err(())) => Err(EndpointError::TransfersNotFound(hash)),
+                None => {
+                    error!("error receiving message");
+                    Err(EndpointError::UnableToSend)
+                }
+            }),
+    )
+}
+
+/// Returns an error unless the request is authenticated for operator routes
+///
+/// # Arguments
+///
+/// * `request` - The http request
+/// * `auth` - How the listener authenticates operator requests
+fn authorize(request: &HttpRequest, auth: &AdminAuth) -> Result<(), EndpointError> {
+    let token = match auth {
+        AdminAuth::ClientCertificate => return Ok(()),
+        AdminAuth::Token(token) => token.as_ref().ok_or(EndpointError::Unauthorized)?,
+    };
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let expected = format!("Bearer {}", token);
+    // Compare every byte, so the time taken does not tell how much of the token matched
+    let difference = provided
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if provided.len() == expected.len() && difference == 0 {
+        Ok(())
+    } else {
+        warn!("refused unauthorized request to {}", request.path());
+        Err(EndpointError::Unauthorized)
+    }
+}
+
+/// Returns the transaction hash and chain in the path
+///
+/// # Arguments
+///
+/// * `info` - Tuple of two strings. The chain and tx hash.
+fn parse_transaction(info: &web::Path<(String, String)>) -> Result<(H256, NetworkType), EndpointError> {
     let clean = utils::clean_0x(&info.1);
     let tx_hash: H256 = H256::from_str(&clean[..]).map_err(|e| {
         error!("error parsing transaction hash: {:?}", e);
         EndpointError::BadTransactionHash(info.1.clone())
     })?;
-    let chain = if info.0.to_uppercase() == "HOME" {
+    let chain = parse_chain(&info.0)?;
+    Ok((tx_hash, chain))
+}
+
+/// Returns the chain named in the path
+///
+/// # Arguments
+///
+/// * `chain` - Name of the chain, home or side
+fn parse_chain(chain: &str) -> Result<NetworkType, EndpointError> {
+    if chain.to_uppercase() == HOME {
         Ok(NetworkType::Home)
-    } else if info.0.to_uppercase() == "SIDE" {
+    } else if chain.to_uppercase() == SIDE {
         Ok(NetworkType::Side)
     } else {
-        Err(EndpointError::BadChain(info.0.clone()))
-    }?;
+        Err(EndpointError::BadChain(chain.to_string()))
+    }
+}
+
+/// Return an HttpResponse given the success of sending the txhash and chain to be scanned
+///
+/// # Arguments
+///
+/// * `tx` - Sender to report new requests
+/// * `request` - The http request, carrying the admin token
+/// * `auth` - How the listener authenticates operator requests
+/// * `info` - Tuple of two strings. The chain and tx hash.
+fn search(
+    tx: &mpsc::UnboundedSender<RequestType>,
+    request: &HttpRequest,
+    auth: &AdminAuth,
+    info: &web::Path<(String, String)>,
+) -> Result<HttpResponse, EndpointError> {
+    authorize(request, auth)?;
+    let (tx_hash, chain) = parse_transaction(info)?;
     let request = RequestType::Hash(chain, tx_hash);
     tx.unbounded_send(request).map_err(|e| {
         error!("error sending hash request: {:?}", e);
@@ -176,3 +1179,54 @@ fn search(
     })?;
     Ok(HttpResponse::new(StatusCode::OK))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    #[test]
+    fn authorize_should_require_matching_bearer_token() {
+        // arrange
+        let auth = AdminAuth::Token(Some("secret".to_string()));
+        let valid = test::TestRequest::with_header("Authorization", "Bearer secret").to_http_request();
+        let invalid = test::TestRequest::with_header("Authorization", "Bearer secreT").to_http_request();
+        let missing = test::TestRequest::default().to_http_request();
+        // act
+        let accepted = authorize(&valid, &auth);
+        let wrong_token = authorize(&invalid, &auth);
+        let no_header = authorize(&missing, &auth);
+        let not_configured = authorize(&valid, &AdminAuth::Token(None));
+        let client_certificate = authorize(&missing, &AdminAuth::ClientCertificate);
+        // assert
+        assert!(accepted.is_ok());
+        assert!(wrong_token.is_err());
+        assert!(no_header.is_err());
+        assert!(not_configured.is_err());
+        assert!(client_certificate.is_ok());
+    }
+
+    #[test]
+    fn withdrawal_status_should_report_whether_relay_approved() {
+        // arrange
+        let account = Address::from_low_u64_be(1);
+        let other = Address::from_low_u64_be(2);
+        let withdrawal = (Address::from_low_u64_be(3), U256::from(90), false);
+        // act
+        let approved = WithdrawalStatus::new(
+            H256::zero(),
+            (withdrawal.0, 100.into()),
+            withdrawal,
+            &[other, account],
+            account,
+        );
+        let unapproved = WithdrawalStatus::new(H256::zero(), (withdrawal.0, 100.into()), withdrawal, &[other], account);
+        // assert
+        assert!(approved.approved);
+        assert_eq!(approved.approvals.len(), 2);
+        assert_eq!(approved.amount, "90");
+        assert_eq!(approved.transfer_amount, "100");
+        assert!(!unapproved.approved);
+        assert!(!unapproved.processed);
+    }
+}
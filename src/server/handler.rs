@@ -1,13 +1,24 @@
-use crate::relay::{Network, NetworkType};
-use crate::server::endpoint::{NetworkStatus, RequestType, StatusResponse};
+use crate::anchors::anchor::Anchor;
+use crate::flush::plan::PlanFlush;
+use crate::flush::snapshot::SnapshotBalances;
+use crate::metrics;
+use crate::relay::{Network, NetworkType, TransferApprovalState};
+use crate::server::endpoint::{
+    AnchorResponse, HeldResponse, NetworkStatus, QueueResponse, QueueStatus, RequestType, StatusResponse,
+    WithdrawalStatus,
+};
+use crate::store::pause::{PauseStatus, PauseTarget};
 use crate::transfers::past::{FindTransferInTransaction, ValidateAndApproveTransfer};
+use crate::transfers::rescan::RescanTransfers;
+use crate::transfers::withdrawal::{DoesRequireApproval, GetApprovers};
+use std::time::Duration;
 use tokio_core::reactor;
 use web3::contract::Options;
 use web3::futures::future;
 use web3::futures::prelude::*;
 use web3::futures::sync::mpsc;
 use web3::futures::try_ready;
-use web3::types::{BlockNumber, U256};
+use web3::types::{BlockNumber, H256, U256};
 use web3::DuplexTransport;
 
 pub struct HandleRequests<T: DuplexTransport + 'static> {
@@ -79,14 +90,329 @@ impl<T: DuplexTransport + 'static> Future for HandleRequests<T> {
                     self.handle.spawn(future);
                 }
                 Some(RequestType::Status(ref tx)) => handle.spawn(StatusCheck::new(&homechain, &sidechain, tx)),
+                Some(RequestType::Withdrawals(chain, tx_hash, ref tx)) => {
+                    let (source, target) = match chain {
+                        NetworkType::Home => (homechain, sidechain),
+                        NetworkType::Side => (sidechain, homechain),
+                    };
+                    handle.spawn(WithdrawalsCheck::new(&source, &target, &tx_hash, tx))
+                }
+                Some(RequestType::Held(ref tx)) => {
+                    let response = match (homechain.limits.read(), sidechain.limits.read()) {
+                        (Ok(home), Ok(side)) => Ok(HeldResponse::new(home.held(), side.held())),
+                        _ => {
+                            error!("error acquiring limits lock");
+                            Err(())
+                        }
+                    };
+                    if tx.unbounded_send(response).is_err() {
+                        error!("error sending held response");
+                    }
+                }
+                Some(RequestType::Release(withdrawal_hash, ref tx)) => {
+                    let released = release(&homechain, &sidechain, &withdrawal_hash, &handle)
+                        || release(&sidechain, &homechain, &withdrawal_hash, &handle);
+                    if tx.unbounded_send(Ok(released)).is_err() {
+                        error!("error sending release response");
+                    }
+                }
+                Some(RequestType::Anchor(block_number, ref tx)) => {
+                    handle.spawn(anchor_block(&sidechain, &homechain, block_number, &handle, tx))
+                }
+                Some(RequestType::Unapprove(chain, tx_hash, ref tx)) => {
+                    let (source, target) = match chain {
+                        NetworkType::Home => (homechain, sidechain),
+                        NetworkType::Side => (sidechain, homechain),
+                    };
+                    let reply = tx.clone();
+                    let future = FindTransferInTransaction::new(&source, &tx_hash).then(move |result| {
+                        let withdrawal_hashes = result.map(|transfers| {
+                            transfers
+                                .iter()
+                                .map(|transfer| {
+                                    handle.spawn(transfer.unapprove_withdrawal(&source, &target));
+                                    transfer.get_withdrawal_hash()
+                                })
+                                .collect()
+                        });
+                        if reply.unbounded_send(withdrawal_hashes).is_err() {
+                            error!("error sending unapprove response");
+                        }
+                        Ok(())
+                    });
+                    self.handle.spawn(future);
+                }
+                Some(RequestType::Queue(ref tx)) => {
+                    let response = QueueResponse::new(queue_status(&homechain), queue_status(&sidechain));
+                    if tx.unbounded_send(Ok(response)).is_err() {
+                        error!("error sending queue response");
+                    }
+                }
+                Some(RequestType::Pause(target, ref tx)) => {
+                    let status = match homechain.pause.write() {
+                        Ok(mut pause) => {
+                            if pause.pause(target) {
+                                info!("paused {}", target);
+                            }
+                            metrics::paused(&pause.status());
+                            Ok(pause.status())
+                        }
+                        Err(e) => {
+                            error!("error acquiring pause lock: {:?}", e);
+                            Err(())
+                        }
+                    };
+                    if tx.unbounded_send(status).is_err() {
+                        error!("error sending pause response");
+                    }
+                }
+                Some(RequestType::Resume(target, ref tx)) => {
+                    let status = match target {
+                        PauseTarget::HomeToSide => resume_transfers(&homechain, &sidechain, &handle),
+                        PauseTarget::SideToHome => resume_transfers(&sidechain, &homechain, &handle),
+                        PauseTarget::Anchors => resume_anchors(&sidechain, &homechain, &handle),
+                    };
+                    if tx.unbounded_send(status).is_err() {
+                        error!("error sending resume response");
+                    }
+                }
+                Some(RequestType::Rescan(chain, from, to, ref rescans, ref tx)) => {
+                    let (source, target) = match chain {
+                        NetworkType::Home => (homechain, sidechain),
+                        NetworkType::Side => (sidechain, homechain),
+                    };
+                    // Registered here rather than in the endpoint, so only jobs the relay runs are listed
+                    let progress = match rescans.write() {
+                        Ok(mut rescans) => {
+                            let job = rescans.start(chain, from, to);
+                            handle.spawn(RescanTransfers::new(&source, &target, &handle, &job));
+                            job.read().map(|progress| progress.clone()).map_err(|e| {
+                                error!("error acquiring rescan job lock: {:?}", e);
+                            })
+                        }
+                        Err(e) => {
+                            error!("error acquiring rescan jobs lock: {:?}", e);
+                            Err(())
+                        }
+                    };
+                    if tx.unbounded_send(progress).is_err() {
+                        error!("error sending rescan response");
+                    }
+                }
+                Some(RequestType::FlushPlan(block_number, ref tx)) => {
+                    let reply = tx.clone();
+                    let future = PlanFlush::new(&sidechain, &homechain, block_number.into()).then(move |plan| {
+                        if reply.unbounded_send(plan).is_err() {
+                            error!("error sending flush plan response");
+                        }
+                        Ok(())
+                    });
+                    handle.spawn(future)
+                }
+                Some(RequestType::Balances(chain, block_number, sample, ref tx)) => {
+                    let source = match chain {
+                        NetworkType::Home => homechain,
+                        NetworkType::Side => sidechain,
+                    };
+                    let reply = tx.clone();
+                    let future = SnapshotBalances::new(&source, block_number.into(), sample).then(move |snapshot| {
+                        if reply.unbounded_send(snapshot).is_err() {
+                            error!("error sending balances response");
+                        }
+                        Ok(())
+                    });
+                    handle.spawn(future)
+                }
+                Some(RequestType::Paused(ref tx)) => {
+                    let status = homechain.pause.read().map(|pause| pause.status()).map_err(|e| {
+                        error!("error acquiring pause lock: {:?}", e);
+                    });
+                    if tx.unbounded_send(status).is_err() {
+                        error!("error sending pause status response");
+                    }
+                }
                 None => {}
             };
         }
     }
 }
 
+/// Returns the nonces and pending transactions of the transaction queue of a network
+///
+/// # Arguments
+///
+/// * `network` - Network of the queue
+fn queue_status<T: DuplexTransport + 'static>(network: &Network<T>) -> QueueStatus {
+    let (next_nonce, released_nonces) = network.transactions.nonces();
+    QueueStatus::new(
+        next_nonce,
+        &released_nonces,
+        &network.transactions.pending_transactions(),
+        network.transactions.depth(),
+    )
+}
+
+/// Returns a Future that posts a confirmed block of the source network as an anchor on the target, regardless of the
+/// anchor frequency. Replies with None if the block is not found or not confirmed yet
+///
+/// # Arguments
+///
+/// * `source` - Network where the block is, the sidechain
+/// * `target` - Network where the anchor is posted, the homechain
+/// * `block_number` - Number of the block to anchor
+/// * `handle` - Handle to spawn the anchor transaction
+/// * `tx` - Sender to reply with the anchor
+fn anchor_block<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    target: &Network<T>,
+    block_number: u64,
+    handle: &reactor::Handle,
+    tx: &mpsc::UnboundedSender<Result<Option<AnchorResponse>, ()>>,
+) -> impl Future<Item = (), Error = ()> {
+    let source = source.clone();
+    let target = target.clone();
+    let handle = handle.clone();
+    let reply = tx.clone();
+    let network_type = source.network_type;
+    let confirmations = source.confirmations;
+    source
+        .web3
+        .eth()
+        .block_number()
+        .map_err(move |e| {
+            error!("error getting block number on {:?}: {:?}", network_type, e);
+        })
+        .and_then(move |head| {
+            if head.as_u64() < block_number.saturating_add(confirmations) {
+                warn!("block {} is not confirmed on {:?} yet", block_number, network_type);
+                return future::Either::A(future::ok(None));
+            }
+            future::Either::B(Anchor::fetch(&source, block_number.into()))
+        })
+        .then(move |result| {
+            let response = result.map(|anchor| {
+                anchor.map(|anchor| {
+                    handle.spawn(anchor.process(&target));
+                    AnchorResponse::new(anchor.block_number().as_u64(), anchor.block_hash())
+                })
+            });
+            if reply.unbounded_send(response).is_err() {
+                error!("error sending anchor response");
+            }
+            Ok(())
+        })
+}
+
+/// Resumes approvals for transfers from the source network, and approves the transfers buffered while paused, one at a
+/// time in the order they were buffered. Returns the pause state after resuming
+///
+/// # Arguments
+///
+/// * `source` - Network where the transfers were made
+/// * `target` - Network where the withdrawals are approved
+/// * `handle` - Handle to spawn the approvals
+fn resume_transfers<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    target: &Network<T>,
+    handle: &reactor::Handle,
+) -> Result<PauseStatus, ()> {
+    let (resumed, status) = {
+        let mut pause = source.pause.write().map_err(|e| {
+            error!("error acquiring pause lock: {:?}", e);
+        })?;
+        let resumed = pause.resume_transfers(source.network_type);
+        metrics::paused(&pause.status());
+        (resumed, pause.status())
+    };
+    let resumed_target = PauseTarget::transfers_from(source.network_type);
+    // Only the resume that unpaused the backlog approves it, so no transfer is approved twice at once
+    if resumed {
+        info!("resumed {}, approving buffered transfers", resumed_target);
+        handle.spawn(source.approve_backlog(target, handle));
+    } else {
+        info!("{} was not paused", resumed_target);
+    }
+    Ok(status)
+}
+
+/// Resumes anchors, and posts the anchors buffered while paused in the order they were buffered, unless the sidechain
+/// was flushed. Returns the pause state after resuming
+///
+/// # Arguments
+///
+/// * `source` - Network where the blocks are, the sidechain
+/// * `target` - Network where the anchors are posted, the homechain
+/// * `handle` - Handle to spawn the anchor transactions
+fn resume_anchors<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    target: &Network<T>,
+    handle: &reactor::Handle,
+) -> Result<PauseStatus, ()> {
+    let (backlog, status) = {
+        let mut pause = source.pause.write().map_err(|e| {
+            error!("error acquiring pause lock: {:?}", e);
+        })?;
+        let backlog = pause.resume_anchors();
+        metrics::paused(&pause.status());
+        (backlog, pause.status())
+    };
+    let flushed = source.flushed.read().map(|flushed| flushed.is_some()).unwrap_or(true);
+    if flushed {
+        warn!(
+            "resumed anchors, dropping {} buffered anchors after the flush",
+            backlog.len()
+        );
+    } else {
+        info!("resumed anchors, posting {} buffered anchors", backlog.len());
+        for anchor in backlog {
+            handle.spawn(anchor.process(target));
+        }
+    }
+    Ok(status)
+}
+
+/// Releases a transfer held on the source network and approves it on the target, regardless of limits.
+/// Returns false if the transfer is not held on the source
+///
+/// # Arguments
+///
+/// * `source` - Network where the transfer was made
+/// * `target` - Network where the withdrawal is approved
+/// * `withdrawal_hash` - Withdrawal hash of the held transfer
+/// * `handle` - Handle to spawn the approval
+fn release<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    target: &Network<T>,
+    withdrawal_hash: &H256,
+    handle: &reactor::Handle,
+) -> bool {
+    let transfer = match source.limits.write() {
+        Ok(mut limits) => {
+            let transfer = limits.release(withdrawal_hash);
+            metrics::held_transfers(source.network_type, limits.held().len());
+            transfer
+        }
+        Err(e) => {
+            error!("error acquiring limits lock on {:?}: {:?}", source.network_type, e);
+            None
+        }
+    };
+    match transfer {
+        Some(transfer) => {
+            info!("releasing held transfer on {:?}: {}", source.network_type, transfer);
+            if let Ok(mut pending) = source.pending.write() {
+                pending.put(*withdrawal_hash, TransferApprovalState::Sent);
+            }
+            handle.spawn(transfer.approve_withdrawal(source, target));
+            true
+        }
+        None => false,
+    }
+}
+
 pub struct StatusCheck {
     future: Box<dyn Future<Item = Vec<Option<U256>>, Error = ()>>,
+    endpoints: (Option<String>, Option<String>),
     tx: mpsc::UnboundedSender<Result<StatusResponse, ()>>,
 }
 
@@ -156,7 +482,12 @@ impl StatusCheck {
             Box::new(side_nct_future),
         ];
         let future = Box::new(future::join_all(futures));
-        StatusCheck { future, tx: tx.clone() }
+        let endpoint = |network: &Network<T>| network.endpoint.read().ok().and_then(|endpoint| endpoint.clone());
+        StatusCheck {
+            future,
+            endpoints: (endpoint(homechain), endpoint(sidechain)),
+            tx: tx.clone(),
+        }
     }
 }
 
@@ -167,8 +498,17 @@ impl Future for StatusCheck {
         let results = self.future.poll();
         match results {
             Ok(Async::Ready(results)) => {
-                let home = NetworkStatus::new(results[0], results[1], results[2]);
-                let side = NetworkStatus::new(results[3], results[4], results[5]);
+                metrics::balances(NetworkType::Home, results[0], results[2]);
+                metrics::balances(NetworkType::Side, results[3], results[5]);
+                if let Some(block) = results[1] {
+                    metrics::last_block(NetworkType::Home, block.as_u64());
+                }
+                if let Some(block) = results[4] {
+                    metrics::last_block(NetworkType::Side, block.as_u64());
+                }
+                let (home_endpoint, side_endpoint) = self.endpoints.clone();
+                let home = NetworkStatus::new(results[0], results[1], results[2], home_endpoint);
+                let side = NetworkStatus::new(results[3], results[4], results[5], side_endpoint);
                 let send_result = self.tx.unbounded_send(Ok(StatusResponse::new(home, side)));
                 if send_result.is_err() {
                     error!("error sending status response");
@@ -186,3 +526,133 @@ impl Future for StatusCheck {
         Ok(Async::Ready(()))
     }
 }
+
+/// How often RefreshMetrics checks the balances and last blocks reported on /metrics
+pub const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+enum RefreshMetricsState {
+    Check(StatusCheck),
+    Wait(reactor::Timeout),
+}
+
+/// Future that keeps the balance and last block gauges up to date with a StatusCheck every METRICS_REFRESH_INTERVAL,
+/// so scraping /metrics makes no calls to the chains. Never resolves
+pub struct RefreshMetrics<T: DuplexTransport + 'static> {
+    homechain: Network<T>,
+    sidechain: Network<T>,
+    handle: reactor::Handle,
+    tx: mpsc::UnboundedSender<Result<StatusResponse, ()>>,
+    rx: mpsc::UnboundedReceiver<Result<StatusResponse, ()>>,
+    state: RefreshMetricsState,
+}
+
+impl<T: DuplexTransport + 'static> RefreshMetrics<T> {
+    /// Returns a newly created RefreshMetrics Future, that checks the status right away
+    ///
+    /// # Arguments
+    ///
+    /// * `homechain` - Network to be used as the home chain
+    /// * `sidechain` - Network to be used as the side chain
+    /// * `handle` - Handle to create the timeouts between checks
+    pub fn new(homechain: &Network<T>, sidechain: &Network<T>, handle: &reactor::Handle) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let state = RefreshMetricsState::Check(StatusCheck::new(homechain, sidechain, &tx));
+        RefreshMetrics {
+            homechain: homechain.clone(),
+            sidechain: sidechain.clone(),
+            handle: handle.clone(),
+            tx,
+            rx,
+            state,
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for RefreshMetrics<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                RefreshMetricsState::Check(ref mut check) => {
+                    try_ready!(check.poll());
+                    // StatusCheck sets the gauges itself, the response is not needed
+                    while let Ok(Async::Ready(Some(_))) = self.rx.poll() {}
+                    let timeout =
+                        reactor::Timeout::new(METRICS_REFRESH_INTERVAL, &self.handle).expect("error creating timeout");
+                    RefreshMetricsState::Wait(timeout)
+                }
+                RefreshMetricsState::Wait(ref mut timeout) => {
+                    try_ready!(timeout.poll().map_err(|e| {
+                        error!("error waiting to refresh metrics: {:?}", e);
+                    }));
+                    RefreshMetricsState::Check(StatusCheck::new(&self.homechain, &self.sidechain, &self.tx))
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+/// Future that reports the withdrawal on the target contract of every transfer in a source transaction
+pub struct WithdrawalsCheck {
+    future: Box<dyn Future<Item = Vec<WithdrawalStatus>, Error = ()>>,
+    tx: mpsc::UnboundedSender<Result<Vec<WithdrawalStatus>, ()>>,
+}
+
+impl WithdrawalsCheck {
+    /// Returns a newly created WithdrawalsCheck Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transaction took place
+    /// * `target` - Network where the withdrawals are approved
+    /// * `tx_hash` - Transaction hash to check
+    /// * `tx` - Sender for the response
+    fn new<T: DuplexTransport + 'static>(
+        source: &Network<T>,
+        target: &Network<T>,
+        tx_hash: &H256,
+        tx: &mpsc::UnboundedSender<Result<Vec<WithdrawalStatus>, ()>>,
+    ) -> Self {
+        let target = target.clone();
+        let future = FindTransferInTransaction::new(source, tx_hash).and_then(move |transfers| {
+            let futures: Vec<_> = transfers
+                .iter()
+                .map(|transfer| {
+                    let withdrawal_hash = transfer.get_withdrawal_hash();
+                    let transfer_details = (transfer.destination, transfer.amount);
+                    let account = target.account;
+                    DoesRequireApproval::get_withdrawal(&target, transfer)
+                        .join(GetApprovers::new(&target, &withdrawal_hash))
+                        .map(move |(withdrawal, approvers)| {
+                            WithdrawalStatus::new(withdrawal_hash, transfer_details, withdrawal, &approvers, account)
+                        })
+                })
+                .collect();
+            future::join_all(futures)
+        });
+        WithdrawalsCheck {
+            future: Box::new(future),
+            tx: tx.clone(),
+        }
+    }
+}
+
+impl Future for WithdrawalsCheck {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.future.poll() {
+            Ok(Async::Ready(withdrawals)) => Ok(withdrawals),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            // Errors are logged in the Futures
+            Err(()) => Err(()),
+        };
+        if self.tx.unbounded_send(result).is_err() {
+            error!("error sending withdrawals response");
+        }
+        Ok(Async::Ready(()))
+    }
+}
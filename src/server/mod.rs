@@ -2,4 +2,4 @@ pub mod endpoint;
 pub mod handler;
 
 pub use self::endpoint::RequestType;
-pub use self::handler::HandleRequests;
+pub use self::handler::{HandleRequests, RefreshMetrics};
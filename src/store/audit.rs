@@ -0,0 +1,384 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use web3::types::{Address, H256, U256};
+
+use crate::eth::utils::clean_0x;
+use crate::relay::NetworkType;
+use crate::transfers::transfer::Transfer;
+
+/// Records returned by a query when no limit is given
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Most records returned by a single query
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// What happened to a relay contract transaction sent for a transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    /// Broadcast to the target chain
+    Sent,
+    /// Replaced by a transaction with a higher gas price
+    Replaced,
+    /// Mined and confirmed
+    Succeeded,
+    /// Reverted, or could not be sent or confirmed
+    Failed,
+}
+
+/// Transfer on the source chain that a relay contract transaction is sent for
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditTransfer {
+    /// Source chain, home or side
+    pub chain: String,
+    pub tx_hash: H256,
+    pub block_number: u64,
+    pub withdrawal_hash: H256,
+    pub destination: Address,
+    pub amount: U256,
+    /// Fees on the target contract when the approval was sent
+    pub fees: Option<U256>,
+}
+
+impl AuditTransfer {
+    /// Returns the audit details of a transfer
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was made
+    /// * `transfer` - The transfer
+    /// * `fees` - Fees on the target contract, if known
+    pub fn new(source: NetworkType, transfer: &Transfer, fees: Option<U256>) -> Self {
+        AuditTransfer {
+            chain: format!("{:?}", source).to_lowercase(),
+            tx_hash: transfer.tx_hash,
+            block_number: transfer.block_number.as_u64(),
+            withdrawal_hash: transfer.get_withdrawal_hash(),
+            destination: transfer.destination,
+            amount: transfer.amount,
+            fees,
+        }
+    }
+}
+
+/// One line of the audit log
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditRecord {
+    /// Unix timestamp of the record
+    pub time: u64,
+    #[serde(flatten)]
+    pub transfer: AuditTransfer,
+    /// Relay contract function called on the target chain, approveWithdrawal or unapproveWithdrawal
+    pub function: String,
+    /// Hash of the transaction on the target chain, if one was sent
+    pub target_tx_hash: Option<H256>,
+    pub status: AuditStatus,
+    /// Seconds since the first transaction for this call was sent
+    pub elapsed: Option<u64>,
+}
+
+impl AuditRecord {
+    /// Returns a record stamped with the current time
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - Transfer the transaction is sent for
+    /// * `function` - Relay contract function called
+    /// * `target_tx_hash` - Hash of the transaction, if one was sent
+    /// * `status` - What happened to the transaction
+    /// * `elapsed` - Seconds since the first transaction was sent
+    pub fn new(
+        transfer: &AuditTransfer,
+        function: &str,
+        target_tx_hash: Option<H256>,
+        status: AuditStatus,
+        elapsed: Option<u64>,
+    ) -> Self {
+        AuditRecord {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            transfer: transfer.clone(),
+            function: function.to_string(),
+            target_tx_hash,
+            status,
+            elapsed,
+        }
+    }
+}
+
+/// Filters and page of a query on the audit log
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Source chain, home or side
+    pub chain: Option<String>,
+    /// Earliest unix timestamp
+    pub from: Option<u64>,
+    /// Latest unix timestamp
+    pub to: Option<u64>,
+    pub destination: Option<String>,
+    /// Matching records to skip
+    pub offset: Option<usize>,
+    /// Most records to return, capped at MAX_PAGE_SIZE
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    /// Returns true if the record passes every filter of the query
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - Record to check
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.chain
+            .as_ref()
+            .map_or(true, |chain| chain.eq_ignore_ascii_case(&record.transfer.chain))
+            && self.from.map_or(true, |from| record.time >= from)
+            && self.to.map_or(true, |to| record.time <= to)
+            && self.destination.as_ref().map_or(true, |destination| {
+                clean_0x(destination).eq_ignore_ascii_case(&format!("{:x}", record.transfer.destination))
+            })
+    }
+}
+
+/// Page of records from the audit log, oldest first
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    /// Offset of the next page, if there are more matching records
+    pub next: Option<usize>,
+}
+
+/// Append-only log of every relay contract transaction sent for a transfer, as JSON lines.
+///
+/// Once the log grows past max_bytes, it is moved to `<path>.1`, older files move up by one, and files past max_files
+/// are deleted
+#[derive(Debug)]
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    /// Returns a log that records nothing
+    pub fn disabled() -> Self {
+        AuditLog {
+            path: None,
+            max_bytes: 0,
+            max_files: 0,
+        }
+    }
+
+    /// Opens the log at the given path, creating its directory
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the current log file
+    /// * `max_bytes` - Size the log file can grow to before it is rotated
+    /// * `max_files` - Number of rotated files to keep
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self, Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(AuditLog {
+            path: Some(path.to_path_buf()),
+            max_bytes,
+            max_files,
+        })
+    }
+
+    /// Appends a record to the log, rotating it first if it is full
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - Record to append
+    pub fn append(&self, record: &AuditRecord) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.write(path, record) {
+                error!("error writing audit record to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    /// Returns a page of the records that match the query, oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Filters and page to return
+    pub fn query(&self, query: &AuditQuery) -> Result<AuditPage, Error> {
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let mut records = Vec::new();
+        let mut matched = 0;
+        if let Some(path) = &self.path {
+            let files = (1..=self.max_files).rev().map(|index| rotated(path, index));
+            for file in files.chain(Some(path.clone())) {
+                if !file.exists() {
+                    continue;
+                }
+                for line in BufReader::new(File::open(&file)?).lines() {
+                    let line = line?;
+                    let record: AuditRecord = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!("skipping bad audit record in {:?}: {:?}", file, e);
+                            continue;
+                        }
+                    };
+                    if !query.matches(&record) {
+                        continue;
+                    }
+                    matched += 1;
+                    if matched > offset + limit {
+                        return Ok(AuditPage {
+                            records,
+                            next: Some(offset + limit),
+                        });
+                    }
+                    if matched > offset {
+                        records.push(record);
+                    }
+                }
+            }
+        }
+        Ok(AuditPage { records, next: None })
+    }
+
+    fn write(&self, path: &Path, record: &AuditRecord) -> Result<(), Error> {
+        if fs::metadata(path).map(|m| m.len() >= self.max_bytes).unwrap_or(false) {
+            self.rotate(path)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    fn rotate(&self, path: &Path) -> Result<(), Error> {
+        if self.max_files == 0 {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        let oldest = rotated(path, self.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for index in (1..self.max_files).rev() {
+            let file = rotated(path, index);
+            if file.exists() {
+                fs::rename(&file, rotated(path, index + 1))?;
+            }
+        }
+        fs::rename(path, rotated(path, 1))?;
+        Ok(())
+    }
+}
+
+/// Returns the path of a rotated log file, higher indexes are older
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("relay-{}-{}.jsonl", name, std::process::id()));
+        for index in 0..=2 {
+            let _ = fs::remove_file(if index == 0 {
+                path.clone()
+            } else {
+                rotated(&path, index)
+            });
+        }
+        path
+    }
+
+    fn record(chain: &str, time: u64) -> AuditRecord {
+        AuditRecord {
+            time,
+            transfer: AuditTransfer {
+                chain: chain.to_string(),
+                tx_hash: H256::zero(),
+                block_number: 1,
+                withdrawal_hash: H256::zero(),
+                destination: Address::from_low_u64_be(1),
+                amount: 100.into(),
+                fees: Some(10.into()),
+            },
+            function: "approveWithdrawal".to_string(),
+            target_tx_hash: None,
+            status: AuditStatus::Sent,
+            elapsed: None,
+        }
+    }
+
+    #[test]
+    fn query_should_page_through_rotated_files_in_order() {
+        // arrange
+        let path = temp_path("audit");
+        let log = AuditLog::open(&path, 1, 2).unwrap();
+        for time in 0..4 {
+            log.append(&record("home", time));
+        }
+        let query = AuditQuery {
+            limit: Some(2),
+            ..AuditQuery::default()
+        };
+        // act
+        let first = log.query(&query).unwrap();
+        let second = log
+            .query(&AuditQuery {
+                offset: first.next,
+                ..query
+            })
+            .unwrap();
+        // assert
+        let times: Vec<u64> = first
+            .records
+            .iter()
+            .chain(second.records.iter())
+            .map(|r| r.time)
+            .collect();
+        // Every append rotates, so only the current file and the two newest rotated ones are kept
+        assert_eq!(times, vec![1, 2, 3]);
+        assert_eq!(first.next, Some(2));
+        assert_eq!(second.next, None);
+        for index in 0..=2 {
+            let _ = fs::remove_file(if index == 0 {
+                path.clone()
+            } else {
+                rotated(&path, index)
+            });
+        }
+    }
+
+    #[test]
+    fn query_should_filter_by_chain_time_and_destination() {
+        // arrange
+        let query = AuditQuery {
+            chain: Some("SIDE".to_string()),
+            from: Some(5),
+            to: Some(10),
+            destination: Some("0x0000000000000000000000000000000000000001".to_string()),
+            ..AuditQuery::default()
+        };
+        // act
+        let matching = query.matches(&record("side", 5));
+        let other_chain = query.matches(&record("home", 5));
+        let too_late = query.matches(&record("side", 11));
+        // assert
+        assert!(matching);
+        assert!(!other_chain);
+        assert!(!too_late);
+    }
+}
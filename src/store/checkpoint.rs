@@ -0,0 +1,112 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::file::{read_json, write_json};
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CheckpointRecord {
+    block: u64,
+}
+
+/// Durable record of the last block on a chain where every transfer to the relay has been processed
+#[derive(Debug)]
+pub struct CheckpointStore {
+    path: Option<PathBuf>,
+    block: Option<u64>,
+}
+
+impl CheckpointStore {
+    /// Returns a store that is never written to disk
+    pub fn in_memory() -> Self {
+        CheckpointStore {
+            path: None,
+            block: None,
+        }
+    }
+
+    /// Opens the store at the given path, loading the existing checkpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the json file backing this store
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let record: Option<CheckpointRecord> = read_json(path)?;
+        let block = record.map(|r| r.block);
+        info!("loaded checkpoint {:?} from {:?}", block, path);
+        Ok(CheckpointStore {
+            path: Some(path.to_path_buf()),
+            block,
+        })
+    }
+
+    /// Returns the last fully processed block, if any
+    pub fn get(&self) -> Option<u64> {
+        self.block
+    }
+
+    /// Moves the checkpoint to the last block of a scanned range, unless blocks between the checkpoint and the start
+    /// of the range were never scanned. Returns false if the range leaves such a gap
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - First block of the scanned range
+    /// * `to` - Last block of the scanned range, fully processed
+    pub fn advance(&mut self, from: u64, to: u64) -> bool {
+        if self.block.map_or(false, |current| from > current + 1) {
+            return false;
+        }
+        self.set(to);
+        true
+    }
+
+    /// Moves the checkpoint forward to the given block and writes it to disk.
+    /// Blocks at or below the current checkpoint are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - Last fully processed block
+    pub fn set(&mut self, block: u64) {
+        if self.block.map_or(false, |current| current >= block) {
+            return;
+        }
+        self.block = Some(block);
+        if let Some(path) = &self.path {
+            if let Err(e) = write_json(path, &CheckpointRecord { block }) {
+                error!("error writing checkpoint to {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_should_only_move_forward() {
+        // arrange
+        let mut store = CheckpointStore::in_memory();
+        // act
+        store.set(10);
+        store.set(5);
+        // assert
+        assert_eq!(store.get(), Some(10));
+    }
+
+    #[test]
+    fn advance_should_not_skip_unscanned_blocks() {
+        // arrange
+        let mut store = CheckpointStore::in_memory();
+        store.set(10);
+        // act
+        let gap = store.advance(20, 30);
+        let adjacent = store.advance(11, 15);
+        let overlapping = store.advance(5, 25);
+        // assert
+        assert!(!gap);
+        assert!(adjacent);
+        assert!(overlapping);
+        assert_eq!(store.get(), Some(25));
+    }
+}
@@ -0,0 +1,36 @@
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// Reads a json document from disk, returning None if the file does not exist yet
+///
+/// # Arguments
+///
+/// * `path` - Path of the json file
+pub fn read_json<D: DeserializeOwned>(path: &Path) -> Result<Option<D>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+/// Writes a json document to disk.
+/// The document is written to a temporary file first, then renamed over the original, so a crash mid-write never
+/// leaves a truncated file behind
+///
+/// # Arguments
+///
+/// * `path` - Path of the json file
+/// * `value` - Value to serialize
+pub fn write_json<S: Serialize>(path: &Path, value: &S) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(value)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
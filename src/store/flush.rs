@@ -0,0 +1,235 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use web3::types::{Address, H256, U256, U64};
+
+use super::file::{read_json, write_json};
+use crate::flush::plan::{FlushPlan, WalletPlan};
+
+/// Where the withdrawal of a wallet is at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalStatus {
+    /// Not approved by this relay yet
+    Pending,
+    /// Approved, waiting for the withdrawal to be processed
+    Approved,
+    /// Processed on the other chain
+    Processed,
+}
+
+/// Withdrawal of one wallet, in the order the offsets were handed out
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FlushWithdrawal {
+    pub address: Address,
+    pub balance: U256,
+    /// Address the balance is withdrawn to on the other chain
+    pub destination: Address,
+    /// Block number given to the withdrawal, the flush block plus the offset of the wallet
+    pub block_number: U64,
+    pub status: WithdrawalStatus,
+}
+
+/// Progress of a flush, from its plan to the withdrawal of the leftover
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FlushProgress {
+    /// Hash of the flush transaction
+    pub transaction_hash: H256,
+    pub plan: FlushPlan,
+    pub withdrawals: Vec<FlushWithdrawal>,
+    pub leftover_withdrawn: bool,
+}
+
+impl FlushProgress {
+    /// Returns a new progress, with every wallet the plan withdraws pending
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_hash` - Hash of the flush transaction
+    /// * `plan` - Plan of the flush
+    pub fn new(transaction_hash: H256, plan: FlushPlan) -> Self {
+        let withdrawals = plan
+            .wallets
+            .iter()
+            .filter(|wallet| wallet.plan == WalletPlan::Withdraw)
+            .filter_map(|wallet| {
+                wallet.block_number.map(|block_number| FlushWithdrawal {
+                    address: wallet.address,
+                    balance: wallet.balance,
+                    destination: wallet.destination.unwrap_or(wallet.address),
+                    block_number,
+                    status: WithdrawalStatus::Pending,
+                })
+            })
+            .collect();
+        FlushProgress {
+            transaction_hash,
+            plan,
+            withdrawals,
+            leftover_withdrawn: false,
+        }
+    }
+
+    /// Returns the number of wallets processed so far
+    pub fn processed(&self) -> usize {
+        self.withdrawals
+            .iter()
+            .filter(|withdrawal| withdrawal.status == WithdrawalStatus::Processed)
+            .count()
+    }
+}
+
+/// Durable record of the progress of a flush, so a restart resumes it instead of scanning the balances again.
+///
+/// Written through to a json file after every change
+#[derive(Debug)]
+pub struct FlushStore {
+    path: Option<PathBuf>,
+    progress: Option<FlushProgress>,
+}
+
+impl FlushStore {
+    /// Returns a store without any flush, that is never written to disk
+    pub fn in_memory() -> Self {
+        FlushStore {
+            path: None,
+            progress: None,
+        }
+    }
+
+    /// Opens the store at the given path, loading the progress of the last flush
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the json file backing this store
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let progress: Option<FlushProgress> = read_json(path)?;
+        if let Some(progress) = &progress {
+            info!(
+                "loaded flush {:?} from {:?}: {} of {} wallets processed",
+                progress.transaction_hash,
+                path,
+                progress.processed(),
+                progress.withdrawals.len()
+            );
+        }
+        Ok(FlushStore {
+            path: Some(path.to_path_buf()),
+            progress,
+        })
+    }
+
+    /// Returns the progress of the flush made in the given transaction, if it was started
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_hash` - Hash of the flush transaction
+    pub fn get(&self, transaction_hash: &H256) -> Option<FlushProgress> {
+        self.progress
+            .as_ref()
+            .filter(|progress| progress.transaction_hash == *transaction_hash)
+            .cloned()
+    }
+
+    /// Records the start of a flush, replacing any other flush
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - Progress of the flush, with its plan
+    pub fn start(&mut self, progress: &FlushProgress) {
+        self.progress = Some(progress.clone());
+        self.persist();
+    }
+
+    /// Records where the withdrawal of a wallet is at
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the wallet
+    /// * `status` - Status of its withdrawal
+    pub fn set_status(&mut self, address: &Address, status: WithdrawalStatus) {
+        let withdrawal = self.progress.as_mut().and_then(|progress| {
+            progress
+                .withdrawals
+                .iter_mut()
+                .find(|withdrawal| withdrawal.address == *address)
+        });
+        if let Some(withdrawal) = withdrawal {
+            withdrawal.status = status;
+            self.persist();
+        }
+    }
+
+    /// Records the withdrawal of the leftover, which completes the flush
+    pub fn leftover_withdrawn(&mut self) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.leftover_withdrawn = true;
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write_json(path, &self.progress) {
+                error!("error writing flush progress to {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flush::plan::PlannedWallet;
+
+    fn planned(address: u64, plan: WalletPlan, block_number: Option<u64>) -> PlannedWallet {
+        PlannedWallet {
+            address: Address::from_low_u64_be(address),
+            balance: 100.into(),
+            plan,
+            destination: block_number.map(|_| Address::from_low_u64_be(address)),
+            block_number: block_number.map(U64::from),
+        }
+    }
+
+    #[test]
+    fn set_status_should_only_change_the_flush_in_progress() {
+        // arrange
+        let plan = FlushPlan {
+            block_number: 10.into(),
+            block_hash: H256::zero(),
+            fees: 1.into(),
+            wallets: vec![
+                planned(1, WalletPlan::Withdraw, Some(10)),
+                planned(2, WalletPlan::Contract, None),
+                planned(3, WalletPlan::Withdraw, Some(11)),
+            ],
+            fee_wallet: Address::zero(),
+            leftover: 0.into(),
+            leftover_block_number: 13.into(),
+            policy_hash: H256::zero(),
+        };
+        let mut store = FlushStore::in_memory();
+        let flush = H256::from_low_u64_be(1);
+        // act
+        store.start(&FlushProgress::new(flush, plan));
+        store.set_status(&Address::from_low_u64_be(3), WithdrawalStatus::Processed);
+        store.set_status(&Address::from_low_u64_be(2), WithdrawalStatus::Processed);
+        // assert
+        let progress = store.get(&flush).unwrap();
+        let statuses: Vec<(U64, WithdrawalStatus)> = progress
+            .withdrawals
+            .iter()
+            .map(|withdrawal| (withdrawal.block_number, withdrawal.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (10.into(), WithdrawalStatus::Pending),
+                (11.into(), WithdrawalStatus::Processed)
+            ]
+        );
+        assert_eq!(progress.processed(), 1);
+        assert!(store.get(&H256::from_low_u64_be(2)).is_none());
+    }
+}
@@ -0,0 +1,374 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use web3::types::{Address, H256, U256};
+
+use super::file::{read_json, write_json};
+use crate::transfers::transfer::Transfer;
+
+/// Safety limits on the value approved for transfers from one chain
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LimitConfig {
+    /// Largest single transfer
+    pub max_transfer: Option<U256>,
+    /// Most value approved within the window
+    pub max_window_total: Option<U256>,
+    /// Most value approved to a single destination within the window
+    pub max_destination_total: Option<U256>,
+    /// Seconds covered by the rolling window
+    pub window: u64,
+}
+
+/// Transfer approved within the window
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+struct ApprovedRecord {
+    withdrawal_hash: H256,
+    destination: Address,
+    amount: U256,
+    /// Unix timestamp of the approval
+    time: u64,
+}
+
+/// Transfer that went over a limit, and waits for an operator to release it
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct HeldTransfer {
+    pub withdrawal_hash: H256,
+    pub transfer: Transfer,
+    /// Limit the transfer went over
+    pub reason: String,
+    /// Unix timestamp of when it was held
+    pub held: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct LimitRecords {
+    approved: Vec<ApprovedRecord>,
+    held: Vec<HeldTransfer>,
+    /// Held transfers released by an operator, that skip the limits once
+    released: Vec<H256>,
+}
+
+/// Returns the current unix timestamp
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Durable record of the value approved for transfers from one chain, and of the transfers held over the limits.
+///
+/// Written through to a json file, so restarting the relay neither resets the window nor releases held transfers
+#[derive(Debug)]
+pub struct LimitStore {
+    path: Option<PathBuf>,
+    config: LimitConfig,
+    records: LimitRecords,
+}
+
+impl LimitStore {
+    /// Returns a store that is never written to disk
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Limits to enforce
+    pub fn in_memory(config: LimitConfig) -> Self {
+        LimitStore {
+            path: None,
+            config,
+            records: LimitRecords::default(),
+        }
+    }
+
+    /// Opens the store at the given path, loading approvals and held transfers
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the json file backing this store
+    /// * `config` - Limits to enforce
+    pub fn open(path: &Path, config: LimitConfig) -> Result<Self, Error> {
+        let records: LimitRecords = read_json(path)?.unwrap_or_default();
+        info!("loaded {} held transfers from {:?}", records.held.len(), path);
+        Ok(LimitStore {
+            path: Some(path.to_path_buf()),
+            config,
+            records,
+        })
+    }
+
+    /// Checks a transfer against the limits, and counts it in the window if it is within them, until it is rolled
+    /// back. A transfer counts once, however many times its approval is retried.
+    /// Returns the limit it goes over otherwise
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - Transfer about to be approved
+    /// * `now` - Current unix timestamp
+    pub fn check(&mut self, transfer: &Transfer, now: u64) -> Result<(), String> {
+        let withdrawal_hash = transfer.get_withdrawal_hash();
+        let window = self.config.window;
+        self.records
+            .approved
+            .retain(|approved| approved.time.saturating_add(window) > now);
+        if let Some(index) = self.records.released.iter().position(|hash| *hash == withdrawal_hash) {
+            self.records.released.remove(index);
+            self.approve(transfer, now);
+            return Ok(());
+        }
+        if let Some(held) = self
+            .records
+            .held
+            .iter()
+            .find(|held| held.withdrawal_hash == withdrawal_hash)
+        {
+            return Err(held.reason.clone());
+        }
+        // Approvals are retried, the same transfer only counts once
+        if self
+            .records
+            .approved
+            .iter()
+            .any(|approved| approved.withdrawal_hash == withdrawal_hash)
+        {
+            return Ok(());
+        }
+        if let Some(max) = self.config.max_transfer {
+            if transfer.amount > max {
+                return Err(format!(
+                    "amount {} over the single transfer limit {}",
+                    transfer.amount, max
+                ));
+            }
+        }
+        if let Some(max) = self.config.max_window_total {
+            let total = self
+                .records
+                .approved
+                .iter()
+                .fold(transfer.amount, |total, approved| total.saturating_add(approved.amount));
+            if total > max {
+                return Err(format!(
+                    "{} approved in {}s, over the window limit {}",
+                    total, window, max
+                ));
+            }
+        }
+        if let Some(max) = self.config.max_destination_total {
+            let total = self
+                .records
+                .approved
+                .iter()
+                .filter(|approved| approved.destination == transfer.destination)
+                .fold(transfer.amount, |total, approved| total.saturating_add(approved.amount));
+            if total > max {
+                return Err(format!(
+                    "{} approved to {:?} in {}s, over the destination limit {}",
+                    total, transfer.destination, window, max
+                ));
+            }
+        }
+        self.approve(transfer, now);
+        Ok(())
+    }
+
+    /// Holds a transfer that went over a limit. Returns false if it was already held
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - Transfer to hold
+    /// * `reason` - Limit the transfer went over
+    /// * `now` - Current unix timestamp
+    pub fn hold(&mut self, transfer: &Transfer, reason: &str, now: u64) -> bool {
+        let withdrawal_hash = transfer.get_withdrawal_hash();
+        if self
+            .records
+            .held
+            .iter()
+            .any(|held| held.withdrawal_hash == withdrawal_hash)
+        {
+            return false;
+        }
+        self.records.held.push(HeldTransfer {
+            withdrawal_hash,
+            transfer: *transfer,
+            reason: reason.to_string(),
+            held: now,
+        });
+        self.persist();
+        true
+    }
+
+    /// Returns every held transfer, oldest first
+    pub fn held(&self) -> &[HeldTransfer] {
+        &self.records.held
+    }
+
+    /// Releases a held transfer, so its next approval skips the limits. Returns the transfer, if it was held
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the held transfer
+    pub fn release(&mut self, withdrawal_hash: &H256) -> Option<Transfer> {
+        let held = self.take_held(withdrawal_hash)?;
+        self.records.released.push(*withdrawal_hash);
+        self.persist();
+        Some(held.transfer)
+    }
+
+    /// Drops a held transfer whose log was removed. Returns true if it was held
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the held transfer
+    pub fn discard(&mut self, withdrawal_hash: &H256) -> bool {
+        let discarded = self.take_held(withdrawal_hash).is_some();
+        if discarded {
+            self.persist();
+        }
+        discarded
+    }
+
+    /// Stops counting a transfer in the window, because its approval failed or was undone. Returns true if it was
+    /// counted
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    pub fn rollback(&mut self, withdrawal_hash: &H256) -> bool {
+        let counted = self.records.approved.len();
+        self.records
+            .approved
+            .retain(|approved| approved.withdrawal_hash != *withdrawal_hash);
+        let rolled_back = self.records.approved.len() < counted;
+        if rolled_back {
+            self.persist();
+        }
+        rolled_back
+    }
+
+    fn take_held(&mut self, withdrawal_hash: &H256) -> Option<HeldTransfer> {
+        let index = self
+            .records
+            .held
+            .iter()
+            .position(|held| held.withdrawal_hash == *withdrawal_hash)?;
+        Some(self.records.held.remove(index))
+    }
+
+    fn approve(&mut self, transfer: &Transfer, now: u64) {
+        let withdrawal_hash = transfer.get_withdrawal_hash();
+        if self
+            .records
+            .approved
+            .iter()
+            .any(|approved| approved.withdrawal_hash == withdrawal_hash)
+        {
+            return;
+        }
+        self.records.approved.push(ApprovedRecord {
+            withdrawal_hash,
+            destination: transfer.destination,
+            amount: transfer.amount,
+            time: now,
+        });
+        self.persist();
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write_json(path, &self.records) {
+                error!("error writing transfer limits to {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::U64;
+
+    fn transfer(tx: u64, destination: u64, amount: u64) -> Transfer {
+        Transfer {
+            destination: Address::from_low_u64_be(destination),
+            amount: amount.into(),
+            tx_hash: H256::from_low_u64_be(tx),
+            block_hash: H256::zero(),
+            block_number: U64::zero(),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn check_should_enforce_limits_within_window() {
+        // arrange
+        let mut store = LimitStore::in_memory(LimitConfig {
+            max_transfer: Some(100.into()),
+            max_window_total: Some(150.into()),
+            max_destination_total: Some(80.into()),
+            window: 60,
+        });
+        // act
+        let too_large = store.check(&transfer(1, 1, 101), 0);
+        let first = store.check(&transfer(2, 1, 50), 0);
+        let same_destination = store.check(&transfer(3, 1, 50), 10);
+        let other_destination = store.check(&transfer(4, 2, 80), 10);
+        let over_window = store.check(&transfer(5, 3, 30), 20);
+        let after_window = store.check(&transfer(6, 3, 30), 60);
+        // assert
+        assert!(too_large.is_err());
+        assert!(first.is_ok());
+        assert!(same_destination.is_err());
+        assert!(other_destination.is_ok());
+        assert!(over_window.is_err());
+        assert!(after_window.is_ok());
+    }
+
+    #[test]
+    fn released_transfer_should_skip_limits_once() {
+        // arrange
+        let mut store = LimitStore::in_memory(LimitConfig {
+            max_transfer: Some(100.into()),
+            ..LimitConfig::default()
+        });
+        let large = transfer(1, 1, 500);
+        let reason = store.check(&large, 0).unwrap_err();
+        assert!(store.hold(&large, &reason, 0));
+        // act
+        let while_held = store.check(&large, 1);
+        let released = store.release(&large.get_withdrawal_hash());
+        let after_release = store.check(&large, 2);
+        // assert
+        assert!(while_held.is_err());
+        assert_eq!(released, Some(large));
+        assert!(after_release.is_ok());
+        assert!(store.held().is_empty());
+    }
+
+    #[test]
+    fn rollback_should_free_the_window_after_a_failed_approval() {
+        // arrange
+        let mut store = LimitStore::in_memory(LimitConfig {
+            max_window_total: Some(100.into()),
+            window: 60,
+            ..LimitConfig::default()
+        });
+        let failed = transfer(1, 1, 80);
+        let next = transfer(2, 2, 80);
+        // act
+        let first = store.check(&failed, 0);
+        let retried = store.check(&failed, 1);
+        let while_counted = store.check(&next, 2);
+        let rolled_back = store.rollback(&failed.get_withdrawal_hash());
+        let repeated = store.rollback(&failed.get_withdrawal_hash());
+        let after_rollback = store.check(&next, 3);
+        // assert
+        assert!(first.is_ok());
+        assert!(retried.is_ok());
+        assert!(while_counted.is_err());
+        assert!(rolled_back);
+        assert!(!repeated);
+        assert!(after_rollback.is_ok());
+    }
+}
@@ -0,0 +1,14 @@
+pub mod audit;
+pub mod checkpoint;
+pub mod file;
+pub mod flush;
+pub mod limits;
+pub mod pause;
+pub mod transfers;
+
+pub use self::audit::AuditLog;
+pub use self::checkpoint::CheckpointStore;
+pub use self::flush::FlushStore;
+pub use self::limits::LimitStore;
+pub use self::pause::PauseStore;
+pub use self::transfers::{TransferRecord, TransferStore};
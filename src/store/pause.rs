@@ -0,0 +1,361 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use web3::types::H256;
+
+use super::file::{read_json, write_json};
+use crate::anchors::anchor::Anchor;
+use crate::relay::NetworkType;
+use crate::transfers::transfer::Transfer;
+
+/// Part of the relay that can be paused on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PauseTarget {
+    /// Approvals on the sidechain for transfers on the homechain
+    HomeToSide,
+    /// Approvals on the homechain for transfers on the sidechain
+    SideToHome,
+    /// Sidechain anchors posted on the homechain
+    Anchors,
+}
+
+impl PauseTarget {
+    /// Returns the direction of approvals for transfers made on the given network
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers are made
+    pub fn transfers_from(source: NetworkType) -> Self {
+        match source {
+            NetworkType::Home => PauseTarget::HomeToSide,
+            NetworkType::Side => PauseTarget::SideToHome,
+        }
+    }
+}
+
+impl FromStr for PauseTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "home-to-side" => Ok(PauseTarget::HomeToSide),
+            "side-to-home" => Ok(PauseTarget::SideToHome),
+            "anchors" => Ok(PauseTarget::Anchors),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PauseTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PauseTarget::HomeToSide => write!(f, "home-to-side"),
+            PauseTarget::SideToHome => write!(f, "side-to-home"),
+            PauseTarget::Anchors => write!(f, "anchors"),
+        }
+    }
+}
+
+/// Whether one target is paused, and what it buffered while paused, oldest first
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Backlog<T> {
+    paused: bool,
+    buffered: Vec<T>,
+}
+
+impl<T> Default for Backlog<T> {
+    fn default() -> Self {
+        Backlog {
+            paused: false,
+            buffered: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PauseRecords {
+    home_to_side: Backlog<Transfer>,
+    side_to_home: Backlog<Transfer>,
+    anchors: Backlog<Anchor>,
+}
+
+impl PauseRecords {
+    fn transfers(&mut self, source: NetworkType) -> &mut Backlog<Transfer> {
+        match source {
+            NetworkType::Home => &mut self.home_to_side,
+            NetworkType::Side => &mut self.side_to_home,
+        }
+    }
+}
+
+/// Whether a target is paused, and how much it has buffered
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PauseState {
+    pub paused: bool,
+    pub buffered: usize,
+}
+
+/// Pause state of every target
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PauseStatus {
+    pub home_to_side: PauseState,
+    pub side_to_home: PauseState,
+    pub anchors: PauseState,
+}
+
+/// Durable record of what is paused, and of the transfers and anchors buffered while paused.
+///
+/// Written through to a json file, so a restart neither resumes nor loses the backlog
+#[derive(Debug)]
+pub struct PauseStore {
+    path: Option<PathBuf>,
+    records: PauseRecords,
+}
+
+impl PauseStore {
+    /// Returns a store with nothing paused, that is never written to disk
+    pub fn in_memory() -> Self {
+        PauseStore {
+            path: None,
+            records: PauseRecords::default(),
+        }
+    }
+
+    /// Opens the store at the given path, loading what was paused and buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the json file backing this store
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let records: PauseRecords = read_json(path)?.unwrap_or_default();
+        let store = PauseStore {
+            path: Some(path.to_path_buf()),
+            records,
+        };
+        info!("loaded pause state from {:?}: {:?}", path, store.status());
+        Ok(store)
+    }
+
+    /// Returns true if the target is paused
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Target to check
+    pub fn is_paused(&self, target: PauseTarget) -> bool {
+        match target {
+            PauseTarget::HomeToSide => self.records.home_to_side.paused,
+            PauseTarget::SideToHome => self.records.side_to_home.paused,
+            PauseTarget::Anchors => self.records.anchors.paused,
+        }
+    }
+
+    /// Pauses the target. Returns false if it was already paused
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Target to pause
+    pub fn pause(&mut self, target: PauseTarget) -> bool {
+        let paused = match target {
+            PauseTarget::HomeToSide => &mut self.records.home_to_side.paused,
+            PauseTarget::SideToHome => &mut self.records.side_to_home.paused,
+            PauseTarget::Anchors => &mut self.records.anchors.paused,
+        };
+        let changed = !*paused;
+        *paused = true;
+        self.persist();
+        changed
+    }
+
+    /// Buffers a transfer instead of approving it, if approvals for its source are paused.
+    /// Returns true if the transfer is buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was made
+    /// * `transfer` - Transfer about to be approved
+    pub fn buffer_transfer(&mut self, source: NetworkType, transfer: &Transfer) -> bool {
+        let withdrawal_hash = transfer.get_withdrawal_hash();
+        let backlog = self.records.transfers(source);
+        if !backlog.paused {
+            return false;
+        }
+        // The same transfer is seen by the live and past watchers
+        if !backlog
+            .buffered
+            .iter()
+            .any(|buffered| buffered.get_withdrawal_hash() == withdrawal_hash)
+        {
+            backlog.buffered.push(*transfer);
+            self.persist();
+        }
+        true
+    }
+
+    /// Drops a buffered transfer whose log was removed, or whose approval is done. Returns true if it was buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was made
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    pub fn discard_transfer(&mut self, source: NetworkType, withdrawal_hash: &H256) -> bool {
+        let backlog = self.records.transfers(source);
+        let before = backlog.buffered.len();
+        backlog
+            .buffered
+            .retain(|buffered| buffered.get_withdrawal_hash() != *withdrawal_hash);
+        let discarded = backlog.buffered.len() != before;
+        if discarded {
+            self.persist();
+        }
+        discarded
+    }
+
+    /// Resumes approvals for transfers from the source. Returns false if they were not paused.
+    /// The transfers buffered while paused stay in the backlog until discarded once approved, so a restart in between
+    /// does not lose them
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were made
+    pub fn resume_transfers(&mut self, source: NetworkType) -> bool {
+        let backlog = self.records.transfers(source);
+        let changed = backlog.paused;
+        backlog.paused = false;
+        self.persist();
+        changed
+    }
+
+    /// Returns the transfers buffered while paused that still wait for their approval, oldest first.
+    /// Empty while approvals for transfers from the source are paused
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were made
+    pub fn resumed_transfers(&mut self, source: NetworkType) -> Vec<Transfer> {
+        let backlog = self.records.transfers(source);
+        if backlog.paused {
+            return Vec::new();
+        }
+        backlog.buffered.clone()
+    }
+
+    /// Buffers an anchor instead of posting it, if anchors are paused. Returns true if the anchor is buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `anchor` - Anchor about to be posted
+    pub fn buffer_anchor(&mut self, anchor: &Anchor) -> bool {
+        if !self.records.anchors.paused {
+            return false;
+        }
+        if !self.records.anchors.buffered.contains(anchor) {
+            self.records.anchors.buffered.push(*anchor);
+            self.persist();
+        }
+        true
+    }
+
+    /// Resumes anchors, and returns the anchors buffered while paused, oldest first
+    pub fn resume_anchors(&mut self) -> Vec<Anchor> {
+        let backlog = std::mem::replace(&mut self.records.anchors, Backlog::default());
+        self.persist();
+        backlog.buffered
+    }
+
+    /// Returns the pause state of every target
+    pub fn status(&self) -> PauseStatus {
+        PauseStatus {
+            home_to_side: PauseState {
+                paused: self.records.home_to_side.paused,
+                buffered: self.records.home_to_side.buffered.len(),
+            },
+            side_to_home: PauseState {
+                paused: self.records.side_to_home.paused,
+                buffered: self.records.side_to_home.buffered.len(),
+            },
+            anchors: PauseState {
+                paused: self.records.anchors.paused,
+                buffered: self.records.anchors.buffered.len(),
+            },
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write_json(path, &self.records) {
+                error!("error writing pause state to {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::{Address, U64};
+
+    fn transfer(tx: u64) -> Transfer {
+        Transfer {
+            destination: Address::from_low_u64_be(1),
+            amount: 100.into(),
+            tx_hash: H256::from_low_u64_be(tx),
+            block_hash: H256::zero(),
+            block_number: U64::zero(),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn resume_should_return_buffered_transfers_in_order() {
+        // arrange
+        let mut store = PauseStore::in_memory();
+        let not_paused = store.buffer_transfer(NetworkType::Home, &transfer(1));
+        store.pause(PauseTarget::HomeToSide);
+        // act
+        store.buffer_transfer(NetworkType::Home, &transfer(2));
+        store.buffer_transfer(NetworkType::Home, &transfer(3));
+        store.buffer_transfer(NetworkType::Home, &transfer(2));
+        let other_direction = store.buffer_transfer(NetworkType::Side, &transfer(4));
+        let status = store.status();
+        let while_paused = store.resumed_transfers(NetworkType::Home);
+        let resumed = store.resume_transfers(NetworkType::Home);
+        let backlog = store.resumed_transfers(NetworkType::Home);
+        // assert
+        assert!(!not_paused);
+        assert!(!other_direction);
+        assert_eq!(
+            status.home_to_side,
+            PauseState {
+                paused: true,
+                buffered: 2
+            }
+        );
+        assert!(while_paused.is_empty());
+        assert!(resumed);
+        assert_eq!(backlog, vec![transfer(2), transfer(3)]);
+        assert!(!store.is_paused(PauseTarget::HomeToSide));
+    }
+
+    #[test]
+    fn resumed_transfers_should_stay_buffered_until_discarded() {
+        // arrange
+        let mut store = PauseStore::in_memory();
+        store.pause(PauseTarget::SideToHome);
+        store.buffer_transfer(NetworkType::Side, &transfer(1));
+        store.buffer_transfer(NetworkType::Side, &transfer(2));
+        store.resume_transfers(NetworkType::Side);
+        // act
+        let resumed_again = store.resume_transfers(NetworkType::Side);
+        let discarded = store.discard_transfer(NetworkType::Side, &transfer(1).get_withdrawal_hash());
+        let backlog = store.resumed_transfers(NetworkType::Side);
+        // assert
+        assert!(!resumed_again);
+        assert!(discarded);
+        assert_eq!(backlog, vec![transfer(2)]);
+        assert_eq!(store.status().side_to_home.buffered, 1);
+    }
+}
@@ -0,0 +1,309 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor;
+use web3::futures::prelude::*;
+use web3::futures::try_ready;
+use web3::types::H256;
+
+use super::file::{read_json, write_json};
+use crate::extensions::shutdown::Shutdown;
+use crate::relay::TransferApprovalState;
+
+/// Number of settled transfers to remember before the oldest ones are dropped
+pub const MAX_SETTLED_TRANSFERS: usize = 4096;
+
+/// Shortest time between two writes of the store, changes in between are written together
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Last known state of a single transfer, keyed by its withdrawal hash
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TransferRecord {
+    pub withdrawal_hash: H256,
+    pub state: TransferApprovalState,
+    /// Unix timestamp of the last state change
+    pub updated: u64,
+}
+
+/// Durable store of transfer approval states.
+///
+/// Changes are written to a json file at most once per PERSIST_INTERVAL, so the relay remembers what it has seen,
+/// approved and unapproved across restarts. Changes made in between are written by the next change after the
+/// interval, or by flush. Transfers that are still in flight are never dropped, nor removed transfers with approvals
+/// still watching them, only the oldest settled transfers once there are more than MAX_SETTLED_TRANSFERS of them.
+#[derive(Debug)]
+pub struct TransferStore {
+    path: Option<PathBuf>,
+    records: HashMap<H256, TransferRecord>,
+    /// Number of approvals waiting on each transfer, that are cancelled if it is removed
+    watchers: HashMap<H256, usize>,
+    /// Whether there are changes not written to disk yet
+    dirty: bool,
+    persisted: Option<Instant>,
+}
+
+impl TransferStore {
+    /// Returns a store that is never written to disk
+    pub fn in_memory() -> Self {
+        TransferStore {
+            path: None,
+            records: HashMap::new(),
+            watchers: HashMap::new(),
+            dirty: false,
+            persisted: None,
+        }
+    }
+
+    /// Opens the store at the given path, loading any existing records
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the json file backing this store
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let records: Vec<TransferRecord> = read_json(path)?.unwrap_or_default();
+        info!("loaded {} transfer states from {:?}", records.len(), path);
+        Ok(TransferStore {
+            path: Some(path.to_path_buf()),
+            records: records
+                .into_iter()
+                .map(|record| (record.withdrawal_hash, record))
+                .collect(),
+            watchers: HashMap::new(),
+            dirty: false,
+            persisted: None,
+        })
+    }
+
+    /// Returns the state of a transfer, if known
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    pub fn peek(&self, withdrawal_hash: &H256) -> Option<&TransferApprovalState> {
+        self.records.get(withdrawal_hash).map(|record| &record.state)
+    }
+
+    /// Records the new state of a transfer, and writes the store to disk unless it was written within PERSIST_INTERVAL
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    /// * `state` - New state of the transfer
+    pub fn put(&mut self, withdrawal_hash: H256, state: TransferApprovalState) {
+        let updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.records.insert(
+            withdrawal_hash,
+            TransferRecord {
+                withdrawal_hash,
+                state,
+                updated,
+            },
+        );
+        self.evict_settled();
+        self.dirty = true;
+        let due = self
+            .persisted
+            .map_or(true, |persisted| persisted.elapsed() >= PERSIST_INTERVAL);
+        if due {
+            self.flush();
+        }
+    }
+
+    /// Writes the changes not written to disk yet
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.persist();
+            self.dirty = false;
+            self.persisted = Some(Instant::now());
+        }
+    }
+
+    /// Records an approval waiting on a transfer, so the transfer is not dropped while the approval checks whether it
+    /// was removed
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    pub fn watch(&mut self, withdrawal_hash: H256) {
+        *self.watchers.entry(withdrawal_hash).or_insert(0) += 1;
+    }
+
+    /// Records that an approval stopped waiting on a transfer
+    ///
+    /// # Arguments
+    ///
+    /// * `withdrawal_hash` - Withdrawal hash of the transfer
+    pub fn unwatch(&mut self, withdrawal_hash: H256) {
+        if let Some(watchers) = self.watchers.get_mut(&withdrawal_hash) {
+            *watchers -= 1;
+            if *watchers == 0 {
+                self.watchers.remove(&withdrawal_hash);
+            }
+        }
+    }
+
+    /// Returns the records of every transfer that is still in flight
+    pub fn in_flight(&self) -> Vec<TransferRecord> {
+        self.records
+            .values()
+            .filter(|record| !record.state.is_settled())
+            .copied()
+            .collect()
+    }
+
+    /// Drops the oldest settled transfers once there are too many. Removed transfers are kept while approvals are
+    /// waiting on them, as they only stop once they see the transfer was removed
+    fn evict_settled(&mut self) {
+        let watchers = &self.watchers;
+        let mut settled: Vec<(u64, H256)> = self
+            .records
+            .values()
+            .filter(|record| record.state.is_settled())
+            .filter(|record| {
+                record.state != TransferApprovalState::Removed || !watchers.contains_key(&record.withdrawal_hash)
+            })
+            .map(|record| (record.updated, record.withdrawal_hash))
+            .collect();
+        if settled.len() <= MAX_SETTLED_TRANSFERS {
+            return;
+        }
+        settled.sort();
+        let excess = settled.len() - MAX_SETTLED_TRANSFERS;
+        for (_, withdrawal_hash) in settled.iter().take(excess) {
+            self.records.remove(withdrawal_hash);
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.path {
+            let records: Vec<&TransferRecord> = self.records.values().collect();
+            if let Err(e) = write_json(path, &records) {
+                error!("error writing transfer states to {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// Future that writes the changes of a transfer store that were not written yet, every PERSIST_INTERVAL, until the
+/// shutdown is complete
+pub struct PersistTransfers {
+    store: Arc<RwLock<TransferStore>>,
+    shutdown: Shutdown,
+    handle: reactor::Handle,
+    timeout: reactor::Timeout,
+}
+
+impl PersistTransfers {
+    /// Returns a newly created PersistTransfers Future
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Store to write
+    /// * `shutdown` - Shutdown of the relay, the store is flushed one last time before it completes
+    /// * `handle` - Handle to the event loop
+    pub fn new(store: &Arc<RwLock<TransferStore>>, shutdown: &Shutdown, handle: &reactor::Handle) -> Self {
+        PersistTransfers {
+            store: store.clone(),
+            shutdown: shutdown.clone(),
+            handle: handle.clone(),
+            timeout: reactor::Timeout::new(PERSIST_INTERVAL, handle).expect("error creating timeout"),
+        }
+    }
+}
+
+impl Future for PersistTransfers {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            try_ready!(self.timeout.poll().map_err(|e| {
+                error!("error waiting to write transfer states: {:?}", e);
+            }));
+            self.store
+                .write()
+                .map_err(|e| {
+                    error!("Failed to acquire write lock {:?}", e);
+                })?
+                .flush();
+            if self.shutdown.is_completed() {
+                return Ok(Async::Ready(()));
+            }
+            self.timeout = reactor::Timeout::new(PERSIST_INTERVAL, &self.handle).expect("error creating timeout");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("relay-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn should_reload_states_after_reopen() {
+        // arrange
+        let path = temp_path("reload");
+        let mut store = TransferStore::open(&path).unwrap();
+        // act
+        store.put(H256::from_low_u64_be(1), TransferApprovalState::Sent);
+        store.put(H256::from_low_u64_be(2), TransferApprovalState::Removed);
+        store.flush();
+        let reopened = TransferStore::open(&path).unwrap();
+        // assert
+        assert_eq!(
+            reopened.peek(&H256::from_low_u64_be(1)),
+            Some(&TransferApprovalState::Sent)
+        );
+        assert_eq!(
+            reopened.peek(&H256::from_low_u64_be(2)),
+            Some(&TransferApprovalState::Removed)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_not_evict_in_flight_transfers() {
+        // arrange
+        let mut store = TransferStore::in_memory();
+        store.put(H256::zero(), TransferApprovalState::Sent);
+        // act
+        for i in 1..=(MAX_SETTLED_TRANSFERS as u64 + 10) {
+            store.put(H256::from_low_u64_be(i), TransferApprovalState::Mined);
+        }
+        // assert
+        assert_eq!(store.peek(&H256::zero()), Some(&TransferApprovalState::Sent));
+        assert_eq!(store.in_flight().len(), 1);
+        assert_eq!(store.records.len(), MAX_SETTLED_TRANSFERS + 1);
+    }
+
+    #[test]
+    fn should_not_evict_watched_removed_transfers() {
+        // arrange
+        let mut store = TransferStore::in_memory();
+        store.put(H256::zero(), TransferApprovalState::Removed);
+        store.watch(H256::zero());
+        // act
+        for i in 1..=(MAX_SETTLED_TRANSFERS as u64 + 10) {
+            store.put(H256::from_low_u64_be(i), TransferApprovalState::Mined);
+        }
+        let watched = store.peek(&H256::zero()).copied();
+        store.unwatch(H256::zero());
+        store.put(H256::from_low_u64_be(0xffff), TransferApprovalState::Mined);
+        // assert
+        assert_eq!(watched, Some(TransferApprovalState::Removed));
+        assert_eq!(store.peek(&H256::zero()), None);
+    }
+}
@@ -0,0 +1,292 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_core::reactor;
+use web3::futures::future::join_all;
+use web3::futures::prelude::*;
+use web3::futures::try_ready;
+use web3::types::{Log, H256, U64};
+use web3::DuplexTransport;
+
+use super::past::{transfer_log_filter, transfers_from_logs, ValidateAndApproveTransfer, LOOKBACK_RANGE};
+use super::transfer::Transfer;
+use crate::eth::websocket::Backoff;
+use crate::metrics;
+use crate::relay::{Network, TransferApprovalState};
+use crate::store::limits::unix_time;
+
+/// Number of blocks to check for transfers in a single log query
+pub const BACKFILL_WINDOW: u64 = 1_000;
+/// Delay before the first retry of a failed backfill
+pub const BACKFILL_RETRY_INITIAL: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed backfill
+pub const BACKFILL_RETRY_MAX: Duration = Duration::from_secs(60);
+/// Attempts at a window before a transfer whose approval keeps failing is held, so the backfill moves past it
+pub const BACKFILL_APPROVAL_ATTEMPTS: u32 = 5;
+
+enum BackfillState {
+    GetHead(Box<dyn Future<Item = U64, Error = ()>>),
+    GetLogs(u64, u64, u64, Box<dyn Future<Item = Vec<Log>, Error = ()>>),
+    GetTransfers(u64, u64, u64, Box<dyn Future<Item = Vec<Transfer>, Error = ()>>),
+    Approve(u64, u64, u64, Box<dyn Future<Item = Vec<(Transfer, bool)>, Error = ()>>),
+    Retry(reactor::Timeout),
+}
+
+/// Future that finds every Transfer to the relay since the last checkpoint and approves any that were missed.
+/// Walks the chain in windows of BACKFILL_WINDOW blocks, and moves the checkpoint forward once the approvals of a
+/// window are done. Without a checkpoint, it only looks back LOOKBACK_RANGE blocks.
+/// A failed backfill starts again from the checkpoint after a backoff, until it completes or the relay shuts down.
+/// A transfer whose approval fails BACKFILL_APPROVAL_ATTEMPTS times is held for an operator to release, instead of
+/// failing its window forever
+pub struct BackfillTransfers<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    target: Network<T>,
+    handle: reactor::Handle,
+    backoff: Backoff,
+    failures: HashMap<H256, u32>,
+    state: BackfillState,
+}
+
+impl<T: DuplexTransport + 'static> BackfillTransfers<T> {
+    /// Returns a newly created BackfillTransfers Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were performed
+    /// * `target` - Network where the transfers will be approved for a withdrawal
+    /// * `handle` - Handle to spawn new futures
+    pub fn new(source: &Network<T>, target: &Network<T>, handle: &reactor::Handle) -> Self {
+        BackfillTransfers {
+            source: source.clone(),
+            target: target.clone(),
+            handle: handle.clone(),
+            backoff: Backoff::new(BACKFILL_RETRY_INITIAL, BACKFILL_RETRY_MAX),
+            failures: HashMap::new(),
+            state: BackfillTransfers::get_head(source),
+        }
+    }
+
+    /// Returns the GetHead state, that starts the backfill from the checkpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were performed
+    fn get_head(source: &Network<T>) -> BackfillState {
+        let network_type = source.network_type;
+        let future = source.web3.eth().block_number().map_err(move |e| {
+            error!("error getting block number on {:?}: {:?}", network_type, e);
+        });
+        BackfillState::GetHead(Box::new(future))
+    }
+
+    /// Returns the GetLogs state for the window starting at the given block
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - First block of the window
+    /// * `end` - Last block to backfill
+    fn next_window(&self, start: u64, end: u64) -> BackfillState {
+        let window_end = cmp::min(start + BACKFILL_WINDOW - 1, end);
+        let network_type = self.source.network_type;
+        debug!(
+            "backfilling transfers between {} and {} on {:?}",
+            start, window_end, network_type
+        );
+        let filter = transfer_log_filter(&self.source, start, window_end);
+        let future = self.source.web3.eth().logs(filter).map_err(move |e| {
+            error!("error getting transfer logs on {:?}: {:?}", network_type, e);
+        });
+        BackfillState::GetLogs(start, window_end, end, Box::new(future))
+    }
+
+    /// Counts the failed approvals of a window. Returns true once every failed transfer is out of attempts, after
+    /// holding them, so the window is done
+    ///
+    /// # Arguments
+    ///
+    /// * `failed` - Transfers of the window whose approval failed
+    fn give_up(&mut self, failed: &[Transfer]) -> bool {
+        let mut retry = false;
+        for transfer in failed {
+            let attempts = self.failures.entry(transfer.get_withdrawal_hash()).or_insert(0);
+            *attempts += 1;
+            retry |= *attempts < BACKFILL_APPROVAL_ATTEMPTS;
+        }
+        if retry {
+            return false;
+        }
+        let network_type = self.source.network_type;
+        let reason = format!("approval failed {} times while backfilling", BACKFILL_APPROVAL_ATTEMPTS);
+        let now = unix_time();
+        for transfer in failed {
+            let withdrawal_hash = transfer.get_withdrawal_hash();
+            self.failures.remove(&withdrawal_hash);
+            error!("holding transfer on {:?}, {}: {}", network_type, reason, transfer);
+            match self.source.limits.write() {
+                Ok(mut limits) => {
+                    if limits.hold(transfer, &reason, now) {
+                        metrics::transfer_held(network_type, limits.held().len());
+                    }
+                }
+                Err(e) => error!("error acquiring limits lock on {:?}: {:?}", network_type, e),
+            }
+            match self.source.pending.write() {
+                Ok(mut pending) => pending.put(withdrawal_hash, TransferApprovalState::Held),
+                Err(e) => error!("error acquiring pending lock on {:?}: {:?}", network_type, e),
+            }
+        }
+        true
+    }
+
+    /// Polls the current state, failing if any step of the backfill fails
+    fn poll_backfill(&mut self) -> Poll<(), ()> {
+        let network_type = self.source.network_type;
+        loop {
+            let next = match self.state {
+                BackfillState::GetHead(ref mut future) => {
+                    let head = try_ready!(future.poll()).as_u64();
+                    if head < self.source.confirmations {
+                        return Ok(Async::Ready(()));
+                    }
+                    let end = head - self.source.confirmations;
+                    let checkpoint = self
+                        .source
+                        .checkpoint
+                        .read()
+                        .map_err(|e| {
+                            error!("Failed to acquire read lock {:?}", e);
+                        })?
+                        .get();
+                    let start = match checkpoint {
+                        Some(block) => block + 1,
+                        None => end.saturating_sub(LOOKBACK_RANGE),
+                    };
+                    if start > end {
+                        info!("no blocks to backfill on {:?}", network_type);
+                        return Ok(Async::Ready(()));
+                    }
+                    info!("backfilling transfers from {} to {} on {:?}", start, end, network_type);
+                    self.next_window(start, end)
+                }
+                BackfillState::GetLogs(start, window_end, end, ref mut future) => {
+                    let logs = try_ready!(future.poll());
+                    debug!("found {} transfers on {:?}", logs.len(), network_type);
+                    BackfillState::GetTransfers(start, window_end, end, transfers_from_logs(&self.source, logs))
+                }
+                BackfillState::GetTransfers(start, window_end, end, ref mut future) => {
+                    let transfers = try_ready!(future.poll());
+                    let futures: Vec<Box<dyn Future<Item = (Transfer, bool), Error = ()>>> = transfers
+                        .into_iter()
+                        .map(|transfer| {
+                            let future: Box<dyn Future<Item = (Transfer, bool), Error = ()>> = Box::new(
+                                ValidateAndApproveTransfer::new(&self.source, &self.target, &self.handle, &transfer)
+                                    .waiting_for_approval()
+                                    .then(move |result| Ok((transfer, result.is_ok()))),
+                            );
+                            future
+                        })
+                        .collect();
+                    BackfillState::Approve(start, window_end, end, Box::new(join_all(futures)))
+                }
+                BackfillState::Approve(start, window_end, end, ref mut future) => {
+                    let failed: Vec<Transfer> = try_ready!(future.poll())
+                        .into_iter()
+                        .filter(|(_, approved)| !approved)
+                        .map(|(transfer, _)| transfer)
+                        .collect();
+                    if !failed.is_empty() && !self.give_up(&failed) {
+                        return Err(());
+                    }
+                    self.backoff.reset();
+                    let advanced = self
+                        .source
+                        .checkpoint
+                        .write()
+                        .map_err(|e| {
+                            error!("Failed to acquire write lock {:?}", e);
+                        })?
+                        .advance(start, window_end);
+                    if !advanced {
+                        // Windows start right after the checkpoint, so only a concurrent change gets here
+                        error!("checkpoint on {:?} is behind block {}", network_type, start);
+                        return Err(());
+                    }
+                    if window_end >= end {
+                        info!("finished backfilling transfers on {:?}", network_type);
+                        return Ok(Async::Ready(()));
+                    }
+                    self.next_window(window_end + 1, end)
+                }
+                BackfillState::Retry(ref mut timeout) => {
+                    try_ready!(timeout.poll().map_err(|e| {
+                        error!("error waiting to retry the backfill on {:?}: {:?}", network_type, e);
+                    }));
+                    BackfillTransfers::get_head(&self.source)
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for BackfillTransfers<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.poll_backfill() {
+                Err(()) if !self.source.shutdown.is_triggered() => {
+                    let delay = self.backoff.delay();
+                    warn!(
+                        "backfill failed on {:?}, retrying from the checkpoint in {}s",
+                        self.source.network_type,
+                        delay.as_secs()
+                    );
+                    let timeout = reactor::Timeout::new(delay, &self.handle).expect("error creating timeout");
+                    self.state = BackfillState::Retry(timeout);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::transport::MockTransport;
+    use crate::relay::NetworkType;
+    use web3::types::{Address, U256};
+
+    #[test]
+    fn give_up_should_hold_a_transfer_once_out_of_attempts() {
+        // arrange
+        let eloop = reactor::Core::new().unwrap();
+        let mock = MockTransport::new();
+        let source = mock.new_network(NetworkType::Home).unwrap();
+        let target = mock.new_network(NetworkType::Side).unwrap();
+        let mut backfill = BackfillTransfers::new(&source, &target, &eloop.handle());
+        let transfer = Transfer {
+            destination: Address::zero(),
+            amount: U256::one(),
+            tx_hash: H256::zero(),
+            block_hash: H256::zero(),
+            block_number: U64::zero(),
+            removed: false,
+        };
+        // act
+        let retried: Vec<bool> = (1..BACKFILL_APPROVAL_ATTEMPTS)
+            .map(|_| backfill.give_up(&[transfer]))
+            .collect();
+        let held = backfill.give_up(&[transfer]);
+        // assert
+        assert!(retried.iter().all(|done| !done));
+        assert!(held);
+        assert_eq!(source.limits.read().unwrap().held().len(), 1);
+        assert_eq!(
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
+            Some(&TransferApprovalState::Held)
+        );
+    }
+}
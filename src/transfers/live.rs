@@ -1,4 +1,3 @@
-use lru::LruCache;
 use std::sync::{PoisonError, RwLockWriteGuard};
 use tokio_core::reactor;
 use web3::futures::future::{ok, Either, Future};
@@ -9,10 +8,12 @@ use web3::types::{Address, Filter, Log, H256, U256};
 use web3::DuplexTransport;
 use web3::Error;
 
-use super::transfer::Transfer;
+use super::transfer::{log_withdrawal_hash, Transfer};
 use crate::eth::Event;
 use crate::extensions::flushed::{Flushed, FlushedStream};
+use crate::metrics;
 use crate::relay::{Network, TransferApprovalState};
+use crate::store::TransferStore;
 
 /// Stream of events that have match the given filter.
 /// Passes the transaction receipt and log over the given tx upon confirmation (or removal)
@@ -41,7 +42,7 @@ impl<T: DuplexTransport + 'static> WatchLiveLogs<T> {
             .clone()
             .eth_subscribe()
             .subscribe_logs(filter.clone())
-            .flushed(&source.flushed.clone());
+            .flushed(&source.flushed.clone(), &source.shutdown);
 
         WatchLiveLogs {
             source: source.clone(),
@@ -63,12 +64,25 @@ impl<T: DuplexTransport + 'static> WatchLiveLogs<T> {
                 Box::new(Either::A(ok(())))
             },
             |tx_hash| {
+                let withdrawal_hash = log_withdrawal_hash(&log).unwrap_or(tx_hash);
+                if !removed {
+                    if let Ok(mut pending) = source.pending.write().map_err(|e| {
+                        error!("Failed to acquire write lock {:?}", e);
+                    }) {
+                        if pending.peek(&withdrawal_hash).is_none() {
+                            metrics::transfer_seen(network_type);
+                            pending.put(withdrawal_hash, TransferApprovalState::Seen);
+                        }
+                    }
+                }
                 let future = source
-                    .get_receipt(removed, tx_hash)
+                    .get_receipt(removed, tx_hash, withdrawal_hash)
                     .and_then(move |receipt_option| receipt_option.ok_or(()))
                     .and_then(move |receipt| {
                         let event = Event::new(&log, &receipt);
-                        tx.unbounded_send(event).unwrap();
+                        if tx.unbounded_send(event).is_err() {
+                            warn!("error sending live transfer on {:?}, processing stopped", network_type);
+                        }
                         Ok(())
                     })
                     .map_err(move |_| {
@@ -143,55 +157,84 @@ impl<T: DuplexTransport + 'static> ProcessTransfer<T> {
         &self,
         transfer: Transfer,
         state: Option<TransferApprovalState>,
-    ) -> Result<(), PoisonError<RwLockWriteGuard<'_, LruCache<H256, TransferApprovalState>>>> {
+    ) -> Result<(), PoisonError<RwLockWriteGuard<'_, TransferStore>>> {
+        let withdrawal_hash = transfer.get_withdrawal_hash();
         match state {
-            Some(TransferApprovalState::Sent) => {
+            Some(TransferApprovalState::Sent) | Some(TransferApprovalState::Mined) => {
                 if transfer.removed {
                     self.source
                         .pending
                         .write()?
-                        .put(transfer.tx_hash, TransferApprovalState::Removed);
-                    self.handle.spawn(transfer.unapprove_withdrawal(&self.target));
+                        .put(withdrawal_hash, TransferApprovalState::Removed);
+                    self.handle
+                        .spawn(transfer.unapprove_withdrawal(&self.source, &self.target));
                 }
             }
-            Some(TransferApprovalState::Removed) => {
+            Some(TransferApprovalState::Removed) | Some(TransferApprovalState::Unapproved) => {
                 // Remove logs can be added again
-                if !transfer.removed {
+                if !transfer.removed && !self.source.buffer_if_paused(&transfer) {
                     self.source
                         .pending
                         .write()?
-                        .put(transfer.tx_hash, TransferApprovalState::Sent);
+                        .put(withdrawal_hash, TransferApprovalState::Sent);
                     self.handle
                         .spawn(transfer.approve_withdrawal(&self.source, &self.target));
                 }
             }
-            None => {
+            Some(TransferApprovalState::Held) => {
+                // Never approved, so there is nothing to unapprove
+                if transfer.removed {
+                    if let Ok(mut limits) = self.source.limits.write() {
+                        limits.discard(&withdrawal_hash);
+                        metrics::held_transfers(self.source.network_type, limits.held().len());
+                    }
+                    self.source
+                        .pending
+                        .write()?
+                        .put(withdrawal_hash, TransferApprovalState::Removed);
+                }
+            }
+            Some(TransferApprovalState::Paused) => {
+                // Never approved, so there is nothing to unapprove
+                if transfer.removed {
+                    if let Ok(mut pause) = self.source.pause.write() {
+                        pause.discard_transfer(self.source.network_type, &withdrawal_hash);
+                        metrics::paused(&pause.status());
+                    }
+                    self.source
+                        .pending
+                        .write()?
+                        .put(withdrawal_hash, TransferApprovalState::Removed);
+                }
+            }
+            Some(TransferApprovalState::Seen) | Some(TransferApprovalState::Confirmed) | None => {
                 if transfer.removed {
                     // Write removed state
                     self.source
                         .pending
                         .write()?
-                        .put(transfer.tx_hash, TransferApprovalState::Removed);
-                    // LRU Cache will drop values, so we need to recheck the chain
+                        .put(withdrawal_hash, TransferApprovalState::Removed);
+                    // Approvals sent by a previous run may not be in the store, so we need to recheck the chain
+                    let source = self.source.clone();
                     let target = self.target.clone();
                     let unapprove_future =
                         transfer
                             .check_withdrawal(&self.target, None)
                             .and_then(move |not_approved| {
                                 if !not_approved {
-                                    Either::A(transfer.unapprove_withdrawal(&target))
+                                    Either::A(transfer.unapprove_withdrawal(&source, &target))
                                 } else {
                                     Either::B(ok(()))
                                 }
                             });
                     self.handle.spawn(unapprove_future);
-                } else {
+                } else if !self.source.buffer_if_paused(&transfer) {
                     self.source
                         .pending
                         .write()?
-                        .put(transfer.tx_hash, TransferApprovalState::Sent);
+                        .put(withdrawal_hash, TransferApprovalState::Sent);
 
-                    // LRU Cache will drop values, so we need to recheck the chain
+                    // Approvals sent by a previous run may not be in the store, so we need to recheck the chain
                     let source = self.source.clone();
                     let target = self.target.clone();
                     let approve_future = transfer
@@ -200,6 +243,11 @@ impl<T: DuplexTransport + 'static> ProcessTransfer<T> {
                             if not_approved {
                                 Either::A(transfer.approve_withdrawal(&source, &target))
                             } else {
+                                if let Ok(mut pending) = source.pending.write().map_err(|e| {
+                                    error!("Failed to acquire write lock {:?}", e);
+                                }) {
+                                    pending.put(withdrawal_hash, TransferApprovalState::Mined);
+                                }
                                 Either::B(ok(()))
                             }
                         });
@@ -227,10 +275,15 @@ impl<T: DuplexTransport + 'static> Future for ProcessTransfer<T> {
                     }
                     let amount: U256 = event.log.data.0[..32].into();
                     let removed = event.log.removed.unwrap_or(false);
-                    let transfer =
+                    let mut transfer =
                         Transfer::from_receipt(destination, amount, removed, &event.receipt).map_err(|e| {
                             error!("error getting transfer from receipt {:?}: {:?}", event.receipt, e);
                         })?;
+                    // A removed log keeps the block it was first mined in, which is what the withdrawal was keyed on
+                    if let (Some(block_hash), Some(block_number)) = (event.log.block_hash, event.log.block_number) {
+                        transfer.block_hash = block_hash;
+                        transfer.block_number = block_number;
+                    }
                     info!(
                         "transfer event on {:?} confirmed, approving {}",
                         network_type, &transfer
@@ -240,7 +293,7 @@ impl<T: DuplexTransport + 'static> Future for ProcessTransfer<T> {
                             error!("Failed to acquire read lock {:?}", e);
                         })?;
 
-                        lock.peek(&transfer.tx_hash).copied()
+                        lock.peek(&transfer.get_withdrawal_hash()).copied()
                     };
                     self.advance_transfer_approval(transfer, value).map_err(|e| {
                         error!("Failed to acquire write lock {:?}", e);
@@ -290,7 +343,7 @@ mod tests {
         processor.advance_transfer_approval(transfer, None).unwrap();
         // assert
         assert_eq!(
-            source.pending.read().unwrap().peek(&transfer.tx_hash),
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
             Some(&TransferApprovalState::Sent)
         );
     }
@@ -318,7 +371,10 @@ mod tests {
             .advance_transfer_approval(transfer, Some(TransferApprovalState::Sent))
             .unwrap();
         // assert
-        assert_eq!(source.pending.read().unwrap().peek(&transfer.tx_hash), None);
+        assert_eq!(
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
+            None
+        );
     }
 
     #[test]
@@ -345,7 +401,7 @@ mod tests {
             .unwrap();
         // assert
         assert_eq!(
-            source.pending.read().unwrap().peek(&transfer.tx_hash),
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
             Some(&TransferApprovalState::Sent)
         );
     }
@@ -374,7 +430,7 @@ mod tests {
             .unwrap();
         // assert
         assert_eq!(
-            source.pending.read().unwrap().peek(&transfer.tx_hash),
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
             Some(&TransferApprovalState::Removed)
         );
     }
@@ -401,7 +457,7 @@ mod tests {
         processor.advance_transfer_approval(transfer, None).unwrap();
         // assert
         assert_eq!(
-            source.pending.read().unwrap().peek(&transfer.tx_hash),
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
             Some(&TransferApprovalState::Removed)
         );
     }
@@ -429,6 +485,9 @@ mod tests {
             .advance_transfer_approval(transfer, Some(TransferApprovalState::Removed))
             .unwrap();
         // assert
-        assert_eq!(source.pending.read().unwrap().peek(&transfer.tx_hash), None);
+        assert_eq!(
+            source.pending.read().unwrap().peek(&transfer.get_withdrawal_hash()),
+            None
+        );
     }
 }
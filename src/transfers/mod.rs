@@ -1,4 +1,6 @@
+pub mod backfill;
 pub mod live;
 pub mod past;
+pub mod rescan;
 pub mod transfer;
 pub mod withdrawal;
@@ -3,22 +3,165 @@ use web3::futures::future;
 use web3::futures::prelude::*;
 use web3::futures::sync::mpsc;
 use web3::futures::try_ready;
-use web3::types::{Address, BlockNumber, FilterBuilder, TransactionReceipt, H256, U256};
+use web3::types::{Address, BlockNumber, Filter, FilterBuilder, Log, TransactionReceipt, H256, U256};
 use web3::DuplexTransport;
 use web3::Error;
 
 use super::transfer::Transfer;
+use super::withdrawal::ApproveWithdrawal;
 use crate::eth::contracts::TRANSFER_EVENT_SIGNATURE;
+use crate::eth::scan::ScanLogs;
 use crate::extensions::flushed::Flushed;
 use crate::extensions::timeout::Timeout;
-use crate::relay::Network;
+use crate::relay::{Network, TransferApprovalState};
 
 pub const LOOKBACK_RANGE: u64 = 1_000;
 pub const LOOKBACK_LEEWAY: u64 = 5;
 
+/// Messages sent from a past transfer scan to ProcessPastTransfers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PastTransfer {
+    /// A transfer found in the scanned window
+    Transfer(Transfer),
+    /// Every transfer between these two blocks, inclusive, has been sent
+    Scanned(u64, u64),
+}
+
+/// Returns a filter for Transfer logs to the relay contract between two blocks
+///
+/// # Arguments
+///
+/// * `source` - Network where the transfers are performed
+/// * `from` - First block to check
+/// * `to` - Last block to check
+pub fn transfer_log_filter<T: DuplexTransport + 'static>(source: &Network<T>, from: u64, to: u64) -> Filter {
+    FilterBuilder::default()
+        .address(vec![source.token.address()])
+        .from_block(BlockNumber::from(from))
+        .to_block(BlockNumber::from(to))
+        .topics(
+            Some(vec![TRANSFER_EVENT_SIGNATURE.into()]),
+            None,
+            Some(vec![source.relay.address().into()]),
+            None,
+        )
+        .build()
+}
+
+/// Returns a Future with the Transfers in the given logs, in the same order as the logs.
+/// Removed logs and mints are skipped. Fails if the receipt of any other log is missing, so no transfer is dropped
+///
+/// # Arguments
+///
+/// * `source` - Network where the transfers are performed
+/// * `logs` - Transfer logs to the relay contract
+pub fn transfers_from_logs<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    logs: Vec<Log>,
+) -> Box<dyn Future<Item = Vec<Transfer>, Error = ()>> {
+    let network_type = source.network_type;
+    let futures: Vec<_> = logs
+        .into_iter()
+        .filter_map(|log| {
+            if Some(true) == log.removed {
+                warn!("found removed log on {:?}", network_type);
+                return None;
+            }
+            let tx_hash = match log.transaction_hash {
+                Some(tx_hash) => tx_hash,
+                None => {
+                    warn!("log missing transaction hash on {:?}", network_type);
+                    return None;
+                }
+            };
+            let destination: Address = log.topics[1].into();
+            let amount: U256 = log.data.0[..32].into();
+            if destination == Address::zero() {
+                info!("found mint on {:?}. Skipping", network_type);
+                return None;
+            }
+            info!("found transfer event on {:?} in tx hash {:?}", network_type, &tx_hash);
+            let future = source
+                .web3
+                .eth()
+                .transaction_receipt(tx_hash)
+                .map_err(move |e| {
+                    error!("error getting transaction receipt on {:?}: {:?}", network_type, e);
+                })
+                .and_then(move |receipt_option| match receipt_option {
+                    Some(receipt) => Transfer::from_receipt(destination, amount, false, &receipt).map_err(|msg| {
+                        error!("error producing transfer from receipt on {:?}: {}", network_type, msg);
+                    }),
+                    None => {
+                        error!(
+                            "no receipt found for transaction hash {} on {:?}",
+                            &tx_hash, network_type
+                        );
+                        Err(())
+                    }
+                });
+            Some(future)
+        })
+        .collect();
+    Box::new(future::join_all(futures))
+}
+
+/// Returns a Future that scans a lookback window for transfers, and sends them to ProcessPastTransfers in block order,
+/// followed by the window, so the checkpoint only moves once they are done. Nothing is sent if any receipt is missing
+///
+/// # Arguments
+///
+/// * `source` - Network where the transfers are performed
+/// * `handle` - Handle to spawn new futures
+/// * `tx` - Sender to ProcessPastTransfers
+/// * `from` - First block to check
+/// * `to` - Last block to check
+fn scan_lookback<T: DuplexTransport + 'static>(
+    source: &Network<T>,
+    handle: &reactor::Handle,
+    tx: &mpsc::UnboundedSender<PastTransfer>,
+    from: u64,
+    to: u64,
+) -> impl Future<Item = (), Error = Error> {
+    let network_type = source.network_type;
+    let filter_source = source.clone();
+    let source = source.clone();
+    let handle = handle.clone();
+    let tx = tx.clone();
+    ScanLogs::new(&source, from, to, move |from, to| {
+        transfer_log_filter(&filter_source, from, to)
+    })
+    .map(|(_, logs)| logs)
+    .concat2()
+    .map_err(|_| Error::Internal)
+    .and_then(move |logs| {
+        debug!("found {} transfers on {:?}", logs.len(), network_type);
+        handle.spawn(transfers_from_logs(&source, logs).then(move |result| {
+            match result {
+                Ok(transfers) => {
+                    for transfer in transfers {
+                        if tx.unbounded_send(PastTransfer::Transfer(transfer)).is_err() {
+                            warn!("error sending past transfer on {:?}, processing stopped", network_type);
+                            return Ok(());
+                        }
+                    }
+                    if tx.unbounded_send(PastTransfer::Scanned(from, to)).is_err() {
+                        warn!("error sending scanned blocks on {:?}, processing stopped", network_type);
+                    }
+                }
+                Err(_) => {
+                    error!("error finding transfers up to {} on {:?}", to, network_type);
+                }
+            };
+            Ok(())
+        }));
+        Ok(())
+    })
+}
+
 /// Future to handle the Stream of missed transfers by checking them, and approving them
 pub struct ProcessPastTransfers<T: DuplexTransport + 'static> {
-    stream: mpsc::UnboundedReceiver<Transfer>,
+    stream: mpsc::UnboundedReceiver<PastTransfer>,
     future: Option<ValidateAndApproveTransfer<T>>,
     source: Network<T>,
     target: Network<T>,
@@ -35,7 +178,7 @@ impl<T: DuplexTransport + 'static> ProcessPastTransfers<T> {
     /// * `handle` - Handle to spawn new futures
     pub fn new(
         source: &Network<T>,
-        rx: mpsc::UnboundedReceiver<Transfer>,
+        rx: mpsc::UnboundedReceiver<PastTransfer>,
         target: &Network<T>,
         handle: &reactor::Handle,
     ) -> Self {
@@ -63,13 +206,28 @@ impl<T: DuplexTransport + 'static> Future for ProcessPastTransfers<T> {
             } else {
                 let transfer_option = try_ready!(self.stream.poll());
                 match transfer_option {
-                    Some(transfer) => {
-                        self.future = Some(ValidateAndApproveTransfer::new(
-                            &self.source,
-                            &self.target,
-                            &self.handle,
-                            &transfer,
-                        ))
+                    Some(PastTransfer::Transfer(transfer)) => {
+                        self.future = Some(
+                            ValidateAndApproveTransfer::new(&self.source, &self.target, &self.handle, &transfer)
+                                .waiting_for_approval(),
+                        )
+                    }
+                    Some(PastTransfer::Scanned(from, to)) => {
+                        // Transfers are approved one at a time, so everything in the window is done
+                        let advanced = self
+                            .source
+                            .checkpoint
+                            .write()
+                            .map_err(|e| {
+                                error!("Failed to acquire write lock {:?}", e);
+                            })?
+                            .advance(from, to);
+                        if !advanced {
+                            debug!(
+                                "not moving the checkpoint to {} on {:?}, blocks before {} were not scanned",
+                                to, self.source.network_type, from
+                            );
+                        }
                     }
                     None => {
                         return Ok(Async::Ready(()));
@@ -80,13 +238,16 @@ impl<T: DuplexTransport + 'static> Future for ProcessPastTransfers<T> {
     }
 }
 
-/// Future to check a transfer against the contract and approve is necessary
+/// Future to check a transfer against the contract and approve is necessary.
+/// Resolves to true if an approval was sent, or once it is done if waiting for the approval
 pub struct ValidateAndApproveTransfer<T: DuplexTransport + 'static> {
     source: Network<T>,
     target: Network<T>,
     handle: reactor::Handle,
     transfer: Transfer,
     future: Box<dyn Future<Item = bool, Error = ()>>,
+    wait: bool,
+    approval: Option<ApproveWithdrawal<T>>,
 }
 
 impl<T: DuplexTransport + 'static> ValidateAndApproveTransfer<T> {
@@ -109,16 +270,27 @@ impl<T: DuplexTransport + 'static> ValidateAndApproveTransfer<T> {
             handle: handle.clone(),
             transfer: *transfer,
             future: Box::new(future),
+            wait: false,
+            approval: None,
         }
     }
+
+    /// Returns this Future, resolving once the approval is done instead of once it is sent, and failing if it fails
+    pub fn waiting_for_approval(self) -> Self {
+        ValidateAndApproveTransfer { wait: true, ..self }
+    }
 }
 
 impl<T: DuplexTransport + 'static> Future for ValidateAndApproveTransfer<T> {
-    type Item = ();
+    type Item = bool;
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(ref mut approval) = self.approval {
+            try_ready!(approval.poll());
+            return Ok(Async::Ready(true));
+        }
         let needs_approval = try_ready!(self.future.poll());
-        if needs_approval {
+        if needs_approval && !self.source.buffer_if_paused(&self.transfer) {
             let source = self.source.clone();
             let target = self.target.clone();
             let handle = self.handle.clone();
@@ -126,9 +298,22 @@ impl<T: DuplexTransport + 'static> Future for ValidateAndApproveTransfer<T> {
                 "approving withdrawal on {:?} from missed transfer on {:?}: {:?}",
                 target.network_type, source.network_type, self.transfer
             );
-            handle.spawn(self.transfer.approve_withdrawal(&source, &target));
+            source
+                .pending
+                .write()
+                .map_err(|e| {
+                    error!("Failed to acquire write lock {:?}", e);
+                })?
+                .put(self.transfer.get_withdrawal_hash(), TransferApprovalState::Sent);
+            let approval = self.transfer.approve_withdrawal(&source, &target);
+            if self.wait {
+                self.approval = Some(approval);
+                return self.poll();
+            }
+            handle.spawn(approval);
+            return Ok(Async::Ready(true));
         }
-        Ok(Async::Ready(()))
+        Ok(Async::Ready(false))
     }
 }
 
@@ -144,152 +329,84 @@ impl WatchPastTransfers {
     /// * `handle` - Handle to spawn new futures
     pub fn new<T: DuplexTransport + 'static>(
         source: &Network<T>,
-        tx: mpsc::UnboundedSender<Transfer>,
+        tx: mpsc::UnboundedSender<PastTransfer>,
         handle: &reactor::Handle,
     ) -> Self {
-        let token_address = source.token.address();
-        let relay_address = source.relay.address();
         let network_type = source.network_type;
         let interval = source.interval;
         let confirmations = source.confirmations * 2;
         let web3 = source.web3.clone();
         let timeout = source.timeout;
         let flushed = source.flushed.clone();
+        let shutdown = source.shutdown.clone();
+        let source = source.clone();
 
         let future = {
             let handle = handle.clone();
 
-            web3
-            .clone()
-            .eth_subscribe()
-            .subscribe_new_heads()
-            .flushed(&flushed)
-            .timeout(timeout, &handle)
-            .for_each(move |head| {
+            web3.clone()
+                .eth_subscribe()
+                .subscribe_new_heads()
+                .flushed(&flushed, &shutdown)
+                .timeout(timeout, &handle)
+                .for_each(move |head| {
                     let web3 = web3.clone();
                     let handle = handle.clone();
+                    let source = source.clone();
                     let tx = tx.clone();
-                    head.number.map_or_else(|| {
-                        warn!("No block number in header on {:?}", network_type);
-                        future::Either::A(future::ok(()))
-                    },
-                    move |block_number| {
-                        block_number.checked_rem(interval.into()).map(|u| u.as_u64()).map_or_else(|| {
-                            error!("Error computing block_number({}) % interval({})", block_number, interval);
-                            future::Either::A(future::err(Error::Internal))
-                        }, move |remainder| {
-                            if remainder != 0 {
-                                return future::Either::A(future::ok(()));
-                            }
-                            let handle = handle.clone();
-                            let tx = tx.clone();
-                            let web3 = web3.clone();
-                            future::Either::B(web3.eth()
-                                .block_number()
-                                .and_then(move |block| {
-                                    let from = if block.as_u64() < confirmations + LOOKBACK_RANGE  {
-                                        0
-                                    } else {
-                                        block.as_u64() - confirmations - LOOKBACK_RANGE
-                                    };
-                                    if block.as_u64() < confirmations + LOOKBACK_LEEWAY {
-                                        error!("Not enough blocks to check");
-                                        return Err(Error::Internal);
-                                    }
-                                    let to = block.as_u64() - confirmations - LOOKBACK_LEEWAY;
-                                    debug!(
-                                        "checking logs between {} and {} on {:?}",
-                                        from,
-                                        to,
-                                        network_type,
-                                    );
-                                    Ok(FilterBuilder::default()
-                                        .address(vec![token_address])
-                                        .from_block(BlockNumber::from(from))
-                                        .to_block(BlockNumber::from(to))
-                                        .topics(
-                                            Some(vec![TRANSFER_EVENT_SIGNATURE.into()]),
-                                            None,
-                                            Some(vec![relay_address.into()]),
-                                            None,
-                                        ).build())
-                                }).and_then(move |filter| {
-                                    let web3 = web3.clone();
-                                    web3.clone().eth().logs(filter).and_then(move |logs| {
-                                        let tx = tx.clone();
-                                        let web3 = web3.clone();
-                                        let handle = handle.clone();
-                                        debug!(
-                                            "found {} transfers on {:?}",
-                                            logs.len(),
-                                            network_type
+                    head.number.map_or_else(
+                        || {
+                            warn!("No block number in header on {:?}", network_type);
+                            future::Either::A(future::ok(()))
+                        },
+                        move |block_number| {
+                            block_number
+                                .checked_rem(interval.into())
+                                .map(|u| u.as_u64())
+                                .map_or_else(
+                                    || {
+                                        error!(
+                                            "Error computing block_number({}) % interval({})",
+                                            block_number, interval
                                         );
-                                        logs.iter().for_each(|log| {
-                                            if Some(true) == log.removed {
-                                                warn!("found removed log on {:?}", network_type);
-                                                return;
-                                            }
-
-                                            log.transaction_hash.map_or_else(
-                                                || {
-                                                    warn!("log missing transaction hash on {:?}", network_type);
-                                                },
-                                                |tx_hash| {
-                                                    let tx = tx.clone();
-                                                    let destination: Address = log.topics[1].into();
-                                                    let amount: U256 = log.data.0[..32].into();
-                                                    if destination == Address::zero() {
-                                                        info!("found mint on {:?}. Skipping", network_type);
-                                                        return;
+                                        future::Either::A(future::err(Error::Internal))
+                                    },
+                                    move |remainder| {
+                                        if remainder != 0 {
+                                            return future::Either::A(future::ok(()));
+                                        }
+                                        future::Either::B(
+                                            web3.eth()
+                                                .block_number()
+                                                .and_then(move |block| {
+                                                    let from = if block.as_u64() < confirmations + LOOKBACK_RANGE {
+                                                        0
+                                                    } else {
+                                                        block.as_u64() - confirmations - LOOKBACK_RANGE
+                                                    };
+                                                    if block.as_u64() < confirmations + LOOKBACK_LEEWAY {
+                                                        error!("Not enough blocks to check");
+                                                        return Err(Error::Internal);
                                                     }
-                                                    info!("found transfer event on {:?} in tx hash {:?}", network_type, &tx_hash);
-                                                    handle.spawn(
-                                                        web3.eth()
-                                                            .transaction_receipt(tx_hash)
-                                                            .and_then(move |transaction_receipt| {
-                                                                let tx_hash = tx_hash;
-                                                                transaction_receipt.map_or_else(
-                                                                    || {
-                                                                        error!(
-                                                                            "no receipt found for transaction hash {} on {:?}",
-                                                                            &tx_hash,
-                                                                            network_type
-                                                                        );
-                                                                        Ok(())
-                                                                    },
-                                                                    |receipt| {
-                                                                        let transfer_result = Transfer::from_receipt(destination, amount, false, &receipt);
-
-                                                                        match transfer_result {
-                                                                            Ok(transfer) => {
-                                                                                info!(
-                                                                                    "transfer event on {:?} confirmed, approving {}",
-                                                                                    network_type, &transfer
-                                                                                );
-                                                                                tx.unbounded_send(transfer).unwrap();
-                                                                            }
-                                                                            Err(msg) => {
-                                                                                error!("error producing transfer from receipt on {:?}: {}", network_type, msg)
-                                                                            }
-                                                                        };
-                                                                        Ok(())
-                                                                })
-                                                            }).or_else(move |e| {
-                                                                error!("error approving transaction on {:?}: {}", network_type, e);
-                                                                Ok(())
-                                                            })
+                                                    let to = block.as_u64() - confirmations - LOOKBACK_LEEWAY;
+                                                    debug!(
+                                                        "checking logs between {} and {} on {:?}",
+                                                        from, to, network_type,
                                                     );
-                                                },
-                                            );
-                                        });
-                                        Ok(())
-                                    })
-                                }))
-                        })
-                    })
-            }).map_err(move |e| {
-                error!("error in block head stream on {:?}: {:?}", network_type, e);
-            })
+                                                    Ok((from, to))
+                                                })
+                                                .and_then(move |(from, to)| {
+                                                    scan_lookback(&source, &handle, &tx, from, to)
+                                                }),
+                                        )
+                                    },
+                                )
+                        },
+                    )
+                })
+                .map_err(move |e| {
+                    error!("error in block head stream on {:?}: {:?}", network_type, e);
+                })
         };
         WatchPastTransfers(Box::new(future))
     }
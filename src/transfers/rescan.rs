@@ -0,0 +1,270 @@
+use serde_derive::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tokio_core::reactor;
+use web3::futures::future::join_all;
+use web3::futures::prelude::*;
+use web3::futures::try_ready;
+use web3::types::{Log, U64};
+use web3::DuplexTransport;
+
+use super::backfill::BACKFILL_WINDOW;
+use super::past::{transfer_log_filter, transfers_from_logs, ValidateAndApproveTransfer};
+use super::transfer::Transfer;
+use crate::relay::{Network, NetworkType};
+
+/// Number of finished jobs kept for polling
+pub const MAX_FINISHED_JOBS: usize = 100;
+
+/// Where a rescan job is at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RescanState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a rescan of a block range for missed transfers
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RescanProgress {
+    pub id: u64,
+    /// Chain the transfers were made on, home or side
+    pub chain: String,
+    pub from: u64,
+    pub to: u64,
+    /// Last block scanned, which is below `to` if it is not confirmed yet
+    pub end: Option<u64>,
+    pub blocks_scanned: u64,
+    pub transfers_found: u64,
+    pub approvals_sent: u64,
+    pub state: RescanState,
+}
+
+/// Shared handle on the progress of a rescan job, updated by RescanTransfers
+pub type RescanJob = Arc<RwLock<RescanProgress>>;
+
+/// Rescan jobs started through the endpoint, by id
+#[derive(Debug, Default)]
+pub struct RescanJobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, RescanJob>,
+}
+
+impl RescanJobs {
+    /// Registers a new running job, dropping the oldest finished jobs past MAX_FINISHED_JOBS
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were made
+    /// * `from` - First block to scan
+    /// * `to` - Last block to scan
+    pub fn start(&mut self, source: NetworkType, from: u64, to: u64) -> RescanJob {
+        self.next_id += 1;
+        let job = Arc::new(RwLock::new(RescanProgress {
+            id: self.next_id,
+            chain: format!("{:?}", source).to_lowercase(),
+            from,
+            to,
+            end: None,
+            blocks_scanned: 0,
+            transfers_found: 0,
+            approvals_sent: 0,
+            state: RescanState::Running,
+        }));
+        self.jobs.insert(self.next_id, job.clone());
+        let finished: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.read().map(|job| job.state != RescanState::Running).unwrap_or(true))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            self.jobs.remove(id);
+        }
+        job
+    }
+
+    /// Returns the progress of a job, if it is known
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the job
+    pub fn get(&self, id: u64) -> Option<RescanProgress> {
+        self.jobs
+            .get(&id)
+            .and_then(|job| job.read().ok())
+            .map(|job| job.clone())
+    }
+}
+
+enum RescanTransfersState {
+    GetHead(Box<dyn Future<Item = U64, Error = ()>>),
+    GetLogs(u64, u64, u64, Box<dyn Future<Item = Vec<Log>, Error = ()>>),
+    GetTransfers(u64, u64, u64, Box<dyn Future<Item = Vec<Transfer>, Error = ()>>),
+    Approve(u64, u64, u64, Box<dyn Future<Item = Vec<bool>, Error = ()>>),
+}
+
+/// Future that finds every Transfer to the relay in a block range and approves any that were missed.
+/// Walks the range in windows of BACKFILL_WINDOW blocks, stops at the last confirmed block, and leaves the checkpoint
+/// alone
+pub struct RescanTransfers<T: DuplexTransport + 'static> {
+    source: Network<T>,
+    target: Network<T>,
+    handle: reactor::Handle,
+    job: RescanJob,
+    state: RescanTransfersState,
+}
+
+impl<T: DuplexTransport + 'static> RescanTransfers<T> {
+    /// Returns a newly created RescanTransfers Future
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfers were performed
+    /// * `target` - Network where the transfers will be approved for a withdrawal
+    /// * `handle` - Handle to spawn new futures
+    /// * `job` - Progress of the job, with the range to scan
+    pub fn new(source: &Network<T>, target: &Network<T>, handle: &reactor::Handle, job: &RescanJob) -> Self {
+        let network_type = source.network_type;
+        let future = source.web3.eth().block_number().map_err(move |e| {
+            error!("error getting block number on {:?}: {:?}", network_type, e);
+        });
+        RescanTransfers {
+            source: source.clone(),
+            target: target.clone(),
+            handle: handle.clone(),
+            job: job.clone(),
+            state: RescanTransfersState::GetHead(Box::new(future)),
+        }
+    }
+
+    /// Returns the GetLogs state for the window starting at the given block
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - First block of the window
+    /// * `end` - Last block to rescan
+    fn next_window(&self, start: u64, end: u64) -> RescanTransfersState {
+        let window_end = cmp::min(start + BACKFILL_WINDOW - 1, end);
+        let network_type = self.source.network_type;
+        debug!(
+            "rescanning transfers between {} and {} on {:?}",
+            start, window_end, network_type
+        );
+        let filter = transfer_log_filter(&self.source, start, window_end);
+        let future = self.source.web3.eth().logs(filter).map_err(move |e| {
+            error!("error getting transfer logs on {:?}: {:?}", network_type, e);
+        });
+        RescanTransfersState::GetLogs(start, window_end, end, Box::new(future))
+    }
+
+    /// Updates the progress of the job
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Change to the progress
+    fn update<F: FnOnce(&mut RescanProgress)>(&self, update: F) {
+        match self.job.write() {
+            Ok(mut progress) => update(&mut progress),
+            Err(e) => error!("error acquiring rescan job lock: {:?}", e),
+        }
+    }
+
+    fn poll_rescan(&mut self) -> Poll<(), ()> {
+        let network_type = self.source.network_type;
+        loop {
+            let next = match self.state {
+                RescanTransfersState::GetHead(ref mut future) => {
+                    let head = try_ready!(future.poll()).as_u64();
+                    let (from, to) = self
+                        .job
+                        .read()
+                        .map(|job| (job.from, job.to))
+                        .map_err(|e| error!("error acquiring rescan job lock: {:?}", e))?;
+                    let end = cmp::min(to, head.saturating_sub(self.source.confirmations));
+                    self.update(|progress| progress.end = Some(end));
+                    if from > end {
+                        info!("no confirmed blocks to rescan from {} on {:?}", from, network_type);
+                        return Ok(Async::Ready(()));
+                    }
+                    info!("rescanning transfers from {} to {} on {:?}", from, end, network_type);
+                    self.next_window(from, end)
+                }
+                RescanTransfersState::GetLogs(start, window_end, end, ref mut future) => {
+                    let logs = try_ready!(future.poll());
+                    RescanTransfersState::GetTransfers(start, window_end, end, transfers_from_logs(&self.source, logs))
+                }
+                RescanTransfersState::GetTransfers(start, window_end, end, ref mut future) => {
+                    let transfers = try_ready!(future.poll());
+                    let found = transfers.len() as u64;
+                    self.update(|progress| progress.transfers_found += found);
+                    let futures: Vec<ValidateAndApproveTransfer<T>> = transfers
+                        .iter()
+                        .map(|transfer| {
+                            ValidateAndApproveTransfer::new(&self.source, &self.target, &self.handle, transfer)
+                        })
+                        .collect();
+                    RescanTransfersState::Approve(start, window_end, end, Box::new(join_all(futures)))
+                }
+                RescanTransfersState::Approve(start, window_end, end, ref mut future) => {
+                    let approved = try_ready!(future.poll());
+                    let sent = approved.iter().filter(|sent| **sent).count() as u64;
+                    self.update(|progress| {
+                        progress.blocks_scanned += window_end - start + 1;
+                        progress.approvals_sent += sent;
+                    });
+                    if window_end >= end {
+                        info!("finished rescanning transfers on {:?}", network_type);
+                        return Ok(Async::Ready(()));
+                    }
+                    self.next_window(window_end + 1, end)
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+impl<T: DuplexTransport + 'static> Future for RescanTransfers<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_rescan();
+        match result {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(())) => self.update(|progress| progress.state = RescanState::Completed),
+            Err(()) => self.update(|progress| progress.state = RescanState::Failed),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_should_drop_oldest_finished_jobs() {
+        // arrange
+        let mut jobs = RescanJobs::default();
+        let running = jobs.start(NetworkType::Home, 0, 10);
+        for _ in 0..=MAX_FINISHED_JOBS {
+            let job = jobs.start(NetworkType::Side, 0, 10);
+            job.write().unwrap().state = RescanState::Completed;
+        }
+        // act
+        let latest = jobs.start(NetworkType::Home, 5, 6);
+        // assert
+        let latest_id = latest.read().unwrap().id;
+        assert_eq!(jobs.get(1), Some(running.read().unwrap().clone()));
+        assert_eq!(jobs.get(2), None);
+        assert!(jobs.get(3).is_some());
+        assert_eq!(
+            jobs.get(latest_id).map(|job| (job.chain, job.from)),
+            Some(("home".to_string(), 5))
+        );
+    }
+}
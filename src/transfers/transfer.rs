@@ -1,28 +1,28 @@
+use serde_derive::{Deserialize, Serialize};
 use std::fmt;
 use tiny_keccak::keccak256;
-use web3::contract::tokens::Tokenize;
 use web3::futures::future::Future;
-use web3::types::{Address, TransactionReceipt, H256, U256, U64};
+use web3::types::{Address, Log, TransactionReceipt, H256, U256, U64};
 use web3::DuplexTransport;
 
 use crate::eth::transaction::SendTransaction;
 use crate::extensions::removed::{CancelRemoved, ExitOnLogRemoved};
-use crate::relay::Network;
+use crate::relay::{Network, TransferApprovalState};
+use crate::store::audit::AuditTransfer;
 use crate::transfers::withdrawal::{ApproveWithdrawal, DoesRequireApproval, UnapproveParams};
 
 /// Add CheckRemoved trait to SendTransaction, which is called by Transfer::approve_withdrawal
-impl<T, P> CancelRemoved<T, (), ()> for SendTransaction<T, P>
+impl<T> CancelRemoved<T, (), ()> for SendTransaction<T>
 where
     T: DuplexTransport + 'static,
-    P: Tokenize + Clone + 'static,
 {
-    fn cancel_removed(self, target: &Network<T>, tx_hash: H256) -> ExitOnLogRemoved<T, (), ()> {
-        ExitOnLogRemoved::new(target, tx_hash, Box::new(self))
+    fn cancel_removed(self, target: &Network<T>, withdrawal_hash: H256) -> ExitOnLogRemoved<T, (), ()> {
+        ExitOnLogRemoved::new(target, withdrawal_hash, Box::new(self))
     }
 }
 
 /// Represents a token transfer between two networks
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Transfer {
     pub destination: Address,
     pub amount: U256,
@@ -94,32 +94,83 @@ impl Transfer {
         ApproveWithdrawal::new(source, target, self)
     }
 
+    /// Returns a Future that will transaction with "unapprove_withdrawal" on the ERC20Relay contract
+    /// Marks the transfer as unapproved on the source once mined, and stops counting it in the source limits
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was removed
+    /// * `target` - Network where the withdrawal was approved
     pub fn unapprove_withdrawal<T: DuplexTransport + 'static>(
         &self,
+        source: &Network<T>,
         target: &Network<T>,
     ) -> impl Future<Item = (), Error = ()> {
         info!("unapproving withdrawal on {:?}: {} ", target.network_type, self);
+        let pending = source.pending.clone();
+        let limits = source.limits.clone();
+        let withdrawal_hash = self.get_withdrawal_hash();
         SendTransaction::new(
             target,
             "unapproveWithdrawal",
             &UnapproveParams::from(*self),
             target.retries,
         )
+        .audited(AuditTransfer::new(source.network_type, self, None))
+        .and_then(move |_| {
+            if let Ok(mut pending) = pending.write().map_err(|e| {
+                error!("Failed to acquire write lock {:?}", e);
+            }) {
+                // Don't clobber the state if the log was added back while unapproving
+                if pending.peek(&withdrawal_hash) == Some(&TransferApprovalState::Removed) {
+                    pending.put(withdrawal_hash, TransferApprovalState::Unapproved);
+                }
+            }
+            if let Ok(mut limits) = limits.write().map_err(|e| {
+                error!("Failed to acquire write lock {:?}", e);
+            }) {
+                limits.rollback(&withdrawal_hash);
+            }
+            Ok(())
+        })
         .or_else(|_| Ok(()))
     }
 
     pub fn get_withdrawal_hash(&self) -> H256 {
-        let tx_hash = self.tx_hash;
-        let block_hash = self.block_hash;
-        let block_number: &mut [u8] = &mut [0; 32];
-        // Must be 256 bit version of block number to match the hash
-        let block_number_256: U256 = self.block_number.as_u64().into();
-        block_number_256.to_big_endian(block_number);
-        let mut grouped: Vec<u8> = Vec::new();
-        grouped.extend_from_slice(&tx_hash.0);
-        grouped.extend_from_slice(&block_hash.0);
-        grouped.extend_from_slice(block_number);
-        H256(keccak256(&grouped[..]))
+        withdrawal_hash(self.tx_hash, self.block_hash, self.block_number)
+    }
+}
+
+/// Returns the hash the ERC20Relay contract uses to identify a withdrawal
+///
+/// # Arguments
+///
+/// * `tx_hash` - Hash of the transaction with the transfer
+/// * `block_hash` - Hash of the block with the transfer
+/// * `block_number` - Number of the block with the transfer
+pub fn withdrawal_hash(tx_hash: H256, block_hash: H256, block_number: U64) -> H256 {
+    let block_number_bytes: &mut [u8] = &mut [0; 32];
+    // Must be 256 bit version of block number to match the hash
+    let block_number_256: U256 = block_number.as_u64().into();
+    block_number_256.to_big_endian(block_number_bytes);
+    let mut grouped: Vec<u8> = Vec::new();
+    grouped.extend_from_slice(&tx_hash.0);
+    grouped.extend_from_slice(&block_hash.0);
+    grouped.extend_from_slice(block_number_bytes);
+    H256(keccak256(&grouped[..]))
+}
+
+/// Returns the withdrawal hash for a transfer log, if the log has been mined
+///
+/// # Arguments
+///
+/// * `log` - Transfer log
+pub fn log_withdrawal_hash(log: &Log) -> Option<H256> {
+    match (log.transaction_hash, log.block_hash, log.block_number) {
+        (Some(tx_hash), Some(block_hash), Some(block_number)) => {
+            Some(withdrawal_hash(tx_hash, block_hash, block_number))
+        }
+        _ => None,
     }
 }
 
@@ -1,5 +1,5 @@
 use ethabi::Token;
-use web3::contract::Options;
+use web3::contract::{Error as ContractError, Options};
 use web3::futures::prelude::*;
 use web3::futures::try_ready;
 use web3::types::{Address, BlockNumber, H256, U256};
@@ -8,13 +8,32 @@ use web3::DuplexTransport;
 use super::transfer::Transfer;
 use super::ApproveParams;
 use crate::eth::transaction::SendTransaction;
+use crate::eth::utils::is_rpc_revert;
 use crate::extensions::removed::CancelRemoved;
-use crate::relay::Network;
+use crate::metrics;
+use crate::relay::{Network, TransferApprovalState};
+use crate::store::audit::AuditTransfer;
+use crate::store::limits::unix_time;
+
+/// Returns true if a contract call failed because the contract reverted, rather than because the node could not be
+/// reached or did not answer
+///
+/// # Arguments
+///
+/// * `error` - Error returned for the call
+fn is_revert(error: &ContractError) -> bool {
+    match error {
+        ContractError::Api(e) => is_rpc_revert(e),
+        // Some nodes answer a reverted call with empty output, that does not decode
+        ContractError::InvalidOutputType(_) | ContractError::Abi(_) => true,
+        _ => false,
+    }
+}
 
 pub enum DoesRequireApprovalState {
     GetFees(Box<dyn Future<Item = U256, Error = ()>>),
     GetWithdrawal(U256, Box<dyn Future<Item = (Address, U256, bool), Error = ()>>),
-    GetWithdrawalApprovals(usize, GetWithdrawalApprovals),
+    GetApprovers(GetApprovers),
 }
 
 /// Future to check whether or not a withdrawal needs approval
@@ -104,8 +123,7 @@ impl<T: DuplexTransport + 'static> Future for DoesRequireApproval<T> {
                         } else {
                             debug!("transaction not processed on {:?} - checking approvers", network_type);
                             let approval_hash = self.transfer.get_withdrawal_hash();
-                            let approval_future = GetWithdrawalApprovals::new(&self.target, &approval_hash, &0.into());
-                            DoesRequireApprovalState::GetWithdrawalApprovals(0, approval_future)
+                            DoesRequireApprovalState::GetApprovers(GetApprovers::new(&self.target, &approval_hash))
                         }
                     } else {
                         error!(
@@ -115,29 +133,13 @@ impl<T: DuplexTransport + 'static> Future for DoesRequireApproval<T> {
                         return Ok(Async::Ready(false));
                     }
                 }
-                DoesRequireApprovalState::GetWithdrawalApprovals(index, ref mut future) => {
-                    let polled = future.poll();
-                    match polled {
-                        Ok(Async::Ready(approval)) => {
-                            let account = self.target.account;
-                            if approval == account {
-                                return Ok(Async::Ready(false));
-                            } else {
-                                let i = index + 1;
-                                let approval_hash = self.transfer.get_withdrawal_hash();
-                                let approval_future =
-                                    GetWithdrawalApprovals::new(&self.target, &approval_hash, &i.into());
-                                DoesRequireApprovalState::GetWithdrawalApprovals(i, approval_future)
-                            }
-                        }
-                        Ok(Async::NotReady) => {
-                            return Ok(Async::NotReady);
-                        }
-                        Err(()) => {
-                            debug!("have not approved transaction");
-                            return Ok(Async::Ready(true));
-                        }
+                DoesRequireApprovalState::GetApprovers(ref mut future) => {
+                    let approvers = try_ready!(future.poll());
+                    if approvers.contains(&self.target.account) {
+                        return Ok(Async::Ready(false));
                     }
+                    debug!("have not approved transaction");
+                    return Ok(Async::Ready(true));
                 }
             };
             self.state = next;
@@ -145,7 +147,9 @@ impl<T: DuplexTransport + 'static> Future for DoesRequireApproval<T> {
     }
 }
 
-pub struct GetWithdrawalApprovals(Box<dyn Future<Item = Address, Error = ()>>);
+/// Future that resolves to the approver of a withdrawal at an index, or None if the contract reverts because there
+/// is no approver at that index
+pub struct GetWithdrawalApprovals(Box<dyn Future<Item = Option<Address>, Error = ()>>);
 
 impl GetWithdrawalApprovals {
     pub fn new<T: DuplexTransport + 'static>(target: &Network<T>, approval_hash: &H256, index: &U256) -> Self {
@@ -163,12 +167,22 @@ impl GetWithdrawalApprovals {
                     Options::default(),
                     BlockNumber::Latest,
                 )
-                .map_err(move |_| {
-                    // We expect this error
-                    info!(
-                        "found all {} approvers for {:?} on {:?}",
-                        index, network_type, approval_hash
-                    );
+                .map(Some)
+                .or_else(move |e| {
+                    if is_revert(&e) {
+                        // We expect this error
+                        info!(
+                            "found all {} approvers for {:?} on {:?}",
+                            index, network_type, approval_hash
+                        );
+                        Ok(None)
+                    } else {
+                        error!(
+                            "error getting approver {} for {:?} on {:?}: {:?}",
+                            index, approval_hash, network_type, e
+                        );
+                        Err(())
+                    }
                 }),
         );
         GetWithdrawalApprovals(future)
@@ -176,7 +190,7 @@ impl GetWithdrawalApprovals {
 }
 
 impl Future for GetWithdrawalApprovals {
-    type Item = Address;
+    type Item = Option<Address>;
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -184,6 +198,54 @@ impl Future for GetWithdrawalApprovals {
     }
 }
 
+/// Future that collects every address that approved a withdrawal, in the order they approved.
+/// The contract has no count of approvals, so they are read one index at a time until the call reverts. Any other error
+/// fails the Future, rather than returning a partial list
+pub struct GetApprovers {
+    get: Box<dyn Fn(usize) -> GetWithdrawalApprovals>,
+    approvers: Vec<Address>,
+    future: GetWithdrawalApprovals,
+}
+
+impl GetApprovers {
+    /// Returns a newly created GetApprovers Future
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Network where the withdrawal is approved
+    /// * `approval_hash` - Withdrawal hash of the transfer
+    pub fn new<T: DuplexTransport + 'static>(target: &Network<T>, approval_hash: &H256) -> Self {
+        let target = target.clone();
+        let approval_hash = *approval_hash;
+        let get = move |index: usize| GetWithdrawalApprovals::new(&target, &approval_hash, &index.into());
+        let future = get(0);
+        GetApprovers {
+            get: Box::new(get),
+            approvers: Vec::new(),
+            future,
+        }
+    }
+}
+
+impl Future for GetApprovers {
+    type Item = Vec<Address>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.future.poll() {
+                Ok(Async::Ready(Some(approver))) => {
+                    self.approvers.push(approver);
+                    self.future = (self.get)(self.approvers.len());
+                }
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(self.approvers.drain(..).collect())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(()) => return Err(()),
+            }
+        }
+    }
+}
+
 pub struct WaitForWithdrawalProcessed<T: DuplexTransport + 'static> {
     target: Network<T>,
     transfer: Transfer,
@@ -259,6 +321,53 @@ impl<T: DuplexTransport + 'static> ApproveWithdrawal<T> {
         }
     }
 
+    /// Checks the transfer against the value limits of the source network, and holds it if it goes over one.
+    /// Returns true if the transfer is held
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was made
+    /// * `transfer` - Transfer about to be approved
+    fn hold_over_limit(source: &Network<T>, transfer: &Transfer) -> bool {
+        let network_type = source.network_type;
+        let now = unix_time();
+        let mut limits = match source.limits.write() {
+            Ok(limits) => limits,
+            Err(e) => {
+                error!("error acquiring limits lock on {:?}: {:?}", network_type, e);
+                return true;
+            }
+        };
+        let reason = match limits.check(transfer, now) {
+            Ok(()) => return false,
+            Err(reason) => reason,
+        };
+        warn!("holding transfer on {:?}, {}: {}", network_type, reason, transfer);
+        if limits.hold(transfer, &reason, now) {
+            metrics::transfer_held(network_type, limits.held().len());
+        }
+        match source.pending.write() {
+            Ok(mut pending) => pending.put(transfer.get_withdrawal_hash(), TransferApprovalState::Held),
+            Err(e) => error!("error acquiring pending lock on {:?}: {:?}", network_type, e),
+        }
+        true
+    }
+
+    /// Stops counting a transfer in the value limits of the source network, once its approval failed or was cancelled
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Network where the transfer was made
+    /// * `transfer` - Transfer that was not approved
+    fn rollback_limits(source: &Network<T>, transfer: &Transfer) {
+        match source.limits.write() {
+            Ok(mut limits) => {
+                limits.rollback(&transfer.get_withdrawal_hash());
+            }
+            Err(e) => error!("error acquiring limits lock on {:?}: {:?}", source.network_type, e),
+        }
+    }
+
     fn check_flushed(network: &Network<T>) -> Box<dyn Future<Item = U256, Error = ()>> {
         Box::new(
             network
@@ -310,6 +419,8 @@ impl<T: DuplexTransport + 'static> Future for ApproveWithdrawal<T> {
                     if fees >= transfer.amount {
                         warn!("transaction amount {} below fees {}", transfer.amount, fees);
                         return Ok(Async::Ready(()));
+                    } else if ApproveWithdrawal::hold_over_limit(&source, &transfer) {
+                        return Ok(Async::Ready(()));
                     } else {
                         let future = SendTransaction::new(
                             &target,
@@ -317,17 +428,26 @@ impl<T: DuplexTransport + 'static> Future for ApproveWithdrawal<T> {
                             &ApproveParams::from(transfer),
                             target.retries,
                         )
-                        .cancel_removed(&source, transfer.tx_hash);
+                        .audited(AuditTransfer::new(source.network_type, &transfer, Some(fees)))
+                        .cancel_removed(&source, transfer.get_withdrawal_hash());
                         ApproveWithdrawalState::SendTransaction(Box::new(future))
                     }
                 }
                 ApproveWithdrawalState::SendTransaction(ref mut future) => {
-                    let success = try_ready!(future.poll());
+                    let success = match future.poll() {
+                        Ok(Async::Ready(success)) => success,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(()) => {
+                            ApproveWithdrawal::rollback_limits(&source, &transfer);
+                            return Err(());
+                        }
+                    };
                     if success.is_none() {
                         warn!(
                             "log removed from originating chain while waiting on approval confirmations on target {:?}",
                             target.network_type
                         );
+                        ApproveWithdrawal::rollback_limits(&source, &transfer);
                     }
                     return Ok(Async::Ready(()));
                 }
@@ -337,3 +457,37 @@ impl<T: DuplexTransport + 'static> Future for ApproveWithdrawal<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::Error;
+
+    fn rpc_error(message: &str) -> ContractError {
+        ContractError::Api(Error::Rpc(jsonrpc_core::Error {
+            code: jsonrpc_core::ErrorCode::ServerError(-32000),
+            message: message.to_string(),
+            data: None,
+        }))
+    }
+
+    #[test]
+    fn is_revert_should_only_match_reverted_calls() {
+        // arrange
+        let reverted = rpc_error("execution reverted");
+        let invalid_opcode = rpc_error("VM Exception while processing transaction: invalid opcode");
+        let empty_output = ContractError::InvalidOutputType("Expected single element, got a list: []".to_string());
+        let rate_limited = rpc_error("daily request count exceeded, request rate limited");
+        let unreachable = ContractError::Api(Error::Unreachable);
+        // act
+        let results = vec![
+            is_revert(&reverted),
+            is_revert(&invalid_opcode),
+            is_revert(&empty_output),
+            is_revert(&rate_limited),
+            is_revert(&unreachable),
+        ];
+        // assert
+        assert_eq!(results, vec![true, true, true, false, false]);
+    }
+}